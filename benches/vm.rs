@@ -0,0 +1,98 @@
+//! Benchmarks of compute-heavy pavo code, mostly function calls and bindings.
+//!
+//! Run with `cargo bench`. The benchmarks only use the `Interpreter` api, so they also run
+//! against the vm before bindings moved into per-call registers (the parent of the commit that
+//! added this file): check that revision out into a separate worktree, copy this file into its
+//! `benches` directory, and run `cargo bench` in both trees on the same machine.
+//!
+//! Results (ns/iter, before → after) for fib, loop, patterns, closures and captured_mutation
+//! have not been recorded yet: they have to be measured on a machine that can build the crate
+//! and added here.
+
+#![feature(test)]
+
+extern crate test;
+
+use test::Bencher;
+
+use pavo_bootstrap::Interpreter;
+
+fn bench(b: &mut Bencher, src: &str) {
+    let mut interpreter = Interpreter::new();
+    interpreter.eval(src).unwrap();
+    b.iter(|| interpreter.eval(src).unwrap());
+}
+
+// Non-tail calls, each binding a single argument that is never captured.
+#[bench]
+fn bench_fib(b: &mut Bencher) {
+    bench(b, "
+    (sf-letfn {
+        fib ([n] (if (< n 2)
+            n
+            (int-add (fib (int-sub n 1)) (fib (int-sub n 2)))
+        ))
+    } (fib 18))
+    ");
+}
+
+// Tail calls updating multiple arguments.
+#[bench]
+fn bench_loop(b: &mut Bencher) {
+    bench(b, "
+    (sf-letfn {
+        sum ([acc n] (if (= n 0)
+            acc
+            (sum (int-add-wrap acc (int-mul-wrap n n)) (int-sub n 1))
+        ))
+    } (sum 0 20000))
+    ");
+}
+
+// Nested scopes created by pattern matching in every iteration.
+#[bench]
+fn bench_patterns(b: &mut Bencher) {
+    bench(b, "
+    (sf-letfn {
+        step ([state n] (if (= n 0)
+            state
+            (let [a b c] state
+                (let {:x x} {:x (int-add-wrap a b)}
+                    (step [b c x] (int-sub n 1))
+                )
+            )
+        ))
+    } (step [0 1 2] 10000))
+    ");
+}
+
+// Creating closures that capture bindings, and calling them.
+#[bench]
+fn bench_closures(b: &mut Bencher) {
+    bench(b, "
+    (sf-letfn {
+        adder ([x] (sf-lambda [y] (int-add-wrap x y)))
+        run ([acc n] (if (= n 0)
+            acc
+            (run ((adder n) acc) (int-sub n 1))
+        ))
+    } (run 0 10000))
+    ");
+}
+
+// Mutating a binding that a closure captured.
+#[bench]
+fn bench_captured_mutation(b: &mut Bencher) {
+    bench(b, "
+    (sf-letfn {
+        counter ([(:mut count)] [
+            (sf-lambda [] (sf-set! count (int-add count 1)))
+            (sf-lambda [] count)
+        ])
+        run ([inc n] (if (= n 0)
+            nil
+            (sf-do [(inc) (run inc (int-sub n 1))])
+        ))
+    } (let [inc get] (counter 0) (sf-do [(run inc 10000) (get)])))
+    ");
+}
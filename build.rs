@@ -0,0 +1,49 @@
+//! Computes a fingerprint of the interpreter's source code, which the cache of compiled required
+//! files (see `src/bytecode.rs`) stores in each entry. Any change to the code invalidates the
+//! cache, even if the version number stays the same.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+fn main() {
+    let mut files = vec![];
+    collect(Path::new("src"), &mut files).expect("failed to read the source directory");
+    files.push(Path::new("Cargo.toml").to_path_buf());
+    files.sort();
+
+    let mut bytes = vec![];
+    for file in files.iter() {
+        bytes.extend_from_slice(file.to_string_lossy().as_bytes());
+        bytes.push(0);
+        let contents = fs::read(file).expect("failed to read a source file");
+        bytes.extend_from_slice(&(contents.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&contents);
+        println!("cargo:rerun-if-changed={}", file.display());
+    }
+
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rustc-env=PAVO_BUILD_FINGERPRINT={:032x}", hash(&bytes));
+}
+
+fn collect(dir: &Path, files: &mut Vec<std::path::PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+// The 128 bit FNV-1a hash, like `bytecode::hash`.
+fn hash(bytes: &[u8]) -> u128 {
+    let mut h: u128 = 0x6c62272e07bb014262b821756295c58d;
+    for byte in bytes {
+        h ^= *byte as u128;
+        h = h.wrapping_mul(0x0000000001000000000000000000013b);
+    }
+    h
+}
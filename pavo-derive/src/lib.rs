@@ -0,0 +1,274 @@
+//! Derive macros for the `IntoValue` and `FromValue` traits of `pavo_bootstrap::convert`, see
+//! there for how rust data is represented as pavo values.
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{
+    parse_macro_input, parse_quote,
+    Data, DeriveInput, Error, Field, Fields, Generics, Ident, Index, TypeParamBound,
+};
+
+#[proc_macro_derive(IntoValue)]
+pub fn derive_into_value(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    if let Err(err) = check(&input, "IntoValue") {
+        return TokenStream::from(err.to_compile_error());
+    }
+
+    let name = &input.ident;
+    let generics = add_bounds(input.generics.clone(), parse_quote!(::pavo_bootstrap::IntoValue));
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let body = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => {
+                let keys = fields.named.iter().map(field_key);
+                let idents = fields.named.iter().map(|f| f.ident.clone().unwrap());
+                quote! {
+                    ::pavo_bootstrap::convert::keyword_map(vec![
+                        #((#keys, ::pavo_bootstrap::IntoValue::into_value(self.#idents))),*
+                    ])
+                }
+            }
+            Fields::Unnamed(fields) if fields.unnamed.len() == 1 => {
+                quote!(::pavo_bootstrap::IntoValue::into_value(self.0))
+            }
+            Fields::Unnamed(fields) => {
+                let indices = (0..fields.unnamed.len()).map(Index::from);
+                quote! {
+                    ::pavo_bootstrap::value::Value::arr_from_vec(vec![
+                        #(::pavo_bootstrap::IntoValue::into_value(self.#indices)),*
+                    ])
+                }
+            }
+            Fields::Unit => quote!(::pavo_bootstrap::value::Value::nil()),
+        },
+
+        Data::Enum(data) => {
+            let arms = data.variants.iter().map(|variant| {
+                let ident = &variant.ident;
+                let tag = variant_tag(ident);
+
+                match &variant.fields {
+                    Fields::Named(fields) => {
+                        let keys = fields.named.iter().map(field_key);
+                        let idents: Vec<Ident> = fields.named.iter()
+                            .map(|f| f.ident.clone().unwrap())
+                            .collect();
+                        let (bindings, values) = (&idents, &idents);
+                        quote! {
+                            #name::#ident { #(#bindings),* } => ::pavo_bootstrap::convert::tagged(
+                                #tag,
+                                vec![#((#keys, ::pavo_bootstrap::IntoValue::into_value(#values))),*]
+                            ),
+                        }
+                    }
+                    Fields::Unnamed(fields) => {
+                        let names = bindings(fields.unnamed.len());
+                        let (bindings, values) = (&names, &names);
+                        quote! {
+                            #name::#ident(#(#bindings),*) => ::pavo_bootstrap::convert::tagged(
+                                #tag,
+                                vec![("fields", ::pavo_bootstrap::value::Value::arr_from_vec(vec![
+                                    #(::pavo_bootstrap::IntoValue::into_value(#values)),*
+                                ]))]
+                            ),
+                        }
+                    }
+                    Fields::Unit => quote! {
+                        #name::#ident => ::pavo_bootstrap::convert::tagged(#tag, vec![]),
+                    },
+                }
+            });
+
+            quote! {
+                match self {
+                    #(#arms)*
+                }
+            }
+        }
+
+        Data::Union(_) => unreachable!(),
+    };
+
+    let expanded = quote! {
+        impl #impl_generics ::pavo_bootstrap::IntoValue for #name #ty_generics #where_clause {
+            fn into_value(self) -> ::pavo_bootstrap::value::Value {
+                #body
+            }
+        }
+    };
+
+    TokenStream::from(expanded)
+}
+
+#[proc_macro_derive(FromValue)]
+pub fn derive_from_value(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    if let Err(err) = check(&input, "FromValue") {
+        return TokenStream::from(err.to_compile_error());
+    }
+
+    let name = &input.ident;
+    let generics = add_bounds(input.generics.clone(), parse_quote!(::pavo_bootstrap::FromValue));
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let body = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => {
+                let keys = fields.named.iter().map(field_key);
+                let idents = fields.named.iter().map(|f| f.ident.clone().unwrap());
+                quote! {
+                    ::pavo_bootstrap::convert::expect_map(v)?;
+                    Ok(#name {
+                        #(#idents: ::pavo_bootstrap::convert::field(v, #keys)?),*
+                    })
+                }
+            }
+            Fields::Unnamed(fields) if fields.unnamed.len() == 1 => {
+                quote!(Ok(#name(::pavo_bootstrap::FromValue::from_value(v)?)))
+            }
+            Fields::Unnamed(fields) => {
+                let len = fields.unnamed.len();
+                let indices = 0..len;
+                quote! {
+                    ::pavo_bootstrap::convert::expect_arr(v, #len)?;
+                    Ok(#name(#(::pavo_bootstrap::convert::element(v, #indices)?),*))
+                }
+            }
+            Fields::Unit => quote! {
+                <() as ::pavo_bootstrap::FromValue>::from_value(v)?;
+                Ok(#name)
+            },
+        },
+
+        Data::Enum(data) => {
+            let tags: Vec<String> = data.variants.iter().map(|v| variant_tag(&v.ident)).collect();
+            let expected = format!(
+                "a map with the :tag {}",
+                tags.iter().map(|tag| format!(":{}", tag)).collect::<Vec<_>>().join(", ")
+            );
+
+            let arms = data.variants.iter().zip(tags.iter()).map(|(variant, tag)| {
+                let ident = &variant.ident;
+
+                match &variant.fields {
+                    Fields::Named(fields) => {
+                        let keys = fields.named.iter().map(field_key);
+                        let idents = fields.named.iter().map(|f| f.ident.clone().unwrap());
+                        quote! {
+                            #tag => Ok(#name::#ident {
+                                #(#idents: ::pavo_bootstrap::convert::field(v, #keys)?),*
+                            }),
+                        }
+                    }
+                    Fields::Unnamed(fields) => {
+                        let len = fields.unnamed.len();
+                        let indices = 0..len;
+                        quote! {
+                            #tag => {
+                                let fields: ::pavo_bootstrap::value::Value =
+                                    ::pavo_bootstrap::convert::field(v, "fields")?;
+                                ::pavo_bootstrap::convert::expect_arr(&fields, #len)
+                                    .map_err(|err| err.at(":fields"))?;
+                                Ok(#name::#ident(#(
+                                    ::pavo_bootstrap::convert::element(&fields, #indices)
+                                        .map_err(|err| err.at(":fields"))?
+                                ),*))
+                            }
+                        }
+                    }
+                    Fields::Unit => quote! {
+                        #tag => Ok(#name::#ident),
+                    },
+                }
+            });
+
+            quote! {
+                match ::pavo_bootstrap::convert::tag(v)? {
+                    #(#arms)*
+                    _ => Err(::pavo_bootstrap::FromValueError::new(#expected, v)),
+                }
+            }
+        }
+
+        Data::Union(_) => unreachable!(),
+    };
+
+    let expanded = quote! {
+        impl #impl_generics ::pavo_bootstrap::FromValue for #name #ty_generics #where_clause {
+            fn from_value(
+                v: &::pavo_bootstrap::value::Value
+            ) -> Result<Self, ::pavo_bootstrap::FromValueError> {
+                #body
+            }
+        }
+    };
+
+    TokenStream::from(expanded)
+}
+
+// Reject the types whose values could not be represented, see `pavo_bootstrap::convert`.
+fn check(input: &DeriveInput, trait_name: &str) -> Result<(), Error> {
+    match &input.data {
+        Data::Struct(_) => Ok(()),
+
+        Data::Enum(data) => {
+            for variant in data.variants.iter() {
+                if let Fields::Named(fields) = &variant.fields {
+                    for f in fields.named.iter() {
+                        if field_key(f) == "tag" {
+                            return Err(Error::new_spanned(
+                                f,
+                                "enum variants can not have a field named `tag`, it would collide with :tag",
+                            ));
+                        }
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        Data::Union(data) => Err(Error::new_spanned(
+            data.union_token,
+            format!("{} can not be derived for unions", trait_name),
+        )),
+    }
+}
+
+fn add_bounds(mut generics: Generics, bound: TypeParamBound) -> Generics {
+    for param in generics.type_params_mut() {
+        param.bounds.push(bound.clone());
+    }
+    generics
+}
+
+// Names for the fields of tuple variants.
+fn bindings(len: usize) -> Vec<Ident> {
+    (0..len).map(|i| Ident::new(&format!("field{}", i), Span::call_site())).collect()
+}
+
+// The map key of a named field: the field name in kebab-case, like `convert::field_keyword`.
+fn field_key(f: &Field) -> String {
+    let name = f.ident.as_ref().unwrap().to_string();
+    name.trim_start_matches("r#").replace('_', "-")
+}
+
+// The tag of an enum variant: the variant name in kebab-case, like `convert::variant_keyword`.
+fn variant_tag(ident: &Ident) -> String {
+    let mut tag = String::new();
+    for (i, c) in ident.to_string().chars().enumerate() {
+        if c.is_uppercase() {
+            if i > 0 {
+                tag.push('-');
+            }
+            tag.extend(c.to_lowercase());
+        } else {
+            tag.push(c);
+        }
+    }
+    tag
+}
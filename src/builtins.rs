@@ -6,7 +6,7 @@ use math::round;
 use nom::types::CompleteStr;
 use ryu_ecmascript::Buffer;
 
-use crate::context::Context;
+use crate::context::{Abort, Context, TraceEntry};
 use crate::gc_foreign::{OrdMap, OrdSet, Vector, Rope};
 use crate::value::{Value, Atomic, Id, Opaque, BuiltinOpaque, Builtin, self, Fun};
 use crate::read::{is_id_char, parse_id, read as read_};
@@ -38,6 +38,7 @@ pub fn typeof__(v: &Value) -> Value {
         Value::Cell(..) => Value::kw_str("cell"),
         Value::Opaque(_, Opaque::User(_, id)) => Value::Id(Id::Symbol(*id)),
         Value::Opaque(_, Opaque::Builtin(o)) => Value::Id(Id::Symbol(o.type_id())),
+        Value::Opaque(_, Opaque::Host(o)) => Value::Id(Id::Symbol(o.type_id())),
     }
 }
 
@@ -2471,6 +2472,7 @@ pub fn fun_arity(args: Vector<Value>, _cx: &mut Context) -> Result<Value, Value>
     let arity = match f {
         Fun::Opaque{..} => 1,
         Fun::Closure(ref c, _) => c.args.clone(),
+        Fun::Native(ref native, _) => native.arity,
         Fun::Builtin(Builtin::BoolNot) => 1,
         Fun::Builtin(Builtin::BoolAnd) => 2,
         Fun::Builtin(Builtin::BoolOr) => 2,
@@ -2710,6 +2712,7 @@ pub fn fun_arity(args: Vector<Value>, _cx: &mut Context) -> Result<Value, Value>
         Fun::Builtin(Builtin::Not) => 1,
         Fun::Builtin(Builtin::Diverge) => 1,
         Fun::Builtin(Builtin::Trace) => 1,
+        Fun::Builtin(Builtin::Backtrace) => 0,
 
         Fun::Builtin(Builtin::CursorArrNext) => 1,
         Fun::Builtin(Builtin::CursorArrPrev) => 1,
@@ -2924,7 +2927,19 @@ pub fn eval(args: Vector<Value>, cx: &mut Context) -> Result<Value, Value> {
     num_args(&args, 2)?;
     let v = &args.0[0];
     let map = map!(args.0[1]);
+    let env = eval_env(&map)?;
 
+    match compile_(v, &env, cx.optimizes()) {
+        Err(_) => return Err(static_error()),
+        Ok(c) => match c.compute(Vector(ImVector::new()), cx) {
+            Ok(yay) => return Ok(yay),
+            Err(err) => return Err(eval_error(err)),
+        }
+    }
+}
+
+/// The toplevel environment in which `eval` evaluates code, given its options.
+pub fn eval_env(map: &OrdMap<Value, Value>) -> Result<HashMap<Id, (Value, bool)>, Value> {
     let mut env = env::default();
 
     let remove = match map.0.get(&Value::kw_str("remove")) {
@@ -2951,20 +2966,24 @@ pub fn eval(args: Vector<Value>, cx: &mut Context) -> Result<Value, Value> {
         env.insert(id!(key), (val.clone(), false));
     }
 
-    match compile_(v, &env) {
-        Err(_) => return Err(static_error()),
-        Ok(c) => match c.compute(Vector(ImVector::new()), cx) {
-            Ok(yay) => return Ok(yay),
-            Err(err) => return Err(eval_error(err)),
-        }
-    }
+    return Ok(env);
 }
 
 pub fn expand(args: Vector<Value>, cx: &mut Context) -> Result<Value, Value> {
     num_args(&args, 2)?;
     let v = &args.0[0];
-    let map = map!(args.0[1]);
+    let (def_env, macro_env) = expand_env(&map!(args.0[1]))?;
+
+    match expand_(v, &def_env, &macro_env, cx) {
+        Err(_) => return Err(expand_error()),
+        Ok(yay) => return Ok(yay),
+    }
+}
 
+// The environment and the macros for expanding with the given options, see `expand`.
+pub fn expand_env(
+    map: &OrdMap<Value, Value>,
+) -> Result<(HashMap<Id, (Value, bool)>, ImOrdMap<Id, Value>), Value> {
     let mut def_env = env::default();
     let mut macro_env = macros::default();
 
@@ -3008,10 +3027,7 @@ pub fn expand(args: Vector<Value>, cx: &mut Context) -> Result<Value, Value> {
         macro_env.insert(id!(key), val.clone());
     }
 
-    match expand_(v, &def_env, &macro_env, cx) {
-        Err(_) => return Err(expand_error()),
-        Ok(yay) => return Ok(yay),
-    }
+    return Ok((def_env, macro_env));
 }
 
 pub fn exval(args: Vector<Value>, cx: &mut Context) -> Result<Value, Value> {
@@ -3087,11 +3103,10 @@ pub fn not(args: Vector<Value>, _cx: &mut Context) -> Result<Value, Value> {
     })
 }
 
-pub fn diverge(args: Vector<Value>, _cx: &mut Context) -> Result<Value, Value> {
+pub fn diverge(args: Vector<Value>, cx: &mut Context) -> Result<Value, Value> {
     num_args(&args, 1)?;
-    let mut buf = String::new();
-    value::debug_print(&args.0[0], 0, 2, &mut buf);
-    panic!("Called diverge: {}", buf);
+    cx.abort(Abort::Diverge(args.0[0].clone()));
+    Err(Value::nil())
 }
 
 pub fn trace(args: Vector<Value>, _cx: &mut Context) -> Result<Value, Value> {
@@ -3102,6 +3117,46 @@ pub fn trace(args: Vector<Value>, _cx: &mut Context) -> Result<Value, Value> {
     Ok(args.0[0].clone())
 }
 
+pub fn backtrace(args: Vector<Value>, cx: &mut Context) -> Result<Value, Value> {
+    num_args(&args, 0)?;
+    Ok(Value::arr_from_vec(cx.caught_trace().iter().map(trace_entry_to_value).collect()))
+}
+
+fn trace_entry_to_value(entry: &TraceEntry) -> Value {
+    let mut entries = vec![];
+
+    match entry {
+        TraceEntry::Closure { id: None, .. } => {
+            entries.push((Value::kw_str("tag"), Value::kw_str("toplevel")));
+        }
+        TraceEntry::Closure { id: Some(id), name, source, .. } => {
+            entries.push((Value::kw_str("tag"), Value::kw_str("closure")));
+            entries.push((Value::kw_str("id"), Value::int(*id as i64)));
+            if let Some(name) = name {
+                entries.push((Value::kw_str("name"), Value::id(name.clone())));
+            }
+            if let Some(source) = source {
+                entries.push((Value::kw_str("source"), source.clone()));
+            }
+        }
+        TraceEntry::Builtin(name) => {
+            entries.push((Value::kw_str("tag"), Value::kw_str("builtin")));
+            entries.push((Value::kw_str("name"), Value::string_from_str(name)));
+        }
+        TraceEntry::Native(id, name) => {
+            entries.push((Value::kw_str("tag"), Value::kw_str("native")));
+            entries.push((Value::kw_str("id"), Value::int(*id as i64)));
+            entries.push((Value::kw_str("name"), Value::string_from_str(name)));
+        }
+        TraceEntry::Opaque(id) => {
+            entries.push((Value::kw_str("tag"), Value::kw_str("opaque")));
+            entries.push((Value::kw_str("id"), Value::int(*id as i64)));
+        }
+    }
+
+    Value::map_from_vec(entries)
+}
+
 /////////////////////////////////////////////////////////////////////////////
 
 fn macro_do_(args: Vector<Value>, _cx: &mut Context) -> Result<Value, Value> {
@@ -0,0 +1,965 @@
+//! An on-disk cache of the expanded code and the compiled ir of required files, see
+//! `Context::set_cache_dir`.
+//!
+//! Each cache file holds an `Entry`: the expansion of a file's contents under some expand
+//! options, and the chunks it has been compiled to in different environments. A file is named
+//! after a hash of the contents and the options, and also stores them along with a fingerprint of
+//! the interpreter's source code (computed by `build.rs`), so that an entry is ignored whenever
+//! the source, the options or the interpreter change.
+//!
+//! Entries end with a checksum, and the decoded ir is checked to only refer to blocks, registers
+//! and boxes that exist. Corrupt entries are ignored like missing ones, so the file is expanded
+//! and compiled again instead.
+//!
+//! The expansion of a file may create symbols. These are stored relative to the value of the
+//! symbol counter when the expansion started, and are mapped to fresh symbols when loading the
+//! entry, so that loading gives exactly the same results as expanding the file again.
+
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use gc::Gc;
+
+use crate::gc_foreign::{NotNan, Rope, Vector, OrdSet, OrdMap};
+use crate::read::{Position, SourceMap, Span};
+use crate::value::{Value, Atomic, Id};
+use crate::vm::{Addr, Arm, BBId, BB_RETURN, Instruction, IrChunk, Test};
+
+// Changes whenever the encoding changes, independent of the interpreter version.
+const FORMAT_VERSION: u64 = 3;
+const MAGIC: &[u8] = b"pavo-bytecode\n";
+
+/// The 128 bit FNV-1a hash of some bytes.
+pub fn hash(bytes: &[u8]) -> u128 {
+    let mut h: u128 = 0x6c62272e07bb014262b821756295c58d;
+    for byte in bytes {
+        h ^= *byte as u128;
+        h = h.wrapping_mul(0x0000000001000000000000000000013b);
+    }
+    h
+}
+
+/// A value that can not be stored in the cache: functions, cells, opaque values, and symbols
+/// that have not been created by the expansion.
+#[derive(Debug)]
+pub struct Unsupported;
+
+/// The cached results for the contents of a file and some expand options.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    // Identifies the entry, see `Entry::new`.
+    content: u128,
+    options: Vec<u8>,
+    /// The value of the symbol counter before the expansion.
+    pub symbol_base: u64,
+    /// How many symbols the expansion created.
+    pub symbols: u64,
+    /// How many function ids the expansion used.
+    pub funs: u64,
+    /// How many cell ids the expansion used.
+    pub cells: u64,
+    /// How many steps the expansion took, see `Context::set_fuel`.
+    pub steps: u64,
+    pub expanded: Value,
+    /// The spans of the expanded code, see `expand::expand_mapped`.
+    pub source_map: SourceMap,
+    chunks: Vec<Chunk>,
+}
+
+// The chunk that the expanded code compiled to in an environment.
+#[derive(Debug, Clone)]
+struct Chunk {
+    // The encoded names and mutability of the bindings of the environment, see `signature`.
+    env: Vec<u8>,
+    optimize: bool,
+    ir: Gc<IrChunk>,
+}
+
+/// Encode the names of the bindings of an environment and whether they are mutable, which is all
+/// that compilation depends on.
+pub fn signature<'a, I>(env: I) -> Result<Vec<u8>, Unsupported>
+where
+    I: Iterator<Item = (&'a Id, bool)>,
+{
+    let mut names: Vec<(Value, Value)> = env
+        .map(|(id, mutable)| (Value::id(id.clone()), Value::bool_(mutable)))
+        .collect();
+    names.sort();
+
+    let mut w = Writer::new(0, 0);
+    w.value(&Value::map_from_vec(names))?;
+    Ok(w.buf)
+}
+
+impl Entry {
+    /// Create an entry for the expansion of a file whose contents have the given `hash`, which
+    /// created the symbols `symbol_base` to `symbol_base + symbols` and took `steps` steps. Fails
+    /// if the options can not be stored.
+    pub fn new(
+        content: u128,
+        options: &Value,
+        expanded: Value,
+        source_map: SourceMap,
+        symbol_base: u64,
+        symbols: u64,
+        funs: u64,
+        cells: u64,
+        steps: u64,
+    ) -> Result<Entry, Unsupported> {
+        let mut w = Writer::new(0, 0);
+        w.value(options)?;
+
+        Ok(Entry {
+            content,
+            options: w.buf,
+            symbol_base,
+            symbols,
+            funs,
+            cells,
+            steps,
+            expanded,
+            source_map,
+            chunks: vec![],
+        })
+    }
+
+    /// The chunk the expanded code compiled to in an environment with the given signature.
+    pub fn chunk(&self, env: &[u8], optimize: bool) -> Option<Gc<IrChunk>> {
+        self.chunks
+            .iter()
+            .find(|chunk| chunk.env == env && chunk.optimize == optimize)
+            .map(|chunk| chunk.ir.clone())
+    }
+
+    pub fn add_chunk(&mut self, env: Vec<u8>, optimize: bool, ir: Gc<IrChunk>) {
+        self.chunks.push(Chunk { env, optimize, ir });
+    }
+
+    fn encode(&self) -> Result<Vec<u8>, Unsupported> {
+        let mut w = Writer::new(self.symbol_base, self.symbols);
+        w.buf.extend_from_slice(MAGIC);
+        w.uint(FORMAT_VERSION);
+        w.str(env!("PAVO_BUILD_FINGERPRINT"));
+        w.buf.extend_from_slice(&self.content.to_le_bytes());
+        w.bytes(&self.options);
+
+        w.uint(self.symbols);
+        w.uint(self.funs);
+        w.uint(self.cells);
+        w.uint(self.steps);
+        w.value(&self.expanded)?;
+        w.source_map(&self.source_map);
+
+        w.uint(self.chunks.len() as u64);
+        for chunk in self.chunks.iter() {
+            w.bytes(&chunk.env);
+            w.bool_(chunk.optimize);
+            w.chunk(&chunk.ir)?;
+        }
+
+        let checksum = hash(&w.buf);
+        w.buf.extend_from_slice(&checksum.to_le_bytes());
+        Ok(w.buf)
+    }
+
+    // Decode an entry, returning `None` if it is malformed, corrupt or does not belong to the
+    // contents and options. The symbols of the entry are mapped to those starting at
+    // `symbol_base`.
+    fn decode(buf: &[u8], contents: u128, options: &[u8], symbol_base: u64) -> Option<Entry> {
+        if buf.len() < 16 {
+            return None;
+        }
+        let (buf, checksum) = buf.split_at(buf.len() - 16);
+        let mut expected = [0; 16];
+        expected.copy_from_slice(checksum);
+        if hash(buf) != u128::from_le_bytes(expected) {
+            return None;
+        }
+
+        let mut r = Reader { buf, pos: 0, symbol_base, symbols: 0 };
+
+        if r.take(MAGIC.len())? != MAGIC
+            || r.uint()? != FORMAT_VERSION
+            || r.str()? != env!("PAVO_BUILD_FINGERPRINT") {
+            return None;
+        }
+        let mut content = [0; 16];
+        content.copy_from_slice(r.take(16)?);
+        if u128::from_le_bytes(content) != contents || r.bytes()? != options {
+            return None;
+        }
+
+        let symbols = r.uint()?;
+        r.symbols = symbols;
+        let funs = r.uint()?;
+        let cells = r.uint()?;
+        let steps = r.uint()?;
+        let expanded = r.value()?;
+        let source_map = r.source_map()?;
+
+        let mut chunks = vec![];
+        for _ in 0..r.uint()? {
+            let env = r.bytes()?.to_vec();
+            let optimize = r.bool_()?;
+            let ir = Gc::new(r.chunk()?);
+            chunks.push(Chunk { env, optimize, ir });
+        }
+
+        if r.pos != buf.len() {
+            return None;
+        }
+
+        Some(Entry {
+            content: contents,
+            options: options.to_vec(),
+            symbol_base,
+            symbols,
+            funs,
+            cells,
+            steps,
+            expanded,
+            source_map,
+            chunks,
+        })
+    }
+}
+
+/// The cache files in a directory.
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    pub fn new(dir: PathBuf) -> Cache {
+        Cache { dir }
+    }
+
+    fn file(&self, content: u128, options: &[u8]) -> PathBuf {
+        let mut key = content.to_le_bytes().to_vec();
+        key.extend_from_slice(options);
+        self.dir.join(format!("{:032x}.pvc", hash(&key)))
+    }
+
+    /// Load the entry for a file whose contents have the given `hash` and for the expand options,
+    /// mapping the symbols it contains to those starting at `symbol_base`. Returns `None` if there
+    /// is no valid entry.
+    pub fn load(&self, content: u128, options: &Value, symbol_base: u64) -> Option<Entry> {
+        let mut w = Writer::new(0, 0);
+        w.value(options).ok()?;
+
+        let mut buf = vec![];
+        File::open(self.file(content, &w.buf)).ok()?.read_to_end(&mut buf).ok()?;
+        Entry::decode(&buf, content, &w.buf, symbol_base)
+    }
+
+    /// Write an entry to the cache, replacing any previous entry for the same contents and
+    /// options. Failing to do so is not an error, the results are just not cached.
+    pub fn store(&self, entry: &Entry) {
+        if let Ok(buf) = entry.encode() {
+            let path = self.file(entry.content, &entry.options);
+            let _ = write_atomically(&self.dir, &path, &buf);
+        }
+    }
+}
+
+// Write to a temporary file first, so that concurrent runs never see a partial entry.
+fn write_atomically(dir: &Path, path: &Path, buf: &[u8]) -> std::io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+    File::create(&tmp)?.write_all(buf)?;
+    fs::rename(&tmp, path)
+}
+
+struct Writer {
+    buf: Vec<u8>,
+    // The symbols that may be written, see `Entry::symbol_base`.
+    symbol_base: u64,
+    symbols: u64,
+}
+
+impl Writer {
+    fn new(symbol_base: u64, symbols: u64) -> Writer {
+        Writer { buf: vec![], symbol_base, symbols }
+    }
+
+    // LEB128
+    fn uint(&mut self, mut n: u64) {
+        loop {
+            let byte = (n & 0x7f) as u8;
+            n >>= 7;
+            if n == 0 {
+                self.buf.push(byte);
+                return;
+            }
+            self.buf.push(byte | 0x80);
+        }
+    }
+
+    fn bool_(&mut self, b: bool) {
+        self.buf.push(b as u8);
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.uint(bytes.len() as u64);
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn str(&mut self, s: &str) {
+        self.bytes(s.as_bytes());
+    }
+
+    fn bb(&mut self, bb: BBId) {
+        // Shifted by one so that `BB_RETURN` is encoded as zero.
+        self.uint(if bb == BB_RETURN { 0 } else { bb as u64 + 1 });
+    }
+
+    fn id(&mut self, id: &Id) -> Result<(), Unsupported> {
+        match id {
+            Id::User(name) => {
+                self.buf.push(0);
+                self.str(name);
+            }
+            Id::Symbol(n) => {
+                if *n < self.symbol_base || *n - self.symbol_base >= self.symbols {
+                    return Err(Unsupported);
+                }
+                self.buf.push(1);
+                self.uint(*n - self.symbol_base);
+            }
+        }
+        Ok(())
+    }
+
+    fn value(&mut self, v: &Value) -> Result<(), Unsupported> {
+        match v {
+            Value::Atomic(Atomic::Nil) => self.buf.push(0),
+            Value::Atomic(Atomic::Bool(false)) => self.buf.push(1),
+            Value::Atomic(Atomic::Bool(true)) => self.buf.push(2),
+            Value::Atomic(Atomic::Int(n)) => {
+                self.buf.push(3);
+                self.buf.extend_from_slice(&n.to_le_bytes());
+            }
+            Value::Atomic(Atomic::Float(n)) => {
+                self.buf.push(4);
+                self.buf.extend_from_slice(&n.clone().into_inner().to_bits().to_le_bytes());
+            }
+            Value::Atomic(Atomic::Keyword(kw)) => {
+                self.buf.push(5);
+                self.str(kw);
+            }
+            Value::Atomic(Atomic::Char(c)) => {
+                self.buf.push(6);
+                self.uint(*c as u64);
+            }
+            Value::Atomic(Atomic::String(s)) => {
+                self.buf.push(7);
+                self.str(&s.0.to_string());
+            }
+            Value::Atomic(Atomic::Bytes(b)) => {
+                self.buf.push(8);
+                self.bytes(&b.0.iter().cloned().collect::<Vec<u8>>());
+            }
+            Value::Id(id) => {
+                self.buf.push(9);
+                self.id(id)?;
+            }
+            Value::Arr(arr) => {
+                self.buf.push(10);
+                self.values(arr.0.iter())?;
+            }
+            Value::App(app) => {
+                self.buf.push(11);
+                self.values(app.0.iter())?;
+            }
+            Value::Set(set) => {
+                self.buf.push(12);
+                self.values(set.0.iter())?;
+            }
+            Value::Map(map) => {
+                self.buf.push(13);
+                self.uint(map.0.len() as u64);
+                for (key, val) in map.0.iter() {
+                    self.value(key)?;
+                    self.value(val)?;
+                }
+            }
+            Value::Fun(_) | Value::Cell(..) | Value::Opaque(..) => return Err(Unsupported),
+        }
+        Ok(())
+    }
+
+    fn values<'a, I>(&mut self, vals: I) -> Result<(), Unsupported>
+    where
+        I: ExactSizeIterator<Item = &'a Value>,
+    {
+        self.uint(vals.len() as u64);
+        for v in vals {
+            self.value(v)?;
+        }
+        Ok(())
+    }
+
+    fn option_value(&mut self, v: &Option<Value>) -> Result<(), Unsupported> {
+        match v {
+            None => self.bool_(false),
+            Some(v) => {
+                self.bool_(true);
+                self.value(v)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn position(&mut self, pos: &Position) {
+        self.uint(pos.offset as u64);
+        self.uint(pos.line as u64);
+        self.uint(pos.column as u64);
+    }
+
+    fn option_span(&mut self, span: &Option<Span>) {
+        match span {
+            None => self.bool_(false),
+            Some(span) => {
+                self.bool_(true);
+                self.position(&span.start);
+                self.position(&span.end);
+            }
+        }
+    }
+
+    fn source_map(&mut self, map: &SourceMap) {
+        self.option_span(&map.span);
+        self.uint(map.children().len() as u64);
+        for child in map.children() {
+            self.source_map(child);
+        }
+    }
+
+    fn ids(&mut self, ids: &[Id]) -> Result<(), Unsupported> {
+        self.uint(ids.len() as u64);
+        for id in ids {
+            self.id(id)?;
+        }
+        Ok(())
+    }
+
+    fn addr(&mut self, addr: &Addr) {
+        match addr {
+            Addr::Stack => self.buf.push(0),
+            Addr::Local(i) => {
+                self.buf.push(1);
+                self.uint(*i as u64);
+            }
+            Addr::Boxed(i) => {
+                self.buf.push(2);
+                self.uint(*i as u64);
+            }
+            Addr::Captured(i) => {
+                self.buf.push(3);
+                self.uint(*i as u64);
+            }
+        }
+    }
+
+    fn addrs(&mut self, addrs: &[Addr]) {
+        self.uint(addrs.len() as u64);
+        for addr in addrs {
+            self.addr(addr);
+        }
+    }
+
+    fn test(&mut self, test: &Test) -> Result<(), Unsupported> {
+        match test {
+            Test::Atomic(a) => {
+                self.buf.push(0);
+                self.value(&Value::Atomic(a.clone()))?;
+            }
+            Test::Set(set) => {
+                self.buf.push(1);
+                self.value(&Value::set(set.clone()))?;
+            }
+            Test::Arr(len) => {
+                self.buf.push(2);
+                self.uint(*len as u64);
+            }
+            Test::App(len) => {
+                self.buf.push(3);
+                self.uint(*len as u64);
+            }
+            Test::Map => self.buf.push(4),
+            Test::HasKey(key) => {
+                self.buf.push(5);
+                self.value(key)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn instruction(&mut self, instruction: &Instruction) -> Result<(), Unsupported> {
+        match instruction {
+            Instruction::Literal(v) => {
+                self.buf.push(0);
+                self.value(v)?;
+            }
+            Instruction::Arr(count) => {
+                self.buf.push(1);
+                self.uint(*count as u64);
+            }
+            Instruction::App(count) => {
+                self.buf.push(2);
+                self.uint(*count as u64);
+            }
+            Instruction::Set(count) => {
+                self.buf.push(3);
+                self.uint(*count as u64);
+            }
+            Instruction::Map(count) => {
+                self.buf.push(4);
+                self.uint(*count as u64);
+            }
+            Instruction::FunLiteral(chunk, args, captures) => {
+                self.buf.push(5);
+                self.chunk(chunk)?;
+                self.uint(*args as u64);
+                self.addrs(captures);
+            }
+            Instruction::Jump(bb) => {
+                self.buf.push(6);
+                self.bb(*bb);
+            }
+            Instruction::CondJump(yay, nay) => {
+                self.buf.push(7);
+                self.bb(*yay);
+                self.bb(*nay);
+            }
+            Instruction::Switch(addr, arms, default) => {
+                self.buf.push(8);
+                self.addr(addr);
+                self.uint(arms.len() as u64);
+                for arm in arms {
+                    self.test(&arm.test)?;
+                    self.bb(arm.target);
+                }
+                self.bb(*default);
+            }
+            Instruction::Item(from, index, to) => {
+                self.buf.push(9);
+                self.addr(from);
+                self.uint(*index as u64);
+                self.addr(to);
+            }
+            Instruction::Entry(from, key, to) => {
+                self.buf.push(10);
+                self.addr(from);
+                self.value(key)?;
+                self.addr(to);
+            }
+            Instruction::Throw => self.buf.push(11),
+            Instruction::SetCatchHandler(bb) => {
+                self.buf.push(12);
+                self.bb(*bb);
+            }
+            Instruction::Push(addr) => {
+                self.buf.push(13);
+                self.addr(addr);
+            }
+            Instruction::Pop(addr) => {
+                self.buf.push(14);
+                self.addr(addr);
+            }
+            Instruction::DoubleTop => self.buf.push(15),
+            Instruction::DropTop => self.buf.push(16),
+            Instruction::Call(count, push) => {
+                self.buf.push(17);
+                self.uint(*count as u64);
+                self.bool_(*push);
+            }
+            Instruction::TailCall(count, push) => {
+                self.buf.push(18);
+                self.uint(*count as u64);
+                self.bool_(*push);
+            }
+        }
+        Ok(())
+    }
+
+    fn chunk(&mut self, chunk: &IrChunk) -> Result<(), Unsupported> {
+        self.uint(chunk.basic_blocks.len() as u64);
+        for block in chunk.basic_blocks.iter() {
+            self.uint(block.len() as u64);
+            for instruction in block.iter() {
+                self.instruction(instruction)?;
+            }
+        }
+
+        match &chunk.name {
+            None => self.bool_(false),
+            Some(name) => {
+                self.bool_(true);
+                self.id(name)?;
+            }
+        }
+        self.option_value(&chunk.source)?;
+        self.option_span(&chunk.span);
+        match &chunk.module {
+            None => self.bool_(false),
+            Some(module) => {
+                self.bool_(true);
+                self.str(module);
+            }
+        }
+        self.ids(&chunk.locals)?;
+        self.ids(&chunk.boxes)?;
+        self.ids(&chunk.captures)?;
+        self.addrs(&chunk.params);
+
+        for source in chunk.block_sources.iter() {
+            self.option_span(source);
+        }
+
+        self.uint(chunk.branches.len() as u64);
+        for branches in chunk.branches.iter() {
+            self.uint(branches.len() as u64);
+            for bb in branches.iter() {
+                self.bb(*bb);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Every method returns `None` if the input is malformed.
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    // The symbols of the entry are mapped to `symbol_base` to `symbol_base + symbols`.
+    symbol_base: u64,
+    symbols: u64,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.buf.len() - self.pos < n {
+            return None;
+        }
+        self.pos += n;
+        Some(&self.buf[self.pos - n..self.pos])
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn uint(&mut self) -> Option<u64> {
+        let mut n: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            n |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Some(n);
+            }
+        }
+        None
+    }
+
+    fn usize(&mut self) -> Option<usize> {
+        let n = self.uint()?;
+        if n > std::usize::MAX as u64 { None } else { Some(n as usize) }
+    }
+
+    // The length of a sequence, checked against the remaining input so that malformed input can
+    // not trigger huge allocations.
+    fn len(&mut self) -> Option<usize> {
+        let n = self.usize()?;
+        if n > self.buf.len() - self.pos { None } else { Some(n) }
+    }
+
+    fn bool_(&mut self) -> Option<bool> {
+        match self.byte()? {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+
+    fn bytes(&mut self) -> Option<&'a [u8]> {
+        let len = self.len()?;
+        self.take(len)
+    }
+
+    fn str(&mut self) -> Option<&'a str> {
+        std::str::from_utf8(self.bytes()?).ok()
+    }
+
+    fn bb(&mut self) -> Option<BBId> {
+        match self.usize()? {
+            0 => Some(BB_RETURN),
+            bb => Some(bb - 1),
+        }
+    }
+
+    fn id(&mut self) -> Option<Id> {
+        match self.byte()? {
+            0 => Some(Id::user(self.str()?)),
+            1 => {
+                let n = self.uint()?;
+                if n >= self.symbols {
+                    return None;
+                }
+                Some(Id::Symbol(self.symbol_base + n))
+            }
+            _ => None,
+        }
+    }
+
+    fn value(&mut self) -> Option<Value> {
+        Some(match self.byte()? {
+            0 => Value::nil(),
+            1 => Value::bool_(false),
+            2 => Value::bool_(true),
+            3 => {
+                let mut n = [0; 8];
+                n.copy_from_slice(self.take(8)?);
+                Value::int(i64::from_le_bytes(n))
+            }
+            4 => {
+                let mut n = [0; 8];
+                n.copy_from_slice(self.take(8)?);
+                let n = f64::from_bits(u64::from_le_bytes(n));
+                if !n.is_finite() {
+                    return None;
+                }
+                Value::Atomic(Atomic::Float(unsafe { NotNan::unchecked_new(n) }))
+            }
+            5 => Value::kw_str(self.str()?),
+            6 => {
+                let c = self.uint()?;
+                if c > std::u32::MAX as u64 {
+                    return None;
+                }
+                Value::char_(std::char::from_u32(c as u32)?)
+            }
+            7 => Value::string(Rope(ropey::Rope::from_str(self.str()?))),
+            8 => Value::bytes_from_vec(self.bytes()?.to_vec()),
+            9 => Value::id(self.id()?),
+            10 => Value::arr(Vector(self.values()?.into_iter().collect())),
+            11 => Value::app(Vector(self.values()?.into_iter().collect())),
+            12 => Value::set(OrdSet(self.values()?.into_iter().collect())),
+            13 => {
+                let mut entries = vec![];
+                for _ in 0..self.len()? {
+                    entries.push((self.value()?, self.value()?));
+                }
+                Value::map(OrdMap(entries.into_iter().collect()))
+            }
+            _ => return None,
+        })
+    }
+
+    fn values(&mut self) -> Option<Vec<Value>> {
+        let mut vals = vec![];
+        for _ in 0..self.len()? {
+            vals.push(self.value()?);
+        }
+        Some(vals)
+    }
+
+    fn option_value(&mut self) -> Option<Option<Value>> {
+        if self.bool_()? { Some(Some(self.value()?)) } else { Some(None) }
+    }
+
+    fn position(&mut self) -> Option<Position> {
+        let offset = self.usize()?;
+        let line = self.uint()?;
+        if line > std::u32::MAX as u64 {
+            return None;
+        }
+        let column = self.usize()?;
+        Some(Position { offset, line: line as u32, column })
+    }
+
+    fn option_span(&mut self) -> Option<Option<Span>> {
+        if self.bool_()? {
+            Some(Some(Span { start: self.position()?, end: self.position()? }))
+        } else {
+            Some(None)
+        }
+    }
+
+    fn source_map(&mut self) -> Option<SourceMap> {
+        let span = self.option_span()?;
+        let mut children = vec![];
+        for _ in 0..self.len()? {
+            children.push(self.source_map()?);
+        }
+        Some(SourceMap::new(span, children))
+    }
+
+    fn ids(&mut self) -> Option<Vec<Id>> {
+        let mut ids = vec![];
+        for _ in 0..self.len()? {
+            ids.push(self.id()?);
+        }
+        Some(ids)
+    }
+
+    fn addr(&mut self) -> Option<Addr> {
+        Some(match self.byte()? {
+            0 => Addr::Stack,
+            1 => Addr::Local(self.usize()?),
+            2 => Addr::Boxed(self.usize()?),
+            3 => Addr::Captured(self.usize()?),
+            _ => return None,
+        })
+    }
+
+    fn addrs(&mut self) -> Option<Vec<Addr>> {
+        let mut addrs = vec![];
+        for _ in 0..self.len()? {
+            addrs.push(self.addr()?);
+        }
+        Some(addrs)
+    }
+
+    fn test(&mut self) -> Option<Test> {
+        Some(match self.byte()? {
+            0 => match self.value()? {
+                Value::Atomic(a) => Test::Atomic(a),
+                _ => return None,
+            },
+            1 => match self.value()? {
+                Value::Set(set) => Test::Set(set),
+                _ => return None,
+            },
+            2 => Test::Arr(self.usize()?),
+            3 => Test::App(self.usize()?),
+            4 => Test::Map,
+            5 => Test::HasKey(self.value()?),
+            _ => return None,
+        })
+    }
+
+    fn instruction(&mut self) -> Option<Instruction> {
+        Some(match self.byte()? {
+            0 => Instruction::Literal(self.value()?),
+            1 => Instruction::Arr(self.usize()?),
+            2 => Instruction::App(self.usize()?),
+            3 => Instruction::Set(self.usize()?),
+            4 => Instruction::Map(self.usize()?),
+            5 => {
+                let chunk = Gc::new(self.chunk()?);
+                Instruction::FunLiteral(chunk, self.usize()?, self.addrs()?)
+            }
+            6 => Instruction::Jump(self.bb()?),
+            7 => Instruction::CondJump(self.bb()?, self.bb()?),
+            8 => {
+                let addr = self.addr()?;
+                let mut arms = vec![];
+                for _ in 0..self.len()? {
+                    arms.push(Arm { test: self.test()?, target: self.bb()? });
+                }
+                Instruction::Switch(addr, arms, self.bb()?)
+            }
+            9 => Instruction::Item(self.addr()?, self.usize()?, self.addr()?),
+            10 => Instruction::Entry(self.addr()?, self.value()?, self.addr()?),
+            11 => Instruction::Throw,
+            12 => Instruction::SetCatchHandler(self.bb()?),
+            13 => Instruction::Push(self.addr()?),
+            14 => Instruction::Pop(self.addr()?),
+            15 => Instruction::DoubleTop,
+            16 => Instruction::DropTop,
+            17 => Instruction::Call(self.usize()?, self.bool_()?),
+            18 => Instruction::TailCall(self.usize()?, self.bool_()?),
+            _ => return None,
+        })
+    }
+
+    fn chunk(&mut self) -> Option<IrChunk> {
+        let mut basic_blocks = vec![];
+        for _ in 0..self.len()? {
+            let mut block = vec![];
+            for _ in 0..self.len()? {
+                block.push(self.instruction()?);
+            }
+            basic_blocks.push(block);
+        }
+
+        let name = if self.bool_()? { Some(self.id()?) } else { None };
+        let source = self.option_value()?;
+        let span = self.option_span()?;
+        let module = if self.bool_()? { Some(self.str()?.to_string()) } else { None };
+        let locals = self.ids()?;
+        let boxes = self.ids()?;
+        let captures = self.ids()?;
+        let params = self.addrs()?;
+
+        let mut block_sources = vec![];
+        for _ in 0..basic_blocks.len() {
+            block_sources.push(self.option_span()?);
+        }
+
+        let mut branches = vec![];
+        for _ in 0..self.len()? {
+            let mut bbs = vec![];
+            for _ in 0..self.len()? {
+                bbs.push(self.bb()?);
+            }
+            branches.push(bbs);
+        }
+
+        let chunk = IrChunk {
+            basic_blocks,
+            name,
+            source,
+            span,
+            module,
+            locals,
+            boxes,
+            captures,
+            params,
+            block_sources,
+            branches,
+        };
+        if valid_chunk(&chunk) { Some(chunk) } else { None }
+    }
+}
+
+// Whether the instructions of a decoded chunk only refer to blocks, registers and boxes that
+// exist, so that running it can not index out of bounds. Nested chunks have already been checked
+// when they were decoded.
+fn valid_chunk(chunk: &IrChunk) -> bool {
+    let block = |bb: &BBId| *bb < chunk.basic_blocks.len();
+    // Only jumps and catch handlers may target `BB_RETURN`.
+    let target = |bb: &BBId| *bb == BB_RETURN || block(bb);
+    let addr = |addr: &Addr| match addr {
+        Addr::Stack => true,
+        Addr::Local(i) => *i < chunk.locals.len(),
+        Addr::Boxed(i) => *i < chunk.boxes.len(),
+        Addr::Captured(i) => *i < chunk.captures.len(),
+    };
+    let capture = |captured: &Addr| match captured {
+        Addr::Boxed(_) | Addr::Captured(_) => addr(captured),
+        _ => false,
+    };
+
+    let instruction = |instruction: &Instruction| match instruction {
+        Instruction::FunLiteral(inner, _, captures) => {
+            captures.len() == inner.captures.len() && captures.iter().all(capture)
+        }
+        Instruction::Jump(bb) | Instruction::SetCatchHandler(bb) => target(bb),
+        Instruction::CondJump(yay, nay) => block(yay) && block(nay),
+        Instruction::Switch(scrutinee, arms, default) => {
+            addr(scrutinee) && arms.iter().all(|arm| block(&arm.target)) && block(default)
+        }
+        Instruction::Item(from, _, to) | Instruction::Entry(from, _, to) => addr(from) && addr(to),
+        Instruction::Push(a) | Instruction::Pop(a) => addr(a),
+        _ => true,
+    };
+
+    !chunk.basic_blocks.is_empty()
+        && chunk.params.iter().all(addr)
+        && chunk.basic_blocks.iter().all(|instructions| instructions.iter().all(instruction))
+        && chunk.branches.iter().all(|bbs| bbs.iter().all(block))
+}
@@ -2,6 +2,8 @@ use std::collections::HashMap;
 
 use im_rc::OrdMap;
 
+use crate::compile::StaticError;
+use crate::read::Span;
 use crate::special_forms::{Code, Pattern};
 use crate::value::{Value, Id};
 
@@ -11,7 +13,11 @@ pub enum BindingError {
     Immutable(Id),
 }
 
-pub fn check_toplevel(c: Code, bindings: &HashMap<Id, (Value, bool)>) -> Result<(), BindingError> {
+fn binding(err: BindingError, span: Option<Span>) -> StaticError {
+    StaticError::Binding(err, span)
+}
+
+pub fn check_toplevel(c: Code, bindings: &HashMap<Id, (Value, bool)>) -> Result<(), StaticError> {
     let mut env = OrdMap::new();
 
     for (key, (_, mutability)) in bindings.iter() {
@@ -24,13 +30,13 @@ pub fn check_toplevel(c: Code, bindings: &HashMap<Id, (Value, bool)>) -> Result<
 pub fn check(
     c: Code,
     bindings: &OrdMap<Id, bool /*mutability*/>
-) -> Result<(), BindingError> {
+) -> Result<(), StaticError> {
     match c {
         Code::Atomic(..) | Code::Fun(..) | Code::Cell(..) | Code::Opaque(..) => Ok(()),
 
-        Code::Id(id) => match bindings.get(&id) {
+        Code::Id(id, at) => match bindings.get(&id) {
             Some(_) => Ok(()),
-            None => Err(BindingError::Free(id.clone())),
+            None => Err(binding(BindingError::Free(id.clone()), at.0)),
         }
 
         Code::Arr(vals) => {
@@ -71,30 +77,30 @@ pub fn check(
             Ok(())
         }
 
-        Code::SetBang(id, body) => {
+        Code::SetBang(id, at, body) => {
             match bindings.get(&id) {
                 Some(true) => check(*body, bindings),
-                Some(false) => Err(BindingError::Immutable(id.clone())),
-                None => Err(BindingError::Free(id.clone())),
+                Some(false) => Err(binding(BindingError::Immutable(id.clone()), at.0)),
+                None => Err(binding(BindingError::Free(id.clone()), at.0)),
             }
         }
 
         Code::Throw(thrown) => check(*thrown, bindings),
 
-        Code::Try(try_, mutable, bound, catch) => {
+        Code::Try(try_, mutable, bound, catch, _) => {
             let _ = check(*try_, bindings)?;
             check(*catch, &bindings.update(bound.clone(), mutable))
         }
 
         Code::Case(v, patterns) => {
             check(*v, bindings)?;
-            for (pattern, then) in patterns.0.iter() {
+            for (pattern, then, _) in patterns.0.iter() {
                 check(then.clone(), &bindings_from_pattern(bindings, pattern))?;
             }
             return Ok(());
         }
 
-        Code::Lambda(args, body) => {
+        Code::Lambda(args, body, _) => {
             let mut fn_bindings = bindings.clone();
             for (mutable, bound) in args.0.iter() {
                 fn_bindings = fn_bindings.update((*bound).clone(), *mutable);
@@ -109,7 +115,7 @@ pub fn check(
             }
             let cont_bindings = cont_bindings;
 
-            for (args, body) in defs.0.values() {
+            for (args, body, _) in defs.0.values() {
                 let mut fn_bindings = cont_bindings.clone();
                 for (mutable, bound) in args.0.iter() {
                     fn_bindings = fn_bindings.update((*bound).clone(), *mutable);
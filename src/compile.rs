@@ -1,81 +1,150 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
-use im_rc::{Vector as ImVector, OrdMap as ImOrdMap};
-use gc::Gc;
+use im_rc::Vector as ImVector;
+use gc::{Gc, GcCell};
 
 use crate::builtins;
 use crate::check::{check_toplevel, BindingError};
-use crate::gc_foreign::{Vector, OrdMap};
-use crate::special_forms::{Code, to_code, SpecialFormSyntaxError, Pattern};
-use crate::value::{Value, Id};
-use crate::vm::{Closure, DeBruijn, BindingId, BBId, BB_RETURN, Instruction, IrChunk, Addr, Environment, CompiledPattern};
+use crate::optimize;
+use crate::gc_foreign::{Vector, OrdSet};
+use crate::read::{SourceMap, Span};
+use crate::special_forms::{Code, to_code_mapped, SpecialFormSyntaxError, Pattern, FunSource};
+use crate::value::{Value, Id, Atomic};
+use crate::vm::{Closure, BBId, BB_RETURN, Instruction, IrChunk, Addr, Arm, Test};
 
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum StaticError {
-    SpecialFormSyntax(SpecialFormSyntaxError),
-    Binding(BindingError),
+    /// A malformed special form, with the span of the offending value if it is known.
+    SpecialFormSyntax(SpecialFormSyntaxError, Option<Span>),
+    /// A free or immutable identifier, with the span of the identifier if it is known.
+    Binding(BindingError, Option<Span>),
+}
+
+impl StaticError {
+    /// Where in the source code the error occured, if known.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            StaticError::SpecialFormSyntax(_, span) | StaticError::Binding(_, span) => *span,
+        }
+    }
 }
 
 impl From<SpecialFormSyntaxError> for StaticError {
     fn from(err: SpecialFormSyntaxError) -> Self {
-        StaticError::SpecialFormSyntax(err)
+        StaticError::SpecialFormSyntax(err, None)
     }
 }
 
 impl From<BindingError> for StaticError {
     fn from(err: BindingError) -> Self {
-        StaticError::Binding(err)
+        StaticError::Binding(err, None)
     }
 }
 
 use Instruction::*;
 
-// A stack of lexical scopes, mapping identifiers to their numeric BindingIds.
-struct Stack(Vec<HashMap<Id, BindingId>>);
+// The bindings of a function that is being compiled.
+struct Function {
+    // The lexical scopes within the function, mapping identifiers to the index of their binder.
+    scopes: Vec<HashMap<Id, usize>>,
+    // The name of each binder of the function, and whether any nested function captures it.
+    binders: Vec<(Id, bool)>,
+    // The bindings the function captures, with their addresses in the enclosing function.
+    captures: Vec<(Id, Addr)>,
+}
+
+impl Function {
+    fn new() -> Function {
+        Function {
+            scopes: vec![HashMap::new()],
+            binders: vec![],
+            captures: vec![],
+        }
+    }
+}
+
+// The functions that are being compiled, the innermost one last. The first one is not a real
+// function, its binders are the toplevel bindings.
+//
+// While a function is being compiled, its binders are addressed as `Addr::Local(binder)`. Only
+// once all of its code has been compiled is it known which binders are captured by nested
+// functions, `BBB::into_ir` then assigns the actual registers and boxes.
+//
+// `module` is the module being compiled (`None` for the entrypoint), the chunks are marked with it.
+struct Stack(Vec<Function>, Option<String>);
 
 impl Stack {
-    // Create a new, empty Stack.
-    fn new() -> Stack {
-        Stack(vec![])
+    fn push_fun(&mut self) {
+        self.0.push(Function::new());
+    }
+
+    fn pop_fun(&mut self) -> Function {
+        self.0.pop().unwrap()
     }
 
     fn push_scope(&mut self) {
-        self.0.push(HashMap::new());
+        self.0.last_mut().unwrap().scopes.push(HashMap::new());
     }
 
     fn pop_scope(&mut self) {
-        self.0.pop();
+        self.0.last_mut().unwrap().scopes.pop();
+    }
+
+    fn add(&mut self, id: &Id) -> Addr {
+        let fun = self.0.last_mut().unwrap();
+        let binder = fun.binders.len();
+        fun.binders.push((id.clone(), false));
+        fun.scopes.last_mut().unwrap().insert(id.clone(), binder);
+        Addr::Local(binder)
+    }
+
+    // A binder that can not be referred to by name, for values the compiler needs to keep around.
+    fn temp(&mut self, id: &Id) -> Addr {
+        let fun = self.0.last_mut().unwrap();
+        fun.binders.push((id.clone(), false));
+        Addr::Local(fun.binders.len() - 1)
     }
 
-    fn add(&mut self, id: &Id) -> usize {
-        let scopes_len = self.0.len();
-        let scope = &mut self.0[scopes_len - 1];
-        let len = scope.len();
-        scope.insert(id.clone(), len);
-        len
+    fn resolve(&mut self, id: &Id) -> Addr {
+        let innermost = self.0.len() - 1;
+        self.resolve_in(innermost, id)
     }
 
-    fn resolve(&self, id: &Id) -> DeBruijn {
-        for (up, env) in self.0.iter().rev().enumerate() {
-            if let Some(offset) = env.get(id) {
-                return DeBruijn {
-                    up,
-                    id: *offset,
-                };
+    // Resolve an id in the function at the given index, capturing it from the enclosing
+    // functions if necessary.
+    fn resolve_in(&mut self, f: usize, id: &Id) -> Addr {
+        for scope in self.0[f].scopes.iter().rev() {
+            if let Some(binder) = scope.get(id) {
+                return Addr::Local(*binder);
             }
         }
 
-        println!("{:?}", id);
+        // All free ids of a function refer to the same binders, those in scope where the
+        // function is defined. So each id needs to be captured only once.
+        if let Some(capture) = self.0[f].captures.iter().position(|(name, _)| name == id) {
+            return Addr::Captured(capture);
+        }
+
+        if f == 0 {
+            unreachable!(
+                "Always at least one environment, id {:?} can not be unbound (caught by static checks)",
+                id
+            );
+        }
 
-        unreachable!("Always at least one environment, id can not be unused (caught by static checks)");
+        let outer = self.resolve_in(f - 1, id);
+        if let Addr::Local(binder) = outer {
+            self.0[f - 1].binders[binder].1 = true;
+        }
+        self.0[f].captures.push((id.clone(), outer));
+        Addr::Captured(self.0[f].captures.len() - 1)
     }
 
-    fn from_toplevel(toplevel: &HashMap<Id, (Value, bool)>) -> Stack {
-        let mut ret = Stack::new();
-        ret.push_scope();
+    fn from_toplevel(toplevel: &HashMap<Id, (Value, bool)>, module: Option<&str>) -> Stack {
+        let mut ret = Stack(vec![Function::new()], module.map(str::to_string));
 
-        for (i, (name, _)) in toplevel.iter().enumerate() {
-            ret.0[0].insert(name.clone(), i);
+        for name in toplevel.keys() {
+            ret.add(name);
         }
 
         ret
@@ -93,23 +162,36 @@ struct BBB {
     current: BBId,
     // Index of the block to which a trap instruction should jump.
     trap_handler: BBId,
+    // See `IrChunk::block_sources`.
+    sources: Vec<Option<Span>>,
+    // See `IrChunk::branches`.
+    branches: Vec<Vec<BBId>>,
 }
 
 impl BBB {
-    fn new() -> BBB {
+    // Create a builder whose first block begins evaluating the expression at the given span.
+    fn new(source: Option<Span>) -> BBB {
         BBB {
             blocks: vec![vec![]],
             current: 0,
             trap_handler: BB_RETURN,
+            sources: vec![source],
+            branches: vec![],
         }
     }
 
     // Create a new, empty basic block, and return it's id.
     fn new_block(&mut self) -> BBId {
         self.blocks.push(vec![]);
+        self.sources.push(None);
         return self.blocks.len() - 1;
     }
 
+    // Record that the block begins evaluating the expression at the given span.
+    fn tag_block(&mut self, bb: BBId, source: Option<Span>) {
+        self.sources[bb] = source;
+    }
+
     // Set the block on which the BBB operates.
     fn set_active_block(&mut self, bb: BBId) {
         self.current = bb;
@@ -124,35 +206,135 @@ impl BBB {
         self.append(Literal(Value::nil()))
     }
 
-    // Consume the builder to create an IrChunk.
-    fn into_ir(self) -> IrChunk {
+    // Consume the builder to create an IrChunk for the compiled function, replacing the binder
+    // addresses (see `Stack`) with registers and boxes.
+    fn into_ir(
+        mut self,
+        name: Option<Id>,
+        source: Option<&FunSource>,
+        module: Option<String>,
+        params: Vec<Addr>,
+        fun: Function,
+    ) -> IrChunk {
+        let mut locals = vec![];
+        let mut boxes = vec![];
+        let addrs: Vec<Addr> = fun.binders
+            .into_iter()
+            .map(|(id, captured)| if captured {
+                boxes.push(id);
+                Addr::Boxed(boxes.len() - 1)
+            } else {
+                locals.push(id);
+                Addr::Local(locals.len() - 1)
+            })
+            .collect();
+
+        for block in self.blocks.iter_mut() {
+            for inst in block.iter_mut() {
+                match inst {
+                    Push(addr) | Pop(addr) => assign(addr, &addrs),
+                    Switch(addr, _, _) => assign(addr, &addrs),
+                    Item(from, _, to) | Entry(from, _, to) => {
+                        assign(from, &addrs);
+                        assign(to, &addrs);
+                    }
+                    FunLiteral(_, _, captures) => {
+                        for addr in captures.iter_mut() {
+                            assign(addr, &addrs);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let mut params = params;
+        for addr in params.iter_mut() {
+            assign(addr, &addrs);
+        }
+
         IrChunk {
             basic_blocks: self.blocks,
+            name,
+            source: source.map(|source| source.form.clone()),
+            span: source.and_then(|source| source.span.0),
+            module,
+            locals,
+            boxes,
+            captures: fun.captures.into_iter().map(|(id, _)| id).collect(),
+            params,
+            block_sources: self.sources,
+            branches: self.branches,
         }
     }
 }
 
+// If `optimize` is true, the ir is run through the passes of `optimize::optimize`.
 pub fn compile(
     v: &Value,
     toplevel: &HashMap<Id, (Value, bool)>,
+    optimize: bool,
 ) -> Result<Closure, StaticError> {
-    compile_code(to_code(v)?, toplevel)
+    compile_mapped(v, &SourceMap::default(), None, toplevel, optimize)
 }
 
+/// Like `compile`, with the spans of the value taken from the given source map. `module` is the
+/// module the value has been read from, `None` for the entrypoint.
+pub fn compile_mapped(
+    v: &Value,
+    map: &SourceMap,
+    module: Option<&str>,
+    toplevel: &HashMap<Id, (Value, bool)>,
+    optimize: bool,
+) -> Result<Closure, StaticError> {
+    let c = to_code_mapped(v, map)?;
+    compile_code(c, map.span, module, toplevel, optimize)
+}
+
+// `span` is where the code has been read from, if known.
 pub fn compile_code(
     c: Code,
+    span: Option<Span>,
+    module: Option<&str>,
     toplevel: &HashMap<Id, (Value, bool)>,
+    optimize: bool,
 ) -> Result<Closure, StaticError> {
+    let ir = compile_chunk(c, span, module, toplevel, optimize)?;
+    return Ok(close(Gc::new(ir), toplevel));
+}
+
+/// Compile code to the chunk of the top-level code, without creating a closure for it.
+pub fn compile_chunk(
+    c: Code,
+    span: Option<Span>,
+    module: Option<&str>,
+    toplevel: &HashMap<Id, (Value, bool)>,
+    optimize: bool,
+) -> Result<IrChunk, StaticError> {
     check_toplevel(c.clone(), toplevel)?;
 
-    let mut s = Stack::from_toplevel(toplevel);
-    let chunk = Gc::new(compile_lambda(Vector(ImVector::new()), c, &mut s));
+    let mut s = Stack::from_toplevel(toplevel, module);
+    let (mut ir, _) = compile_lambda(Vector(ImVector::new()), c, None, None, span, &mut s);
+    if optimize {
+        optimize::optimize(&mut ir);
+    }
 
-    return Ok(Closure {
-        fun: chunk,
-        env: Environment::child(Environment::from_toplevel(toplevel)),
+    return Ok(ir);
+}
+
+/// Create the closure for the chunk of some top-level code, which captures the toplevel bindings
+/// it uses.
+pub fn close(ir: Gc<IrChunk>, toplevel: &HashMap<Id, (Value, bool)>) -> Closure {
+    let captures = ir.captures
+        .iter()
+        .map(|name| Gc::new(GcCell::new(toplevel[name].0.clone())))
+        .collect();
+
+    return Closure {
+        fun: ir,
+        captures,
         args: 0,
-    });
+    };
 }
 
 fn code_to_ir(c: Code, push: bool, bbb: &mut BBB, tail: bool, s: &mut Stack) {
@@ -234,9 +416,9 @@ fn code_to_ir(c: Code, push: bool, bbb: &mut BBB, tail: bool, s: &mut Stack) {
             }
         }
 
-        Code::Id(id) => {
-            let db = s.resolve(&id);
-            bbb.append(Push(Addr::env(db)));
+        Code::Id(id, _) => {
+            let addr = s.resolve(&id);
+            bbb.append(Push(addr));
         }
 
         Code::Quote(q) => {
@@ -261,11 +443,11 @@ fn code_to_ir(c: Code, push: bool, bbb: &mut BBB, tail: bool, s: &mut Stack) {
             }
         }
 
-        Code::SetBang(id, rhs) => {
+        Code::SetBang(id, _, rhs) => {
             code_to_ir(*rhs, true, bbb, false, s);
 
-            let db = s.resolve(&id);
-            bbb.append(Pop(Addr::env(db)));
+            let addr = s.resolve(&id);
+            bbb.append(Pop(addr));
 
             if push {
                 bbb.push_nil();
@@ -277,9 +459,10 @@ fn code_to_ir(c: Code, push: bool, bbb: &mut BBB, tail: bool, s: &mut Stack) {
             bbb.append(Throw);
         }
 
-        Code::Try(yay, _, binder, nay) => {
+        Code::Try(yay, _, binder, nay, nay_source) => {
             let bb_catch = bbb.new_block();
             let bb_cont = bbb.new_block();
+            bbb.tag_block(bb_catch, nay_source.0);
 
             let prev_trap_handler = bbb.trap_handler;
             bbb.trap_handler = bb_catch;
@@ -292,11 +475,9 @@ fn code_to_ir(c: Code, push: bool, bbb: &mut BBB, tail: bool, s: &mut Stack) {
             bbb.set_active_block(bb_catch);
             bbb.append(SetCatchHandler(prev_trap_handler));
             s.push_scope();
-            bbb.append(PushScope);
-            let db = DeBruijn { up: 0, id: s.add(&binder) };
-            bbb.append(Pop(Addr::env(db)));
+            let addr = s.add(&binder);
+            bbb.append(Pop(addr));
             code_to_ir(*nay, push, bbb, tail, s);
-            bbb.append(PopScope);
             s.pop_scope();
             bbb.append(Jump(bb_cont));
 
@@ -310,125 +491,373 @@ fn code_to_ir(c: Code, push: bool, bbb: &mut BBB, tail: bool, s: &mut Stack) {
                 return;
             }
 
-            let bbs: Vec<(BBId, BBId)> = branches.0.iter().map(|_|
-                (bbb.new_block(), bbb.new_block()
-            )).collect();
+            let bbs_then: Vec<BBId> = branches.0.iter().map(|_| bbb.new_block()).collect();
             let bb_failure = bbb.new_block();
             let bb_cont = bbb.new_block();
 
+            for (i, (_, _, then_source)) in branches.0.iter().enumerate() {
+                bbb.tag_block(bbs_then[i], then_source.0);
+            }
+            bbb.branches.push(bbs_then.clone());
+
+            let mut case = Case {
+                then: bbs_then,
+                failure: bb_failure,
+                occurrences: BTreeMap::new(),
+            };
+
             code_to_ir(*c, true, bbb, false, s);
-            bbb.append(Jump(bbs[0].0));
+            let scrutinee = case.occurrence(&vec![], s);
+            bbb.append(Pop(scrutinee));
+            let bb_tree = bbb.current;
 
-            for (i, (pattern, then)) in branches.0.iter().enumerate() {
-                bbb.set_active_block(bbs[i].0);
-                bbb.append(DoubleTop);
-                let bb_next = if i + 1 < branches.0.len() {
-                    bbs[i + 1].0
-                } else {
-                    bb_failure
-                };
-                if i != 0 {
-                    bbb.append(PopScope);
-                }
+            let mut rows = Vec::with_capacity(branches.0.len());
+            for (i, (pattern, then, _)) in branches.0.iter().enumerate() {
                 s.push_scope();
-                bbb.append(PushScope);
-                bbb.append(Match(compile_pattern(pattern, s), bbs[i].1, bb_next));
-
-                bbb.set_active_block(bbs[i].1);
-                bbb.append(DropTop);
+                let mut bindings = vec![];
+                let pat = compile_pattern(pattern, vec![], &mut bindings, s);
+                rows.push(Row { pats: vec![pat], branch: i });
+
+                // The bindings are written in the order in which they appear in the pattern.
+                bbb.set_active_block(case.then[i]);
+                for (binder, path) in bindings {
+                    bbb.append(Push(case.occurrence(&path, s)));
+                    bbb.append(Pop(binder));
+                }
                 code_to_ir(then.clone(), push, bbb, tail, s);
-                bbb.append(PopScope);
                 s.pop_scope();
                 bbb.append(Jump(bb_cont));
             }
 
             bbb.set_active_block(bb_failure);
-            bbb.append(PopScope);
-            bbb.append(DropTop);
             bbb.append(Literal(builtins::type_error()));
             bbb.append(Throw);
 
+            bbb.set_active_block(bb_tree);
+            case.tree(rows, vec![Column { path: vec![], map: false }], bbb, s);
+
             bbb.set_active_block(bb_cont);
         }
 
-        Code::Lambda(args, body) => {
+        Code::Lambda(args, body, source) => {
             let len = args.0.len();
-            let ir_chunk = Gc::new(compile_lambda(args, *body, s));
-            bbb.append(FunLiteral(ir_chunk, len));
+            let body_span = source.body.0;
+            let (ir_chunk, captures) = compile_lambda(args, *body, None, Some(&source), body_span, s);
+            bbb.append(FunLiteral(Gc::new(ir_chunk), len, captures));
         }
 
         Code::LetFn(defs, cont) => {
             s.push_scope();
-            bbb.append(PushScope);
 
             for name in defs.0.keys() {
                 s.add(name);
             }
 
-            for (name, (args, body)) in defs.0.iter() {
+            for (name, (args, body, source)) in defs.0.iter() {
                 let len = args.0.len();
-                let db = s.resolve(name);
-                let ir_chunk = Gc::new(compile_lambda(args.clone(), body.clone(), s));
-                bbb.append(FunLiteral(ir_chunk, len));
-                bbb.append(Pop(Addr::env(db)));
+                let addr = s.resolve(name);
+                let (ir_chunk, captures) = compile_lambda(
+                    args.clone(),
+                    body.clone(),
+                    Some(name.clone()),
+                    Some(source),
+                    source.body.0,
+                    s
+                );
+                bbb.append(FunLiteral(Gc::new(ir_chunk), len, captures));
+                bbb.append(Pop(addr));
             }
 
             code_to_ir(*cont, push, bbb, tail, s);
 
-            bbb.append(PopScope);
             s.pop_scope();
         }
     }
 }
 
-fn compile_lambda(args: Vector<(bool, Id)>, body: Code, s: &mut Stack) -> IrChunk {
-    let mut bbb = BBB::new();
-    s.push_scope();
+fn compile_lambda(
+    args: Vector<(bool, Id)>,
+    body: Code,
+    name: Option<Id>,
+    source: Option<&FunSource>,
+    body_span: Option<Span>,
+    s: &mut Stack,
+) -> (IrChunk, Vec<Addr>) {
+    let mut bbb = BBB::new(body_span);
+    s.push_fun();
 
-    for (_, binder) in args.0.iter() {
-        s.add(binder);
-    }
+    let params = args.0.iter().map(|(_, binder)| s.add(binder)).collect();
 
     code_to_ir(body, true, &mut bbb, true, s);
-    s.pop_scope();
+    let fun = s.pop_fun();
 
-    return bbb.into_ir();
+    // Where the closures get the captured boxes from, in the enclosing function.
+    let captures = fun.captures.iter().map(|(_, addr)| addr.clone()).collect();
+    let module = s.1.clone();
+    return (bbb.into_ir(name, source, module, params, fun), captures);
 }
 
-fn compile_pattern(p: &Pattern, s: &mut Stack) -> CompiledPattern {
+// Replace a binder address with the register or box assigned to the binder, see `BBB::into_ir`.
+fn assign(addr: &mut Addr, addrs: &[Addr]) {
+    if let Addr::Local(binder) = addr {
+        *addr = addrs[*binder].clone();
+    }
+}
+
+// Identifies a part of the value a `sf-case` examines, by how to get there from the whole value.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Step {
+    Item(usize),
+    Entry(Value),
+}
+
+type Path = Vec<Step>;
+
+fn child(path: &Path, step: Step) -> Path {
+    let mut child = path.clone();
+    child.push(step);
+    child
+}
+
+// A pattern without its bindings, see `compile_pattern`.
+#[derive(Debug, Clone, PartialEq)]
+enum Pat {
+    // Matches anything.
+    Wild,
+    Atomic(Atomic),
+    Set(OrdSet<Value>),
+    Arr(Vec<Pat>),
+    App(Vec<Pat>),
+    // The entries in the order of their keys.
+    Map(Vec<(Value, Pat)>),
+}
+
+// Add the binders of a pattern to the innermost scope, and record for each binder which part of the
+// value it binds. The binders are recorded in the order in which they appear in the pattern.
+fn compile_pattern(
+    p: &Pattern,
+    path: Path,
+    bindings: &mut Vec<(Addr, Path)>,
+    s: &mut Stack,
+) -> Pat {
     match p {
         Pattern::Name(_, id) => {
-            let db = DeBruijn { up: 0, id: s.add(&id) };
-            return CompiledPattern::Name(db);
-        }
-        Pattern::Atomic(a) => CompiledPattern::Atomic(a.clone()),
-        Pattern::Set(set) => CompiledPattern::Set(set.clone()),
-        Pattern::Arr(arr) => {
-            let ret = CompiledPattern::Arr(Vector(
-                arr.0.iter()
-                .map(|p_| compile_pattern(p_, s))
-                .collect()
-            ));
-            return ret;
-        }
-        Pattern::App(app) => {
-            let ret = CompiledPattern::App(Vector(
-                app.0.iter()
-                .map(|p_| compile_pattern(p_, s))
-                .collect()
-            ));
-            return ret;
-        }
-        Pattern::Map(map) => {
-            let mut comp_map = ImOrdMap::new();
-            for (k, p_) in map.0.iter() {
-                comp_map.insert(k.clone(), compile_pattern(p_, s));
-            }
-            return CompiledPattern::Map(OrdMap(comp_map));
+            bindings.push((s.add(&id), path));
+            Pat::Wild
         }
+        Pattern::Atomic(a) => Pat::Atomic(a.clone()),
+        Pattern::Set(set) => Pat::Set(set.clone()),
+        Pattern::Arr(arr) => Pat::Arr(
+            arr.0.iter()
+            .enumerate()
+            .map(|(i, p_)| compile_pattern(p_, child(&path, Step::Item(i)), bindings, s))
+            .collect()
+        ),
+        Pattern::App(app) => Pat::App(
+            app.0.iter()
+            .enumerate()
+            .map(|(i, p_)| compile_pattern(p_, child(&path, Step::Item(i)), bindings, s))
+            .collect()
+        ),
+        Pattern::Map(map) => Pat::Map(
+            map.0.iter()
+            .map(|(k, p_)| {
+                (k.clone(), compile_pattern(p_, child(&path, Step::Entry(k.clone())), bindings, s))
+            })
+            .collect()
+        ),
         Pattern::Named(_, id, inner) => {
-            let db = DeBruijn { up: 0, id: s.add(&id) };
-            return CompiledPattern::Named(db, Box::new(compile_pattern(inner, s)));
+            bindings.push((s.add(&id), path.clone()));
+            compile_pattern(inner, path, bindings, s)
         }
     }
 }
+
+// A row of the pattern matrix of a `sf-case`: the patterns the parts of the value (given by the
+// columns) have to match for the branch to be taken.
+struct Row {
+    pats: Vec<Pat>,
+    branch: usize,
+}
+
+// A part of the value examined by a `sf-case`.
+#[derive(Clone)]
+struct Column {
+    path: Path,
+    // Whether the part is already known to be a map.
+    map: bool,
+}
+
+// Compiles the patterns of a `sf-case` into a decision tree, so that each part of the value is
+// examined at most once.
+struct Case {
+    // The blocks of the branches.
+    then: Vec<BBId>,
+    // The block to jump to if no pattern matches.
+    failure: BBId,
+    // The registers holding the parts of the value.
+    occurrences: BTreeMap<Path, Addr>,
+}
+
+impl Case {
+    // The register holding a part of the value. Registers are shared between the paths through
+    // the tree, since only one of them is ever taken.
+    fn occurrence(&mut self, path: &Path, s: &mut Stack) -> Addr {
+        if let Some(addr) = self.occurrences.get(path) {
+            return addr.clone();
+        }
+
+        let addr = s.temp(&Id::user("#case"));
+        self.occurrences.insert(path.clone(), addr.clone());
+        addr
+    }
+
+    // Append the code that decides which of the rows matches to the active block. The first
+    // matching row is the branch to take.
+    fn tree(&mut self, rows: Vec<Row>, columns: Vec<Column>, bbb: &mut BBB, s: &mut Stack) {
+        let first = match rows.first() {
+            Some(first) => first,
+            None => return bbb.append(Jump(self.failure)),
+        };
+
+        // The first column where the first row still needs to examine something.
+        let col = match first.pats.iter().position(|pat| *pat != Pat::Wild) {
+            Some(col) => col,
+            None => return bbb.append(Jump(self.then[first.branch])),
+        };
+        let addr = self.occurrence(&columns[col].path, s);
+
+        match first.pats[col].clone() {
+            Pat::Map(entries) if columns[col].map => {
+                // Test for the presence of a key, the value is known to be a map.
+                let key = entries[0].0.clone();
+                let entry_path = child(&columns[col].path, Step::Entry(key.clone()));
+                let bb_yay = bbb.new_block();
+                let bb_nay = bbb.new_block();
+                bbb.append(Switch(addr.clone(), vec![Arm { test: Test::HasKey(key.clone()), target: bb_yay }], bb_nay));
+
+                let mut yay = vec![];
+                let mut nay = vec![];
+                for row in rows.into_iter() {
+                    let (rest, entry) = match &row.pats[col] {
+                        Pat::Map(entries) => match entries.iter().position(|(k, _)| *k == key) {
+                            Some(i) => {
+                                let mut rest = entries.clone();
+                                let (_, entry) = rest.remove(i);
+                                (Some(rest), Some(entry))
+                            }
+                            None => (None, None),
+                        },
+                        _ => (None, None),
+                    };
+
+                    match (rest, entry) {
+                        (Some(rest), Some(entry)) => {
+                            let mut pats = row.pats;
+                            pats[col] = if rest.is_empty() { Pat::Wild } else { Pat::Map(rest) };
+                            pats.push(entry);
+                            yay.push(Row { pats, branch: row.branch });
+                        }
+                        _ => {
+                            let mut pats = row.pats.clone();
+                            pats.push(Pat::Wild);
+                            yay.push(Row { pats, branch: row.branch });
+                            nay.push(row);
+                        }
+                    }
+                }
+
+                let mut yay_columns = columns.clone();
+                yay_columns.push(Column { path: entry_path.clone(), map: false });
+
+                bbb.set_active_block(bb_yay);
+                let to = self.occurrence(&entry_path, s);
+                bbb.append(Entry(addr.clone(), key, to));
+                self.tree(yay, yay_columns, bbb, s);
+
+                bbb.set_active_block(bb_nay);
+                self.tree(nay, columns, bbb, s);
+            }
+
+            _ => {
+                // Switch on the kind of value, in the order in which the rows mention them.
+                let mut tests: Vec<Test> = vec![];
+                for row in rows.iter() {
+                    if let Some(test) = test(&row.pats[col]) {
+                        if !tests.contains(&test) {
+                            tests.push(test);
+                        }
+                    }
+                }
+
+                let arms: Vec<Arm> = tests
+                    .into_iter()
+                    .map(|test| Arm { test, target: bbb.new_block() })
+                    .collect();
+                let bb_default = bbb.new_block();
+                bbb.append(Switch(addr.clone(), arms.clone(), bb_default));
+
+                for arm in arms.iter() {
+                    let arity = match arm.test {
+                        Test::Arr(len) | Test::App(len) => len,
+                        _ => 0,
+                    };
+
+                    let mut specialized = vec![];
+                    for row in rows.iter() {
+                        let mut pats = row.pats.clone();
+                        match &row.pats[col] {
+                            Pat::Wild => {
+                                pats.extend((0..arity).map(|_| Pat::Wild));
+                            }
+                            pat if test(pat).as_ref() == Some(&arm.test) => match pat {
+                                Pat::Arr(items) | Pat::App(items) => {
+                                    pats[col] = Pat::Wild;
+                                    pats.extend(items.iter().cloned());
+                                }
+                                // Only the presence of the entries remains to be tested.
+                                Pat::Map(entries) if !entries.is_empty() => {}
+                                _ => pats[col] = Pat::Wild,
+                            },
+                            _ => continue,
+                        }
+                        specialized.push(Row { pats, branch: row.branch });
+                    }
+
+                    let mut arm_columns = columns.clone();
+                    if arm.test == Test::Map {
+                        arm_columns[col].map = true;
+                    }
+
+                    bbb.set_active_block(arm.target);
+                    for i in 0..arity {
+                        let item_path = child(&columns[col].path, Step::Item(i));
+                        let to = self.occurrence(&item_path, s);
+                        bbb.append(Item(addr.clone(), i, to));
+                        arm_columns.push(Column { path: item_path, map: false });
+                    }
+                    self.tree(specialized, arm_columns, bbb, s);
+                }
+
+                let default = rows
+                    .into_iter()
+                    .filter(|row| row.pats[col] == Pat::Wild)
+                    .collect();
+                bbb.set_active_block(bb_default);
+                self.tree(default, columns, bbb, s);
+            }
+        }
+    }
+}
+
+// The test that tells whether a value is of the kind a pattern requires, `None` for wildcards.
+fn test(pat: &Pat) -> Option<Test> {
+    match pat {
+        Pat::Wild => None,
+        Pat::Atomic(a) => Some(Test::Atomic(a.clone())),
+        Pat::Set(set) => Some(Test::Set(set.clone())),
+        Pat::Arr(items) => Some(Test::Arr(items.len())),
+        Pat::App(items) => Some(Test::App(items.len())),
+        Pat::Map(_) => Some(Test::Map),
+    }
+}
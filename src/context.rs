@@ -1,16 +1,23 @@
-use std::env::{current_dir, set_current_dir};
 use std::path::PathBuf;
-use std::collections::BTreeMap;
-use std::fs::File;
-use std::io::prelude::*;
+use std::collections::{BTreeMap, BTreeSet};
 
+use gc::Gc;
 use im_rc::{OrdMap as ImOrdMap, Vector as ImVector};
 use nom::types::CompleteStr;
 
 use crate::builtins;
+use crate::bytecode::{self, Cache, Entry};
+use crate::compile;
+use crate::coverage::Coverage;
+use crate::deps::Require;
+use crate::expand;
 use crate::gc_foreign::{OrdMap, Vector};
-use crate::read::read;
-use crate::value::{Value, Atomic, NUM_BUILTIN_OPAQUES};
+use crate::profile::{Profiler, ProfileKey};
+use crate::read::{read_located, SourceMap, Span};
+use crate::resolve::{err_require, FileResolver, ModuleResolver};
+use crate::special_forms::to_code_mapped;
+use crate::value::{Value, Id, NUM_BUILTIN_OPAQUES};
+use crate::vm::{BBId, Debugger, IrChunk, Pause};
 
 /// Global state tracked throughout the execution.
 ///
@@ -20,18 +27,98 @@ pub struct Context {
     symbol_id: u64,
     fun_id: u64,
     cell_id: u64,
+    // How many calls are currently being executed, see `enter_frame`.
     level: usize,
+    max_level: usize,
+    // How many of those calls recurse on the rust stack, see `enter_native`.
+    native_level: usize,
+    max_native_level: usize,
     require_cache: RequireCache,
+    resolver: Box<dyn ModuleResolver>,
+    // The module that the top-level code belongs to, see `set_entrypoint`.
+    entrypoint: Option<String>,
+    abort: Option<Abort>,
+    // How many more steps may be taken, `None` if unlimited.
+    fuel: Option<u64>,
+    // How many steps have been taken in total.
+    fuel_consumed: u64,
+    // The functions that the currently thrown value propagated out of, innermost first.
+    trace: Vec<TraceEntry>,
+    // The trace of the most recently caught value.
+    caught_trace: Vec<TraceEntry>,
+    debugger: Option<Box<dyn Debugger>>,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    // Whether compiled code is run through the optimization passes.
+    optimize: bool,
+    // Where required files are cached on disk, if anywhere.
+    cache: Option<Cache>,
 }
 
+/// A reason to stop the execution. Unlike thrown values, these can not be caught.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Abort {
+    /// `(diverge v)` has been called with the value `v`.
+    Diverge(Value),
+    /// The evaluation exceeded its fuel, see `Context::set_fuel`.
+    OutOfFuel,
+    /// The calls were nested deeper than allowed, see `Context::set_max_call_depth`.
+    CallDepth,
+}
+
+/// A function that a thrown value propagated out of.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum TraceEntry {
+    /// A closure, with its function id (`None` for top-level code), its name if it was defined
+    /// via `sf-letfn`, the form that defined it (`None` for top-level code), where that form has
+    /// been read from if known, and the module it is part of (`None` for the entrypoint).
+    Closure {
+        id: Option<u64>,
+        name: Option<Id>,
+        source: Option<Value>,
+        span: Option<Span>,
+        module: Option<String>,
+    },
+    /// A builtin function, by the name it is bound to in the default environment, e.g. `int-add`,
+    /// see `env::builtin_name`.
+    Builtin(String),
+    /// A function defined by the host, with its function id and name.
+    Native(u64, String),
+    /// A function created by `opaque`, with its function id.
+    Opaque(u64),
+}
+
+/// The default maximum call depth, see `Context::set_max_call_depth`.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 100_000;
+
+/// The default maximum depth of computations that recurse on the rust stack, see
+/// `Context::set_max_native_depth`. This is chosen so that the 2MiB stack that rust gives to
+/// spawned threads suffices even in debug builds.
+pub const DEFAULT_MAX_NATIVE_DEPTH: usize = 250;
+
 impl Context {
     pub fn new() -> Context {
         Context {
             symbol_id: NUM_BUILTIN_OPAQUES,
             fun_id: 0,
             cell_id: 0,
-            level: 0, // no semantic effect, only for debugging information
+            level: 0,
+            max_level: DEFAULT_MAX_CALL_DEPTH,
+            native_level: 0,
+            max_native_level: DEFAULT_MAX_NATIVE_DEPTH,
             require_cache: RequireCache::new(),
+            resolver: Box::new(FileResolver),
+            entrypoint: None,
+            abort: None,
+            fuel: None,
+            fuel_consumed: 0,
+            trace: vec![],
+            caught_trace: vec![],
+            debugger: None,
+            profiler: None,
+            coverage: None,
+            optimize: true,
+            cache: None,
         }
     }
 
@@ -57,120 +144,546 @@ impl Context {
         return old;
     }
 
-    pub fn inc_level(&mut self) {
+    /// Limit how deeply calls may be nested. Exceeding the limit aborts the execution with
+    /// `Abort::CallDepth`.
+    pub fn set_max_call_depth(&mut self, max: usize) {
+        self.max_level = max;
+    }
+
+    pub fn max_call_depth(&self) -> usize {
+        self.max_level
+    }
+
+    /// Limit how deeply computations may recurse on the rust stack (builtins calling back into
+    /// pavo functions, nested macro applications during expansion), in addition to the call
+    /// depth. Exceeding the limit
+    /// aborts the execution with `Abort::CallDepth`. Raise it only when running on a stack larger
+    /// than the 2MiB `DEFAULT_MAX_NATIVE_DEPTH` is chosen for.
+    pub fn set_max_native_depth(&mut self, max: usize) {
+        self.max_native_level = max;
+    }
+
+    pub fn max_native_depth(&self) -> usize {
+        self.max_native_level
+    }
+
+    /// How many calls are currently being executed.
+    pub fn call_depth(&self) -> usize {
+        self.level
+    }
+
+    /// Record that a call has started. If this exceeds the maximum call depth, this aborts the
+    /// execution and returns false, the caller should then return an error without calling
+    /// `leave_frame`.
+    pub fn enter_frame(&mut self) -> bool {
+        if self.level >= self.max_level {
+            self.abort(Abort::CallDepth);
+            return false;
+        }
+
         self.level += 1;
+        return true;
     }
 
-    pub fn dec_level(&mut self) {
+    /// Record that a call started via `enter_frame` has finished.
+    pub fn leave_frame(&mut self) {
         self.level -= 1;
     }
 
+    /// Like `enter_frame`, but for computations that recurse on the rust stack, which are
+    /// additionally limited by `set_max_native_depth` to avoid overflowing the stack.
+    pub fn enter_native(&mut self) -> bool {
+        if self.native_level >= self.max_native_level {
+            self.abort(Abort::CallDepth);
+            return false;
+        }
+
+        if !self.enter_frame() {
+            return false;
+        }
+
+        self.native_level += 1;
+        return true;
+    }
+
+    /// Record that a computation started via `enter_native` has finished.
+    pub fn leave_native(&mut self) {
+        self.native_level -= 1;
+        self.leave_frame();
+    }
+
+    /// Stop the execution. The caller should then return an error, which is propagated without
+    /// running any catch handlers. Only the first abort is recorded.
+    pub fn abort(&mut self, abort: Abort) {
+        if self.abort.is_none() {
+            self.abort = Some(abort);
+        }
+    }
+
+    pub fn is_aborting(&self) -> bool {
+        self.abort.is_some()
+    }
+
+    pub fn aborted(&self) -> Option<&Abort> {
+        self.abort.as_ref()
+    }
+
+    /// Clear the abort, so that the context can be used for further evaluation.
+    pub fn take_abort(&mut self) -> Option<Abort> {
+        self.abort.take()
+    }
+
+    /// Limit how many steps the execution may take from now on, `None` lifts the limit. Every
+    /// instruction executed by the vm and every call to a builtin or native function is a step.
+    /// Once the fuel is exhausted, the execution aborts with `Abort::OutOfFuel`.
+    ///
+    /// The fuel is not replenished automatically, set it again before resuming evaluation after
+    /// an `OutOfFuel` abort.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    /// The remaining fuel, `None` if the execution is unlimited.
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// How many steps have been taken by this context in total.
+    pub fn fuel_consumed(&self) -> u64 {
+        self.fuel_consumed
+    }
+
+    /// Take a step. If there is no fuel left, this aborts the execution and returns false, the
+    /// caller should then return an error.
+    pub fn consume_fuel(&mut self) -> bool {
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.step();
+        }
+
+        match self.fuel {
+            Some(0) => {
+                self.abort(Abort::OutOfFuel);
+                false
+            }
+            Some(fuel) => {
+                self.fuel = Some(fuel - 1);
+                self.fuel_consumed += 1;
+                true
+            }
+            None => {
+                self.fuel_consumed += 1;
+                true
+            }
+        }
+    }
+
+    /// Record that a new value is being thrown, discarding the current trace.
+    pub fn trace_start(&mut self) {
+        self.trace.clear();
+    }
+
+    /// Record that the currently thrown value propagated out of a function.
+    pub fn trace_push(&mut self, entry: TraceEntry) {
+        self.trace.push(entry);
+    }
+
+    /// Record that the currently thrown value has been caught.
+    pub fn trace_caught(&mut self) {
+        self.caught_trace = std::mem::replace(&mut self.trace, vec![]);
+    }
+
+    /// The trace of the most recently caught value, innermost function first.
+    pub fn caught_trace(&self) -> &[TraceEntry] {
+        &self.caught_trace
+    }
+
+    /// Take the trace of the currently thrown value, innermost function first.
+    pub fn take_trace(&mut self) -> Vec<TraceEntry> {
+        std::mem::replace(&mut self.trace, vec![])
+    }
+
+    /// Install a hook that is called before every instruction the vm executes, or remove it.
+    pub fn set_debugger(&mut self, debugger: Option<Box<dyn Debugger>>) {
+        self.debugger = debugger;
+    }
+
+    pub fn is_debugging(&self) -> bool {
+        self.debugger.is_some()
+    }
+
+    /// Call the debugger, if there is one.
+    pub fn debug(&mut self, pause: &Pause) {
+        if let Some(mut debugger) = self.debugger.take() {
+            debugger.before_instruction(pause);
+            self.debugger = Some(debugger);
+        }
+    }
+
+    /// Start recording which functions the steps and the time of the execution are spent in.
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) {
+        self.profiler = profiler;
+    }
+
+    /// Stop profiling, returning the profiler with everything it recorded.
+    pub fn take_profiler(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+
+    pub fn is_profiling(&self) -> bool {
+        self.profiler.is_some()
+    }
+
+    /// Record that a function is being called, if profiling.
+    pub fn profile_enter<F: FnOnce() -> TraceEntry>(&mut self, key: ProfileKey, describe: F) {
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.enter(key, describe);
+        }
+    }
+
+    /// Record that the innermost function being called has returned, if profiling.
+    pub fn profile_leave(&mut self) {
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.leave();
+        }
+    }
+
+    /// Start recording which basic blocks are executed how often.
+    pub fn set_coverage(&mut self, coverage: Option<Coverage>) {
+        self.coverage = coverage;
+    }
+
+    /// Stop recording coverage, returning everything that has been recorded.
+    pub fn take_coverage(&mut self) -> Option<Coverage> {
+        self.coverage.take()
+    }
+
+    pub fn is_covering(&self) -> bool {
+        self.coverage.is_some()
+    }
+
+    /// Record that a basic block is being executed, if recording coverage.
+    pub fn cover_block(&mut self, chunk: &Gc<IrChunk>, bb: BBId) {
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.enter_block(chunk, bb);
+        }
+    }
+
+    /// Set whether code is optimized when it is compiled, see `optimize::optimize`. This is on by
+    /// default.
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+
+    pub fn optimizes(&self) -> bool {
+        self.optimize
+    }
+
+    /// Cache the expanded and compiled code of required files in the given directory, so that
+    /// later runs can skip reading, expanding and compiling them, or stop caching with `None`.
+    ///
+    /// Loading a file from the cache has the same results as expanding it again, except that
+    /// the traces of the expansion are skipped. The steps that the expansion took are charged
+    /// all the same (see `set_fuel`), and if there is not enough fuel left for them, the file is
+    /// expanded instead so that evaluation runs out of fuel at the same point. Expansions that
+    /// contain functions, cells or opaque values, or that require other files, are not cached.
+    /// Nothing is cached while recording coverage, which needs the source of each file.
+    pub fn set_cache_dir(&mut self, dir: Option<PathBuf>) {
+        self.cache = dir.map(Cache::new);
+    }
+
+    // Load the expansion of a file from the disk cache into the require cache, returns whether
+    // there was a cached expansion.
+    fn load_cached(&mut self, key: &str, expand_opts: &ImOrdMap<Value, Value>) -> bool {
+        if self.cache.is_none() || self.coverage.is_some() {
+            return false;
+        }
+        let content = match self.content_hash(key) {
+            Some(content) => content,
+            None => return false,
+        };
+
+        let options = Value::map(OrdMap(expand_opts.clone()));
+        let entry = match self.cache.as_ref().unwrap().load(content, &options, self.symbol_id) {
+            Some(entry) => entry,
+            None => return false,
+        };
+
+        // Account for the steps and the ids the expansion would have used.
+        match self.fuel {
+            Some(fuel) if fuel < entry.steps => return false,
+            Some(fuel) => self.fuel = Some(fuel - entry.steps),
+            None => {}
+        }
+        self.fuel_consumed += entry.steps;
+        self.symbol_id = self.symbol_id.checked_add(entry.symbols).expect("symbol id counter overflow");
+        self.fun_id = self.fun_id.checked_add(entry.funs).expect("function id counter overflow");
+        self.cell_id = self.cell_id.checked_add(entry.cells).expect("cell id counter overflow");
+
+        let expanded_key = (key.to_string(), expand_opts.clone());
+        self.require_cache.expanded.insert(
+            expanded_key.clone(),
+            Ok((entry.expanded.clone(), entry.source_map.clone())),
+        );
+        self.require_cache.entries.insert(expanded_key, entry);
+        return true;
+    }
+
+    // Write the expansion of a file to the disk cache, `before` are the id counters, the number
+    // of required files and the consumed fuel before the expansion.
+    fn store_cached(
+        &mut self,
+        key: &str,
+        expand_opts: &ImOrdMap<Value, Value>,
+        expanded: &(Value, SourceMap),
+        before: (u64, u64, u64, usize, u64),
+    ) {
+        let (symbol_id, fun_id, cell_id, requires, fuel_consumed) = before;
+        if self.cache.is_none() || self.coverage.is_some() || self.require_cache.len() != requires {
+            return;
+        }
+        let content = match self.content_hash(key) {
+            Some(content) => content,
+            None => return,
+        };
+
+        let options = Value::map(OrdMap(expand_opts.clone()));
+        if let Ok(entry) = Entry::new(
+            content,
+            &options,
+            expanded.0.clone(),
+            expanded.1.clone(),
+            symbol_id,
+            self.symbol_id - symbol_id,
+            self.fun_id - fun_id,
+            self.cell_id - cell_id,
+            self.fuel_consumed - fuel_consumed,
+        ) {
+            self.cache.as_ref().unwrap().store(&entry);
+            self.require_cache.entries.insert((key.to_string(), expand_opts.clone()), entry);
+        }
+    }
+
+    fn content_hash(&mut self, key: &str) -> Option<u128> {
+        if let Some(content) = self.require_cache.hashes.get(key) {
+            return Some(*content);
+        }
+
+        let content = bytecode::hash(self.resolver.source(key).ok()?.as_bytes());
+        self.require_cache.hashes.insert(key.to_string(), content);
+        Some(content)
+    }
+
+    // Evaluate the expansion of a required file like `builtins::eval` does, using the compiled
+    // chunk from the disk cache if there is one.
+    fn eval_required(
+        &mut self,
+        key: &str,
+        expand_opts: &ImOrdMap<Value, Value>,
+        expanded: &(Value, SourceMap),
+    ) -> Result<Value, Value> {
+        let env = builtins::eval_env(&OrdMap(expand_opts.clone()))?;
+        let module = key;
+        let key = (key.to_string(), expand_opts.clone());
+        let signature = bytecode::signature(env.iter().map(|(id, (_, mutable))| (id, *mutable))).ok();
+
+        let cached = match (self.require_cache.entries.get(&key), &signature) {
+            (Some(entry), Some(signature)) => entry.chunk(signature, self.optimize),
+            _ => None,
+        };
+
+        let ir = match cached {
+            Some(ir) => ir,
+            None => {
+                let (expanded, map) = expanded;
+                let ir = match to_code_mapped(expanded, map).and_then(|c| {
+                    compile::compile_chunk(c, map.span, Some(module), &env, self.optimize)
+                }) {
+                    Ok(ir) => Gc::new(ir),
+                    Err(_) => return Err(builtins::static_error()),
+                };
+
+                if let (Some(entry), Some(signature)) = (self.require_cache.entries.get_mut(&key), signature) {
+                    entry.add_chunk(signature, self.optimize, ir.clone());
+                    if let Some(cache) = self.cache.as_ref() {
+                        cache.store(entry);
+                    }
+                }
+                ir
+            }
+        };
+
+        match compile::close(ir, &env).compute(Vector(ImVector::new()), self) {
+            Ok(yay) => Ok(yay),
+            Err(err) => Err(builtins::eval_error(err)),
+        }
+    }
+
+    /// Use the given resolver to find the code that `require` loads, instead of the default
+    /// `FileResolver`.
+    pub fn set_resolver(&mut self, resolver: Box<dyn ModuleResolver>) {
+        self.resolver = resolver;
+    }
+
+    /// Set the key of the module that the top-level code belongs to, which `require` resolves
+    /// values relative to (e.g. relative paths with the `FileResolver`) unless it is loading a
+    /// module.
+    pub fn set_entrypoint(&mut self, key: Option<String>) {
+        self.entrypoint = key;
+    }
+
+    /// Every distinct call to `require` so far, in the order in which they happened. Calls are
+    /// distinct if they differ in the requiring module, the required module (or the value, if it
+    /// could not be resolved) or the options. See `deps` for rendering them as a dependency graph.
+    pub fn requires(&self) -> &[Require] {
+        &self.require_cache.requires
+    }
+
+    /// The key of the module that the top-level code belongs to, see `set_entrypoint`.
+    pub fn entrypoint(&self) -> Option<&str> {
+        self.entrypoint.as_ref().map(String::as_str)
+    }
+
     pub fn require(
         &mut self,
         v: &Value,
         expand_opts: &ImOrdMap<Value, Value>,
         eval_opts: &ImOrdMap<Value, Value>
-    ) -> Result<Value, Value>{
-        match v {
-            Value::Atomic(Atomic::String(s)) => {
-                let old_dir = current_dir().unwrap();
-                let s: String = s.0.clone().into();
-                let path = PathBuf::from(s).canonicalize().unwrap();
-                let newdir = path.parent().unwrap();
-
-                match self.require_cache.evaled.get(&(path.clone(), expand_opts.clone(), eval_opts.clone())) {
-                    Some(yay) => return yay.clone(),
-                    None => {
-                        match self.require_cache.expanded.get(&(path.clone(), expand_opts.clone())) {
-                            Some(Ok(yay)) => {
-                                set_current_dir(&newdir).unwrap();
-                                match builtins::eval(Vector(ImVector::from(vec![
-                                    yay.clone(),
-                                    Value::map(OrdMap(expand_opts.clone())),
-                                    ])), self) {
-                                    Err(err) => {
-                                        self.require_cache.evaled.insert((path.clone(), expand_opts.clone(), eval_opts.clone()), Err(err.clone()));
-                                        set_current_dir(&old_dir).unwrap();
-                                        return Err(err);
-                                    }
-                                    Ok(expanded) => {
-                                        self.require_cache.evaled.insert((path.clone(), expand_opts.clone(), eval_opts.clone()), Ok(expanded.clone()));
-                                        let ret = self.require(v, expand_opts, eval_opts);
-                                        set_current_dir(&old_dir).unwrap();
-                                        return ret;
-                                    }
-                                }
-                            }
-                            Some(Err(nope)) => {
-                                set_current_dir(&old_dir).unwrap();
-                                return Err(nope.clone());
-                            }
-                            None => {
-                                match self.require_cache.read.get(&path) {
-                                    Some(Ok(yay)) => {
-                                        set_current_dir(&newdir).unwrap();
-                                        match builtins::expand(Vector(ImVector::from(vec![
-                                            yay.clone(),
-                                            Value::map(OrdMap(expand_opts.clone())),
-                                            ])), self) {
-                                            Err(err) => {
-                                                self.require_cache.expanded.insert((path.clone(), expand_opts.clone()), Err(err.clone()));
-                                                set_current_dir(&old_dir).unwrap();
-                                                return Err(err);
-                                            }
-                                            Ok(expanded) => {
-                                                self.require_cache.expanded.insert((path.clone(), expand_opts.clone()), Ok(expanded.clone()));
-                                                let ret = self.require(v, expand_opts, eval_opts);
-                                                set_current_dir(&old_dir).unwrap();
-                                                return ret;
-                                            }
-                                        }
-                                    }
-                                    Some(Err(nope)) => {
-                                        set_current_dir(&old_dir).unwrap();
-                                        return Err(nope.clone());
-                                    }
-                                    None => {
-                                        match load_file(&path) {
-                                            Err(err) => {
-                                                self.require_cache.read.insert(path.clone(), Err(err.clone()));
-                                                set_current_dir(&old_dir).unwrap();
-                                                return Err(err);
-                                            }
-                                            Ok(src) => {
-                                                match read(CompleteStr(&src)) {
-                                                    Err(_) => {
-                                                        let err = require_error();
-                                                        self.require_cache.read.insert(path.clone(), Err(err.clone()));
-                                                        set_current_dir(&old_dir).unwrap();
-                                                        return Err(err);
-                                                    }
-                                                    Ok(code) => {
-                                                        self.require_cache.read.insert(path.clone(), Ok(code));
-                                                        let ret = self.require(v, expand_opts, eval_opts);
-                                                        set_current_dir(&old_dir).unwrap();
-                                                        return ret;
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
+    ) -> Result<Value, Value> {
+        let from = self.require_cache.loading.last().or(self.entrypoint.as_ref()).cloned();
+        let resolved = self.resolver.resolve(v, from.as_ref().map(String::as_str));
+
+        let distinct = (
+            from.clone(),
+            resolved.clone().map_err(|_| v.clone()),
+            expand_opts.clone(),
+            eval_opts.clone(),
+        );
+        if self.require_cache.distinct.insert(distinct) {
+            self.require_cache.requires.push(Require {
+                from,
+                v: v.clone(),
+                to: resolved.clone(),
+                expand_opts: expand_opts.clone(),
+                eval_opts: eval_opts.clone(),
+            });
+        }
+        let key = resolved?;
+
+        if let Some(start) = self.require_cache.loading.iter().position(|loading| *loading == key) {
+            let mut cycle: Vec<Value> = self.require_cache.loading[start..]
+                .iter()
+                .map(|key| Value::string_from_str(key))
+                .collect();
+            cycle.push(Value::string_from_str(&key));
+            self.require_cache.cycles += 1;
+            return Err(err_require_cycle(cycle));
+        }
+
+        let evaled_key = (key.clone(), expand_opts.clone(), eval_opts.clone());
+        if let Some(result) = self.require_cache.evaled.get(&evaled_key) {
+            return result.clone();
+        }
+
+        let expanded = self.require_expanded(&key, expand_opts)?;
+        let cycles = self.require_cache.cycles;
+        self.require_cache.loading.push(key.clone());
+        let result = self.eval_required(&key, expand_opts, &expanded);
+        self.require_cache.loading.pop();
+
+        // A cycle describes the modules that were being loaded at the time, requiring the module
+        // again from elsewhere might not run into it.
+        if self.require_cache.cycles == cycles {
+            self.require_cache.evaled.insert(evaled_key, result.clone());
+        }
+        return result;
+    }
+
+    fn require_expanded(
+        &mut self,
+        key: &str,
+        expand_opts: &ImOrdMap<Value, Value>,
+    ) -> Result<(Value, SourceMap), Value> {
+        let expanded_key = (key.to_string(), expand_opts.clone());
+        if let Some(result) = self.require_cache.expanded.get(&expanded_key) {
+            return result.clone();
+        }
+        if self.load_cached(key, expand_opts) {
+            return self.require_cache.expanded[&expanded_key].clone();
+        }
+
+        let (read, map) = self.require_read(key)?;
+        let (env, macros) = builtins::expand_env(&OrdMap(expand_opts.clone()))?;
+        let before = (
+            self.symbol_id,
+            self.fun_id,
+            self.cell_id,
+            self.require_cache.len(),
+            self.fuel_consumed,
+        );
+        let cycles = self.require_cache.cycles;
+        self.require_cache.loading.push(key.to_string());
+        let result = expand::expand_mapped(&read, &map, &env, &macros, self)
+            .map_err(|_| builtins::expand_error());
+        self.require_cache.loading.pop();
+
+        // See `require` for why expansions that ran into a cycle are not cached.
+        if self.require_cache.cycles == cycles {
+            if let Ok(expanded) = &result {
+                self.store_cached(key, expand_opts, expanded, before);
             }
+            self.require_cache.expanded.insert(expanded_key, result.clone());
+        }
+        return result;
+    }
 
-            _ => return Err(require_error()),
+    fn require_read(&mut self, key: &str) -> Result<(Value, SourceMap), Value> {
+        if let Some(result) = self.require_cache.read.get(key) {
+            return result.clone();
         }
+
+        let result = match self.resolver.source(key) {
+            Err(err) => Err(err),
+            Ok(src) => match read_located(CompleteStr(&src)) {
+                Err(_) => Err(err_require()),
+                Ok(located) => {
+                    let map = located.source_map();
+                    if let Some(coverage) = self.coverage.as_mut() {
+                        coverage.add_source(key, located.clone());
+                    }
+                    Ok((located.value, map))
+                }
+            },
+        };
+
+        self.require_cache.read.insert(key.to_string(), result.clone());
+        return result;
     }
 }
 
 pub struct RequireCache {
-    read: BTreeMap<PathBuf, Result<Value, Value>>,
-    expanded: BTreeMap<(PathBuf, ImOrdMap<Value, Value>), Result<Value, Value>>,
-    evaled: BTreeMap<(PathBuf, ImOrdMap<Value, Value>, ImOrdMap<Value, Value>), Result<Value, Value>>,
+    // All keyed by the keys of the modules, see `ModuleResolver::resolve`.
+    read: BTreeMap<String, Result<(Value, SourceMap), Value>>,
+    expanded: BTreeMap<(String, ImOrdMap<Value, Value>), Result<(Value, SourceMap), Value>>,
+    evaled: BTreeMap<(String, ImOrdMap<Value, Value>, ImOrdMap<Value, Value>), Result<Value, Value>>,
+    // The hashes of the source code of the modules, for looking them up in the disk cache.
+    hashes: BTreeMap<String, u128>,
+    // The disk cache entries of the expansions that have been loaded from or written to it.
+    entries: BTreeMap<(String, ImOrdMap<Value, Value>), Entry>,
+    // The modules that are currently being expanded or evaluated, innermost last. Requiring one
+    // of them again would never finish.
+    loading: Vec<String>,
+    // How many times requiring a module failed because it was already being loaded.
+    cycles: usize,
+    // See `Context::requires`.
+    requires: Vec<Require>,
+    // What tells the `requires` apart: the requiring module, the required module or the value
+    // that could not be resolved, and the options.
+    distinct: BTreeSet<(
+        Option<String>,
+        Result<String, Value>,
+        ImOrdMap<Value, Value>,
+        ImOrdMap<Value, Value>,
+    )>,
 }
 
 impl RequireCache {
@@ -179,25 +692,26 @@ impl RequireCache {
             read: BTreeMap::new(),
             expanded: BTreeMap::new(),
             evaled: BTreeMap::new(),
+            hashes: BTreeMap::new(),
+            entries: BTreeMap::new(),
+            loading: vec![],
+            cycles: 0,
+            requires: vec![],
+            distinct: BTreeSet::new(),
         };
     }
+
+    // How many results have been cached.
+    fn len(&self) -> usize {
+        self.read.len() + self.expanded.len() + self.evaled.len()
+    }
 }
 
-fn require_error() -> Value {
+// `{:tag :err-require :cycle [keys...]}`, the keys of the modules that require each other, the
+// first one being the same as the last one.
+fn err_require_cycle(cycle: Vec<Value>) -> Value {
     Value::map(OrdMap(ImOrdMap::from(vec![
             (Value::kw_str("tag"), Value::kw_str("err-require")),
+            (Value::kw_str("cycle"), Value::arr_from_vec(cycle)),
         ])))
 }
-
-fn load_file(p: &PathBuf) -> Result<String, Value> {
-    match File::open(p) {
-        Err(_) => return Err(require_error()),
-        Ok(mut file) => {
-            let mut contents = String::new();
-            match file.read_to_string(&mut contents) {
-                Err(_) => return Err(require_error()),
-                Ok(_) => return Ok(contents),
-            }
-        }
-    }
-}
@@ -0,0 +1,417 @@
+//! Conversion between rust data and pavo values.
+//!
+//! `IntoValue` and `FromValue` are implemented for the primitive types, strings, the standard
+//! collections, options and tuples of up to twelve elements, and can be derived for structs and
+//! enums:
+//!
+//! - structs with named fields become maps from keywords to the field values, with the field
+//!   names converted to kebab-case (`foo_bar` becomes `:foo-bar`)
+//! - tuple structs become arrays, newtype structs their inner value, unit structs nil
+//! - enum variants become maps tagged with the variant name in kebab-case
+//!   (`NumArgs` becomes `{:tag :num-args}`), the fields of struct-like variants are added to the
+//!   map, the fields of tuple variants are stored as an array under the key `:fields`, struct-like
+//!   variants can not have a field named `tag`
+//!
+//! Missing map entries are treated as nil, so `Option` fields may be omitted.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use failure::Fail;
+
+use crate::value::{Value, Atomic, self};
+
+/// Types that can be converted into a pavo value.
+pub trait IntoValue {
+    fn into_value(self) -> Value;
+}
+
+/// Types that can be created from a pavo value.
+pub trait FromValue: Sized {
+    fn from_value(v: &Value) -> Result<Self, FromValueError>;
+}
+
+/// A value that could not be converted into a rust type.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct FromValueError {
+    /// A description of what was expected, e.g. "an int".
+    pub expected: String,
+    /// The (sub-)value that did not match.
+    pub found: Value,
+    /// How to get from the converted value to the mismatch: map keys and array indices,
+    /// outermost first.
+    pub path: Vec<String>,
+}
+
+impl FromValueError {
+    pub fn new<S: Into<String>>(expected: S, found: &Value) -> FromValueError {
+        FromValueError {
+            expected: expected.into(),
+            found: found.clone(),
+            path: vec![],
+        }
+    }
+
+    /// Record that the error occured inside the given map key or array index.
+    pub fn at<S: Into<String>>(mut self, segment: S) -> FromValueError {
+        self.path.insert(0, segment.into());
+        self
+    }
+}
+
+impl fmt::Display for FromValueError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut found = String::new();
+        value::debug_print(&self.found, 0, 0, &mut found);
+
+        write!(f, "expected {}", self.expected)?;
+        if !self.path.is_empty() {
+            write!(f, " at {}", self.path.join(" "))?;
+        }
+        write!(f, ", found {}", found)
+    }
+}
+
+impl Fail for FromValueError {}
+
+impl IntoValue for Value {
+    fn into_value(self) -> Value {
+        self
+    }
+}
+
+impl FromValue for Value {
+    fn from_value(v: &Value) -> Result<Self, FromValueError> {
+        Ok(v.clone())
+    }
+}
+
+impl IntoValue for () {
+    fn into_value(self) -> Value {
+        Value::nil()
+    }
+}
+
+impl FromValue for () {
+    fn from_value(v: &Value) -> Result<Self, FromValueError> {
+        match v {
+            Value::Atomic(Atomic::Nil) => Ok(()),
+            _ => Err(FromValueError::new("nil", v)),
+        }
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self) -> Value {
+        Value::bool_(self)
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(v: &Value) -> Result<Self, FromValueError> {
+        match v {
+            Value::Atomic(Atomic::Bool(b)) => Ok(*b),
+            _ => Err(FromValueError::new("a bool", v)),
+        }
+    }
+}
+
+macro_rules! int_conversions {
+    ($($t:ty),*) => {$(
+        impl FromValue for $t {
+            fn from_value(v: &Value) -> Result<Self, FromValueError> {
+                match v {
+                    Value::Atomic(Atomic::Int(n)) if *n >= <$t>::min_value() as i64
+                        && *n as i128 <= <$t>::max_value() as i128 => Ok(*n as $t),
+                    _ => Err(FromValueError::new(format!(
+                        "an int between {} and {}", <$t>::min_value(), <$t>::max_value()
+                    ), v)),
+                }
+            }
+        }
+    )*};
+}
+
+int_conversions!(i8, i16, i32, u8, u16, u32, u64, usize);
+
+// Only the types whose values all fit into an i64 can be converted infallibly.
+macro_rules! int_into_value {
+    ($($t:ty),*) => {$(
+        impl IntoValue for $t {
+            fn into_value(self) -> Value {
+                Value::int(self as i64)
+            }
+        }
+    )*};
+}
+
+int_into_value!(i8, i16, i32, i64, u8, u16, u32);
+
+impl FromValue for i64 {
+    fn from_value(v: &Value) -> Result<Self, FromValueError> {
+        match v {
+            Value::Atomic(Atomic::Int(n)) => Ok(*n),
+            _ => Err(FromValueError::new("an int", v)),
+        }
+    }
+}
+
+/// Panics on NaN and infinities, like `Value::float`.
+impl IntoValue for f64 {
+    fn into_value(self) -> Value {
+        Value::float(self)
+    }
+}
+
+impl FromValue for f64 {
+    fn from_value(v: &Value) -> Result<Self, FromValueError> {
+        match v {
+            Value::Atomic(Atomic::Float(n)) => Ok(n.clone().into_inner()),
+            _ => Err(FromValueError::new("a float", v)),
+        }
+    }
+}
+
+impl IntoValue for char {
+    fn into_value(self) -> Value {
+        Value::char_(self)
+    }
+}
+
+impl FromValue for char {
+    fn from_value(v: &Value) -> Result<Self, FromValueError> {
+        match v {
+            Value::Atomic(Atomic::Char(c)) => Ok(*c),
+            _ => Err(FromValueError::new("a char", v)),
+        }
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self) -> Value {
+        Value::string_from_str(&self)
+    }
+}
+
+impl<'a> IntoValue for &'a str {
+    fn into_value(self) -> Value {
+        Value::string_from_str(self)
+    }
+}
+
+impl FromValue for String {
+    fn from_value(v: &Value) -> Result<Self, FromValueError> {
+        match v {
+            Value::Atomic(Atomic::String(s)) => Ok(s.0.to_string()),
+            _ => Err(FromValueError::new("a string", v)),
+        }
+    }
+}
+
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value(self) -> Value {
+        match self {
+            None => Value::nil(),
+            Some(t) => t.into_value(),
+        }
+    }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(v: &Value) -> Result<Self, FromValueError> {
+        match v {
+            Value::Atomic(Atomic::Nil) => Ok(None),
+            _ => T::from_value(v).map(Some),
+        }
+    }
+}
+
+impl<T: IntoValue> IntoValue for Vec<T> {
+    fn into_value(self) -> Value {
+        Value::arr_from_vec(self.into_iter().map(IntoValue::into_value).collect())
+    }
+}
+
+impl<T: FromValue> FromValue for Vec<T> {
+    fn from_value(v: &Value) -> Result<Self, FromValueError> {
+        match v.as_arr() {
+            None => Err(FromValueError::new("an array", v)),
+            Some(arr) => arr.0
+                .iter()
+                .enumerate()
+                .map(|(i, elem)| T::from_value(elem).map_err(|err| err.at(i.to_string())))
+                .collect(),
+        }
+    }
+}
+
+impl<T: IntoValue> IntoValue for BTreeSet<T> {
+    fn into_value(self) -> Value {
+        Value::set_from_vec(self.into_iter().map(IntoValue::into_value).collect())
+    }
+}
+
+impl<T: FromValue + Ord> FromValue for BTreeSet<T> {
+    fn from_value(v: &Value) -> Result<Self, FromValueError> {
+        match v.as_set() {
+            None => Err(FromValueError::new("a set", v)),
+            Some(set) => set.0.iter().map(T::from_value).collect(),
+        }
+    }
+}
+
+impl<K: IntoValue, V: IntoValue> IntoValue for BTreeMap<K, V> {
+    fn into_value(self) -> Value {
+        Value::map_from_vec(
+            self.into_iter().map(|(k, v)| (k.into_value(), v.into_value())).collect()
+        )
+    }
+}
+
+impl<K: FromValue + Ord, V: FromValue> FromValue for BTreeMap<K, V> {
+    fn from_value(v: &Value) -> Result<Self, FromValueError> {
+        match v.as_map() {
+            None => Err(FromValueError::new("a map", v)),
+            Some(map) => map.0
+                .iter()
+                .map(|(key, val)| {
+                    let segment = path_segment(key);
+                    Ok((
+                        K::from_value(key).map_err(|err| err.at(segment.clone()))?,
+                        V::from_value(val).map_err(|err| err.at(segment))?,
+                    ))
+                })
+                .collect(),
+        }
+    }
+}
+
+macro_rules! tuple_conversions {
+    ($len:expr, $($t:ident $i:tt),+) => {
+        impl<$($t: IntoValue),+> IntoValue for ($($t,)+) {
+            fn into_value(self) -> Value {
+                Value::arr_from_vec(vec![$(self.$i.into_value()),+])
+            }
+        }
+
+        impl<$($t: FromValue),+> FromValue for ($($t,)+) {
+            fn from_value(v: &Value) -> Result<Self, FromValueError> {
+                match v.as_arr() {
+                    Some(arr) if arr.0.len() == $len => Ok(($(
+                        $t::from_value(&arr.0[$i]).map_err(|err| err.at($i.to_string()))?,
+                    )+)),
+                    _ => Err(FromValueError::new(format!("an array of length {}", $len), v)),
+                }
+            }
+        }
+    };
+}
+
+tuple_conversions!(1, A 0);
+tuple_conversions!(2, A 0, B 1);
+tuple_conversions!(3, A 0, B 1, C 2);
+tuple_conversions!(4, A 0, B 1, C 2, D 3);
+tuple_conversions!(5, A 0, B 1, C 2, D 3, E 4);
+tuple_conversions!(6, A 0, B 1, C 2, D 3, E 4, F 5);
+tuple_conversions!(7, A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+tuple_conversions!(8, A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+tuple_conversions!(9, A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8);
+tuple_conversions!(10, A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9);
+tuple_conversions!(11, A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10);
+tuple_conversions!(12, A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10, L 11);
+
+/// The keyword for the rust field name `name`: the name in kebab-case (`foo_bar` becomes
+/// `foo-bar`).
+pub fn field_keyword(name: &str) -> String {
+    name.trim_start_matches("r#").replace('_', "-")
+}
+
+/// The keyword for the rust enum variant name `name`: the name in kebab-case (`NumArgs` becomes
+/// `num-args`).
+pub fn variant_keyword(name: &str) -> String {
+    let mut kw = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() {
+            if i > 0 {
+                kw.push('-');
+            }
+            kw.extend(c.to_lowercase());
+        } else {
+            kw.push(c);
+        }
+    }
+    kw
+}
+
+fn path_segment(key: &Value) -> String {
+    let mut out = String::new();
+    value::debug_print(key, 0, 0, &mut out);
+    out
+}
+
+// Helpers for the code generated by the derive macros of the `pavo-derive` crate.
+
+#[doc(hidden)]
+pub fn expect_map(v: &Value) -> Result<(), FromValueError> {
+    match v.as_map() {
+        Some(_) => Ok(()),
+        None => Err(FromValueError::new("a map", v)),
+    }
+}
+
+#[doc(hidden)]
+pub fn expect_arr(v: &Value, len: usize) -> Result<(), FromValueError> {
+    match v.as_arr() {
+        Some(arr) if arr.0.len() == len => Ok(()),
+        _ => Err(FromValueError::new(format!("an array of length {}", len), v)),
+    }
+}
+
+/// Convert the n-th element of an array that has been checked with `expect_arr`.
+#[doc(hidden)]
+pub fn element<T: FromValue>(v: &Value, n: usize) -> Result<T, FromValueError> {
+    match v.as_arr().and_then(|arr| arr.0.get(n)) {
+        Some(elem) => T::from_value(elem).map_err(|err| err.at(n.to_string())),
+        None => Err(FromValueError::new(format!("an array of length {}", n + 1), v)),
+    }
+}
+
+/// Convert the entry for the keyword `key` of a map, treating a missing entry as nil.
+#[doc(hidden)]
+pub fn field<T: FromValue>(v: &Value, key: &str) -> Result<T, FromValueError> {
+    let map = match v.as_map() {
+        Some(map) => map,
+        None => return Err(FromValueError::new("a map", v)),
+    };
+
+    match map.0.get(&Value::kw_str(key)) {
+        Some(val) => T::from_value(val).map_err(|err| err.at(format!(":{}", key))),
+        None => T::from_value(&Value::nil())
+            .map_err(|_| FromValueError::new(format!("a map with the key :{}", key), v)),
+    }
+}
+
+/// The keyword under the key `:tag` of a map.
+#[doc(hidden)]
+pub fn tag(v: &Value) -> Result<&str, FromValueError> {
+    match v.as_map().and_then(|map| map.0.get(&Value::kw_str("tag"))) {
+        Some(tag) => match tag.as_kw() {
+            Some(kw) => Ok(kw),
+            None => Err(FromValueError::new("a keyword", tag).at(":tag".to_string())),
+        },
+        None => Err(FromValueError::new("a map with the key :tag", v)),
+    }
+}
+
+#[doc(hidden)]
+pub fn tagged(tag: &str, mut entries: Vec<(&str, Value)>) -> Value {
+    entries.insert(0, ("tag", Value::kw_str(tag)));
+    keyword_map(entries)
+}
+
+#[doc(hidden)]
+pub fn keyword_map(entries: Vec<(&str, Value)>) -> Value {
+    Value::map_from_vec(
+        entries.into_iter().map(|(key, val)| (Value::kw_str(key), val)).collect()
+    )
+}
@@ -0,0 +1,273 @@
+//! Records how often each basic block of the executed code ran, and reports this per source file
+//! in the lcov tracefile format, see `Context::set_coverage`.
+//!
+//! Blocks are mapped to source code via `IrChunk::block_sources`, the spans of the expressions
+//! whose evaluation they begin. Blocks without a span (e.g. because a macro created their code)
+//! are not reported.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{self, Write};
+
+use gc::Gc;
+
+use crate::read::Located;
+use crate::value::{self, Value};
+use crate::vm::{BBId, Instruction, IrChunk};
+
+struct Chunk {
+    // Holding on to the chunk keeps its address from being reused.
+    ir: Gc<IrChunk>,
+    // How often each block has been entered.
+    hits: Vec<u64>,
+}
+
+pub struct Coverage {
+    // All chunks encountered so far, a chunk always comes after its parent.
+    chunks: Vec<Chunk>,
+    // Maps the addresses of the chunks to their indices in `chunks`.
+    indices: HashMap<usize, usize>,
+    // The code of the source files, by path.
+    sources: BTreeMap<String, Vec<Located>>,
+    // The path of the file the top-level code has been read from, see `add_entrypoint_source`.
+    entrypoint: Option<String>,
+}
+
+// The part of a file covered by a basic block.
+#[derive(Clone, Copy)]
+struct Span {
+    start: usize,
+    end: usize,
+    first_line: u32,
+    last_line: u32,
+    hits: u64,
+}
+
+// What is reported about a single source file.
+#[derive(Default)]
+struct Report {
+    // Line, name and number of calls of each function.
+    functions: Vec<(u32, String, u64)>,
+    // Line, group, index within the group, and number of executions of each branch.
+    branches: Vec<(u32, usize, usize, u64)>,
+    spans: Vec<Span>,
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage {
+            chunks: vec![],
+            indices: HashMap::new(),
+            sources: BTreeMap::new(),
+            entrypoint: None,
+        }
+    }
+
+    /// Make the code read from a source file available for locating the blocks. Code may be added
+    /// in multiple parts, e.g. one per top-level form.
+    pub fn add_source(&mut self, path: &str, located: Located) {
+        self.sources.entry(path.to_string()).or_insert_with(Vec::new).push(located);
+    }
+
+    /// Like `add_source`, for the file of the top-level code, whose chunks do not belong to a
+    /// module (see `IrChunk::module`).
+    pub fn add_entrypoint_source(&mut self, path: &str, located: Located) {
+        self.entrypoint = Some(path.to_string());
+        self.add_source(path, located);
+    }
+
+    /// Record that a basic block of the chunk is being executed.
+    pub fn enter_block(&mut self, chunk: &Gc<IrChunk>, bb: BBId) {
+        let index = match self.indices.get(&address(chunk)) {
+            Some(index) => *index,
+            None => self.register(chunk),
+        };
+        self.chunks[index].hits[bb] += 1;
+    }
+
+    // Start tracking a chunk and all chunks nested inside of it, so that code that never runs is
+    // reported as well. Returns the index of the chunk.
+    fn register(&mut self, chunk: &Gc<IrChunk>) -> usize {
+        let index = self.chunks.len();
+        self.indices.insert(address(chunk), index);
+        self.chunks.push(Chunk {
+            ir: chunk.clone(),
+            hits: vec![0; chunk.basic_blocks.len()],
+        });
+
+        for block in chunk.basic_blocks.iter() {
+            for instruction in block.iter() {
+                if let Instruction::FunLiteral(inner, ..) = instruction {
+                    if !self.indices.contains_key(&address(inner)) {
+                        self.register(inner);
+                    }
+                }
+            }
+        }
+
+        return index;
+    }
+
+    /// Write an lcov tracefile with a record for each source file.
+    pub fn write_lcov<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let mut reports: BTreeMap<&str, Report> = self.sources
+            .keys()
+            .map(|path| (&path[..], Report::default()))
+            .collect();
+        // Numbers the `sf-case` expressions, to group their branches.
+        let mut group = 0;
+
+        for chunk in self.chunks.iter() {
+            let path = match chunk.ir.module.as_ref().or(self.entrypoint.as_ref()) {
+                Some(path) => path,
+                None => continue,
+            };
+            let report = match reports.get_mut(&path[..]) {
+                Some(report) => report,
+                None => continue,
+            };
+
+            if let (Some(_), Some(span)) = (&chunk.ir.source, chunk.ir.span) {
+                let name = match &chunk.ir.name {
+                    Some(name) => {
+                        let mut out = String::new();
+                        value::debug_print(&Value::id(name.clone()), 0, 0, &mut out);
+                        out
+                    }
+                    None => "fn".to_string(),
+                };
+                report.functions.push((span.start.line, format!("{}@{}", name, span.start), chunk.hits[0]));
+            }
+
+            let mut spans = HashMap::new();
+            for (bb, source) in chunk.ir.block_sources.iter().enumerate() {
+                if let Some(source) = source {
+                    let span = Span {
+                        start: source.start.offset,
+                        end: source.end.offset,
+                        first_line: source.start.line,
+                        last_line: source.end.line,
+                        hits: chunk.hits[bb],
+                    };
+                    report.spans.push(span);
+                    spans.insert(bb, span);
+                }
+            }
+
+            for branches in chunk.ir.branches.iter() {
+                for (i, bb) in branches.iter().enumerate() {
+                    if let Some(span) = spans.get(bb) {
+                        report.branches.push((span.first_line, group, i, span.hits));
+                    }
+                }
+                group += 1;
+            }
+        }
+
+        for (path, report) in reports.iter() {
+            let mut code_lines = BTreeSet::new();
+            for located in self.sources[*path].iter() {
+                collect_lines(located, &mut code_lines);
+            }
+            write_record(w, path, report, &code_lines)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn address(chunk: &Gc<IrChunk>) -> usize {
+    &**chunk as *const IrChunk as usize
+}
+
+// The lines on which any expression begins.
+fn collect_lines(located: &Located, lines: &mut BTreeSet<u32>) {
+    lines.insert(located.pos.line);
+    for child in located.children.iter() {
+        collect_lines(child, lines);
+    }
+}
+
+// How often a line has been executed: the maximum of the hits of the innermost spans that
+// contain it. `None` if no span contains the line.
+fn line_hits(line: u32, spans: &[Span]) -> Option<u64> {
+    let mut containing: Vec<Span> = spans
+        .iter()
+        .filter(|span| span.first_line <= line && line <= span.last_line)
+        .cloned()
+        .collect();
+    containing.sort_by(|a, b| a.start.cmp(&b.start).then(b.end.cmp(&a.end)));
+    containing.dedup_by(|later, earlier| {
+        let same = later.start == earlier.start && later.end == earlier.end;
+        if same {
+            earlier.hits = earlier.hits.max(later.hits);
+        }
+        same
+    });
+
+    // Spans are either nested or disjoint, so a span is innermost unless the next one in this
+    // order starts inside of it.
+    let mut hits = None;
+    for (i, span) in containing.iter().enumerate() {
+        let innermost = match containing.get(i + 1) {
+            Some(next) => next.start >= span.end,
+            None => true,
+        };
+        if innermost {
+            hits = Some(hits.unwrap_or(0).max(span.hits));
+        }
+    }
+
+    return hits;
+}
+
+fn write_record<W: Write>(
+    w: &mut W,
+    path: &str,
+    report: &Report,
+    code_lines: &BTreeSet<u32>,
+) -> io::Result<()> {
+    writeln!(w, "TN:")?;
+    writeln!(w, "SF:{}", path)?;
+
+    for (line, name, _) in report.functions.iter() {
+        writeln!(w, "FN:{},{}", line, name)?;
+    }
+    for (_, name, hits) in report.functions.iter() {
+        writeln!(w, "FNDA:{},{}", hits, name)?;
+    }
+    writeln!(w, "FNF:{}", report.functions.len())?;
+    writeln!(w, "FNH:{}", report.functions.iter().filter(|(_, _, hits)| *hits > 0).count())?;
+
+    for (line, group, branch, hits) in report.branches.iter() {
+        let group_hits: u64 = report.branches
+            .iter()
+            .filter(|(_, other, _, _)| other == group)
+            .map(|(_, _, _, hits)| hits)
+            .sum();
+
+        // lcov uses `-` for branches whose branching expression has never been evaluated.
+        if group_hits == 0 {
+            writeln!(w, "BRDA:{},{},{},-", line, group, branch)?;
+        } else {
+            writeln!(w, "BRDA:{},{},{},{}", line, group, branch, hits)?;
+        }
+    }
+    writeln!(w, "BRF:{}", report.branches.len())?;
+    writeln!(w, "BRH:{}", report.branches.iter().filter(|(_, _, _, hits)| *hits > 0).count())?;
+
+    let mut found = 0;
+    let mut hit = 0;
+    for line in code_lines.iter() {
+        if let Some(hits) = line_hits(*line, &report.spans) {
+            writeln!(w, "DA:{},{}", line, hits)?;
+            found += 1;
+            if hits > 0 {
+                hit += 1;
+            }
+        }
+    }
+    writeln!(w, "LF:{}", found)?;
+    writeln!(w, "LH:{}", hit)?;
+
+    writeln!(w, "end_of_record")
+}
@@ -0,0 +1,303 @@
+//! An interactive step debugger for the `debug` subcommand.
+//!
+//! The debugger pauses before the first instruction of the program, and then whenever a step
+//! command completes or a breakpoint is hit. Function breakpoints are checked when a function is
+//! entered, line breakpoints whenever the vm enters a basic block that begins the evaluation of
+//! an expression on that line (see `IrChunk::block_sources`).
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::process;
+
+use rustyline::Editor;
+use rustyline::error::ReadlineError;
+
+use crate::value::{self, Id, Value};
+use crate::vm::{Debugger, Pause, BB_RETURN};
+
+const HELP: &str = "\
+Commands:
+  s, step            execute the next instruction (an empty line does the same)
+  n, next            execute the next instruction, without pausing inside calls
+  o, out             continue until the current function returns
+  c, continue        continue until a breakpoint is hit
+  b <target>         pause when entering a function, the target is either a function name,
+                     a function id (`#12`), or pause at a line (`42`) of the debugged file
+  b                  list the breakpoints
+  d <n>              delete the n-th breakpoint
+  stack              print the temporary values of the current function, topmost first
+  env                print the bindings of the current function
+  where              print the current position
+  q, quit            abort the program
+  help               print this message";
+
+// When to pause next.
+#[derive(Clone, Copy)]
+enum Mode {
+    // Before the next instruction.
+    Step,
+    // Before the next instruction with at most the given call depth.
+    Next(usize),
+    // Before the next instruction with less than the given call depth.
+    Out(usize),
+    // Only at breakpoints.
+    Continue,
+}
+
+enum Breakpoint {
+    Name(String),
+    FunId(u64),
+    Line(u32),
+}
+
+impl Breakpoint {
+    fn parse(s: &str) -> Breakpoint {
+        if s.starts_with('#') {
+            if let Ok(id) = s[1..].parse() {
+                return Breakpoint::FunId(id);
+            }
+        }
+
+        match s.parse() {
+            Ok(line) => Breakpoint::Line(line),
+            Err(_) => Breakpoint::Name(s.to_string()),
+        }
+    }
+}
+
+// Where the commands come from.
+enum Input {
+    Terminal(Editor<()>),
+    // Commands given in advance, see `StepDebugger::scripted`.
+    Script(VecDeque<String>),
+}
+
+pub struct StepDebugger {
+    path: String,
+    input: Input,
+    out: Box<dyn Write>,
+    mode: Mode,
+    breakpoints: Vec<Breakpoint>,
+    // For each call depth, the line at which the most recently entered block of the call there
+    // begins, so that line breakpoints pause once per line rather than once per block.
+    lines: Vec<Option<u32>>,
+}
+
+impl StepDebugger {
+    /// A debugger that reads commands from the terminal, `path` is the file being debugged.
+    pub fn new(path: &str) -> StepDebugger {
+        StepDebugger::with_io(path, Input::Terminal(Editor::<()>::new()), Box::new(io::stdout()))
+    }
+
+    /// A debugger that runs the given commands instead of reading them from the terminal, and
+    /// writes what it prints to `out`. Once the commands run out, the program runs to completion
+    /// without pausing again.
+    pub fn scripted<W: Write + 'static>(path: &str, commands: &[&str], out: W) -> StepDebugger {
+        let commands = commands.iter().map(|command| command.to_string()).collect();
+        StepDebugger::with_io(path, Input::Script(commands), Box::new(out))
+    }
+
+    fn with_io(path: &str, input: Input, out: Box<dyn Write>) -> StepDebugger {
+        StepDebugger {
+            path: path.to_string(),
+            input,
+            out,
+            mode: Mode::Step,
+            breakpoints: vec![],
+            lines: vec![],
+        }
+    }
+
+    fn print(&mut self, s: &str) {
+        writeln!(self.out, "{}", s).expect("failed to write the output of the debugger");
+    }
+
+    // Record the block that is entered before this instruction (if any), returns the line of the
+    // debugged file at which it begins if that differs from the previous block of the call.
+    fn enter_block(&mut self, pause: &Pause) -> Option<u32> {
+        if pause.pc.1 != 0 {
+            return None;
+        }
+
+        self.lines.resize(pause.depth + 1, None);
+        if pause.pc.0 == 0 {
+            // A new call, unrelated to the previous one at this depth.
+            self.lines[pause.depth] = None;
+        }
+
+        let line = match (&pause.chunk.module, pause.chunk.block_sources[pause.pc.0]) {
+            (None, Some(span)) => span.start.line,
+            _ => return None,
+        };
+
+        match std::mem::replace(&mut self.lines[pause.depth], Some(line)) {
+            Some(previous) if previous == line => None,
+            _ => Some(line),
+        }
+    }
+
+    fn is_breakpoint(&self, pause: &Pause, entered_line: Option<u32>) -> bool {
+        self.breakpoints.iter().any(|b| match b {
+            Breakpoint::Line(line) => entered_line == Some(*line),
+            _ if pause.pc != (0, 0) => false,
+            Breakpoint::Name(name) => match &pause.chunk.name {
+                Some(Id::User(fun_name)) => fun_name == name,
+                _ => false,
+            },
+            Breakpoint::FunId(id) => pause.fun_id == Some(*id),
+        })
+    }
+
+    fn should_pause(&self, pause: &Pause, entered_line: Option<u32>) -> bool {
+        match self.mode {
+            Mode::Step => true,
+            Mode::Next(depth) if pause.depth <= depth => true,
+            Mode::Out(depth) if pause.depth < depth => true,
+            _ => self.is_breakpoint(pause, entered_line),
+        }
+    }
+
+    fn print_position(&mut self, pause: &Pause) {
+        let mut out = match pause.fun_id {
+            None => "top-level code".to_string(),
+            Some(id) => format!("function {}", id),
+        };
+        if let Some(name) = &pause.chunk.name {
+            out.push_str(&format!(" {}", show(&Value::id(name.clone()))));
+        }
+        if let Some(span) = pause.chunk.span {
+            let file = pause.chunk.module.as_ref().unwrap_or(&self.path);
+            out.push_str(&format!(" ({}:{})", file, span.start));
+        }
+
+        self.print(&format!("{}, depth {}", out, pause.depth));
+        self.print(&format!("  bb {} #{}: {}", pause.pc.0, pause.pc.1, instruction(pause)));
+    }
+
+    fn print_env(&mut self, pause: &Pause) {
+        let chunk = pause.chunk;
+
+        for (name, v) in chunk.locals.iter().zip(pause.locals.iter()) {
+            self.print(&format!("  {} = {}", show(&Value::id(name.clone())), show(v)));
+        }
+        for (name, v) in chunk.boxes.iter().zip(pause.boxes.iter()) {
+            self.print(&format!("  {} = {}", show(&Value::id(name.clone())), show(&v.borrow())));
+        }
+        for (name, v) in chunk.captures.iter().zip(pause.captures.iter()) {
+            self.print(&format!("  {} = {} (captured)", show(&Value::id(name.clone())), show(&v.borrow())));
+        }
+    }
+
+    // The next command, `None` once there are no more.
+    fn read_command(&mut self) -> Option<String> {
+        match &mut self.input {
+            Input::Terminal(editor) => loop {
+                match editor.readline("debug> ") {
+                    Ok(line) => {
+                        editor.add_history_entry(line.as_str());
+                        return Some(line);
+                    }
+                    Err(ReadlineError::Interrupted) => continue,
+                    Err(ReadlineError::Eof) => process::exit(0),
+                    Err(err) => {
+                        eprintln!("{}", err);
+                        process::exit(1);
+                    }
+                }
+            },
+            Input::Script(commands) => commands.pop_front(),
+        }
+    }
+
+    // Read and run commands until one of them resumes the execution.
+    fn prompt(&mut self, pause: &Pause) {
+        self.print_position(pause);
+
+        loop {
+            let line = match self.read_command() {
+                Some(line) => line,
+                None => {
+                    // The script is done, let the program finish.
+                    self.breakpoints.clear();
+                    self.mode = Mode::Continue;
+                    return;
+                }
+            };
+
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (None, _) | (Some("s"), None) | (Some("step"), None) => {
+                    self.mode = Mode::Step;
+                    return;
+                }
+                (Some("n"), None) | (Some("next"), None) => {
+                    self.mode = Mode::Next(pause.depth);
+                    return;
+                }
+                (Some("o"), None) | (Some("out"), None) => {
+                    self.mode = Mode::Out(pause.depth);
+                    return;
+                }
+                (Some("c"), None) | (Some("continue"), None) => {
+                    self.mode = Mode::Continue;
+                    return;
+                }
+                (Some("b"), None) => {
+                    let listed: Vec<String> = self.breakpoints
+                        .iter()
+                        .enumerate()
+                        .map(|(i, b)| match b {
+                            Breakpoint::Name(name) => format!("  {}: function {}", i, name),
+                            Breakpoint::FunId(id) => format!("  {}: function #{}", i, id),
+                            Breakpoint::Line(line) => format!("  {}: line {}", i, line),
+                        })
+                        .collect();
+                    for line in listed {
+                        self.print(&line);
+                    }
+                }
+                (Some("b"), Some(target)) => self.breakpoints.push(Breakpoint::parse(target)),
+                (Some("d"), Some(n)) => match n.parse::<usize>() {
+                    Ok(n) if n < self.breakpoints.len() => {
+                        self.breakpoints.remove(n);
+                    }
+                    _ => self.print(&format!("no breakpoint {}", n)),
+                },
+                (Some("stack"), None) => {
+                    for v in pause.stack.iter().rev() {
+                        self.print(&format!("  {}", show(v)));
+                    }
+                }
+                (Some("env"), None) => self.print_env(pause),
+                (Some("where"), None) => self.print_position(pause),
+                (Some("q"), None) | (Some("quit"), None) => process::exit(0),
+                (Some("help"), None) => self.print(HELP),
+                _ => self.print("unknown command, enter `help` for a list of commands"),
+            }
+        }
+    }
+}
+
+impl Debugger for StepDebugger {
+    fn before_instruction(&mut self, pause: &Pause) {
+        let entered_line = self.enter_block(pause);
+        if self.should_pause(pause, entered_line) {
+            self.prompt(pause);
+        }
+    }
+}
+
+// Render a value on a single line.
+fn show(v: &Value) -> String {
+    let mut buf = String::new();
+    value::debug_print(v, 0, 0, &mut buf);
+    buf
+}
+
+fn instruction(pause: &Pause) -> String {
+    match format!("{:?}", pause.instruction) {
+        // Closures print their whole ir, which is too much to be helpful here.
+        ref s if s.starts_with("FunLiteral") => "FunLiteral".to_string(),
+        s => s.replace(&BB_RETURN.to_string(), "return"),
+    }
+}
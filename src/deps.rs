@@ -0,0 +1,195 @@
+//! The dependency graph of the modules loaded via `require`, see `Context::requires`.
+//!
+//! The graph can be rendered as a tree (`tree`) or in the Graphviz DOT format (`dot`). Both
+//! point out the modules that have been required with several distinct sets of options, which
+//! are expanded and evaluated once per set, and the values that could not be resolved.
+
+use std::collections::BTreeSet;
+
+use im_rc::OrdMap as ImOrdMap;
+
+use crate::gc_foreign::OrdMap;
+use crate::value::{self, Value};
+
+/// A call to `(require v opts)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Require {
+    /// The key of the requiring module, `None` for top-level code outside of any module.
+    pub from: Option<String>,
+    /// The value passed to `require`.
+    pub v: Value,
+    /// The key of the required module, or the error if `v` could not be resolved.
+    pub to: Result<String, Value>,
+    /// The options relevant to expanding the module.
+    pub expand_opts: ImOrdMap<Value, Value>,
+    /// The options relevant to evaluating the module.
+    pub eval_opts: ImOrdMap<Value, Value>,
+}
+
+impl Require {
+    // All the options, as a single map.
+    fn options(&self) -> ImOrdMap<Value, Value> {
+        self.expand_opts.clone().union(self.eval_opts.clone())
+    }
+}
+
+/// The modules that have been required with more than one distinct set of options, each with
+/// all those sets (as passed to `require`), in the order in which they were first required.
+pub fn option_sets(requires: &[Require]) -> Vec<(String, Vec<ImOrdMap<Value, Value>>)> {
+    let mut sets: Vec<(String, Vec<ImOrdMap<Value, Value>>)> = vec![];
+
+    for r in requires.iter() {
+        if let Ok(key) = &r.to {
+            let options = r.options();
+            match sets.iter_mut().find(|(module, _)| module == key) {
+                Some((_, options_)) => {
+                    if !options_.contains(&options) {
+                        options_.push(options);
+                    }
+                }
+                None => sets.push((key.clone(), vec![options])),
+            }
+        }
+    }
+
+    sets.retain(|(_, options)| options.len() > 1);
+    sets
+}
+
+/// The requires whose value could not be resolved to a module.
+pub fn unresolved(requires: &[Require]) -> Vec<&Require> {
+    requires.iter().filter(|r| r.to.is_err()).collect()
+}
+
+/// Render the modules required by the module `root` (`None` for top-level code outside of any
+/// module) as a tree, followed by the modules required with several sets of options and the
+/// unresolved requires. Modules required more than once are expanded only at their first
+/// occurrence.
+pub fn tree(requires: &[Require], root: Option<&str>) -> String {
+    let mut out = format!("{}\n", name(root));
+    subtree(requires, root, "", &mut vec![], &mut BTreeSet::new(), &mut out);
+
+    let sets = option_sets(requires);
+    if !sets.is_empty() {
+        out.push_str("\nrequired with several sets of options:\n");
+        for (module, options) in sets.iter() {
+            out.push_str(&format!("  {}\n", module));
+            for options in options.iter() {
+                out.push_str(&format!("    {}\n", show(&Value::map(OrdMap(options.clone())))));
+            }
+        }
+    }
+
+    let unresolved = unresolved(requires);
+    if !unresolved.is_empty() {
+        out.push_str("\nunresolved:\n");
+        for r in unresolved.iter() {
+            out.push_str(&format!("  {} (required by {})\n", show(&r.v), name(r.from.as_ref().map(String::as_str))));
+        }
+    }
+
+    out
+}
+
+// `ancestors` are the modules on the path from the root, `printed` those whose requires have
+// already been printed.
+fn subtree(
+    requires: &[Require],
+    module: Option<&str>,
+    prefix: &str,
+    ancestors: &mut Vec<String>,
+    printed: &mut BTreeSet<String>,
+    out: &mut String,
+) {
+    let children: Vec<&Require> = requires
+        .iter()
+        .filter(|r| r.from.as_ref().map(String::as_str) == module)
+        .collect();
+
+    for (i, r) in children.iter().enumerate() {
+        let last = i + 1 == children.len();
+        out.push_str(prefix);
+        out.push_str(if last { "└── " } else { "├── " });
+
+        let key = match &r.to {
+            Ok(key) => key,
+            Err(_) => {
+                out.push_str(&format!("{} (unresolved)\n", show(&r.v)));
+                continue;
+            }
+        };
+
+        out.push_str(key);
+        let options = r.options();
+        if !options.is_empty() {
+            out.push_str(&format!(" {}", show(&Value::map(OrdMap(options)))));
+        }
+
+        if ancestors.contains(key) {
+            out.push_str(" (cycle)\n");
+        } else if printed.contains(key) {
+            out.push_str(" (see above)\n");
+        } else {
+            out.push('\n');
+            printed.insert(key.clone());
+            ancestors.push(key.clone());
+            let prefix = format!("{}{}", prefix, if last { "    " } else { "│   " });
+            subtree(requires, Some(key), &prefix, ancestors, printed, out);
+            ancestors.pop();
+        }
+    }
+}
+
+/// Render the requires as a Graphviz DOT graph. Edges are labeled with the options of the
+/// require if there are any, modules required with several sets of options are drawn in orange,
+/// and unresolved requires lead to dashed red nodes.
+pub fn dot(requires: &[Require]) -> String {
+    let mut out = "digraph dependencies {\n".to_string();
+
+    for (module, _) in option_sets(requires).iter() {
+        out.push_str(&format!("    {} [color=orange];\n", quote(module)));
+    }
+
+    let mut edges = BTreeSet::new();
+    for r in requires.iter() {
+        let from = quote(&name(r.from.as_ref().map(String::as_str)));
+        let to = match &r.to {
+            Ok(key) => quote(key),
+            Err(_) => {
+                let to = quote(&show(&r.v));
+                out.push_str(&format!("    {} [style=dashed color=red];\n", to));
+                to
+            }
+        };
+
+        let options = r.options();
+        let label = if options.is_empty() {
+            String::new()
+        } else {
+            format!(" [label={}]", quote(&show(&Value::map(OrdMap(options)))))
+        };
+
+        let edge = format!("    {} -> {}{};\n", from, to, label);
+        if edges.insert(edge.clone()) {
+            out.push_str(&edge);
+        }
+    }
+
+    out.push_str("}\n");
+    out
+}
+
+fn name(module: Option<&str>) -> String {
+    module.unwrap_or("<top-level>").to_string()
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// Render a value on a single line.
+fn show(v: &Value) -> String {
+    let mut buf = String::new();
+    value::debug_print(v, 0, 0, &mut buf);
+    buf
+}
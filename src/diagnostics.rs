@@ -0,0 +1,149 @@
+//! Human-readable descriptions of the errors of evaluating a form, as printed by the CLI and the
+//! repl.
+
+use crate::check::BindingError;
+use crate::compile::StaticError;
+use crate::context::{Abort, TraceEntry};
+use crate::expand::ExpandError;
+use crate::read::Located;
+use crate::special_forms::{FormType, SpecialFormSyntaxError};
+use crate::value::{self, Value};
+use crate::E;
+
+// Render a value on a single line.
+fn show(v: &Value) -> String {
+    let mut buf = String::new();
+    value::debug_print(v, 0, 0, &mut buf);
+    buf
+}
+
+fn form_name(ft: FormType) -> &'static str {
+    match ft {
+        FormType::Quote => "sf-quote",
+        FormType::Do => "sf-do",
+        FormType::SetBang => "sf-set!",
+        FormType::If => "sf-if",
+        FormType::Throw => "sf-throw",
+        FormType::Try => "sf-try",
+        FormType::Lambda => "sf-lambda",
+        FormType::Case => "sf-case",
+        FormType::LetFn => "sf-letfn",
+        FormType::Def => "def",
+        FormType::DefMacro => "defmacro",
+    }
+}
+
+fn syntax_message(err: &SpecialFormSyntaxError) -> String {
+    match err {
+        SpecialFormSyntaxError::Arity(ft, len) => {
+            format!("{} form with the wrong number of arguments ({})", form_name(*ft), len - 1)
+        }
+        SpecialFormSyntaxError::Id(ft, v) => {
+            format!("{} form expected an identifier, got {}", form_name(*ft), show(v))
+        }
+        SpecialFormSyntaxError::SetBangId(v) => {
+            format!("sf-set! form expected an identifier, got {}", show(v))
+        }
+        SpecialFormSyntaxError::DoNotArray(v) => {
+            format!("sf-do form expected an array, got {}", show(v))
+        }
+        SpecialFormSyntaxError::ArgsNotArray(v) => {
+            format!("expected an array of arguments, got {}", show(v))
+        }
+        SpecialFormSyntaxError::CaseNotArray(v) => {
+            format!("sf-case form expected an array, got {}", show(v))
+        }
+        SpecialFormSyntaxError::LetFnNotMap(v) => {
+            format!("sf-letfn form expected a map, got {}", show(v))
+        }
+        SpecialFormSyntaxError::FnName(v) => {
+            format!("sf-letfn form expected a function name, got {}", show(v))
+        }
+        SpecialFormSyntaxError::OddCases(v) => {
+            format!("sf-case form with an odd number of patterns and bodies: {}", show(v))
+        }
+        SpecialFormSyntaxError::Binder(ft, v) => {
+            format!("{} form expected a binder, got {}", form_name(*ft), show(v))
+        }
+        SpecialFormSyntaxError::Pattern(v) => format!("invalid pattern {}", show(v)),
+        SpecialFormSyntaxError::Foo => "malformed sf-letfn form".to_string(),
+    }
+}
+
+// A one-line description of the error, without its class.
+fn message(err: &E) -> String {
+    match err {
+        E::Expand(ExpandError::Arity(form, _)) => {
+            format!("macro form with the wrong number of arguments: {}", show(form))
+        }
+        E::Expand(ExpandError::MacroThrew(v)) => format!("macro threw {}", show(v)),
+        E::Expand(ExpandError::Type(v, _)) => format!("macro is not a function: {}", show(v)),
+        E::Expand(ExpandError::BodyEval(inner)) => {
+            format!("evaluating a macro definition failed: {}", message(inner))
+        }
+        E::Expand(ExpandError::Pattern { pattern, body, .. }) => {
+            format!("macro definition {} does not match the pattern {}", show(body), show(pattern))
+        }
+        E::Static(StaticError::Binding(BindingError::Free(id), _)) => {
+            format!("unbound identifier {}", show(&Value::id(id.clone())))
+        }
+        E::Static(StaticError::Binding(BindingError::Immutable(id), _)) => {
+            format!("assignment to immutable binding {}", show(&Value::id(id.clone())))
+        }
+        E::Static(StaticError::SpecialFormSyntax(err, _)) => syntax_message(err),
+        E::Eval(v, _) => format!("threw {}", show(v)),
+        E::Abort(Abort::Diverge(v)) => format!("diverged with {}", show(v)),
+        E::Abort(Abort::OutOfFuel) => "ran out of fuel".to_string(),
+        E::Abort(Abort::CallDepth) => "exceeded the maximum call depth".to_string(),
+    }
+}
+
+/// Describe an error that occured while evaluating the located value, `path` names the file it
+/// was read from.
+pub fn describe(path: &str, located: &Located, err: &E) -> String {
+    let pos = err.span().map(|span| span.start).unwrap_or(located.pos);
+
+    match err {
+        E::Expand(_) => format!("{}:{}: expansion error: {}", path, pos, message(err)),
+        E::Static(_) => format!("{}:{}: static error: {}", path, pos, message(err)),
+        E::Eval(v, trace) => {
+            let mut buf = String::new();
+            value::debug_print(v, 0, 2, &mut buf);
+            for entry in trace.iter() {
+                buf.push_str("\n  in ");
+                buf.push_str(&describe_trace_entry(path, entry));
+            }
+            format!("{}:{}: uncaught throw:\n{}", path, pos, buf)
+        }
+        E::Abort(Abort::Diverge(v)) => {
+            let mut buf = String::new();
+            value::debug_print(v, 0, 2, &mut buf);
+            format!("{}:{}: diverged:\n{}", path, pos, buf)
+        }
+        E::Abort(Abort::OutOfFuel) | E::Abort(Abort::CallDepth) => {
+            format!("{}:{}: {}", path, pos, message(err))
+        }
+    }
+}
+
+// `path` is the file of the entrypoint, functions defined in required modules are reported with
+// the key of their module.
+fn describe_trace_entry(path: &str, entry: &TraceEntry) -> String {
+    match entry {
+        TraceEntry::Closure { id: None, .. } => "top-level code".to_string(),
+        TraceEntry::Closure { id: Some(id), name, span, module, .. } => {
+            let mut out = format!("function {}", id);
+            if let Some(name) = name {
+                out.push_str(&format!(" {}", show(&Value::id(name.clone()))));
+            }
+            if let Some(span) = span {
+                let file = module.as_ref().map(String::as_str).unwrap_or(path);
+                out.push_str(&format!(" defined at {}:{}", file, span.start));
+            }
+            out
+        }
+        TraceEntry::Builtin(name) => format!("builtin {}", name),
+        TraceEntry::Native(id, name) => format!("function {} {}", id, name),
+        TraceEntry::Opaque(id) => format!("function {}", id),
+    }
+}
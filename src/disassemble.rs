@@ -0,0 +1,135 @@
+//! Render the ir of compiled code in a readable form.
+//!
+//! Each chunk is printed as a sequence of labeled basic blocks. Jump targets refer to these
+//! labels, the registers and boxes of the bindings are annotated with their names, and the chunks
+//! of nested functions are printed inline, below the instruction that creates them.
+
+use crate::value::{self, Id, Value};
+use crate::vm::{Addr, BBId, BB_RETURN, Closure, Instruction, IrChunk, Test};
+
+/// Render the ir of a closure created by `compile::compile`.
+pub fn disassemble(c: &Closure) -> String {
+    let mut out = String::new();
+    chunk(&c.fun, c.args, 0, &mut out);
+    out
+}
+
+fn chunk(ir: &IrChunk, args: usize, indent: usize, out: &mut String) {
+    line(indent, &header(ir, args), out);
+    if !ir.params.is_empty() {
+        line(indent, &format!("params {}", addresses(ir, &ir.params)), out);
+    }
+
+    for (bb, block) in ir.basic_blocks.iter().enumerate() {
+        line(indent, &format!("{}:", label(bb)), out);
+
+        for instruction in block.iter() {
+            line(indent + 4, &render(ir, instruction), out);
+
+            if let Instruction::FunLiteral(inner, inner_args, _) = instruction {
+                chunk(inner, *inner_args, indent + 8, out);
+            }
+        }
+    }
+}
+
+fn header(ir: &IrChunk, args: usize) -> String {
+    match (&ir.name, &ir.source) {
+        (_, None) => "top-level code".to_string(),
+        (Some(name), _) => format!("function {} ({} args)", show(&Value::id(name.clone())), args),
+        (None, _) => format!("function ({} args)", args),
+    }
+}
+
+fn render(ir: &IrChunk, instruction: &Instruction) -> String {
+    match instruction {
+        Instruction::Literal(v) => format!("literal {}", show(v)),
+        Instruction::Arr(count) => format!("arr {}", count),
+        Instruction::App(count) => format!("app {}", count),
+        Instruction::Set(count) => format!("set {}", count),
+        Instruction::Map(count) => format!("map {}", count),
+        Instruction::FunLiteral(_, args, captures) => {
+            format!("fun-literal {} [{}]", args, addresses(ir, captures))
+        }
+        Instruction::Jump(bb) => format!("jump {}", label(*bb)),
+        Instruction::CondJump(yay, nay) => format!("cond-jump {} {}", label(*yay), label(*nay)),
+        Instruction::Switch(addr, arms, default) => {
+            let arms: Vec<String> = arms
+                .iter()
+                .map(|arm| format!("{} -> {}", test(&arm.test), label(arm.target)))
+                .collect();
+            format!("switch {} [{}] else {}", address(ir, addr), arms.join(", "), label(*default))
+        }
+        Instruction::Item(from, index, to) => {
+            format!("item {} {} {}", address(ir, from), index, address(ir, to))
+        }
+        Instruction::Entry(from, key, to) => {
+            format!("entry {} {} {}", address(ir, from), show(key), address(ir, to))
+        }
+        Instruction::Throw => "throw".to_string(),
+        Instruction::SetCatchHandler(bb) => format!("set-catch-handler {}", label(*bb)),
+        Instruction::Push(addr) => format!("push {}", address(ir, addr)),
+        Instruction::Pop(addr) => format!("pop {}", address(ir, addr)),
+        Instruction::DoubleTop => "double-top".to_string(),
+        Instruction::DropTop => "drop-top".to_string(),
+        Instruction::Call(count, push) => format!("call {}{}", count, discard(*push)),
+        Instruction::TailCall(count, push) => format!("tail-call {}{}", count, discard(*push)),
+    }
+}
+
+fn label(bb: BBId) -> String {
+    if bb == BB_RETURN {
+        "return".to_string()
+    } else {
+        format!("bb{}", bb)
+    }
+}
+
+fn discard(push: bool) -> &'static str {
+    if push { "" } else { " discard" }
+}
+
+// A binding, e.g. `x@box:0` for the binding of `x` in the first box of the call.
+fn address(ir: &IrChunk, addr: &Addr) -> String {
+    let (kind, names, i) = match addr {
+        Addr::Stack => return "stack".to_string(),
+        Addr::Local(i) => ("local", &ir.locals, i),
+        Addr::Boxed(i) => ("box", &ir.boxes, i),
+        Addr::Captured(i) => ("captured", &ir.captures, i),
+    };
+
+    format!("{}@{}:{}", name(names.get(*i)), kind, i)
+}
+
+fn addresses(ir: &IrChunk, addrs: &[Addr]) -> String {
+    addrs.iter().map(|addr| address(ir, addr)).collect::<Vec<String>>().join(" ")
+}
+
+fn name(id: Option<&Id>) -> String {
+    id.map(|id| show(&Value::id(id.clone()))).unwrap_or_else(|| "?".to_string())
+}
+
+fn test(t: &Test) -> String {
+    match t {
+        Test::Atomic(a) => show(&Value::Atomic(a.clone())),
+        Test::Set(set) => show(&Value::set(set.clone())),
+        Test::Arr(len) => format!("arr {}", len),
+        Test::App(len) => format!("app {}", len),
+        Test::Map => "map".to_string(),
+        Test::HasKey(key) => format!("has-key {}", show(key)),
+    }
+}
+
+fn show(v: &Value) -> String {
+    let mut buf = String::new();
+    value::debug_print(v, 0, 0, &mut buf);
+    buf
+}
+
+fn line(indent: usize, s: &str, out: &mut String) {
+    for _ in 0..indent {
+        out.push(' ');
+    }
+    out.push_str(s);
+    out.push('\n');
+}
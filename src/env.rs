@@ -1,6 +1,8 @@
 use std::collections::HashMap;
 
-use crate::value::{Value, Id, Builtin, self};
+use crate::context::Context;
+use crate::gc_foreign::Vector;
+use crate::value::{Value, Id, Builtin, Fun, self};
 
 pub fn default() -> HashMap<Id, (Value, bool)> {
     let mut m = HashMap::new();
@@ -249,6 +251,7 @@ pub fn default() -> HashMap<Id, (Value, bool)> {
     env_add(&mut m, "not", Builtin::Not);
     env_add(&mut m, "diverge", Builtin::Diverge);
     env_add(&mut m, "trace", Builtin::Trace);
+    env_add(&mut m, "backtrace", Builtin::Backtrace);
 
     env_add(&mut m, "require", Builtin::Require);
 
@@ -306,6 +309,36 @@ pub fn default() -> HashMap<Id, (Value, bool)> {
     m
 }
 
+thread_local! {
+    // The names that the builtins are bound to in the default environment.
+    static BUILTIN_NAMES: HashMap<Builtin, String> = default()
+        .into_iter()
+        .filter_map(|(id, (v, _))| match (id, v) {
+            (Id::User(name), Value::Fun(Fun::Builtin(b))) => Some((b, name)),
+            _ => None,
+        })
+        .collect();
+}
+
+/// The name that a builtin is bound to in the default environment, e.g. `int-add` for
+/// `Builtin::IntAdd`. Builtins that are not bound there are named after their variant.
+pub fn builtin_name(b: Builtin) -> String {
+    BUILTIN_NAMES.with(|names| names.get(&b).cloned().unwrap_or_else(|| format!("{:?}", b)))
+}
+
+/// Bind a rust closure as a native function of the given arity, see `Value::native`.
+pub fn env_add_native<F>(
+    m: &mut HashMap<Id, (Value, bool)>,
+    name: &str,
+    arity: usize,
+    f: F,
+    cx: &mut Context,
+) where
+    F: Fn(Vector<Value>, &mut Context) -> Result<Value, Value> + 'static,
+{
+    env_add_val(m, name, Value::native(name, arity, f, cx));
+}
+
 fn env_add(
     m: &mut HashMap<Id, (Value, bool)>,
     name: &str,
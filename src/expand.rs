@@ -2,23 +2,38 @@ use std::collections::HashMap;
 
 use im_rc::{OrdMap as ImOrdMap, Vector as ImVector};
 
-use crate::context::Context;
+use crate::context::{Abort, Context};
 use crate::gc_foreign::Vector;
+use crate::read::{SourceMap, Span};
 use crate::value::{Value, Id};
-use crate::{exval, E};
+use crate::{exval_mapped, E};
 
+/// An error during macro expansion, with the span of the offending code if known.
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum ExpandError {
-    Arity(Value /* the form with incorrect arity */),
+    Arity(Value /* the form with incorrect arity */, Option<Span>),
     MacroThrew(Value /* the thrown value */),
-    Type(Value /* the macro value that isn't a function */),
+    Type(Value /* the macro value that isn't a function */, Option<Span>),
     BodyEval(Box<E>),
     Pattern {
         pattern: Value,
         body: Value,
+        span: Option<Span>,
     },
 }
 
+impl ExpandError {
+    /// Where in the source code the error occured, if known.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            ExpandError::Arity(_, span) | ExpandError::Type(_, span) => *span,
+            ExpandError::Pattern { span, .. } => *span,
+            ExpandError::MacroThrew(_) => None,
+            ExpandError::BodyEval(err) => err.span(),
+        }
+    }
+}
+
 impl From<E> for ExpandError {
     fn from(err: E) -> Self {
         ExpandError::BodyEval(Box::new(err))
@@ -26,111 +41,259 @@ impl From<E> for ExpandError {
 }
 
 pub fn expand(v: &Value, env: &HashMap<Id, (Value, bool)>, macros: &ImOrdMap<Id, Value>, cx: &mut Context) -> Result<Value, ExpandError> {
+    expand_mapped(v, &SourceMap::default(), env, macros, cx).map(|(expanded, _)| expanded)
+}
+
+// Expand the items of a collection, starting at the given index.
+fn expand_items<'a, I>(
+    items: I,
+    map: &SourceMap,
+    env: &HashMap<Id, (Value, bool)>,
+    macros: &ImOrdMap<Id, Value>,
+    cx: &mut Context,
+) -> Result<(Vec<Value>, Vec<SourceMap>), ExpandError>
+where
+    I: Iterator<Item = &'a Value>,
+{
+    let mut expanded = vec![];
+    let mut maps = vec![];
+    for (i, item) in items.enumerate() {
+        let (item, item_map) = expand_mapped(item, map.child(i), env, macros, cx)?;
+        expanded.push(item);
+        maps.push(item_map);
+    }
+    Ok((expanded, maps))
+}
+
+/// Like `expand`, but also map the expanded value to the source code, given the spans of `v`.
+///
+/// Values that are not macro applications keep their spans. The result of a macro application
+/// gets the span of the application, and the parts of it that the macro took from its arguments
+/// keep the spans they had there, see `Origins`.
+pub fn expand_mapped(
+    v: &Value,
+    map: &SourceMap,
+    env: &HashMap<Id, (Value, bool)>,
+    macros: &ImOrdMap<Id, Value>,
+    cx: &mut Context,
+) -> Result<(Value, SourceMap), ExpandError> {
     match v {
         Value::Atomic(..) | Value::Id(..) | Value::Fun(..) | Value::Cell(..)
-        | Value::Opaque(..)  => Ok(v.clone()),
+        | Value::Opaque(..)  => Ok((v.clone(), map.clone())),
 
         Value::Arr(ref vals) => {
-            let mut expanded = Vec::with_capacity(vals.0.len());
-            for item in vals.0.iter() {
-                expanded.push(expand(item, env, macros, cx)?);
-            }
-            return Ok(Value::arr_from_vec(expanded));
+            let (expanded, maps) = expand_items(vals.0.iter(), map, env, macros, cx)?;
+            return Ok((Value::arr_from_vec(expanded), SourceMap::new(map.span, maps)));
         }
 
         Value::Set(ref vals) => {
-            let mut expanded = Vec::with_capacity(vals.0.len());
-            for item in vals.0.iter() {
-                expanded.push(expand(item, env, macros, cx)?);
-            }
-            return Ok(Value::set_from_vec(expanded));
+            let (expanded, maps) = expand_items(vals.0.iter(), map, env, macros, cx)?;
+            let set = Value::set_from_vec(expanded.clone());
+
+            // Expanding may change the order of the elements.
+            let maps = set.as_set().unwrap().0
+                .iter()
+                .map(|elem| maps[expanded.iter().position(|v| v == elem).unwrap()].clone())
+                .collect();
+            return Ok((set, SourceMap::new(map.span, maps)));
         }
 
         Value::Map(ref vals) => {
-            let mut expanded = Vec::with_capacity(vals.0.len());
-            for entry in vals.0.iter() {
-                let key = expand(&entry.0, env, macros, cx)?;
-                let val = expand(&entry.1, env, macros, cx)?;
-                expanded.push((key, val));
+            let mut entries = Vec::with_capacity(vals.0.len());
+            let mut entry_maps = Vec::with_capacity(vals.0.len());
+            for (i, entry) in vals.0.iter().enumerate() {
+                let (key, key_map) = expand_mapped(&entry.0, map.child(2 * i), env, macros, cx)?;
+                let (val, val_map) = expand_mapped(&entry.1, map.child(2 * i + 1), env, macros, cx)?;
+                entries.push((key, val));
+                entry_maps.push((key_map, val_map));
             }
-            return Ok(Value::map_from_vec(expanded));
+            let expanded = Value::map_from_vec(entries.clone());
+
+            // Expanding may change the order of the keys.
+            let mut maps = vec![];
+            for key in expanded.as_map().unwrap().0.keys() {
+                let i = entries.iter().rposition(|(k, _)| k == key).unwrap();
+                maps.push(entry_maps[i].0.clone());
+                maps.push(entry_maps[i].1.clone());
+            }
+            return Ok((expanded, SourceMap::new(map.span, maps)));
         }
 
         Value::App(ref vals) => {
             if vals.0.len() == 0 {
-                return Ok(v.clone());
+                return Ok((v.clone(), map.clone()));
             }
 
             let fst = &vals.0[0];
 
             match fst {
                 Value::Id(Id::User(id)) if id == "sf-quote" => {
-                    Ok(v.clone())
+                    Ok((v.clone(), map.clone()))
                 }
 
                 Value::Id(Id::User(id)) if id == "macro" => {
                     if vals.0.len() != 4 {
-                        return Err(ExpandError::Arity(v.clone()));
+                        return Err(ExpandError::Arity(v.clone(), map.span));
                     }
 
-                    let body = exval(&vals.0[2], env, macros, env, cx)?;
-                    let new_macros = match_macro(&body, &vals.0[1], macros)?;
-                    expand(&vals.0[3], env, &new_macros, cx)
+                    let body = exval_mapped(&vals.0[2], map.child(2), env, macros, env, cx)?;
+                    let new_macros = match_macro(&body, &vals.0[1], macros, map.child_span(1))?;
+                    expand_mapped(&vals.0[3], map.child(3), env, &new_macros, cx)
                 }
 
                 Value::Id(Id::User(id)) if id == "sf-quote" || id == "sf-do" || id == "sf-set!" || id == "sf-if" || id == "sf-throw" || id == "sf-try" || id == "sf-lambda" || id == "sf-letfn" || id == "sf-match" => {
-                    let mut expanded = Vec::with_capacity(vals.0.len());
-                    for item in vals.0.iter() {
-                        expanded.push(expand(item, env, macros, cx)?);
-                    }
-                    return Ok(Value::app_from_vec(expanded));
+                    let (expanded, maps) = expand_items(vals.0.iter(), map, env, macros, cx)?;
+                    return Ok((Value::app_from_vec(expanded), SourceMap::new(map.span, maps)));
                 }
 
                 Value::Id(id) => match macros.get(id) {
                     Some(macro_) => {
                         match macro_ {
                             Value::Fun(macro_fun) => {
-                                cx.inc_level();
+                                // Expanding the result of a macro recurses on the rust stack, so
+                                // nested macro applications count against the native depth.
+                                // Nested values that are not macro applications do not.
+                                if !cx.enter_native() {
+                                    return Err(aborted(cx));
+                                }
+
                                 let result = macro_fun.compute(
                                     Vector(ImVector::from(
                                         vals.0.iter().map(Clone::clone).skip(1).collect::<Vec<Value>>()
                                     )),
                                     cx
                                 );
-                                cx.dec_level();
 
-                                match result {
-                                    Ok(yay) => return expand(&yay, env, macros, cx),
-                                    Err(nay) => return Err(ExpandError::MacroThrew(nay)),
-                                }
+                                let expanded = match result {
+                                    Ok(yay) => {
+                                        let yay_map = Origins::new(v, map).map_result(&yay);
+                                        expand_mapped(&yay, &yay_map, env, macros, cx)
+                                    }
+                                    Err(_) if cx.is_aborting() => Err(aborted(cx)),
+                                    Err(nay) => Err(ExpandError::MacroThrew(nay)),
+                                };
+                                cx.leave_native();
+                                return expanded;
                             }
 
-                            _ => return Err(ExpandError::Type(macro_.clone())),
+                            _ => return Err(ExpandError::Type(macro_.clone(), map.span)),
                         }
                     }
 
                     None => {
-                        let mut expanded = Vec::with_capacity(vals.0.len());
-                        for item in vals.0.iter() {
-                            expanded.push(expand(item, env, macros, cx)?);
-                        }
-                        return Ok(Value::app_from_vec(expanded));
+                        let (expanded, maps) = expand_items(vals.0.iter(), map, env, macros, cx)?;
+                        return Ok((Value::app_from_vec(expanded), SourceMap::new(map.span, maps)));
                     }
                 }
 
                 _ => {
-                    let mut expanded = Vec::with_capacity(vals.0.len());
-                    for item in vals.0.iter() {
-                        expanded.push(expand(item, env, macros, cx)?);
-                    }
-                    return Ok(Value::app_from_vec(expanded));
+                    let (expanded, maps) = expand_items(vals.0.iter(), map, env, macros, cx)?;
+                    return Ok((Value::app_from_vec(expanded), SourceMap::new(map.span, maps)));
                 }
             }
         }
     }
 }
 
-fn match_macro(body: &Value, pattern: &Value, macros: &ImOrdMap<Id, Value>) -> Result<ImOrdMap<Id, Value>, ExpandError> {
+// The error for an expansion that has been aborted, rather than a macro that threw.
+fn aborted(cx: &Context) -> ExpandError {
+    let abort = cx.aborted().cloned().unwrap_or(Abort::CallDepth);
+    ExpandError::BodyEval(Box::new(E::Abort(abort)))
+}
+
+// Maps the result of a macro application to the source code of its arguments.
+//
+// A macro can only pass on values from its arguments, not where they came from. So the result is
+// searched for the arguments and their sub-values, in the order in which they appear in the
+// source. Each of them is used at most once (unless a macro duplicates an argument), so that
+// e.g. the two `nil`s in `(if c nil nil)` keep their own spans.
+struct Origins<'a> {
+    // The arguments and all their sub-values that have a span, in source order, each with the
+    // index after its last sub-value.
+    candidates: Vec<(Value, &'a SourceMap, usize)>,
+    used: Vec<bool>,
+    // The span of the macro application.
+    span: Option<Span>,
+}
+
+impl<'a> Origins<'a> {
+    fn new(app: &Value, map: &'a SourceMap) -> Origins<'a> {
+        let mut candidates = vec![];
+        for (i, arg) in app.as_app().unwrap().0.iter().enumerate().skip(1) {
+            add_candidates(arg, map.child(i), &mut candidates);
+        }
+
+        Origins {
+            used: vec![false; candidates.len()],
+            candidates,
+            span: map.span,
+        }
+    }
+
+    fn map_result(&mut self, result: &Value) -> SourceMap {
+        let mut map = self.map(result);
+        if map.span.is_none() {
+            map.span = self.span;
+        }
+        map
+    }
+
+    fn map(&mut self, v: &Value) -> SourceMap {
+        let used = &self.used;
+        let found = self.candidates
+            .iter()
+            .enumerate()
+            .position(|(i, (candidate, _, _))| !used[i] && candidate == v)
+            .or_else(|| self.candidates.iter().position(|(candidate, _, _)| candidate == v));
+
+        match found {
+            Some(i) => {
+                let (_, map, end) = &self.candidates[i];
+                for used in self.used[i..*end].iter_mut() {
+                    *used = true;
+                }
+                (*map).clone()
+            }
+            None => {
+                let children = sub_values(v).iter().map(|child| self.map(child)).collect();
+                SourceMap::new(None, children)
+            }
+        }
+    }
+}
+
+fn add_candidates<'a>(v: &Value, map: &'a SourceMap, candidates: &mut Vec<(Value, &'a SourceMap, usize)>) {
+    if map.span.is_none() {
+        return;
+    }
+
+    let index = candidates.len();
+    candidates.push((v.clone(), map, 0));
+    for (i, child) in sub_values(v).iter().enumerate() {
+        add_candidates(child, map.child(i), candidates);
+    }
+    candidates[index].2 = candidates.len();
+}
+
+// The sub-values in the order of a `SourceMap`.
+fn sub_values(v: &Value) -> Vec<Value> {
+    match v {
+        Value::Arr(vals) | Value::App(vals) => vals.0.iter().cloned().collect(),
+        Value::Set(vals) => vals.0.iter().cloned().collect(),
+        Value::Map(vals) => vals.0
+            .iter()
+            .flat_map(|(key, val)| vec![key.clone(), val.clone()])
+            .collect(),
+        _ => vec![],
+    }
+}
+
+fn match_macro(
+    body: &Value,
+    pattern: &Value,
+    macros: &ImOrdMap<Id, Value>,
+    span: Option<Span>,
+) -> Result<ImOrdMap<Id, Value>, ExpandError> {
     match pattern {
         Value::Id(id) => Ok(macros.update(id.clone(), body.clone())),
 
@@ -141,17 +304,17 @@ fn match_macro(body: &Value, pattern: &Value, macros: &ImOrdMap<Id, Value>) -> R
 
                     for (pattern_key, pattern_val) in pattern_map.0.iter() {
                         match body_map.0.get(pattern_key) {
-                            None => return Err(ExpandError::Pattern { pattern: pattern.clone(), body: body.clone()}),
-                            Some(body_val) => ret = match_macro(body_val, pattern_val, macros)?.union(ret),
+                            None => return Err(ExpandError::Pattern { pattern: pattern.clone(), body: body.clone(), span }),
+                            Some(body_val) => ret = match_macro(body_val, pattern_val, macros, span)?.union(ret),
                         }
                     }
 
                     return Ok(ret);
                 }
-                None => return Err(ExpandError::Pattern { pattern: pattern.clone(), body: body.clone()}),
+                None => return Err(ExpandError::Pattern { pattern: pattern.clone(), body: body.clone(), span }),
             }
         }
 
-        _ => Err(ExpandError::Pattern { pattern: pattern.clone(), body: body.clone()})
+        _ => Err(ExpandError::Pattern { pattern: pattern.clone(), body: body.clone(), span })
     }
 }
@@ -0,0 +1,238 @@
+//! A long-lived pavo session for embedding pavo in a rust application.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use im_rc::OrdMap as ImOrdMap;
+use nom::types::CompleteStr;
+
+use crate::check::check_toplevel;
+use crate::compile;
+use crate::context::Context;
+use crate::coverage::Coverage;
+use crate::deps::Require;
+use crate::env::{self, env_add_native};
+use crate::expand;
+use crate::gc_foreign::Vector;
+use crate::macros;
+use crate::profile::Profiler;
+use crate::read::{read_forms, read, ParseError, SourceMap};
+use crate::resolve::ModuleResolver;
+use crate::special_forms::to_code_mapped;
+use crate::toplevel::exval_form;
+use crate::value::{Value, Id, HostData};
+use crate::vm::{Closure, Debugger};
+use crate::{ExecuteError, E};
+
+/// Owns all state needed to evaluate pavo code: the execution context, the definitions and the
+/// macros available to the code.
+///
+/// Definitions and macros persist across evaluations, either by adding them from rust or via
+/// the top-level `(def name exp)` and `(defmacro name exp)` forms in the evaluated source.
+pub struct Interpreter {
+    cx: Context,
+    env: HashMap<Id, (Value, bool)>,
+    macros: ImOrdMap<Id, Value>,
+}
+
+impl Interpreter {
+    /// Create an interpreter with the default toplevel definitions and macros.
+    pub fn new() -> Interpreter {
+        Interpreter {
+            cx: Context::default(),
+            env: env::default(),
+            macros: macros::default(),
+        }
+    }
+
+    /// Evaluate source code consisting of any number of top-level forms, returning the value of
+    /// the last one (or nil if there are none).
+    pub fn eval(&mut self, src: &str) -> Result<Value, ExecuteError> {
+        let mut yay = Value::nil();
+        for form in read_forms(CompleteStr(src))? {
+            yay = self.eval_mapped(&form.value, &form.source_map())?;
+        }
+        return Ok(yay);
+    }
+
+    /// Evaluate a value as a top-level form.
+    ///
+    /// If the evaluation diverges, the error is returned and the interpreter remains usable.
+    pub fn eval_value(&mut self, v: &Value) -> Result<Value, E> {
+        self.eval_mapped(v, &SourceMap::default())
+    }
+
+    pub(crate) fn eval_mapped(&mut self, v: &Value, map: &SourceMap) -> Result<Value, E> {
+        let result = exval_form(v, map, &mut self.env, &mut self.macros, &mut self.cx);
+        self.cx.take_abort();
+        return result;
+    }
+
+    // Expand a top-level form without evaluating it, returning the expansion and its spans.
+    pub(crate) fn expand_mapped(&mut self, v: &Value, map: &SourceMap) -> Result<(Value, SourceMap), E> {
+        let result = expand::expand_mapped(v, map, &self.env, &self.macros, &mut self.cx);
+        self.cx.take_abort();
+        return result.map_err(E::from);
+    }
+
+    // Expand a top-level form and check it for static errors, without evaluating it.
+    pub(crate) fn check_mapped(&mut self, v: &Value, map: &SourceMap) -> Result<(), E> {
+        let (expanded, map) = self.expand_mapped(v, map)?;
+        let c = to_code_mapped(&expanded, &map)?;
+        check_toplevel(c, &self.env)?;
+        Ok(())
+    }
+
+    // Expand and compile a top-level form, without evaluating it.
+    pub(crate) fn compile_mapped(&mut self, v: &Value, map: &SourceMap) -> Result<Closure, E> {
+        let (expanded, map) = self.expand_mapped(v, map)?;
+        let c = compile::compile_mapped(&expanded, &map, None, &self.env, self.cx.optimizes())?;
+        Ok(c)
+    }
+
+    /// Limit how many steps evaluation may take from now on, `None` lifts the limit. Evaluation
+    /// that runs out of fuel fails with `E::Abort(Abort::OutOfFuel)`, which pavo code can not
+    /// catch. See `Context::set_fuel` for what counts as a step.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.cx.set_fuel(fuel);
+    }
+
+    /// The remaining fuel, `None` if evaluation is unlimited.
+    pub fn fuel(&self) -> Option<u64> {
+        self.cx.fuel()
+    }
+
+    /// How many steps all evaluations of this interpreter have taken in total.
+    pub fn fuel_consumed(&self) -> u64 {
+        self.cx.fuel_consumed()
+    }
+
+    /// Limit how deeply calls may be nested, the default is `DEFAULT_MAX_CALL_DEPTH`. Evaluation
+    /// that exceeds the limit fails with `E::Abort(Abort::CallDepth)`.
+    pub fn set_max_call_depth(&mut self, max: usize) {
+        self.cx.set_max_call_depth(max);
+    }
+
+    /// Limit how deeply computations may recurse on the rust stack, the default is
+    /// `DEFAULT_MAX_NATIVE_DEPTH`, which suffices for a 2MiB stack. See
+    /// `Context::set_max_native_depth`.
+    pub fn set_max_native_depth(&mut self, max: usize) {
+        self.cx.set_max_native_depth(max);
+    }
+
+    /// Set whether code is run through the ir optimization passes when it is compiled, which is
+    /// the default. Optimization does not change any results, only the number of steps taken.
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.cx.set_optimize(optimize);
+    }
+
+    /// Start recording how often each part of the evaluated code runs, or stop with `None`. See
+    /// `Coverage` for writing the results as an lcov tracefile.
+    pub fn set_coverage(&mut self, coverage: Option<Coverage>) {
+        self.cx.set_coverage(coverage);
+    }
+
+    /// Stop recording coverage, returning everything that has been recorded.
+    pub fn take_coverage(&mut self) -> Option<Coverage> {
+        self.cx.take_coverage()
+    }
+
+    /// Start recording which functions the steps and the time of the evaluations are spent in, or
+    /// stop with `None`.
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) {
+        self.cx.set_profiler(profiler);
+    }
+
+    /// Stop profiling, returning the profiler with everything it recorded.
+    pub fn take_profiler(&mut self) -> Option<Profiler> {
+        self.cx.take_profiler()
+    }
+
+    /// Install a hook that is called before every instruction the vm executes, or remove it with
+    /// `None`. See `StepDebugger` for an interactive debugger.
+    pub fn set_debugger(&mut self, debugger: Option<Box<dyn Debugger>>) {
+        self.cx.set_debugger(debugger);
+    }
+
+    /// Cache the expanded and compiled code of the files that `require` loads in a directory, so
+    /// that later sessions can skip expanding and compiling them. See `Context::set_cache_dir`.
+    pub fn set_cache_dir(&mut self, dir: Option<PathBuf>) {
+        self.cx.set_cache_dir(dir);
+    }
+
+    /// Set how `require` finds the code to load. By default, a `FileResolver` loads files relative
+    /// to the file that requires them, or to the entrypoint (see `set_entrypoint`) for the
+    /// evaluated code itself, falling back to the working directory if there is no entrypoint.
+    /// See `MemoryResolver` for modules that are not files.
+    pub fn set_resolver<R: ModuleResolver + 'static>(&mut self, resolver: R) {
+        self.cx.set_resolver(Box::new(resolver));
+    }
+
+    /// Set the key of the module that evaluated code belongs to (for the default resolver, the
+    /// path of the file it was read from), so that `require` resolves relative to it.
+    pub fn set_entrypoint(&mut self, key: Option<String>) {
+        self.cx.set_entrypoint(key);
+    }
+
+    /// Every distinct call to `require` so far, see `Context::requires`.
+    pub fn requires(&self) -> &[Require] {
+        self.cx.requires()
+    }
+
+    /// Read a single value from source code without evaluating it.
+    pub fn read(&self, src: &str) -> Result<Value, ParseError> {
+        read(CompleteStr(src))
+    }
+
+    /// Bind a value to a name, for all subsequently evaluated code.
+    pub fn define(&mut self, name: &str, v: Value) {
+        self.env.insert(Id::user(name), (v, false));
+    }
+
+    /// Bind a rust closure taking `arity` arguments to a name, making it callable from pavo.
+    ///
+    /// Returning an `Err` from the closure throws the value, the builtin errors of the form
+    /// `{:tag :err-xxx}` are a good model for what to throw.
+    pub fn define_native<F>(&mut self, name: &str, arity: usize, f: F)
+    where
+        F: Fn(Vector<Value>, &mut Context) -> Result<Value, Value> + 'static,
+    {
+        env_add_native(&mut self.env, name, arity, f, &mut self.cx);
+    }
+
+    /// Allocate a fresh type id for opaque values created via `host`.
+    pub fn new_host_type(&mut self) -> u64 {
+        self.cx.next_symbol_id()
+    }
+
+    /// Wrap rust data in an opaque value of the given type, see `new_host_type`. Use
+    /// `Value::as_host` to get the data back.
+    pub fn host<T: HostData>(&mut self, type_id: u64, data: T) -> Value {
+        Value::host(type_id, data, &mut self.cx)
+    }
+
+    /// Remove a binding, returning its value if it existed.
+    pub fn undefine(&mut self, name: &str) -> Option<Value> {
+        self.env.remove(&Id::user(name)).map(|(v, _)| v)
+    }
+
+    /// The value bound to a name, if any.
+    pub fn lookup(&self, name: &str) -> Option<&Value> {
+        self.env.get(&Id::user(name)).map(|(v, _)| v)
+    }
+
+    /// Make a macro available under the given name, for all subsequently evaluated code.
+    pub fn define_macro(&mut self, name: &str, macro_: Value) {
+        self.macros.insert(Id::user(name), macro_);
+    }
+
+    /// Remove a macro, returning it if it existed.
+    pub fn undefine_macro(&mut self, name: &str) -> Option<Value> {
+        self.macros.remove(&Id::user(name))
+    }
+
+    /// The macro bound to a name, if any.
+    pub fn lookup_macro(&self, name: &str) -> Option<&Value> {
+        self.macros.get(&Id::user(name))
+    }
+}
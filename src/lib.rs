@@ -8,27 +8,55 @@ use nom::types::CompleteStr;
 use im_rc::OrdMap as ImOrdMap;
 
 mod builtins;
+mod bytecode;
 mod check;
 mod compile;
 mod context;
+mod coverage;
+pub mod convert;
+mod debugger;
+pub mod deps;
+mod diagnostics;
+mod disassemble;
 mod env;
 mod expand;
 mod gc_foreign;
+mod interpreter;
 mod macros;
+mod optimize;
+mod profile;
 mod special_forms;
+mod toplevel;
 pub mod value;
 mod read;
+pub mod repl;
+mod resolve;
 mod vm;
 mod opaques;
 pub mod arr;
 pub mod map;
 pub mod set;
+pub mod serde_value;
 
-use compile::StaticError;
-use context::Context;
-use expand::ExpandError;
 use value::{Id, Value};
-use read::{read, ParseError};
+use read::read_located;
+
+pub use check::BindingError;
+pub use compile::StaticError;
+pub use context::{Abort, Context, TraceEntry, DEFAULT_MAX_CALL_DEPTH, DEFAULT_MAX_NATIVE_DEPTH};
+pub use convert::{FromValue, FromValueError, IntoValue};
+pub use coverage::Coverage;
+pub use debugger::StepDebugger;
+pub use deps::Require;
+pub use expand::ExpandError;
+pub use gc_foreign::Vector;
+pub use interpreter::Interpreter;
+pub use profile::{Profiler, ProfileKey};
+pub use pavo_derive::{FromValue, IntoValue};
+pub use read::{FormReader, Located, ParseError, ParseErrorKind, Position, SourceMap, Span, StreamError};
+pub use resolve::{err_require, FileResolver, MemoryResolver, ModuleResolver, SearchPathResolver};
+pub use special_forms::{FormType, SpecialFormSyntaxError};
+pub use vm::{Debugger, Pause};
 
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum ExecuteError {
@@ -52,7 +80,21 @@ impl From<E> for ExecuteError {
 pub enum E {
     Expand(ExpandError),
     Static(StaticError),
-    Eval(Value),
+    /// A value was thrown and not caught, together with the functions it propagated out of.
+    Eval(Value, Vec<TraceEntry>),
+    Abort(Abort),
+}
+
+impl E {
+    /// Where in the source code the error occured, if known. Evaluation errors do not have a
+    /// span, their trace gives the functions they propagated out of.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            E::Expand(err) => err.span(),
+            E::Static(err) => err.span(),
+            E::Eval(..) | E::Abort(..) => None,
+        }
+    }
 }
 
 impl From<StaticError> for E {
@@ -69,7 +111,7 @@ impl From<ExpandError> for E {
 
 impl From<Value> for E {
     fn from(err: Value) -> Self {
-        E::Eval(err)
+        E::Eval(err, vec![])
     }
 }
 
@@ -80,9 +122,39 @@ pub fn exval(
     env: &HashMap<Id, (Value, bool)>,
     cx: &mut Context,
 ) -> Result<Value, E> {
-    let expanded = expand::expand(v, m_env, macros, cx)?;
-    let c = compile::compile(&expanded, env)?;
-    c.compute(gc_foreign::Vector(im_rc::Vector::new()), cx).map_err(|nay| E::Eval(nay))
+    exval_mapped(v, &SourceMap::default(), m_env, macros, env, cx)
+}
+
+/// Like `exval`, with the spans of `v` given by a source map, so that errors and the compiled
+/// code refer to the source code.
+pub fn exval_mapped(
+    v: &Value,
+    map: &SourceMap,
+    m_env: &HashMap<Id, (Value, bool)>,
+    macros: &ImOrdMap<Id, Value>,
+    env: &HashMap<Id, (Value, bool)>,
+    cx: &mut Context,
+) -> Result<Value, E> {
+    let result = exval_(v, map, m_env, macros, env, cx).map_err(|err| match cx.aborted() {
+        Some(abort) => E::Abort(abort.clone()),
+        None => err,
+    });
+    // Discard the trace of values that did not escape as an `E::Eval`, e.g. a thrown macro.
+    cx.take_trace();
+    return result;
+}
+
+fn exval_(
+    v: &Value,
+    map: &SourceMap,
+    m_env: &HashMap<Id, (Value, bool)>,
+    macros: &ImOrdMap<Id, Value>,
+    env: &HashMap<Id, (Value, bool)>,
+    cx: &mut Context,
+) -> Result<Value, E> {
+    let (expanded, map) = expand::expand_mapped(v, map, m_env, macros, cx)?;
+    let c = compile::compile_mapped(&expanded, &map, None, env, cx.optimizes())?;
+    c.compute(gc_foreign::Vector(im_rc::Vector::new()), cx).map_err(|nay| E::Eval(nay, cx.take_trace()))
 }
 
 pub fn execute(src: &str) -> Result<Value, ExecuteError> {
@@ -90,11 +162,24 @@ pub fn execute(src: &str) -> Result<Value, ExecuteError> {
     let default_env = env::default();
     let default_macros = macros::default();
 
-    let v = read(CompleteStr(src))?;
-    let yay = exval(&v, &default_env, &default_macros, &default_env, &mut default_cx)?;
+    let located = read_located(CompleteStr(src))?;
+    let yay = exval_mapped(
+        &located.value,
+        &located.source_map(),
+        &default_env,
+        &default_macros,
+        &default_env,
+        &mut default_cx,
+    )?;
     return Ok(yay);
 }
 
+/// Evaluate a sequence of top-level forms, returning the value of the last one (or nil if there
+/// are none).
+pub fn execute_forms(src: &str) -> Result<Value, ExecuteError> {
+    Interpreter::new().eval(src)
+}
+
 // #[cfg(test)]
 // mod tests {
 //     use super::{Value, execute, ExecuteError, E, value};
@@ -3,58 +3,135 @@
 #![feature(copysign)]
 
 use std::collections::HashMap;
-use std::io::{self, Read};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
 use std::fs::File;
-use std::env::set_current_dir;
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::str::FromStr;
 
 use nom::types::CompleteStr;
 use im_rc::OrdMap as ImOrdMap;
 use structopt::StructOpt;
 
 mod builtins;
+mod bytecode;
 mod check;
 mod compile;
 mod context;
+mod coverage;
+mod debugger;
+mod deps;
+mod diagnostics;
+mod disassemble;
 mod env;
 mod expand;
 mod gc_foreign;
 mod macros;
+mod optimize;
+mod profile;
 mod special_forms;
+mod toplevel;
 mod value;
 mod read;
+mod resolve;
 mod vm;
 mod opaques;
 mod arr;
 mod map;
 
 use compile::StaticError;
-use context::Context;
+use context::{Abort, Context, TraceEntry};
+use coverage::Coverage;
+use diagnostics::describe;
+use disassemble::disassemble;
 use expand::ExpandError;
+use profile::Profiler;
 use value::{Id, Value};
-use read::{read, ParseError};
+use read::{read_located, FormReader, Located, ParseError, SourceMap, Span, StreamError};
+use resolve::SearchPathResolver;
 
 #[derive(StructOpt)]
-struct Cli {
-    /// The pavo file to run.
-    #[structopt(parse(from_os_str))]
-    entrypoint: std::path::PathBuf,
+#[structopt(name = "pavo")]
+enum Cli {
+    /// Run a pavo file.
+    #[structopt(name = "run")]
+    Run {
+        /// Evaluate the file as a sequence of top-level forms rather than as a single expression.
+        #[structopt(long = "forms")]
+        forms: bool,
+        /// Abort after executing this many steps (vm instructions and builtin calls).
+        #[structopt(long = "fuel")]
+        fuel: Option<u64>,
+        /// Write the steps taken per call stack to this file, in the folded format of
+        /// flamegraph tools, and print the steps, calls and time per function to stderr.
+        #[structopt(long = "profile", parse(from_os_str))]
+        profile: Option<PathBuf>,
+        /// Write an lcov tracefile to this file, telling how often each part of the code of the
+        /// entrypoint and of the files it requires has been executed.
+        #[structopt(long = "coverage", parse(from_os_str))]
+        coverage: Option<PathBuf>,
+        /// Print an intermediate stage of the file instead of evaluating it: `ir` prints the
+        /// instructions the expanded file compiles to.
+        #[structopt(long = "emit", conflicts_with = "forms")]
+        emit: Option<Emit>,
+        /// Compile without running the optimization passes over the ir.
+        #[structopt(long = "no-optimize")]
+        no_optimize: bool,
+        /// Cache the expanded and compiled code of required files in this directory, so that
+        /// later runs can skip expanding and compiling them.
+        #[structopt(long = "cache", parse(from_os_str))]
+        cache: Option<PathBuf>,
+        /// A directory in which to look for the files that keywords passed to `require` refer to,
+        /// e.g. `:std/collections` for `std/collections.pavo`. Can be given multiple times.
+        #[structopt(long = "module-path", parse(from_os_str))]
+        module_path: Vec<PathBuf>,
+        /// The pavo file to run, or `-` to evaluate the forms read from stdin.
+        #[structopt(parse(from_os_str))]
+        entrypoint: PathBuf,
+    },
+    /// Start an interactive session.
+    #[structopt(name = "repl")]
+    Repl,
+    /// Run a pavo file and print the graph of the files it requires.
+    #[structopt(name = "deps")]
+    Deps {
+        /// Evaluate the file as a sequence of top-level forms rather than as a single expression.
+        #[structopt(long = "forms")]
+        forms: bool,
+        /// Print the graph in the Graphviz DOT format instead of as a tree.
+        #[structopt(long = "dot")]
+        dot: bool,
+        /// A directory in which to look for the files that keywords passed to `require` refer to.
+        /// Can be given multiple times.
+        #[structopt(long = "module-path", parse(from_os_str))]
+        module_path: Vec<PathBuf>,
+        /// The pavo file to run, or `-` to evaluate the forms read from stdin.
+        #[structopt(parse(from_os_str))]
+        entrypoint: PathBuf,
+    },
+    /// Run a pavo file in an interactive step debugger.
+    #[structopt(name = "debug")]
+    Debug {
+        /// The pavo file to debug.
+        #[structopt(parse(from_os_str))]
+        entrypoint: PathBuf,
+    },
 }
 
-#[derive(PartialEq, Eq, Debug, Clone)]
-pub enum ExecuteError {
-    Parse(ParseError),
-    E(E),
+// An intermediate stage of the evaluation that can be printed instead of the result.
+enum Emit {
+    Ir,
 }
 
-impl From<ParseError> for ExecuteError {
-    fn from(err: ParseError) -> Self {
-        ExecuteError::Parse(err)
-    }
-}
+impl FromStr for Emit {
+    type Err = String;
 
-impl From<E> for ExecuteError {
-    fn from(err: E) -> Self {
-        ExecuteError::E(err)
+    fn from_str(s: &str) -> Result<Emit, String> {
+        match s {
+            "ir" => Ok(Emit::Ir),
+            _ => Err(format!("unknown stage `{}`, expected `ir`", s)),
+        }
     }
 }
 
@@ -62,7 +139,20 @@ impl From<E> for ExecuteError {
 pub enum E {
     Expand(ExpandError),
     Static(StaticError),
-    Eval(Value),
+    /// A value was thrown and not caught, together with the functions it propagated out of.
+    Eval(Value, Vec<TraceEntry>),
+    Abort(Abort),
+}
+
+impl E {
+    // Where in the source code the error occured, if known.
+    fn span(&self) -> Option<Span> {
+        match self {
+            E::Expand(err) => err.span(),
+            E::Static(err) => err.span(),
+            E::Eval(..) | E::Abort(..) => None,
+        }
+    }
 }
 
 impl From<StaticError> for E {
@@ -79,64 +169,372 @@ impl From<ExpandError> for E {
 
 impl From<Value> for E {
     fn from(err: Value) -> Self {
-        E::Eval(err)
+        E::Eval(err, vec![])
     }
 }
 
-pub fn exval(
+pub fn exval_mapped(
     v: &Value,
+    map: &SourceMap,
     m_env: &HashMap<Id, (Value, bool)>,
     macros: &ImOrdMap<Id, Value>,
     env: &HashMap<Id, (Value, bool)>,
     cx: &mut Context,
 ) -> Result<Value, E> {
-    let expanded = expand::expand(v, m_env, macros, cx)?;
-    let c = compile::compile(&expanded, env)?;
-    c.compute(gc_foreign::Vector(im_rc::Vector::new()), cx).map_err(|nay| E::Eval(nay))
+    let result = exval_(v, map, m_env, macros, env, cx).map_err(|err| match cx.aborted() {
+        Some(abort) => E::Abort(abort.clone()),
+        None => err,
+    });
+    // Discard the trace of values that did not escape as an `E::Eval`, e.g. a thrown macro.
+    cx.take_trace();
+    return result;
 }
 
-pub fn execute(src: &str) -> Result<Value, ExecuteError> {
-    let mut default_cx = Context::default();
-    let default_env = env::default();
-    let default_macros = macros::default();
+fn exval_(
+    v: &Value,
+    map: &SourceMap,
+    m_env: &HashMap<Id, (Value, bool)>,
+    macros: &ImOrdMap<Id, Value>,
+    env: &HashMap<Id, (Value, bool)>,
+    cx: &mut Context,
+) -> Result<Value, E> {
+    let (expanded, map) = expand::expand_mapped(v, map, m_env, macros, cx)?;
+    let c = compile::compile_mapped(&expanded, &map, None, env, cx.optimizes())?;
+    c.compute(gc_foreign::Vector(im_rc::Vector::new()), cx).map_err(|nay| E::Eval(nay, cx.take_trace()))
+}
+
+// Exit codes of the binary, one per class of error.
+const EXIT_IO: i32 = 1;
+const EXIT_PARSE: i32 = 2;
+const EXIT_EXPAND: i32 = 3;
+const EXIT_STATIC: i32 = 4;
+const EXIT_THROWN: i32 = 5;
+const EXIT_DIVERGE: i32 = 6;
+const EXIT_FUEL: i32 = 7;
+const EXIT_DEPTH: i32 = 8;
 
-    let v = read(CompleteStr(src))?;
-    let yay = exval(&v, &default_env, &default_macros, &default_env, &mut default_cx)?;
-    return Ok(yay);
+fn exit_code(err: &E) -> i32 {
+    match err {
+        E::Expand(_) => EXIT_EXPAND,
+        E::Static(_) => EXIT_STATIC,
+        E::Eval(..) => EXIT_THROWN,
+        E::Abort(Abort::Diverge(_)) => EXIT_DIVERGE,
+        E::Abort(Abort::OutOfFuel) => EXIT_FUEL,
+        E::Abort(Abort::CallDepth) => EXIT_DEPTH,
+    }
 }
 
-fn main() -> Result<(), io::Error> {
-    let args = Cli::from_args();
+// Print a report of the error to stderr, returning the exit code.
+fn report(path: &str, located: &Located, err: E) -> i32 {
+    eprintln!("{}", describe(path, located, &err));
+    exit_code(&err)
+}
 
-    let mut file = File::open(&args.entrypoint)?;
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
+fn report_parse_error(path: &str, err: ParseError) -> i32 {
+    eprintln!("{}:{}", path, err);
+    EXIT_PARSE
+}
+
+fn report_io_error(path: &str, err: io::Error) -> i32 {
+    eprintln!("{}: {}", path, err);
+    EXIT_IO
+}
+
+fn print(v: &Value) {
+    let mut buf = String::new();
+    value::debug_print(v, 0, 2, &mut buf);
+    println!("{}", buf);
+}
+
+// Evaluate the forms one after the other, with definitions carrying over to later forms.
+fn run_forms<R: BufRead>(
+    path: &str,
+    forms: FormReader<R>,
+    cx: &mut Context,
+    read: &mut Vec<Located>,
+) -> Result<Value, i32> {
+    let mut env = env::default();
+    let mut macros = macros::default();
 
-    match args.entrypoint.parent() {
-        Some(parent) => {
-            set_current_dir(parent)?;
+    let mut last = Value::nil();
+    for form in forms {
+        let located = match form {
+            Ok(located) => located,
+            Err(StreamError::Io(err)) => return Err(report_io_error(path, err)),
+            Err(StreamError::Parse(err)) => return Err(report_parse_error(path, err)),
+        };
+
+        let result = toplevel::exval_form(&located.value, &located.source_map(), &mut env, &mut macros, cx);
+        if cx.is_covering() {
+            read.push(located.clone());
         }
-        None => {
-            panic!("Argument must point to a source code file inside a directory");
+
+        match result {
+            Ok(yay) => last = yay,
+            Err(err) => return Err(report(path, &located, err)),
         }
     }
 
-    match execute(&contents) {
+    return Ok(last);
+}
+
+// Print the value of a successful evaluation, returning the exit code.
+fn print_result(result: Result<Value, i32>) -> i32 {
+    match result {
         Ok(yay) => {
-            let mut buf = String::new();
-            value::debug_print(&yay, 0, 2, &mut buf);
-            println!("{}", buf);
-            return Ok(());
+            print(&yay);
+            0
+        }
+        Err(code) => code,
+    }
+}
+
+// The CLI runs on a thread with a stack this large, so that deeply nested code can be expanded
+// and evaluated.
+const STACK_SIZE: usize = 256 * 1024 * 1024;
+// The maximum native depth that `STACK_SIZE` suffices for, see
+// `Context::set_max_native_depth`. The default is chosen for much smaller stacks.
+const MAX_NATIVE_DEPTH: usize = 25_000;
+
+fn main() {
+    let cli = Cli::from_args();
+    let code = thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || run_cli(cli))
+        .expect("failed to spawn the interpreter thread")
+        .join()
+        // The panic has already been reported by the thread.
+        .unwrap_or(101);
+
+    process::exit(code);
+}
+
+// A context for running code on the thread of the CLI.
+fn context() -> Context {
+    let mut cx = Context::default();
+    cx.set_max_native_depth(MAX_NATIVE_DEPTH);
+    cx
+}
+
+fn run_cli(cli: Cli) -> i32 {
+    match cli {
+        Cli::Run { emit: Some(Emit::Ir), no_optimize, entrypoint, .. } => {
+            emit_ir(!no_optimize, entrypoint)
         }
-        Err(err) => {
-            match err {
-                ExecuteError::E(E::Eval(err)) => {
-                    let mut buf = String::new();
-                    value::debug_print(&err, 0, 2, &mut buf);
-                    panic!("Thrown:\n{}", buf);
-                }
-                _ => panic!("{:?}", err),
-            }
+        Cli::Run { forms, fuel, profile, coverage, no_optimize, cache, module_path, entrypoint, .. } => {
+            let modules = Modules { cache, path: module_path };
+            run(forms, fuel, profile, coverage, !no_optimize, modules, entrypoint)
         }
+        Cli::Repl => {
+            pavo_bootstrap::repl::run(MAX_NATIVE_DEPTH);
+            0
+        }
+        Cli::Deps { forms, dot, module_path, entrypoint } => deps(forms, dot, module_path, entrypoint),
+        Cli::Debug { entrypoint } => debug(entrypoint),
+    }
+}
+
+// Where required files are looked up and cached.
+struct Modules {
+    cache: Option<PathBuf>,
+    path: Vec<PathBuf>,
+}
+
+fn run(
+    forms: bool,
+    fuel: Option<u64>,
+    profile: Option<PathBuf>,
+    coverage: Option<PathBuf>,
+    optimize: bool,
+    modules: Modules,
+    entrypoint: PathBuf,
+) -> i32 {
+    let source_path = match entrypoint.canonicalize() {
+        Ok(absolute) => absolute.display().to_string(),
+        Err(_) => "<stdin>".to_string(),
+    };
+
+    let mut cx = context();
+    cx.set_fuel(fuel);
+    cx.set_optimize(optimize);
+    cx.set_cache_dir(modules.cache);
+    if !modules.path.is_empty() {
+        cx.set_resolver(Box::new(SearchPathResolver::new(modules.path)));
     }
+    if profile.is_some() {
+        cx.set_profiler(Some(Profiler::new()));
+    }
+    if coverage.is_some() {
+        cx.set_coverage(Some(Coverage::new()));
+    }
+
+    // The forms that have been evaluated, for listing the lines of code in the coverage.
+    let mut read = vec![];
+
+    let path = if entrypoint.as_os_str() == "-" {
+        "<stdin>".to_string()
+    } else {
+        entrypoint.display().to_string()
+    };
+    let code = print_result(run_entrypoint(forms, &path, &entrypoint, &mut cx, &mut read));
+
+    if let (Some(out), Some(profiler)) = (profile, cx.take_profiler()) {
+        if let Err(err) = write_profile(&path, &out, &profiler) {
+            return report_io_error(&out.display().to_string(), err);
+        }
+    }
+
+    if let (Some(out), Some(mut coverage)) = (coverage, cx.take_coverage()) {
+        for located in read {
+            coverage.add_entrypoint_source(&source_path, located);
+        }
+
+        if let Err(err) = write_coverage(&out, &coverage) {
+            return report_io_error(&out.display().to_string(), err);
+        }
+    }
+
+    code
+}
+
+fn run_entrypoint(
+    forms: bool,
+    path: &str,
+    entrypoint: &PathBuf,
+    cx: &mut Context,
+    read: &mut Vec<Located>,
+) -> Result<Value, i32> {
+    if entrypoint.as_os_str() == "-" {
+        let stdin = io::stdin();
+        return run_forms(path, FormReader::new(stdin.lock()), cx, read);
+    }
+
+    let file = open(path, entrypoint, cx)?;
+
+    if forms {
+        return run_forms(path, FormReader::new(BufReader::new(file)), cx, read);
+    }
+
+    let located = read_file(path, file)?;
+    let result = run_located(path, &located, cx);
+    if cx.is_covering() {
+        read.push(located);
+    }
+    result
+}
+
+// Write the folded stacks to `out` and print the flat report to stderr.
+fn write_profile(path: &str, out: &PathBuf, profiler: &Profiler) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(out)?);
+    profiler.write_folded(&mut file, path)?;
+    file.flush()?;
+
+    eprint!("{}", profiler.report(path));
+    Ok(())
+}
+
+fn write_coverage(out: &PathBuf, coverage: &Coverage) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(out)?);
+    coverage.write_lcov(&mut file)?;
+    file.flush()
+}
+
+// Print the disassembled ir of the file's expression after expansion.
+fn emit_ir(optimize: bool, entrypoint: PathBuf) -> i32 {
+    let path = entrypoint.display().to_string();
+
+    let mut cx = context();
+    let located = match open(&path, &entrypoint, &mut cx).and_then(|file| read_file(&path, file)) {
+        Ok(located) => located,
+        Err(code) => return code,
+    };
+
+    let env = env::default();
+    let macros = macros::default();
+
+    let compiled = expand::expand_mapped(&located.value, &located.source_map(), &env, &macros, &mut cx)
+        .map_err(E::from)
+        .and_then(|(expanded, map)| {
+            compile::compile_mapped(&expanded, &map, None, &env, optimize).map_err(E::from)
+        });
+
+    match compiled {
+        Ok(c) => {
+            print!("{}", disassemble(&c));
+            return 0;
+        }
+        Err(err) => report(&path, &located, err),
+    }
+}
+
+fn debug(entrypoint: PathBuf) -> i32 {
+    let path = entrypoint.display().to_string();
+
+    let mut cx = context();
+    let located = match open(&path, &entrypoint, &mut cx).and_then(|file| read_file(&path, file)) {
+        Ok(located) => located,
+        Err(code) => return code,
+    };
+
+    cx.set_debugger(Some(Box::new(debugger::StepDebugger::new(&path))));
+    print_result(run_located(&path, &located, &mut cx))
+}
+
+// Run the file, then print the graph of the files it required. The graph is printed even if the
+// evaluation fails, with the files required up to that point.
+fn deps(forms: bool, dot: bool, module_path: Vec<PathBuf>, entrypoint: PathBuf) -> i32 {
+    let mut cx = context();
+    if !module_path.is_empty() {
+        cx.set_resolver(Box::new(SearchPathResolver::new(module_path)));
+    }
+
+    let path = if entrypoint.as_os_str() == "-" {
+        "<stdin>".to_string()
+    } else {
+        entrypoint.display().to_string()
+    };
+    let code = match run_entrypoint(forms, &path, &entrypoint, &mut cx, &mut vec![]) {
+        Ok(_) => 0,
+        Err(code) => code,
+    };
+
+    if dot {
+        print!("{}", deps::dot(cx.requires()));
+    } else {
+        print!("{}", deps::tree(cx.requires(), cx.entrypoint()));
+    }
+    code
+}
+
+// Open the file, and make it the module that requires are resolved relative to.
+fn open(path: &str, entrypoint: &PathBuf, cx: &mut Context) -> Result<File, i32> {
+    let file = match File::open(entrypoint) {
+        Ok(file) => file,
+        Err(err) => return Err(report_io_error(path, err)),
+    };
+
+    match entrypoint.canonicalize() {
+        Ok(absolute) => cx.set_entrypoint(Some(absolute.display().to_string())),
+        Err(err) => return Err(report_io_error(path, err)),
+    }
+
+    return Ok(file);
+}
+
+fn read_file(path: &str, mut file: File) -> Result<Located, i32> {
+    let mut contents = String::new();
+    if let Err(err) = file.read_to_string(&mut contents) {
+        return Err(report_io_error(path, err));
+    }
+
+    read_located(CompleteStr(&contents)).map_err(|err| report_parse_error(path, err))
+}
+
+// Evaluate the file's single expression and print the result.
+fn run_located(path: &str, located: &Located, cx: &mut Context) -> Result<Value, i32> {
+    let default_env = env::default();
+    let default_macros = macros::default();
+
+    exval_mapped(&located.value, &located.source_map(), &default_env, &default_macros, &default_env, cx)
+        .map_err(|err| report(path, located, err))
 }
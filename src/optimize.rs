@@ -0,0 +1,285 @@
+//! Optimization passes over the ir produced by the compiler, see `optimize`.
+//!
+//! None of the passes change the result of a computation, but they do change the number of steps
+//! it takes (see `Context::set_fuel`), and which instructions a debugger gets to see.
+
+use std::collections::HashMap;
+
+use gc::Gc;
+
+use crate::value::Value;
+use crate::vm::{Addr, BBId, BB_RETURN, Instruction, IrChunk};
+
+use Instruction::*;
+
+/// Optimize a chunk created by the compiler, including the chunks of all functions nested inside
+/// of it.
+pub fn optimize(chunk: &mut IrChunk) {
+    for block in chunk.basic_blocks.iter_mut() {
+        for instruction in block.iter_mut() {
+            if let FunLiteral(inner, _, _) = instruction {
+                let mut optimized = (**inner).clone();
+                optimize(&mut optimized);
+                *inner = Gc::new(optimized);
+            }
+        }
+    }
+
+    fold_literals(chunk);
+    peephole(chunk);
+    thread_jumps(chunk);
+    remove_dead_blocks(chunk);
+    merge_blocks(chunk);
+    fold_literals(chunk);
+    peephole(chunk);
+    remove_dead_blocks(chunk);
+}
+
+// The jump targets of an instruction, excluding `BB_RETURN`.
+fn targets(instruction: &Instruction) -> Vec<BBId> {
+    let targets = match instruction {
+        Jump(bb) | SetCatchHandler(bb) => vec![*bb],
+        CondJump(yay, nay) => vec![*yay, *nay],
+        Switch(_, arms, default) => {
+            arms.iter().map(|arm| arm.target).chain(std::iter::once(*default)).collect()
+        }
+        _ => vec![],
+    };
+    targets.into_iter().filter(|bb| *bb != BB_RETURN).collect()
+}
+
+fn targets_mut(instruction: &mut Instruction) -> Vec<&mut BBId> {
+    let targets = match instruction {
+        Jump(bb) | SetCatchHandler(bb) => vec![bb],
+        CondJump(yay, nay) => vec![yay, nay],
+        Switch(_, arms, default) => {
+            arms.iter_mut().map(|arm| &mut arm.target).chain(std::iter::once(default)).collect()
+        }
+        _ => vec![],
+    };
+    targets.into_iter().filter(|bb| **bb != BB_RETURN).collect()
+}
+
+// Constant folding: a collection built only from literals becomes a literal itself.
+
+fn fold_literals(chunk: &mut IrChunk) {
+    for block in chunk.basic_blocks.iter_mut() {
+        let mut i = 0;
+        while i < block.len() {
+            let count = match &block[i] {
+                Arr(count) | App(count) | Set(count) => *count,
+                Map(count) => *count * 2,
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            };
+
+            if count > i || !block[i - count..i].iter().all(|inst| is_literal(inst)) {
+                i += 1;
+                continue;
+            }
+
+            // The values in the order they have been pushed.
+            let vals: Vec<Value> = block[i - count..i]
+                .iter()
+                .map(|inst| match inst {
+                    Literal(v) => v.clone(),
+                    _ => unreachable!(),
+                })
+                .collect();
+
+            let folded = match &block[i] {
+                Arr(_) => Value::arr_from_vec(vals),
+                App(_) => Value::app_from_vec(vals),
+                Set(_) => Value::set_from_vec(vals),
+                _ => {
+                    let mut entries = Vec::with_capacity(vals.len() / 2);
+                    let mut vals = vals.into_iter();
+                    while let (Some(key), Some(val)) = (vals.next(), vals.next()) {
+                        entries.push((key, val));
+                    }
+                    Value::map_from_vec(entries)
+                }
+            };
+
+            block[i] = Literal(folded);
+            block.drain(i - count..i);
+            i = i - count + 1;
+        }
+    }
+}
+
+fn is_literal(inst: &Instruction) -> bool {
+    match inst {
+        Literal(_) => true,
+        _ => false,
+    }
+}
+
+// Peephole cleanup: remove values that are pushed only to be dropped again, resolve conditional
+// jumps on literals, and drop the instructions following an unconditional transfer of control.
+
+fn peephole(chunk: &mut IrChunk) {
+    for block in chunk.basic_blocks.iter_mut() {
+        let mut out: Vec<Instruction> = Vec::with_capacity(block.len());
+
+        for instruction in block.drain(..) {
+            match (out.pop(), instruction) {
+                (Some(Literal(_)), DropTop) | (Some(DoubleTop), DropTop) => {}
+                (Some(Push(ref addr)), DropTop) if *addr != Addr::Stack => {}
+
+                (Some(Literal(v)), CondJump(yay, nay)) => {
+                    out.push(Jump(if v.truthy() { yay } else { nay }));
+                }
+
+                (last, instruction) => {
+                    out.extend(last);
+                    out.push(instruction);
+                }
+            }
+
+            match out.last() {
+                Some(Jump(_)) | Some(CondJump(..)) | Some(Switch(..)) | Some(Throw) => break,
+                _ => {}
+            }
+        }
+
+        *block = out;
+    }
+}
+
+// Jump threading: jumps to blocks that do nothing but jump on go to the final destination
+// directly. Blocks that begin an expression are kept, so that coverage can still be recorded
+// for them.
+
+fn thread_jumps(chunk: &mut IrChunk) {
+    let forwards: HashMap<BBId, BBId> = chunk.basic_blocks
+        .iter()
+        .enumerate()
+        .filter(|(bb, _)| chunk.block_sources[*bb].is_none())
+        .filter_map(|(bb, block)| match &block[..] {
+            // Running past the end of a block returns from the function.
+            [] => Some((bb, BB_RETURN)),
+            [Jump(target)] => Some((bb, *target)),
+            _ => None,
+        })
+        .collect();
+
+    for block in chunk.basic_blocks.iter_mut() {
+        for instruction in block.iter_mut() {
+            let may_return = match instruction {
+                Jump(_) => true,
+                _ => false,
+            };
+
+            for target in targets_mut(instruction) {
+                // Bounded, in case the jumps form a cycle.
+                for _ in 0..forwards.len() {
+                    match forwards.get(&*target) {
+                        Some(next) if *next != BB_RETURN || may_return => *target = *next,
+                        _ => break,
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Block merging: a block that can only be entered by an unconditional jump from one other block
+// is appended to that block.
+
+fn merge_blocks(chunk: &mut IrChunk) {
+    let mut references = vec![0; chunk.basic_blocks.len()];
+    for block in chunk.basic_blocks.iter() {
+        for instruction in block.iter() {
+            for target in targets(instruction) {
+                references[target] += 1;
+            }
+        }
+    }
+
+    for bb in 0..chunk.basic_blocks.len() {
+        loop {
+            let next = match chunk.basic_blocks[bb].last() {
+                Some(Jump(next)) if *next != BB_RETURN => *next,
+                _ => break,
+            };
+
+            if next == 0 || next == bb || references[next] != 1
+                || chunk.block_sources[next].is_some() {
+                break;
+            }
+
+            let appended = std::mem::replace(&mut chunk.basic_blocks[next], vec![]);
+            references[next] = 0;
+            chunk.basic_blocks[bb].pop();
+            chunk.basic_blocks[bb].extend(appended);
+        }
+    }
+}
+
+// Dead block elimination: blocks that can not be reached from the first block are removed, and
+// the remaining ones are renumbered. The blocks that begin the branches of a `sf-case` are kept
+// (but emptied) even if they are dead, so that coverage reports the branches that can never be
+// taken rather than omitting them.
+
+fn remove_dead_blocks(chunk: &mut IrChunk) {
+    let mut reachable = vec![false; chunk.basic_blocks.len()];
+    reachable[0] = true;
+    let mut todo = vec![0];
+
+    while let Some(bb) = todo.pop() {
+        for instruction in chunk.basic_blocks[bb].iter() {
+            for target in targets(instruction) {
+                if !reachable[target] {
+                    reachable[target] = true;
+                    todo.push(target);
+                }
+            }
+        }
+    }
+
+    let mut kept = reachable.clone();
+    for branches in chunk.branches.iter() {
+        for bb in branches.iter() {
+            kept[*bb] = true;
+        }
+    }
+
+    let mut renumbered = vec![BB_RETURN; chunk.basic_blocks.len()];
+    let mut next = 0;
+    for (bb, is_kept) in kept.iter().enumerate() {
+        if *is_kept {
+            renumbered[bb] = next;
+            next += 1;
+        }
+    }
+
+    let blocks = std::mem::replace(&mut chunk.basic_blocks, vec![]);
+    let sources = std::mem::replace(&mut chunk.block_sources, vec![]);
+    for (bb, (mut block, source)) in blocks.into_iter().zip(sources.into_iter()).enumerate() {
+        if !kept[bb] {
+            continue;
+        }
+
+        if reachable[bb] {
+            for instruction in block.iter_mut() {
+                for target in targets_mut(instruction) {
+                    *target = renumbered[*target];
+                }
+            }
+        } else {
+            // The instructions might refer to blocks that have been removed.
+            block.clear();
+        }
+        chunk.basic_blocks.push(block);
+        chunk.block_sources.push(source);
+    }
+
+    for branches in chunk.branches.iter_mut() {
+        for bb in branches.iter_mut() {
+            *bb = renumbered[*bb];
+        }
+    }
+}
@@ -0,0 +1,226 @@
+//! A profiler that attributes the steps (see `Context::set_fuel`) and the elapsed time of an
+//! execution to the functions being executed, see `Context::set_profiler`.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use crate::context::TraceEntry;
+use crate::value::{Builtin, Id, Value, self};
+
+/// Identifies a function for profiling purposes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ProfileKey {
+    /// Top-level code.
+    Toplevel,
+    /// A closure, native function or opaque function, by its function id.
+    Fun(u64),
+    Builtin(Builtin),
+}
+
+impl ProfileKey {
+    /// The key of a closure with the given function id, `None` for top-level code.
+    pub fn closure(id: Option<u64>) -> ProfileKey {
+        match id {
+            None => ProfileKey::Toplevel,
+            Some(id) => ProfileKey::Fun(id),
+        }
+    }
+}
+
+// What has been measured about a function.
+struct Stats {
+    entry: TraceEntry,
+    calls: u64,
+    // The time spent in the function itself, excluding the functions it called.
+    self_time: Duration,
+}
+
+// A node in the call tree, representing a sequence of nested calls.
+struct Node {
+    key: Option<ProfileKey>,
+    parent: usize,
+    children: HashMap<ProfileKey, usize>,
+    // The steps taken directly in the innermost function of this sequence of calls.
+    steps: u64,
+}
+
+// A function that is currently being executed.
+struct Active {
+    key: ProfileKey,
+    node: usize,
+    start: Instant,
+    // The time spent in the functions called by this one.
+    children_time: Duration,
+}
+
+pub struct Profiler {
+    functions: HashMap<ProfileKey, Stats>,
+    // The call tree, the root is at index 0.
+    nodes: Vec<Node>,
+    // The functions that are currently being executed, the innermost one last.
+    active: Vec<Active>,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            functions: HashMap::new(),
+            nodes: vec![Node {
+                key: None,
+                parent: 0,
+                children: HashMap::new(),
+                steps: 0,
+            }],
+            active: vec![],
+        }
+    }
+
+    /// Record that a function is being called. `describe` is only used the first time a
+    /// function is encountered.
+    pub fn enter<F: FnOnce() -> TraceEntry>(&mut self, key: ProfileKey, describe: F) {
+        self.functions.entry(key).or_insert_with(|| Stats {
+            entry: describe(),
+            calls: 0,
+            self_time: Duration::default(),
+        }).calls += 1;
+
+        let parent = self.active.last().map(|active| active.node).unwrap_or(0);
+        let node = match self.nodes[parent].children.get(&key) {
+            Some(node) => *node,
+            None => {
+                self.nodes.push(Node {
+                    key: Some(key),
+                    parent,
+                    children: HashMap::new(),
+                    steps: 0,
+                });
+                let node = self.nodes.len() - 1;
+                self.nodes[parent].children.insert(key, node);
+                node
+            }
+        };
+
+        self.active.push(Active {
+            key,
+            node,
+            start: Instant::now(),
+            children_time: Duration::default(),
+        });
+    }
+
+    /// Record that the innermost function that is being called has returned (or thrown).
+    pub fn leave(&mut self) {
+        if let Some(active) = self.active.pop() {
+            let elapsed = active.start.elapsed();
+
+            if let Some(stats) = self.functions.get_mut(&active.key) {
+                stats.self_time += elapsed.checked_sub(active.children_time).unwrap_or_default();
+            }
+
+            if let Some(parent) = self.active.last_mut() {
+                parent.children_time += elapsed;
+            }
+        }
+    }
+
+    /// Record a step taken by the innermost function that is being called.
+    pub fn step(&mut self) {
+        let node = self.active.last().map(|active| active.node).unwrap_or(0);
+        self.nodes[node].steps += 1;
+    }
+
+    // The steps taken directly in each function.
+    fn self_steps(&self) -> HashMap<ProfileKey, u64> {
+        let mut steps = HashMap::new();
+        for node in self.nodes.iter() {
+            if let Some(key) = node.key {
+                *steps.entry(key).or_insert(0) += node.steps;
+            }
+        }
+        steps
+    }
+
+    /// A table of the steps, calls and time per function, the most expensive functions first.
+    ///
+    /// Closures are labeled with where they have been defined, `path` is the file of the
+    /// top-level code.
+    pub fn report(&self, path: &str) -> String {
+        let steps = self.self_steps();
+        let mut rows: Vec<(u64, &Stats)> = self.functions
+            .iter()
+            .map(|(key, stats)| (steps.get(key).cloned().unwrap_or(0), stats))
+            .collect();
+        rows.sort_by(|a, b| b.0.cmp(&a.0).then(b.1.self_time.cmp(&a.1.self_time)));
+
+        let mut out = format!("{:>12} {:>10} {:>12}  function\n", "steps", "calls", "self ms");
+        for (steps, stats) in rows {
+            let millis = stats.self_time.as_secs() as f64 * 1000.0
+                + stats.self_time.subsec_nanos() as f64 / 1_000_000.0;
+            out.push_str(&format!(
+                "{:>12} {:>10} {:>12.3}  {}\n",
+                steps, stats.calls, millis, label(&stats.entry, path)
+            ));
+        }
+        out
+    }
+
+    /// Write the steps per call stack in the folded format used by flamegraph tools: one line
+    /// per call stack, the function names separated by semicolons, followed by a space and the
+    /// number of steps. `path` is as for `report`.
+    pub fn write_folded<W: Write>(
+        &self,
+        w: &mut W,
+        path: &str,
+    ) -> io::Result<()> {
+        let labels: HashMap<ProfileKey, String> = self.functions
+            .iter()
+            .map(|(key, stats)| (*key, label(&stats.entry, path).replace(';', ",")))
+            .collect();
+
+        for (i, node) in self.nodes.iter().enumerate() {
+            if node.steps == 0 {
+                continue;
+            }
+
+            let mut path = vec![];
+            let mut current = i;
+            while let Some(key) = self.nodes[current].key {
+                path.push(&labels[&key][..]);
+                current = self.nodes[current].parent;
+            }
+            path.reverse();
+
+            writeln!(w, "{} {}", path.join(";"), node.steps)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn label(entry: &TraceEntry, path: &str) -> String {
+    match entry {
+        TraceEntry::Closure { id: None, .. } => "top-level".to_string(),
+        TraceEntry::Closure { id: Some(id), name, span, module, .. } => {
+            let mut out = format!("function {}", id);
+            if let Some(name) = name {
+                out.push(' ');
+                out.push_str(&show_id(name));
+            }
+            if let Some(span) = span {
+                let file = module.as_ref().map(String::as_str).unwrap_or(path);
+                out.push_str(&format!(" ({}:{})", file, span.start));
+            }
+            out
+        }
+        TraceEntry::Builtin(name) => format!("builtin {}", name),
+        TraceEntry::Native(id, name) => format!("function {} {}", id, name),
+        TraceEntry::Opaque(id) => format!("function {}", id),
+    }
+}
+
+fn show_id(id: &Id) -> String {
+    let mut out = String::new();
+    value::debug_print(&Value::id(id.clone()), 0, 0, &mut out);
+    out
+}
@@ -1,55 +1,235 @@
 //! A parser that turns a string of source code into a pavo value.
 
-use failure_derive::Fail;
+use std::fmt;
+use std::io::{self, BufRead};
+
+use failure::Fail;
 use nom::{
-    {is_a, one_of, eof},
+    {is_a, one_of},
     {value, tag, take_while1},
     {do_parse, alt, many0, many1, opt, preceded},
     {delimited, terminated},
-    {named, map, try_parse, separated_list},
+    {named, map, call, try_parse, separated_list},
     none_of, many_m_n, take_until,
-    IResult, Err, Context, ErrorKind,
+    IResult, Err, Context, ErrorKind, Slice,
     types::CompleteStr,
     hex_digit,
 };
+use nom_locate::LocatedSpan;
 use strtod::strtod;
 
 use crate::value::Value;
 
-named!(line_ending(CompleteStr) -> (), value!((), one_of!("\n\r")));
-named!(not_line_ending(CompleteStr) -> (), value!((), none_of!("\n\r")));
+type Input<'a> = LocatedSpan<CompleteStr<'a>>;
+
+/// A location in the source code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Position {
+    /// The offset in bytes from the start of the source.
+    pub offset: usize,
+    /// The line number, starting at 1.
+    pub line: u32,
+    /// The column in characters, starting at 1.
+    pub column: usize,
+}
+
+impl Position {
+    fn of(i: Input) -> Position {
+        Position {
+            offset: i.offset,
+            line: i.line,
+            column: i.get_utf8_column(),
+        }
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// The part of the source code from which a value has been read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Span {
+    pub start: Position,
+    /// The position directly after the value.
+    pub end: Position,
+}
+
+/// The spans of a value and of its sub-values, see `Located::source_map`.
+///
+/// The children are in the order in which the value holds them: the items of arrays and
+/// applications, the elements of sets, and the keys and values of maps (alternating, in the order
+/// of the keys). Values that have not been read from source code (e.g. because a macro created
+/// them) have no span.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SourceMap {
+    pub span: Option<Span>,
+    // `None` if there are no spans for any sub-value.
+    children: Option<Vec<SourceMap>>,
+}
+
+static UNMAPPED: SourceMap = SourceMap { span: None, children: None };
+
+impl SourceMap {
+    pub fn new(span: Option<Span>, children: Vec<SourceMap>) -> SourceMap {
+        SourceMap { span, children: Some(children) }
+    }
+
+    /// The spans of the n-th sub-value, without any spans if they are unknown.
+    pub fn child(&self, n: usize) -> &SourceMap {
+        self.children.as_ref().and_then(|children| children.get(n)).unwrap_or(&UNMAPPED)
+    }
+
+    /// The span of the n-th sub-value, or of the whole value if that is unknown.
+    pub fn child_span(&self, n: usize) -> Option<Span> {
+        self.child(n).span.or(self.span)
+    }
+
+    pub fn children(&self) -> &[SourceMap] {
+        match &self.children {
+            Some(children) => children,
+            None => &[],
+        }
+    }
+}
+
+/// A value produced by the reader, together with the positions at which it starts and ends and the
+/// located values it has been built from.
+///
+/// The children are given in source order. For maps they alternate between keys and values, the
+/// syntactic sugar forms (e.g. `$foo`) have a single child, the inner value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Located {
+    pub value: Value,
+    pub pos: Position,
+    /// The position directly after the value.
+    pub end: Position,
+    pub children: Vec<Located>,
+}
+
+impl Located {
+    pub fn span(&self) -> Span {
+        Span { start: self.pos, end: self.end }
+    }
+
+    /// The spans of the value and of all its sub-values.
+    pub fn source_map(&self) -> SourceMap {
+        let children = match &self.value {
+            // Syntactic sugar, e.g. `$foo` for `(sf-quote foo)`: the head has not been read.
+            Value::App(app) if app.0.len() == self.children.len() + 1 => {
+                std::iter::once(SourceMap::default())
+                    .chain(self.children.iter().map(Located::source_map))
+                    .collect()
+            }
+            Value::Arr(_) | Value::App(_) => self.children.iter().map(Located::source_map).collect(),
+            Value::Set(set) => set.0
+                .iter()
+                .map(|elem| self.children.iter().find(|child| &child.value == elem))
+                .map(|child| child.map(Located::source_map).unwrap_or_default())
+                .collect(),
+            Value::Map(map) => {
+                let mut children = Vec::with_capacity(map.0.len() * 2);
+                for (key, _) in map.0.iter() {
+                    // Of duplicate keys, the last one determines the entry.
+                    let i = (0..self.children.len() / 2)
+                        .rev()
+                        .find(|i| &self.children[2 * i].value == key);
+                    match i {
+                        Some(i) => {
+                            children.push(self.children[2 * i].source_map());
+                            children.push(self.children[2 * i + 1].source_map());
+                        }
+                        None => {
+                            children.push(SourceMap::default());
+                            children.push(SourceMap::default());
+                        }
+                    }
+                }
+                children
+            }
+            _ => vec![],
+        };
+
+        SourceMap::new(Some(self.span()), children)
+    }
+
+    // Move all positions by the given number of bytes and lines.
+    fn shift(&mut self, offset: usize, lines: u32) {
+        self.pos.offset += offset;
+        self.pos.line += lines;
+        self.end.offset += offset;
+        self.end.line += lines;
+
+        for child in self.children.iter_mut() {
+            child.shift(offset, lines);
+        }
+    }
+}
 
-named!(linecomment(CompleteStr) -> (), do_parse!(
+// Codes for the custom nom errors, see `ParseErrorKind` for their meaning.
+const ERR_ID_TOO_LONG: u32 = 0;
+const ERR_INT_RANGE: u32 = 1;
+const ERR_FLOAT_RANGE: u32 = 2;
+const ERR_UNICODE: u32 = 4;
+const ERR_BYTE_RANGE: u32 = 5;
+const ERR_FRESH_NAME: u32 = 6;
+const ERR_UNTERMINATED_STRING: u32 = 7;
+const ERR_ESCAPE: u32 = 8;
+const ERR_UNBALANCED_PAREN: u32 = 9;
+const ERR_UNBALANCED_BRACKET: u32 = 10;
+const ERR_UNBALANCED_BRACE: u32 = 11;
+const ERR_TRAILING: u32 = 12;
+const ERR_SEPARATOR: u32 = 13;
+const ERR_EXPRESSION: u32 = 14;
+
+fn fail<O>(i: Input, code: u32) -> IResult<Input, O> {
+    Err(Err::Failure(Context::Code(i, ErrorKind::Custom(code))))
+}
+
+// The value read by a parser, together with the located values it has been built from.
+type Parsed = (Value, Vec<Located>);
+
+fn leaf(v: Value) -> Parsed {
+    (v, vec![])
+}
+
+fn values(children: &[Located]) -> Vec<Value> {
+    children.iter().map(|child| child.value.clone()).collect()
+}
+
+named!(line_ending(Input) -> (), value!((), one_of!("\n\r")));
+named!(not_line_ending(Input) -> (), value!((), none_of!("\n\r")));
+
+named!(linecomment(Input) -> (), do_parse!(
     tag!("#") >>
     many0!(not_line_ending) >>
     opt!(line_ending) >>
     (())
 ));
 
-named!(ws(CompleteStr) -> (), alt!(
+named!(ws(Input) -> (), alt!(
     value!((), linecomment) |
     value!((), is_a!(",\n\t\r "))
 ));
 
-named!(ws0(CompleteStr) -> (), do_parse!(
+named!(ws0(Input) -> (), do_parse!(
     many0!(ws) >>
     (())
 ));
 
-named!(ws1(CompleteStr) -> (), do_parse!(
+named!(ws1(Input) -> (), do_parse!(
     many1!(ws) >>
     (())
 ));
 
-named!(lbrace(CompleteStr) -> (), do_parse!(tag!("{") >> (())));
-named!(rbrace(CompleteStr) -> (), do_parse!(tag!("}") >> (())));
-named!(lbracket(CompleteStr) -> (), do_parse!(tag!("[") >> (())));
-named!(rbracket(CompleteStr) -> (), do_parse!(tag!("]") >> (())));
-named!(lparen(CompleteStr) -> (), do_parse!(tag!("(") >> (())));
-named!(rparen(CompleteStr) -> (), do_parse!(tag!(")") >> (())));
-named!(at_lbrace(CompleteStr) -> (), do_parse!(tag!("@{") >> (())));
-named!(at_lbracket(CompleteStr) -> (), do_parse!(tag!("@[") >> (())));
-named!(at_tilde(CompleteStr) -> (), do_parse!(tag!("@~") >> (())));
+named!(lbrace(Input) -> (), do_parse!(tag!("{") >> (())));
+named!(lbracket(Input) -> (), do_parse!(tag!("[") >> (())));
+named!(lparen(Input) -> (), do_parse!(tag!("(") >> (())));
+named!(at_lbrace(Input) -> (), do_parse!(tag!("@{") >> (())));
+named!(at_lbracket(Input) -> (), do_parse!(tag!("@[") >> (())));
+named!(at_tilde(Input) -> (), do_parse!(tag!("@~") >> (())));
 
 pub fn is_id_char(c: char) -> bool {
     return c.is_ascii_alphanumeric() || c == '!' || c == '*' || c == '+'
@@ -58,20 +238,21 @@ pub fn is_id_char(c: char) -> bool {
         || c == '|' || c == '&';
 }
 
-fn id_str(i: CompleteStr) -> IResult<CompleteStr, CompleteStr> {
+fn id_str(i: Input) -> IResult<Input, Input> {
     let (i, id) = try_parse!(i, take_while1!(is_id_char));
 
-    if id.len() > 255 {
-        Err(Err::Failure(Context::Code(i, ErrorKind::Custom(0))))
+    if id.fragment.len() > 255 {
+        fail(id, ERR_ID_TOO_LONG)
     } else {
         Ok((i, id))
     }
 }
 
-named!(kw_str(CompleteStr) -> CompleteStr, preceded!(tag!(":"), id_str));
+named!(kw_str(Input) -> Input, preceded!(tag!(":"), id_str));
 
-fn num(i: CompleteStr) -> IResult<CompleteStr, Value> {
-    let start = i;
+fn num(i: Input) -> IResult<Input, Value> {
+    let begin = i;
+    let start = i.fragment.0;
     let (i, has_sign) = try_parse!(i, map!(opt!(one_of!("+-")), |opt| opt.is_some()));
     let (i, is_hex) = try_parse!(i, map!(opt!(tag!("0x")), |opt| opt.is_some()));
     let (i, _) = if is_hex {
@@ -81,7 +262,7 @@ fn num(i: CompleteStr) -> IResult<CompleteStr, Value> {
     };
 
     if is_hex {
-        let end = i;
+        let end = i.fragment.0;
 
         let raw = if has_sign {
                 let mut buf = start[..1].to_string();
@@ -93,7 +274,7 @@ fn num(i: CompleteStr) -> IResult<CompleteStr, Value> {
 
         match i64::from_str_radix(&raw, 16) {
             Ok(n) => return Ok((i, Value::int(n))),
-            Err(_) => return Err(Err::Failure(Context::Code(i, ErrorKind::Custom(1)))),
+            Err(_) => return fail(begin, ERR_INT_RANGE),
         }
     } else {
         let (i, is_float) = try_parse!(i, map!(opt!(tag!(".")), |opt| opt.is_some()));
@@ -106,37 +287,38 @@ fn num(i: CompleteStr) -> IResult<CompleteStr, Value> {
                 take_while1!(|c: char| c.is_ascii_digit()) >>
                 (())
             )));
-            let end = i;
+            let end = i.fragment.0;
 
             let raw = &start[..start.len() - end.len()];
             let f = strtod(raw).unwrap();
             if f.is_finite() {
                 return Ok((i, Value::float(f)));
             } else {
-                return Err(Err::Failure(Context::Code(i, ErrorKind::Custom(2))));
+                return fail(begin, ERR_FLOAT_RANGE);
             }
         } else {
-            let end = i;
+            let end = i.fragment.0;
 
             let raw = &start[..start.len() - end.len()];
 
             match i64::from_str_radix(raw, 10) {
                 Ok(n) => return Ok((i, Value::int(n))),
-                Err(_) => return Err(Err::Failure(Context::Code(i, ErrorKind::Custom(1)))),
+                Err(_) => return fail(begin, ERR_INT_RANGE),
             }
         }
     }
 }
 
-fn byte(i: CompleteStr) -> IResult<CompleteStr, u8> {
-    let start = i;
+fn byte(i: Input) -> IResult<Input, u8> {
+    let begin = i;
+    let start = i.fragment.0;
     let (i, is_hex) = try_parse!(i, map!(opt!(tag!("0x")), |opt| opt.is_some()));
     let (i, _) = if is_hex {
         try_parse!(i, take_while1!(|c: char| c.is_ascii_hexdigit()))
     } else {
         try_parse!(i, take_while1!(|c: char| c.is_ascii_digit()))
     };
-    let end = i;
+    let end = i.fragment.0;
 
     let raw = if is_hex {
         start[2..start.len() - end.len()].to_string()
@@ -146,25 +328,26 @@ fn byte(i: CompleteStr) -> IResult<CompleteStr, u8> {
 
     match u8::from_str_radix(&raw, if is_hex { 16 } else { 10 }) {
         Ok(n) => Ok((i, n)),
-        Err(_) => Err(Err::Failure(Context::Code(i, ErrorKind::Custom(5)))),
+        Err(_) => fail(begin, ERR_BYTE_RANGE),
     }
 }
 
-fn unicode(i: CompleteStr) -> IResult<CompleteStr, char> {
-    let start = i;
+fn unicode(i: Input) -> IResult<Input, char> {
+    let begin = i;
+    let start = i.fragment.0;
     let (i, _) = try_parse!(i, many_m_n!(1, 6, hex_digit));
-    let end = i;
+    let end = i.fragment.0;
 
     let raw = start[..start.len() - end.len()].to_string();
     let numeric = u32::from_str_radix(&raw, 16).unwrap();
 
     match std::char::from_u32(numeric) {
         Some(c) => Ok((i, c)),
-        None => Err(Err::Failure(Context::Code(i, ErrorKind::Custom(4)))),
+        None => fail(begin, ERR_UNICODE),
     }
 }
 
-named!(char_char(CompleteStr) -> char, alt!(
+named!(char_char(Input) -> char, alt!(
     value!('\\', tag!("\\\\")) |
     value!('\'', tag!("\\'")) |
     value!('\t', tag!("\\t")) |
@@ -177,7 +360,7 @@ named!(char_char(CompleteStr) -> char, alt!(
     none_of!("\\'")
 ));
 
-named!(char_str(CompleteStr) -> char, alt!(
+named!(char_str(Input) -> char, alt!(
     value!('\\', tag!("\\\\")) |
     value!('\"', tag!("\\\"")) |
     value!('\t', tag!("\\t")) |
@@ -190,19 +373,32 @@ named!(char_str(CompleteStr) -> char, alt!(
     none_of!("\"\\")
 ));
 
-named!(char_(CompleteStr) -> Value, delimited!(
+named!(char_(Input) -> Value, delimited!(
     tag!("'"),
     map!(char_char, Value::char_),
     do_parse!(tag!("'") >> (()))
 ));
 
-named!(string(CompleteStr) -> Value, delimited!(
-    tag!("\""),
-    map!(many0!(char_str), Value::string_from_vec),
-    do_parse!(tag!("\"") >> (()))
-));
+fn string(i: Input) -> IResult<Input, Value> {
+    let begin = i;
+    let (i, _) = try_parse!(i, tag!("\""));
+    let (i, chars) = try_parse!(i, many0!(char_str));
 
-fn raw_string(i: CompleteStr) -> IResult<CompleteStr, Value> {
+    match tag!(i, "\"") {
+        Ok((i, _)) => return Ok((i, Value::string_from_vec(chars))),
+        Err(Err::Error(_)) => {
+            if i.fragment.0.is_empty() {
+                return fail(begin, ERR_UNTERMINATED_STRING);
+            } else {
+                return fail(i, ERR_ESCAPE);
+            }
+        }
+        Err(err) => return Err(err),
+    }
+}
+
+fn raw_string(i: Input) -> IResult<Input, Value> {
+    let begin = i;
     let (i, leading_ats) = try_parse!(i, many_m_n!(1, 8, tag!("@")));
     let (i, _) = try_parse!(i, tag!("\""));
     let num_ats = leading_ats.len();
@@ -211,40 +407,70 @@ fn raw_string(i: CompleteStr) -> IResult<CompleteStr, Value> {
         at_str.push('@');
     }
 
-    let (i, raw) = try_parse!(i, take_until!(at_str.as_str()));
+    let (i, raw) = match take_until!(i, at_str.as_str()) {
+        Ok(yay) => yay,
+        Err(_) => return fail(begin, ERR_UNTERMINATED_STRING),
+    };
     let (i, _) = try_parse!(i, tag!(at_str.as_str()));
 
-    return Ok((i, Value::string_from_str(&raw)));
+    return Ok((i, Value::string_from_str(raw.fragment.0)));
+}
+
+// Parse the closing delimiter of a collection. If the input ends or a different collection is
+// closed instead, the collection is unbalanced, which is reported with the given error code. Any
+// other input is something that is not an expression.
+fn close<'a>(i: Input<'a>, delimiter: &'static str, code: u32) -> IResult<Input<'a>, ()> {
+    match tag!(i, delimiter) {
+        Ok((i, _)) => Ok((i, ())),
+        Err(Err::Error(_)) => match i.fragment.0.chars().next() {
+            None | Some(')') | Some(']') | Some('}') => fail(i, code),
+            Some(_) => fail(i, ERR_EXPRESSION),
+        },
+        Err(err) => Err(err),
+    }
 }
 
-named!(bytes(CompleteStr) -> Value, map!(
+named!(bytes(Input) -> Value, map!(
     delimited!(
         terminated!(at_lbracket, ws0),
         separated_list!(ws1, byte),
-        preceded!(ws0, rbracket)
+        preceded!(ws0, call!(close, "]", ERR_UNBALANCED_BRACKET))
     ),
     |byte_vec| Value::bytes_from_vec(byte_vec)
 ));
 
-named!(app(CompleteStr) -> Value, map!(
+named!(app(Input) -> Parsed, map!(
     delimited!(
         terminated!(lparen, ws0),
         separated_list!(ws1, obj),
-        preceded!(ws0, rparen)
+        preceded!(ws0, call!(close, ")", ERR_UNBALANCED_PAREN))
     ),
-    |objs| Value::app_from_vec(objs)
+    |objs| (Value::app_from_vec(values(&objs)), objs)
 ));
 
-named!(arr(CompleteStr) -> Value, map!(
+named!(arr(Input) -> Parsed, map!(
     delimited!(
         terminated!(lbracket, ws0),
         separated_list!(ws1, obj),
-        preceded!(ws0, rbracket)
+        preceded!(ws0, call!(close, "]", ERR_UNBALANCED_BRACKET))
     ),
-    |objs| Value::arr_from_vec(objs)
+    |objs| (Value::arr_from_vec(values(&objs)), objs)
 ));
 
-named!(map_(CompleteStr) -> Value, map!(
+fn entries(entries: Vec<(Located, Located)>) -> Parsed {
+    let mut kvs = Vec::with_capacity(entries.len());
+    let mut children = Vec::with_capacity(entries.len() * 2);
+
+    for (key, val) in entries.into_iter() {
+        kvs.push((key.value.clone(), val.value.clone()));
+        children.push(key);
+        children.push(val);
+    }
+
+    (Value::map_from_vec(kvs), children)
+}
+
+named!(map_(Input) -> Parsed, map!(
     delimited!(
         terminated!(lbrace, ws0),
         separated_list!(ws1, do_parse!(
@@ -253,65 +479,69 @@ named!(map_(CompleteStr) -> Value, map!(
             val: obj >>
             ((key, val))
         )),
-        preceded!(ws0, rbrace)
+        preceded!(ws0, call!(close, "}", ERR_UNBALANCED_BRACE))
     ),
-    |entries| Value::map_from_vec(entries)
+    entries
 ));
 
-named!(set(CompleteStr) -> Value, map!(
+named!(set(Input) -> Parsed, map!(
     delimited!(
         terminated!(at_lbrace, ws0),
         separated_list!(ws1, obj),
-        preceded!(ws0, rbrace)
+        preceded!(ws0, call!(close, "}", ERR_UNBALANCED_BRACE))
     ),
-    |objs| Value::set_from_vec(objs)
+    |objs| (Value::set_from_vec(values(&objs)), objs)
 ));
 
-named!(id(CompleteStr) -> Value, map!(id_str, |id| if id.0 == "nil" {
+named!(id(Input) -> Value, map!(id_str, |id| if id.fragment.0 == "nil" {
     Value::nil()
-} else if id.0 == "true" {
+} else if id.fragment.0 == "true" {
     Value::bool_(true)
-} else if id.0 == "false" {
+} else if id.fragment.0 == "false" {
     Value::bool_(false)
 } else {
-    Value::id_str(id.0)
+    Value::id_str(id.fragment.0)
 }));
 
-named!(quote(CompleteStr) -> Value, do_parse!(
+named!(quote(Input) -> Parsed, do_parse!(
     tag!("$") >>
     inner: obj >>
-    (Value::app_from_vec(vec![Value::id_str("sf-quote"), inner]))
+    ((Value::app_from_vec(vec![Value::id_str("sf-quote"), inner.value.clone()]), vec![inner]))
 ));
 
-named!(quasiquote(CompleteStr) -> Value, do_parse!(
+named!(quasiquote(Input) -> Parsed, do_parse!(
     tag!("`") >>
     inner: obj >>
-    (Value::app_from_vec(vec![Value::id_str("quasiquote"), inner]))
+    ((Value::app_from_vec(vec![Value::id_str("quasiquote"), inner.value.clone()]), vec![inner]))
 ));
 
-named!(unquote(CompleteStr) -> Value, do_parse!(
+named!(unquote(Input) -> Parsed, do_parse!(
     tag!("~") >>
     inner: obj >>
-    (Value::app_from_vec(vec![Value::kw_str("unquote"), inner]))
+    ((Value::app_from_vec(vec![Value::kw_str("unquote"), inner.value.clone()]), vec![inner]))
 ));
 
-named!(unquote_splice(CompleteStr) -> Value, do_parse!(
+named!(unquote_splice(Input) -> Parsed, do_parse!(
     at_tilde >>
     inner: obj >>
-    (Value::app_from_vec(vec![Value::kw_str("unquote-splice"), inner]))
+    ((Value::app_from_vec(vec![Value::kw_str("unquote-splice"), inner.value.clone()]), vec![inner]))
 ));
 
-fn fresh_name(i: CompleteStr) -> IResult<CompleteStr, Value> {
+fn fresh_name(i: Input) -> IResult<Input, Parsed> {
+    let begin = i;
     let (i, _) = try_parse!(i, tag!("@"));
     let (i, inner) = try_parse!(i, obj);
 
-    match inner.as_user_id() {
-        Some(_) => return Ok((i, Value::app_from_vec(vec![Value::kw_str("fresh-name"), inner]))),
-        None => return Err(Err::Failure(Context::Code(i, ErrorKind::Custom(6)))),
+    match inner.value.as_user_id() {
+        Some(_) => {
+            let v = Value::app_from_vec(vec![Value::kw_str("fresh-name"), inner.value.clone()]);
+            return Ok((i, (v, vec![inner])));
+        }
+        None => return fail(begin, ERR_FRESH_NAME),
     }
 }
 
-named!(obj(CompleteStr) -> Value, alt!(
+named!(obj_(Input) -> Parsed, alt!(
     quote |
     quasiquote |
     unquote |
@@ -320,49 +550,372 @@ named!(obj(CompleteStr) -> Value, alt!(
     arr |
     map_ |
     set |
-    bytes |
-    char_ |
-    string |
-    raw_string |
-    num |
-    map!(kw_str, |kw| Value::kw_str(kw.0)) |
+    map!(bytes, leaf) |
+    map!(char_, leaf) |
+    map!(string, leaf) |
+    map!(raw_string, leaf) |
+    map!(num, leaf) |
+    map!(kw_str, |kw| leaf(Value::kw_str(kw.fragment.0))) |
     fresh_name |
-    id
+    map!(id, leaf)
 ));
 
-named!(read_(CompleteStr) -> Value, do_parse!(
-    ws0 >>
-    o: obj >>
-    ws0 >>
-    eof!() >>
-    (o)
-));
+fn obj(i: Input) -> IResult<Input, Located> {
+    let pos = Position::of(i);
+    let (i, (value, children)) = try_parse!(i, obj_);
+    let end = Position::of(i);
+
+    return Ok((i, Located { value, pos, end, children }));
+}
+
+fn read_(i: Input) -> IResult<Input, Located> {
+    let (i, _) = try_parse!(i, ws0);
+    let (i, o) = try_parse!(i, obj);
+    let (i, _) = try_parse!(i, ws0);
+
+    if i.fragment.0.is_empty() {
+        return Ok((i, o));
+    } else {
+        return fail(i, ERR_TRAILING);
+    }
+}
+
+// A top-level form, separated from the next one by whitespace.
+fn form(i: Input) -> IResult<Input, Located> {
+    let (i, o) = try_parse!(i, obj);
+
+    if i.fragment.0.is_empty() {
+        return Ok((i, o));
+    }
+
+    match ws1(i) {
+        Ok((i, _)) => return Ok((i, o)),
+        Err(_) => return fail(i, ERR_SEPARATOR),
+    }
+}
+
+fn forms(i: Input) -> IResult<Input, Vec<Located>> {
+    let (mut i, _) = try_parse!(i, ws0);
+    let mut forms = vec![];
+
+    while !i.fragment.0.is_empty() {
+        let (rest, o) = try_parse!(i, form);
+        forms.push(o);
+        i = rest;
+    }
+
+    return Ok((i, forms));
+}
 
 pub fn read(i: CompleteStr) -> Result<Value, ParseError> {
-    match read_(i) {
+    read_located(i).map(|located| located.value)
+}
+
+/// Read a value, keeping track of the source positions of all its sub-values.
+pub fn read_located(i: CompleteStr) -> Result<Located, ParseError> {
+    match read_(Input::new(i)) {
         Ok((_, o)) => return Ok(o),
         Err(Err::Incomplete(_)) => unreachable!(),
         Err(Err::Error(cx)) | Err(Err::Failure(cx)) => {
-            return Err(ParseError(cx.into_error_kind()));
+            return Err(ParseError::from_context(cx, i.0));
+        }
+    }
+}
+
+/// Read a sequence of whitespace-separated top-level forms.
+pub fn read_forms(i: CompleteStr) -> Result<Vec<Located>, ParseError> {
+    match forms(Input::new(i)) {
+        Ok((_, o)) => return Ok(o),
+        Err(Err::Incomplete(_)) => unreachable!(),
+        Err(Err::Error(cx)) | Err(Err::Failure(cx)) => {
+            return Err(ParseError::from_context(cx, i.0));
+        }
+    }
+}
+
+/// An iterator over the top-level forms of a stream of source code.
+///
+/// Input is consumed line by line, only as far as needed to read the next form, so forms can be
+/// processed before the stream has ended (e.g. when reading from stdin). Iteration stops after
+/// the first error.
+pub struct FormReader<R> {
+    src: R,
+    // Input that has been read but not yet dropped, always starts at the beginning of a line.
+    buf: String,
+    // How much of `buf` has already been read as forms.
+    cursor: usize,
+    // The offset in bytes from the start of the stream to the start of `buf`.
+    offset: usize,
+    // The number of lines before the start of `buf`.
+    lines: u32,
+    // Whether the stream has ended.
+    eof: bool,
+    // Whether an error has been emitted.
+    failed: bool,
+}
+
+impl<R: BufRead> FormReader<R> {
+    pub fn new(src: R) -> FormReader<R> {
+        FormReader {
+            src,
+            buf: String::new(),
+            cursor: 0,
+            offset: 0,
+            lines: 0,
+            eof: false,
+            failed: false,
+        }
+    }
+
+    // Append the next line of the stream to the buffer.
+    fn fill(&mut self) -> Result<(), io::Error> {
+        if self.src.read_line(&mut self.buf)? == 0 {
+            self.eof = true;
+        }
+        Ok(())
+    }
+
+    // Drop all complete lines before the cursor from the buffer.
+    fn compact(&mut self) {
+        if let Some(end) = self.buf[..self.cursor].rfind('\n') {
+            self.offset += end + 1;
+            self.lines += self.buf[..=end].matches('\n').count() as u32;
+            self.cursor -= end + 1;
+            self.buf.drain(..=end);
+        }
+    }
+
+    fn error(&mut self, err: StreamError) -> Option<Result<Located, StreamError>> {
+        self.failed = true;
+        Some(Err(err))
+    }
+}
+
+impl<R: BufRead> Iterator for FormReader<R> {
+    type Item = Result<Located, StreamError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        loop {
+            let result = {
+                let i = Input::new(CompleteStr(&self.buf)).slice(self.cursor..);
+                let i = match ws0(i) {
+                    Ok((i, _)) => i,
+                    Err(_) => unreachable!(),
+                };
+
+                if i.fragment.0.is_empty() {
+                    None
+                } else {
+                    match form(i) {
+                        Ok((rest, o)) => Some(Ok((rest.offset, o))),
+                        Err(Err::Incomplete(_)) => unreachable!(),
+                        Err(Err::Error(cx)) | Err(Err::Failure(cx)) => {
+                            Some(Err(ParseError::from_context(cx, &self.buf)))
+                        }
+                    }
+                }
+            };
+
+            match result {
+                None if self.eof => return None,
+                None => {}
+                Some(Ok((cursor, mut o))) => {
+                    self.cursor = cursor;
+                    o.shift(self.offset, self.lines);
+                    self.compact();
+                    return Some(Ok(o));
+                }
+                Some(Err(mut err)) => {
+                    if self.eof || !err.is_incomplete() {
+                        err.pos.offset += self.offset;
+                        err.pos.line += self.lines;
+                        return self.error(StreamError::Parse(err));
+                    }
+                }
+            }
+
+            if let Err(err) = self.fill() {
+                return self.error(StreamError::Io(err));
+            }
+        }
+    }
+}
+
+/// An error that occured while reading forms from a stream.
+#[derive(Debug)]
+pub enum StreamError {
+    Io(io::Error),
+    Parse(ParseError),
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StreamError::Io(err) => write!(f, "{}", err),
+            StreamError::Parse(err) => write!(f, "{}", err),
         }
     }
 }
 
 pub fn parse_id(i: CompleteStr) -> Result<Value, ParseError> {
-    match num(i) {
-        Ok(..) => return Err(ParseError(ErrorKind::RegexpMatch)),
+    let span = Input::new(i);
+
+    match num(span) {
+        Ok(..) => return Err(ParseError::new(ParseErrorKind::ExpectedIdentifier, span, i.0)),
         _ => {
-            match id(i) {
+            match id(span) {
                 Ok((_, o)) => return Ok(o),
                 Err(Err::Incomplete(_)) => unreachable!(),
                 Err(Err::Error(cx)) | Err(Err::Failure(cx)) => {
-                    return Err(ParseError(cx.into_error_kind()));
+                    return Err(ParseError::from_context(cx, i.0));
                 }
             }
         }
     }
 }
 
-#[derive(PartialEq, Eq, Debug, Clone, Fail)]
-#[fail(display = "Parse error of kind {:?}", 0)]
-pub struct ParseError(ErrorKind);
+/// The different reasons for which source code can fail to parse.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ParseErrorKind {
+    /// Input that does not start an expression.
+    ExpectedExpression,
+    /// Input that is not an identifier (only for `parse_id`).
+    ExpectedIdentifier,
+    /// More input after the expression.
+    TrailingInput,
+    /// An identifier or keyword longer than 255 bytes.
+    IdTooLong,
+    /// An integer literal outside of `[int-min-val, int-max-val]`.
+    IntOutOfRange,
+    /// A float literal that rounds to an infinity.
+    FloatOutOfRange,
+    /// A byte literal greater than 255.
+    ByteOutOfRange,
+    /// A `\{...}` escape that does not denote a unicode scalar value.
+    InvalidUnicodeEscape,
+    /// A backslash in a string that does not start a valid escape sequence.
+    InvalidEscape,
+    /// A string literal that is not closed before the end of the input.
+    UnterminatedString,
+    /// A collection that is not closed by the expected delimiter.
+    UnbalancedBracket { expected: char },
+    /// A fresh name (`@foo`) on something that is not an identifier.
+    FreshNameNotId,
+    /// Two top-level forms that are not separated by whitespace.
+    MissingSeparator,
+}
+
+impl ParseErrorKind {
+    fn from_error_kind(kind: ErrorKind) -> ParseErrorKind {
+        match kind {
+            ErrorKind::Custom(ERR_ID_TOO_LONG) => ParseErrorKind::IdTooLong,
+            ErrorKind::Custom(ERR_INT_RANGE) => ParseErrorKind::IntOutOfRange,
+            ErrorKind::Custom(ERR_FLOAT_RANGE) => ParseErrorKind::FloatOutOfRange,
+            ErrorKind::Custom(ERR_UNICODE) => ParseErrorKind::InvalidUnicodeEscape,
+            ErrorKind::Custom(ERR_BYTE_RANGE) => ParseErrorKind::ByteOutOfRange,
+            ErrorKind::Custom(ERR_FRESH_NAME) => ParseErrorKind::FreshNameNotId,
+            ErrorKind::Custom(ERR_UNTERMINATED_STRING) => ParseErrorKind::UnterminatedString,
+            ErrorKind::Custom(ERR_ESCAPE) => ParseErrorKind::InvalidEscape,
+            ErrorKind::Custom(ERR_UNBALANCED_PAREN) => ParseErrorKind::UnbalancedBracket { expected: ')' },
+            ErrorKind::Custom(ERR_UNBALANCED_BRACKET) => ParseErrorKind::UnbalancedBracket { expected: ']' },
+            ErrorKind::Custom(ERR_UNBALANCED_BRACE) => ParseErrorKind::UnbalancedBracket { expected: '}' },
+            ErrorKind::Custom(ERR_TRAILING) => ParseErrorKind::TrailingInput,
+            ErrorKind::Custom(ERR_SEPARATOR) => ParseErrorKind::MissingSeparator,
+            ErrorKind::Custom(ERR_EXPRESSION) => ParseErrorKind::ExpectedExpression,
+            _ => ParseErrorKind::ExpectedExpression,
+        }
+    }
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseErrorKind::ExpectedExpression => write!(f, "expected an expression"),
+            ParseErrorKind::ExpectedIdentifier => write!(f, "expected an identifier"),
+            ParseErrorKind::TrailingInput => write!(f, "expected the end of the input"),
+            ParseErrorKind::IdTooLong => write!(f, "identifier longer than 255 bytes"),
+            ParseErrorKind::IntOutOfRange => write!(f, "integer out of range"),
+            ParseErrorKind::FloatOutOfRange => write!(f, "float out of range"),
+            ParseErrorKind::ByteOutOfRange => write!(f, "byte out of range"),
+            ParseErrorKind::InvalidUnicodeEscape => write!(f, "invalid unicode escape"),
+            ParseErrorKind::InvalidEscape => write!(f, "invalid escape sequence"),
+            ParseErrorKind::UnterminatedString => write!(f, "unterminated string"),
+            ParseErrorKind::UnbalancedBracket { expected } => {
+                write!(f, "unbalanced bracket, expected `{}`", expected)
+            }
+            ParseErrorKind::FreshNameNotId => write!(f, "fresh name on a non-identifier"),
+            ParseErrorKind::MissingSeparator => write!(f, "expected whitespace between expressions"),
+        }
+    }
+}
+
+/// An error that occured while reading source code.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    /// Where in the input the error occured.
+    pub pos: Position,
+    // The full source line in which the error occured.
+    line: String,
+    // How many characters to underline.
+    len: usize,
+    // Whether the parser ran out of input, i.e. whether appending input could fix the error.
+    eof: bool,
+}
+
+impl ParseError {
+    fn new(kind: ParseErrorKind, i: Input, src: &str) -> ParseError {
+        let line_start = src[..i.offset].rfind('\n').map(|n| n + 1).unwrap_or(0);
+        let line_end = src[i.offset..].find('\n').map(|n| i.offset + n).unwrap_or(src.len());
+        let line = src[line_start..line_end].trim_end_matches('\r').replace('\t', " ");
+
+        let len = src[i.offset..line_end]
+            .chars()
+            .take_while(|c| !c.is_whitespace() && !"()[]{},".contains(*c))
+            .count();
+
+        ParseError {
+            kind,
+            pos: Position::of(i),
+            line,
+            len: std::cmp::max(len, 1),
+            eof: i.fragment.0.is_empty() || kind == ParseErrorKind::UnterminatedString,
+        }
+    }
+
+    fn from_context(cx: Context<Input, u32>, src: &str) -> ParseError {
+        let Context::Code(i, kind) = cx;
+        ParseError::new(ParseErrorKind::from_error_kind(kind), i, src)
+    }
+
+    /// Whether the input ended before the parser could decide whether it was valid, e.g. at an
+    /// unclosed bracket. Appending more input might turn such an input valid.
+    pub fn is_incomplete(&self) -> bool {
+        self.eof && self.kind != ParseErrorKind::TrailingInput
+    }
+}
+
+/// Renders the error together with a caret-underlined snippet of the offending source line.
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let line_number = self.pos.line.to_string();
+        let gutter = " ".repeat(line_number.len());
+
+        writeln!(f, "{}: {}", self.pos, self.kind)?;
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", line_number, self.line)?;
+        write!(
+            f,
+            "{} | {}{}",
+            gutter,
+            " ".repeat(self.pos.column - 1),
+            "^".repeat(self.len)
+        )
+    }
+}
+
+impl Fail for ParseError {}
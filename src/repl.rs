@@ -0,0 +1,160 @@
+//! An interactive read-eval-print loop.
+//!
+//! Entries may span multiple lines, the repl keeps reading until all brackets are balanced.
+//! Definitions (`def` and `defmacro`, see the `toplevel` module) persist across entries.
+
+use nom::types::CompleteStr;
+use rustyline::Editor;
+use rustyline::error::ReadlineError;
+
+use crate::diagnostics::describe;
+use crate::disassemble::disassemble;
+use crate::interpreter::Interpreter;
+use crate::read::{read_forms, Located};
+use crate::value::{self, Value};
+use crate::E;
+
+// Used in place of a file name when reporting errors.
+const PATH: &str = "<repl>";
+
+const HELP: &str = "\
+Enter expressions to evaluate them, `(def name exp)` and `(defmacro name exp)` bind values and
+macros for all later entries. Press ctrl-d to exit.
+
+Commands:
+  :expand <exp>  print the macro expansion of <exp>
+  :check <exp>   check <exp> for static errors without evaluating it
+  :ir <exp>      print the intermediate representation <exp> compiles to
+  :help          print this message";
+
+/// What to do with the forms of an entry, see `command`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    Eval,
+    Expand,
+    Check,
+    Ir,
+    Help,
+}
+
+/// Split a complete entry into the command and the source code it applies to. The command is
+/// replaced by whitespace, so that source positions still refer to the entry as typed.
+pub fn command(entry: &str) -> (Command, String) {
+    let trimmed = entry.trim_start();
+    let start = entry.len() - trimmed.len();
+
+    for (name, cmd) in [
+        (":expand", Command::Expand),
+        (":check", Command::Check),
+        (":ir", Command::Ir),
+        (":help", Command::Help),
+    ].iter() {
+        if trimmed.starts_with(name) && trimmed[name.len()..].starts_with(char::is_whitespace) {
+            let end = start + name.len();
+            return (*cmd, format!("{}{}", " ".repeat(end), &entry[end..]));
+        }
+    }
+
+    return (Command::Eval, entry.to_string());
+}
+
+/// The state of a repl session: an interpreter whose definitions persist across entries.
+pub struct Repl {
+    interpreter: Interpreter,
+}
+
+impl Repl {
+    pub fn new(interpreter: Interpreter) -> Repl {
+        Repl { interpreter }
+    }
+
+    /// Apply the command to a single form of an entry, returning the text to print.
+    pub fn run(&mut self, cmd: Command, form: &Located) -> Result<String, E> {
+        let map = form.source_map();
+        match cmd {
+            Command::Eval => {
+                let yay = self.interpreter.eval_mapped(&form.value, &map)?;
+                Ok(print(&yay))
+            }
+            Command::Expand => {
+                let (expanded, _) = self.interpreter.expand_mapped(&form.value, &map)?;
+                Ok(print(&expanded))
+            }
+            Command::Check => {
+                self.interpreter.check_mapped(&form.value, &map)?;
+                Ok("ok".to_string())
+            }
+            Command::Ir => {
+                let c = self.interpreter.compile_mapped(&form.value, &map)?;
+                Ok(disassemble(&c))
+            }
+            Command::Help => Ok(HELP.to_string()),
+        }
+    }
+}
+
+fn print(v: &Value) -> String {
+    let mut buf = String::new();
+    value::debug_print(v, 0, 2, &mut buf);
+    buf
+}
+
+/// Run an interactive session on stdin, with the given limit on how deeply computations may
+/// recurse (see `Interpreter::set_max_native_depth`).
+pub fn run(max_native_depth: usize) {
+    let mut editor = Editor::<()>::new();
+    let mut interpreter = Interpreter::new();
+    interpreter.set_max_native_depth(max_native_depth);
+    let mut repl = Repl::new(interpreter);
+    // The lines of the current entry.
+    let mut entry = String::new();
+
+    loop {
+        let prompt = if entry.is_empty() { "pavo> " } else { "  ... " };
+
+        match editor.readline(prompt) {
+            Ok(line) => {
+                editor.add_history_entry(line.as_str());
+                entry.push_str(&line);
+                entry.push('\n');
+            }
+            Err(ReadlineError::Interrupted) => {
+                entry.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => return,
+            Err(err) => {
+                eprintln!("{}", err);
+                return;
+            }
+        }
+
+        let (cmd, src) = command(&entry);
+
+        let forms = match read_forms(CompleteStr(&src)) {
+            Ok(forms) => forms,
+            Err(ref err) if err.is_incomplete() => continue,
+            Err(err) => {
+                eprintln!("{}:{}", PATH, err);
+                entry.clear();
+                continue;
+            }
+        };
+        entry.clear();
+
+        if let Command::Help = cmd {
+            println!("{}", HELP);
+            continue;
+        }
+
+        for form in forms.iter() {
+            match repl.run(cmd, form) {
+                Ok(out) => println!("{}", out),
+                Err(err) => {
+                    eprintln!("{}", describe(PATH, form, &err));
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,146 @@
+//! How `require` finds the code to load, see `Context::set_resolver`.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Component, Path, PathBuf};
+
+use im_rc::OrdMap as ImOrdMap;
+
+use crate::gc_foreign::OrdMap;
+use crate::value::{Value, Atomic};
+
+/// Maps the first argument of `(require v opts)` to the code to load.
+///
+/// Errors are values that `require` throws, `err_require` gives the error that the builtin
+/// resolvers use.
+pub trait ModuleResolver {
+    /// The key identifying the module that `v` refers to, when required from the module with the
+    /// key `from` (`None` for top-level code that does not belong to a module, see
+    /// `Context::set_entrypoint`). Requiring values that resolve to the same key loads the module
+    /// only once.
+    fn resolve(&mut self, v: &Value, from: Option<&str>) -> Result<String, Value>;
+
+    /// The source code of the module with the given key, as returned by `resolve`.
+    fn source(&mut self, key: &str) -> Result<String, Value>;
+}
+
+/// `{:tag :err-require}`
+pub fn err_require() -> Value {
+    Value::map(OrdMap(ImOrdMap::from(vec![
+            (Value::kw_str("tag"), Value::kw_str("err-require")),
+        ])))
+}
+
+/// The default resolver: strings are paths to files. Relative paths are relative to the
+/// directory of the requiring file, or to the working directory for top-level code that does
+/// not belong to a file. The keys are the canonical paths of the files.
+pub struct FileResolver;
+
+impl ModuleResolver for FileResolver {
+    fn resolve(&mut self, v: &Value, from: Option<&str>) -> Result<String, Value> {
+        match v {
+            Value::Atomic(Atomic::String(s)) => {
+                let s: String = s.0.clone().into();
+                match from.and_then(|from| Path::new(from).parent()) {
+                    Some(dir) => canonical(&dir.join(&s)),
+                    None => canonical(Path::new(&s)),
+                }
+            }
+            _ => Err(err_require()),
+        }
+    }
+
+    fn source(&mut self, key: &str) -> Result<String, Value> {
+        let mut contents = String::new();
+        File::open(key)
+            .and_then(|mut file| file.read_to_string(&mut contents))
+            .map_err(|_| err_require())?;
+        Ok(contents)
+    }
+}
+
+fn canonical(path: &Path) -> Result<String, Value> {
+    match path.canonicalize() {
+        Ok(path) if path.is_file() => Ok(path.display().to_string()),
+        _ => Err(err_require()),
+    }
+}
+
+/// Modules given as source code, which strings refer to by name. Useful for tests and for
+/// embedding pavo where there is no file system.
+pub struct MemoryResolver {
+    modules: BTreeMap<String, String>,
+}
+
+impl MemoryResolver {
+    pub fn new() -> MemoryResolver {
+        MemoryResolver { modules: BTreeMap::new() }
+    }
+
+    /// Add a module, so that `(require "name" opts)` loads the given source code.
+    pub fn insert(&mut self, name: &str, source: &str) {
+        self.modules.insert(name.to_string(), source.to_string());
+    }
+}
+
+impl ModuleResolver for MemoryResolver {
+    fn resolve(&mut self, v: &Value, _from: Option<&str>) -> Result<String, Value> {
+        match v {
+            Value::Atomic(Atomic::String(s)) => {
+                let s: String = s.0.clone().into();
+                if self.modules.contains_key(&s) { Ok(s) } else { Err(err_require()) }
+            }
+            _ => Err(err_require()),
+        }
+    }
+
+    fn source(&mut self, key: &str) -> Result<String, Value> {
+        self.modules.get(key).cloned().ok_or_else(err_require)
+    }
+}
+
+/// Like `FileResolver`, but additionally resolves keywords to files in a list of directories:
+/// `:std/collections` refers to the first `std/collections.pavo` found in the search path.
+pub struct SearchPathResolver {
+    paths: Vec<PathBuf>,
+}
+
+impl SearchPathResolver {
+    pub fn new(paths: Vec<PathBuf>) -> SearchPathResolver {
+        SearchPathResolver { paths }
+    }
+}
+
+impl ModuleResolver for SearchPathResolver {
+    fn resolve(&mut self, v: &Value, from: Option<&str>) -> Result<String, Value> {
+        match v {
+            Value::Atomic(Atomic::Keyword(kw)) => {
+                let mut relative = PathBuf::new();
+                for segment in kw.split('/') {
+                    relative.push(segment);
+                }
+                relative.set_extension("pavo");
+
+                // The keyword must not leave the directories of the search path.
+                if !relative.components().all(|c| match c {
+                    Component::Normal(_) => true,
+                    _ => false,
+                }) {
+                    return Err(err_require());
+                }
+
+                self.paths
+                    .iter()
+                    .filter_map(|dir| canonical(&dir.join(&relative)).ok())
+                    .next()
+                    .ok_or_else(err_require)
+            }
+            _ => FileResolver.resolve(v, from),
+        }
+    }
+
+    fn source(&mut self, key: &str) -> Result<String, Value> {
+        FileResolver.source(key)
+    }
+}
@@ -0,0 +1,826 @@
+//! Conversion between serde data formats and pavo values.
+//!
+//! `to_value` serializes any serde type into a `Value`, `from_value` deserializes any serde type
+//! from a `Value`. `Value` itself implements `Serialize` and `Deserialize`, so pavo values can be
+//! transcoded to and from formats such as JSON or CBOR.
+//!
+//! The data model maps as follows:
+//!
+//! - unit and none are nil, some is the inner value
+//! - integers are ints (integers outside the range of i64 are an error), floats are floats
+//!   (NaN and infinities are an error), chars are chars, strings are strings, bytes are bytes
+//! - sequences and tuples are arrays, sets and applications deserialize as sequences as well
+//! - maps are maps, structs are maps from keywords to the field values, with the field names
+//!   converted to kebab-case (`foo_bar` becomes `:foo-bar`)
+//! - newtype structs are their inner value, unit structs are nil
+//! - enum variants are maps tagged with the variant name in kebab-case, like the builtin error
+//!   values: unit variants are `{:tag :num-args}`, struct variants add their fields to the map,
+//!   newtype and tuple variants store their fields as an array under the key `:fields`
+//!
+//! This is the same representation as the one of the `IntoValue` and `FromValue` derives of the
+//! `convert` module. Field and variant names that are not valid keywords (e.g. renamed to contain
+//! a space) and struct variant fields named `tag` are an error.
+//! - keywords deserialize as strings, so they can be used as map keys and field names; a bare
+//!   keyword deserializes as a unit variant
+//!
+//! Identifiers, functions, cells and opaque values can not be serialized.
+
+use std::fmt;
+
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, Visitor};
+use serde::ser::{self, Serialize};
+use serde::forward_to_deserialize_any;
+
+use crate::convert::{self, field_keyword, variant_keyword};
+use crate::read::is_id_char;
+use crate::value::{Value, Atomic};
+
+/// An error while converting between serde and pavo values.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error(msg.to_string())
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error(msg.to_string())
+    }
+}
+
+/// Serialize any serde type into a pavo value.
+pub fn to_value<T: Serialize + ?Sized>(t: &T) -> Result<Value, Error> {
+    t.serialize(Serializer)
+}
+
+/// Deserialize any serde type from a pavo value.
+pub fn from_value<T: DeserializeOwned>(v: &Value) -> Result<T, Error> {
+    T::deserialize(Deserializer::new(v))
+}
+
+// Check that a field or variant name can be used as a keyword.
+fn keyword(name: String) -> Result<String, Error> {
+    if name.is_empty() || name.len() > 255 || !name.chars().all(is_id_char) {
+        Err(Error(format!("{:?} can not be represented as a pavo keyword", name)))
+    } else {
+        Ok(name)
+    }
+}
+
+// The rust name among `names` that corresponds to the keyword `kw`, if any.
+fn rust_name(
+    names: &'static [&'static str],
+    kw: &str,
+    to_keyword: fn(&str) -> String,
+) -> Option<&'static str> {
+    names.iter().find(|name| to_keyword(name) == kw).cloned()
+}
+
+fn float(n: f64) -> Result<Value, Error> {
+    if n.is_finite() {
+        Ok(Value::float(n))
+    } else {
+        Err(Error(format!("{} can not be represented as a pavo float", n)))
+    }
+}
+
+fn int(n: u64) -> Result<Value, Error> {
+    if n <= std::i64::MAX as u64 {
+        Ok(Value::int(n as i64))
+    } else {
+        Err(Error(format!("{} can not be represented as a pavo int", n)))
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////
+
+/// A serializer that produces pavo values, see `to_value`.
+pub struct Serializer;
+
+impl ser::Serializer for Serializer {
+    type Ok = Value;
+    type Error = Error;
+
+    type SerializeSeq = SerializeArr;
+    type SerializeTuple = SerializeArr;
+    type SerializeTupleStruct = SerializeArr;
+    type SerializeTupleVariant = SerializeArr;
+    type SerializeMap = SerializeMap;
+    type SerializeStruct = SerializeStruct;
+    type SerializeStructVariant = SerializeStruct;
+
+    fn serialize_bool(self, v: bool) -> Result<Value, Error> {
+        Ok(Value::bool_(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Value, Error> {
+        Ok(Value::int(v as i64))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Value, Error> {
+        Ok(Value::int(v as i64))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Value, Error> {
+        Ok(Value::int(v as i64))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Value, Error> {
+        Ok(Value::int(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Value, Error> {
+        Ok(Value::int(v as i64))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Value, Error> {
+        Ok(Value::int(v as i64))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Value, Error> {
+        Ok(Value::int(v as i64))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Value, Error> {
+        int(v)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Value, Error> {
+        float(v as f64)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Value, Error> {
+        float(v)
+    }
+
+    fn serialize_char(self, v: char) -> Result<Value, Error> {
+        Ok(Value::char_(v))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Value, Error> {
+        Ok(Value::string_from_str(v))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Value, Error> {
+        Ok(Value::bytes_from_vec(v.to_vec()))
+    }
+
+    fn serialize_none(self) -> Result<Value, Error> {
+        Ok(Value::nil())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Value, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Value, Error> {
+        Ok(Value::nil())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Value, Error> {
+        Ok(Value::nil())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Value, Error> {
+        Ok(convert::tagged(&keyword(variant_keyword(variant))?, vec![]))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Value, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Value, Error> {
+        let tag = keyword(variant_keyword(variant))?;
+        let fields = Value::arr_from_vec(vec![to_value(value)?]);
+        Ok(convert::tagged(&tag, vec![("fields", fields)]))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeArr, Error> {
+        Ok(SerializeArr {
+            tag: None,
+            vals: Vec::with_capacity(len.unwrap_or(0)),
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeArr, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SerializeArr, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeArr, Error> {
+        Ok(SerializeArr {
+            tag: Some(keyword(variant_keyword(variant))?),
+            vals: Vec::with_capacity(len),
+        })
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<SerializeMap, Error> {
+        Ok(SerializeMap {
+            entries: Vec::with_capacity(len.unwrap_or(0)),
+            key: None,
+        })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<SerializeStruct, Error> {
+        Ok(SerializeStruct {
+            tag: None,
+            entries: Vec::with_capacity(len),
+        })
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeStruct, Error> {
+        Ok(SerializeStruct {
+            tag: Some(keyword(variant_keyword(variant))?),
+            entries: Vec::with_capacity(len),
+        })
+    }
+}
+
+/// Serializes sequences, tuples and tuple variants into arrays.
+pub struct SerializeArr {
+    // The tag of tuple variants, whose array is wrapped in a tagged map.
+    tag: Option<String>,
+    vals: Vec<Value>,
+}
+
+impl SerializeArr {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.vals.push(to_value(value)?);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Value, Error> {
+        let arr = Value::arr_from_vec(self.vals);
+        match self.tag {
+            None => Ok(arr),
+            Some(tag) => Ok(convert::tagged(&tag, vec![("fields", arr)])),
+        }
+    }
+}
+
+impl ser::SerializeSeq for SerializeArr {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for SerializeArr {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeArr {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeArr {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+/// Serializes maps.
+pub struct SerializeMap {
+    entries: Vec<(Value, Value)>,
+    // The key whose value is serialized next.
+    key: Option<Value>,
+}
+
+impl ser::SerializeMap for SerializeMap {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        self.key = Some(to_value(key)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        match self.key.take() {
+            Some(key) => {
+                self.entries.push((key, to_value(value)?));
+                Ok(())
+            }
+            None => Err(Error("serialize_value called before serialize_key".to_string())),
+        }
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        Ok(Value::map_from_vec(self.entries))
+    }
+}
+
+/// Serializes structs and struct variants into maps with keyword keys.
+pub struct SerializeStruct {
+    // The tag of struct variants, whose map is tagged.
+    tag: Option<String>,
+    entries: Vec<(String, Value)>,
+}
+
+impl SerializeStruct {
+    fn push<T: Serialize + ?Sized>(&mut self, key: &str, value: &T) -> Result<(), Error> {
+        let key = keyword(field_keyword(key))?;
+        if self.tag.is_some() && key == "tag" {
+            return Err(Error("a struct variant can not have a field named tag".to_string()));
+        }
+
+        self.entries.push((key, to_value(value)?));
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Value, Error> {
+        let (keys, vals): (Vec<String>, Vec<Value>) = self.entries.into_iter().unzip();
+        let entries = keys.iter().map(String::as_str).zip(vals).collect();
+
+        match self.tag {
+            None => Ok(convert::keyword_map(entries)),
+            Some(tag) => Ok(convert::tagged(&tag, entries)),
+        }
+    }
+}
+
+impl ser::SerializeStruct for SerializeStruct {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.push(key, value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeStruct {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.push(key, value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////
+
+/// A deserializer that reads from a pavo value, see `from_value`.
+pub struct Deserializer<'a> {
+    v: &'a Value,
+}
+
+impl<'a> Deserializer<'a> {
+    pub fn new(v: &'a Value) -> Deserializer<'a> {
+        Deserializer { v }
+    }
+
+    fn unsupported(&self) -> Error {
+        Error(format!("can not deserialize {}", describe(self.v)))
+    }
+}
+
+// A short description of the kind of a value, for error messages.
+fn describe(v: &Value) -> &'static str {
+    match v {
+        Value::Id(..) => "an identifier",
+        Value::Fun(..) => "a function",
+        Value::Cell(..) => "a cell",
+        Value::Opaque(..) => "an opaque value",
+        _ => "this value",
+    }
+}
+
+impl<'de, 'a> de::Deserializer<'de> for Deserializer<'a> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.v {
+            Value::Atomic(Atomic::Nil) => visitor.visit_unit(),
+            Value::Atomic(Atomic::Bool(b)) => visitor.visit_bool(*b),
+            Value::Atomic(Atomic::Int(n)) => visitor.visit_i64(*n),
+            Value::Atomic(Atomic::Float(n)) => visitor.visit_f64(n.clone().into_inner()),
+            Value::Atomic(Atomic::Char(c)) => visitor.visit_char(*c),
+            Value::Atomic(Atomic::String(s)) => visitor.visit_string(s.0.to_string()),
+            Value::Atomic(Atomic::Bytes(b)) => visitor.visit_byte_buf(b.0.iter().cloned().collect()),
+            Value::Atomic(Atomic::Keyword(kw)) => visitor.visit_str(kw),
+            Value::Arr(vals) | Value::App(vals) => visitor.visit_seq(SeqAccess {
+                iter: vals.0.iter(),
+                len: vals.0.len(),
+            }),
+            Value::Set(vals) => visitor.visit_seq(SeqAccess {
+                iter: vals.0.iter(),
+                len: vals.0.len(),
+            }),
+            Value::Map(entries) => visitor.visit_map(MapAccess {
+                iter: entries.0.iter(),
+                len: entries.0.len(),
+                value: None,
+                fields: &[],
+            }),
+            Value::Id(..) | Value::Fun(..) | Value::Cell(..) | Value::Opaque(..) => {
+                Err(self.unsupported())
+            }
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.v {
+            Value::Atomic(Atomic::Nil) => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        // Unknown tags are passed on as they are, so that serde reports them.
+        let variant = |kw: &'a str| rust_name(variants, kw, variant_keyword).unwrap_or(kw);
+
+        match self.v {
+            Value::Atomic(Atomic::Keyword(kw)) => {
+                let variant = variant(kw.as_str());
+                visitor.visit_enum(IntoDeserializer::<Error>::into_deserializer(variant))
+            }
+            Value::Map(entries) => match entries.0.get(&Value::kw_str("tag")) {
+                Some(Value::Atomic(Atomic::Keyword(kw))) => {
+                    visitor.visit_enum(EnumAccess { variant: variant(kw.as_str()), map: self.v })
+                }
+                _ => Err(Error("expected a map with a keyword under the key :tag".to_string())),
+            },
+            _ => Err(Error("expected a keyword or a tagged map".to_string())),
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        match self.v {
+            Value::Map(entries) => visitor.visit_map(MapAccess {
+                iter: entries.0.iter(),
+                len: entries.0.len(),
+                value: None,
+                fields,
+            }),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map
+        identifier ignored_any
+    }
+}
+
+struct SeqAccess<I> {
+    iter: I,
+    len: usize,
+}
+
+impl<'de, 'a, I: Iterator<Item = &'a Value>> de::SeqAccess<'de> for SeqAccess<I> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        match self.iter.next() {
+            Some(v) => {
+                self.len -= 1;
+                seed.deserialize(Deserializer::new(v)).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len)
+    }
+}
+
+struct MapAccess<'a, I> {
+    iter: I,
+    len: usize,
+    // The value belonging to the last key that has been visited.
+    value: Option<&'a Value>,
+    // The field names of the struct being deserialized, keyword keys are converted back to them.
+    fields: &'static [&'static str],
+}
+
+impl<'de, 'a, I: Iterator<Item = (&'a Value, &'a Value)>> de::MapAccess<'de> for MapAccess<'a, I> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Error> {
+        match self.iter.next() {
+            Some((key, value)) => {
+                self.len -= 1;
+                self.value = Some(value);
+                match key.as_kw().and_then(|kw| rust_name(self.fields, kw, field_keyword)) {
+                    Some(field) => {
+                        let field = IntoDeserializer::<Error>::into_deserializer(field);
+                        seed.deserialize(field).map(Some)
+                    }
+                    None => seed.deserialize(Deserializer::new(key)).map(Some),
+                }
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        match self.value.take() {
+            Some(value) => seed.deserialize(Deserializer::new(value)),
+            None => Err(Error("next_value_seed called before next_key_seed".to_string())),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len)
+    }
+}
+
+// Access to an enum variant represented as a tagged map.
+struct EnumAccess<'a> {
+    variant: &'a str,
+    map: &'a Value,
+}
+
+impl<'de, 'a> de::EnumAccess<'de> for EnumAccess<'a> {
+    type Error = Error;
+    type Variant = VariantAccess<'a>;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, VariantAccess<'a>), Error> {
+        let variant = seed.deserialize(IntoDeserializer::<Error>::into_deserializer(self.variant))?;
+        Ok((variant, VariantAccess { map: self.map }))
+    }
+}
+
+struct VariantAccess<'a> {
+    map: &'a Value,
+}
+
+impl<'a> VariantAccess<'a> {
+    fn fields(&self) -> Result<&'a Value, Error> {
+        match self.map.as_map().and_then(|map| map.0.get(&Value::kw_str("fields"))) {
+            Some(fields) => Ok(fields),
+            None => Err(Error("expected a map with the key :fields".to_string())),
+        }
+    }
+}
+
+impl<'de, 'a> de::VariantAccess<'de> for VariantAccess<'a> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        match self.fields()?.as_arr() {
+            Some(fields) if fields.0.len() == 1 => seed.deserialize(Deserializer::new(&fields.0[0])),
+            _ => Err(Error("expected an array of length 1 under the key :fields".to_string())),
+        }
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_seq(Deserializer::new(self.fields()?), visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        match self.map.as_map() {
+            Some(entries) => visitor.visit_map(MapAccess {
+                iter: entries.0.iter().filter(|(key, _)| !key.is_kw("tag")),
+                len: entries.0.len() - 1,
+                value: None,
+                fields,
+            }),
+            None => unreachable!(),
+        }
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////
+
+impl Serialize for Value {
+    fn serialize<S: ser::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::{SerializeMap as _, SerializeSeq as _, Error as _};
+
+        match self {
+            Value::Atomic(Atomic::Nil) => s.serialize_unit(),
+            Value::Atomic(Atomic::Bool(b)) => s.serialize_bool(*b),
+            Value::Atomic(Atomic::Int(n)) => s.serialize_i64(*n),
+            Value::Atomic(Atomic::Float(n)) => s.serialize_f64(n.clone().into_inner()),
+            Value::Atomic(Atomic::Char(c)) => s.serialize_char(*c),
+            Value::Atomic(Atomic::String(st)) => s.serialize_str(&st.0.to_string()),
+            Value::Atomic(Atomic::Bytes(b)) => {
+                s.serialize_bytes(&b.0.iter().cloned().collect::<Vec<u8>>())
+            }
+            Value::Atomic(Atomic::Keyword(kw)) => s.serialize_str(kw),
+            Value::Arr(vals) | Value::App(vals) => {
+                let mut seq = s.serialize_seq(Some(vals.0.len()))?;
+                for v in vals.0.iter() {
+                    seq.serialize_element(v)?;
+                }
+                seq.end()
+            }
+            Value::Set(vals) => {
+                let mut seq = s.serialize_seq(Some(vals.0.len()))?;
+                for v in vals.0.iter() {
+                    seq.serialize_element(v)?;
+                }
+                seq.end()
+            }
+            Value::Map(entries) => {
+                let mut map = s.serialize_map(Some(entries.0.len()))?;
+                for (key, val) in entries.0.iter() {
+                    map.serialize_entry(key, val)?;
+                }
+                map.end()
+            }
+            Value::Id(..) | Value::Fun(..) | Value::Cell(..) | Value::Opaque(..) => {
+                Err(S::Error::custom(format!("can not serialize {}", describe(self))))
+            }
+        }
+    }
+}
+
+impl<'de> de::Deserialize<'de> for Value {
+    fn deserialize<D: de::Deserializer<'de>>(d: D) -> Result<Value, D::Error> {
+        d.deserialize_any(ValueVisitor)
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a value that can be represented in pavo")
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<Value, E> {
+        Ok(Value::bool_(v))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Value, E> {
+        Ok(Value::int(v))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Value, E> {
+        int(v).map_err(E::custom)
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Value, E> {
+        float(v).map_err(E::custom)
+    }
+
+    fn visit_char<E: de::Error>(self, v: char) -> Result<Value, E> {
+        Ok(Value::char_(v))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Value, E> {
+        Ok(Value::string_from_str(v))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Value, E> {
+        Ok(Value::bytes_from_vec(v.to_vec()))
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Value, E> {
+        Ok(Value::nil())
+    }
+
+    fn visit_some<D: de::Deserializer<'de>>(self, d: D) -> Result<Value, D::Error> {
+        d.deserialize_any(ValueVisitor)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Value, E> {
+        Ok(Value::nil())
+    }
+
+    fn visit_newtype_struct<D: de::Deserializer<'de>>(self, d: D) -> Result<Value, D::Error> {
+        d.deserialize_any(ValueVisitor)
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let mut vals = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(v) = seq.next_element()? {
+            vals.push(v);
+        }
+        Ok(Value::arr_from_vec(vals))
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+        let mut entries = Vec::with_capacity(map.size_hint().unwrap_or(0));
+        while let Some(entry) = map.next_entry()? {
+            entries.push(entry);
+        }
+        Ok(Value::map_from_vec(entries))
+    }
+}
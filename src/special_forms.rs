@@ -1,13 +1,17 @@
+use std::cmp::Ordering;
+
 use im_rc::{Vector as ImVector, OrdMap as ImOrdMap, OrdSet as ImOrdSet};
 use gc::{Gc, GcCell};
 
+use crate::compile::StaticError;
 use crate::gc_foreign::{Vector, OrdMap, OrdSet};
+use crate::read::{SourceMap, Span};
 use crate::value::{Value, Id, Atomic, Fun, Opaque};
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Code {
     Atomic(Atomic),
-    Id(Id),
+    Id(Id, At),
     Arr(Vector<Code>),
     App(Vector<Code>),
     Set(OrdSet<Code>),
@@ -17,12 +21,48 @@ pub enum Code {
     Opaque(u64 /* creation id */, Opaque),
     Quote(Value),
     Do(Vector<Code>),
-    SetBang(Id, Box<Code>),
+    SetBang(Id, At /* the id */, Box<Code>),
     Throw(Box<Code>),
-    Try(Box<Code>, bool, Id, Box<Code>),
-    Lambda(Vector<(bool, Id)>, Box<Code>),
-    Case(Box<Code>, Vector<(Pattern, Code)>),
-    LetFn(OrdMap<Id, (Vector<(bool, Id)>, Code)>, Box<Code>)
+    Try(Box<Code>, bool, Id, Box<Code>, At /* the catch expression */),
+    Lambda(Vector<(bool, Id)>, Box<Code>, FunSource),
+    Case(Box<Code>, Vector<(Pattern, Code, At /* the branch expression */)>),
+    LetFn(OrdMap<Id, (Vector<(bool, Id)>, Code, FunSource)>, Box<Code>)
+}
+
+/// Where some code has been read from, if known.
+///
+/// All spans compare as equal, so that they do not affect the order of code. That order is the
+/// order in which the elements of set and map literals are evaluated.
+#[derive(Debug, Clone, Copy)]
+pub struct At(pub Option<Span>);
+
+impl PartialEq for At {
+    fn eq(&self, _other: &At) -> bool {
+        true
+    }
+}
+
+impl Eq for At {}
+
+impl PartialOrd for At {
+    fn partial_cmp(&self, other: &At) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for At {
+    fn cmp(&self, _other: &At) -> Ordering {
+        Ordering::Equal
+    }
+}
+
+/// The definition of a function: the `sf-lambda` form or the `sf-letfn` definition, and where it
+/// and the body of the function have been read from.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FunSource {
+    pub form: Value,
+    pub span: At,
+    pub body: At,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -47,6 +87,8 @@ pub enum FormType {
     Lambda,
     Case,
     LetFn,
+    Def,
+    DefMacro,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd)]
@@ -66,13 +108,27 @@ pub enum SpecialFormSyntaxError {
 }
 
 pub fn to_code(v: &Value) -> Result<Code, SpecialFormSyntaxError> {
+    match to_code_mapped(v, &SourceMap::default()) {
+        Ok(c) => Ok(c),
+        Err(StaticError::SpecialFormSyntax(err, _)) => Err(err),
+        Err(StaticError::Binding(..)) => unreachable!("to_code does not check bindings"),
+    }
+}
+
+fn syntax(err: SpecialFormSyntaxError, span: Option<Span>) -> StaticError {
+    StaticError::SpecialFormSyntax(err, span)
+}
+
+/// Like `to_code`, with the spans of the code taken from the source map of `v`. Errors come with
+/// the span of the offending value.
+pub fn to_code_mapped(v: &Value, map: &SourceMap) -> Result<Code, StaticError> {
     match v {
         Value::Atomic(a) => Ok(Code::Atomic(a.clone())),
-        Value::Id(id) => Ok(Code::Id(id.clone())),
+        Value::Id(id) => Ok(Code::Id(id.clone(), At(map.span))),
         Value::Arr(arr) => {
             let mut code_arr = ImVector::new();
-            for v_ in arr.0.iter() {
-                code_arr.push_back(to_code(v_)?);
+            for (i, v_) in arr.0.iter().enumerate() {
+                code_arr.push_back(to_code_mapped(v_, map.child(i))?);
             }
             return Ok(Code::Arr(Vector(code_arr)));
         }
@@ -84,14 +140,14 @@ pub fn to_code(v: &Value) -> Result<Code, SpecialFormSyntaxError> {
             match app.0[0].as_user_id() {
                 None => {
                     let mut code_app = ImVector::new();
-                    for v_ in app.0.iter() {
-                        code_app.push_back(to_code(v_)?);
+                    for (i, v_) in app.0.iter().enumerate() {
+                        code_app.push_back(to_code_mapped(v_, map.child(i))?);
                     }
                     return Ok(Code::App(Vector(code_app)));
                 }
                 Some("sf-quote") => {
                     if app.0.len() != 2 {
-                        return Err(SpecialFormSyntaxError::Arity(FormType::Quote, app.0.len()));
+                        return Err(syntax(SpecialFormSyntaxError::Arity(FormType::Quote, app.0.len()), map.span));
                     }
 
                     return Ok(Code::Quote(app.0[1].clone()));
@@ -99,15 +155,15 @@ pub fn to_code(v: &Value) -> Result<Code, SpecialFormSyntaxError> {
 
                 Some("sf-do") => {
                     if app.0.len() != 2 {
-                        return Err(SpecialFormSyntaxError::Arity(FormType::Do, app.0.len()));
+                        return Err(syntax(SpecialFormSyntaxError::Arity(FormType::Do, app.0.len()), map.span));
                     }
 
                     match app.0[1].as_arr() {
-                        None => return Err(SpecialFormSyntaxError::DoNotArray(app.0[1].clone())),
+                        None => return Err(syntax(SpecialFormSyntaxError::DoNotArray(app.0[1].clone()), map.child_span(1))),
                         Some(arr) => {
                             let mut code_arr = ImVector::new();
-                            for v_ in arr.0.iter() {
-                                code_arr.push_back(to_code(v_)?);
+                            for (i, v_) in arr.0.iter().enumerate() {
+                                code_arr.push_back(to_code_mapped(v_, map.child(1).child(i))?);
                             }
                             return Ok(Code::Do(Vector(code_arr)));
                         }
@@ -116,113 +172,147 @@ pub fn to_code(v: &Value) -> Result<Code, SpecialFormSyntaxError> {
 
                 Some("sf-set!") => {
                     if app.0.len() != 3 {
-                        return Err(SpecialFormSyntaxError::Arity(FormType::SetBang, app.0.len()));
+                        return Err(syntax(SpecialFormSyntaxError::Arity(FormType::SetBang, app.0.len()), map.span));
                     }
 
                     let id = match app.0[1].as_id() {
                         Some(id) => id,
-                        None => return Err(SpecialFormSyntaxError::SetBangId(app.0[1].clone())),
+                        None => return Err(syntax(SpecialFormSyntaxError::SetBangId(app.0[1].clone()), map.child_span(1))),
                     };
 
-                    return Ok(Code::SetBang(id.clone(), Box::new(to_code(&app.0[2])?)));
+                    return Ok(Code::SetBang(
+                        id.clone(),
+                        At(map.child_span(1)),
+                        Box::new(to_code_mapped(&app.0[2], map.child(2))?),
+                    ));
                 }
 
                 Some("sf-throw") => {
                     if app.0.len() != 2 {
-                        return Err(SpecialFormSyntaxError::Arity(FormType::Throw, app.0.len()));
+                        return Err(syntax(SpecialFormSyntaxError::Arity(FormType::Throw, app.0.len()), map.span));
                     }
 
-                    return Ok(Code::Throw(Box::new(to_code(&app.0[1])?)));
+                    return Ok(Code::Throw(Box::new(to_code_mapped(&app.0[1], map.child(1))?)));
                 }
 
                 Some("sf-try") => {
                     if app.0.len() != 4 {
-                        return Err(SpecialFormSyntaxError::Arity(FormType::Try, app.0.len()));
+                        return Err(syntax(SpecialFormSyntaxError::Arity(FormType::Try, app.0.len()), map.span));
                     }
 
-                    let (mutable, id) = mut_id(&app.0[2], FormType::Try)?;
-                    return Ok(Code::Try(Box::new(to_code(&app.0[1])?), mutable, id, Box::new(to_code(&app.0[3])?)));
+                    let (mutable, id) = mut_id(&app.0[2], FormType::Try).map_err(|err| syntax(err, map.child_span(2)))?;
+                    return Ok(Code::Try(
+                        Box::new(to_code_mapped(&app.0[1], map.child(1))?),
+                        mutable,
+                        id,
+                        Box::new(to_code_mapped(&app.0[3], map.child(3))?),
+                        At(map.child(3).span),
+                    ));
                 }
 
                 Some("sf-lambda") => {
                     if app.0.len() != 3  {
-                        return Err(SpecialFormSyntaxError::Arity(FormType::Lambda, app.0.len()));
+                        return Err(syntax(SpecialFormSyntaxError::Arity(FormType::Lambda, app.0.len()), map.span));
                     }
 
                     match app.0[1].as_arr() {
-                        None => return Err(SpecialFormSyntaxError::ArgsNotArray(app.0[1].clone())),
+                        None => return Err(syntax(SpecialFormSyntaxError::ArgsNotArray(app.0[1].clone()), map.child_span(1))),
                         Some(args_arr) => {
                             let mut args = ImVector::new();
 
-                            for arg in args_arr.0.iter() {
-                                args.push_back(mut_id(&arg, FormType::Lambda)?);
+                            for (i, arg) in args_arr.0.iter().enumerate() {
+                                args.push_back(
+                                    mut_id(&arg, FormType::Lambda)
+                                        .map_err(|err| syntax(err, map.child(1).child_span(i)))?
+                                );
                             }
 
-                            return Ok(Code::Lambda(Vector(args), Box::new(to_code(&app.0[2])?)));
+                            let source = FunSource {
+                                form: v.clone(),
+                                span: At(map.span),
+                                body: At(map.child(2).span),
+                            };
+                            return Ok(Code::Lambda(Vector(args), Box::new(to_code_mapped(&app.0[2], map.child(2))?), source));
                         }
                     }
                 }
 
                 Some("sf-letfn") => {
                     if app.0.len() != 3  {
-                        return Err(SpecialFormSyntaxError::Arity(FormType::Lambda, app.0.len()));
+                        return Err(syntax(SpecialFormSyntaxError::Arity(FormType::Lambda, app.0.len()), map.span));
                     }
 
                     match app.0[1].as_map() {
-                        None => return Err(SpecialFormSyntaxError::LetFnNotMap(app.0[1].clone())),
-                        Some(map) => {
+                        None => return Err(syntax(SpecialFormSyntaxError::LetFnNotMap(app.0[1].clone()), map.child_span(1))),
+                        Some(defs) => {
+                            let defs_map = map.child(1);
                             let mut code_map = ImOrdMap::new();
-                            for (key, val) in map.0.iter() {
+                            for (i, (key, val)) in defs.0.iter().enumerate() {
+                                let key_span = defs_map.child_span(2 * i);
+                                let val_map = defs_map.child(2 * i + 1);
                                 match key.as_id() {
-                                    None => return Err(SpecialFormSyntaxError::FnName(key.clone())),
+                                    None => return Err(syntax(SpecialFormSyntaxError::FnName(key.clone()), key_span)),
                                     Some(name) => {
                                         match val.as_app() {
                                             Some(fun) if fun.0.len() == 2 => {
                                                 match fun.0[0].as_arr() {
-                                                    None => return Err(SpecialFormSyntaxError::ArgsNotArray(fun.0[1].clone())),
+                                                    None => return Err(syntax(SpecialFormSyntaxError::ArgsNotArray(fun.0[1].clone()), val_map.child_span(0))),
                                                     Some(args_arr) => {
                                                         let mut args = ImVector::new();
 
-                                                        for arg in args_arr.0.iter() {
-                                                            args.push_back(mut_id(&arg, FormType::LetFn)?);
+                                                        for (j, arg) in args_arr.0.iter().enumerate() {
+                                                            args.push_back(
+                                                                mut_id(&arg, FormType::LetFn)
+                                                                    .map_err(|err| syntax(err, val_map.child(0).child_span(j)))?
+                                                            );
                                                         }
 
-                                                        code_map.insert(name.clone(), (Vector(args), to_code(&fun.0[1])?));
+                                                        let source = FunSource {
+                                                            form: val.clone(),
+                                                            span: At(val_map.span),
+                                                            body: At(val_map.child(1).span),
+                                                        };
+                                                        code_map.insert(name.clone(), (Vector(args), to_code_mapped(&fun.0[1], val_map.child(1))?, source));
                                                     }
                                                 }
                                             }
-                                            _ => return Err(SpecialFormSyntaxError::Foo),
+                                            _ => return Err(syntax(SpecialFormSyntaxError::Foo, val_map.span.or(map.span))),
                                         }
                                     }
                                 }
                             }
-                            return Ok(Code::LetFn(OrdMap(code_map), Box::new(to_code(&app.0[2])?)));
+                            return Ok(Code::LetFn(OrdMap(code_map), Box::new(to_code_mapped(&app.0[2], map.child(2))?)));
                         }
                     }
                 }
 
                 Some("sf-case") => {
                     if app.0.len() != 3 {
-                        return Err(SpecialFormSyntaxError::Arity(FormType::Case, app.0.len()));
+                        return Err(syntax(SpecialFormSyntaxError::Arity(FormType::Case, app.0.len()), map.span));
                     }
 
-                    let c = to_code(&app.0[1])?;
+                    let c = to_code_mapped(&app.0[1], map.child(1))?;
 
                     match app.0[2].as_arr() {
-                        None => return Err(SpecialFormSyntaxError::CaseNotArray(app.0[2].clone())),
+                        None => return Err(syntax(SpecialFormSyntaxError::CaseNotArray(app.0[2].clone()), map.child_span(2))),
                         Some(arr) => {
                             if arr.0.len() % 2 != 0 {
-                                return Err(SpecialFormSyntaxError::OddCases(app.0[2].clone()));
+                                return Err(syntax(SpecialFormSyntaxError::OddCases(app.0[2].clone()), map.child_span(2)));
                             }
 
+                            let arms_map = map.child(2);
                             let mut cases = ImVector::new();
                             let mut case = Pattern::Atomic(Atomic::Nil); // never used
 
                             for (i, inner) in arr.0.iter().enumerate() {
                                 if i % 2 == 0 {
-                                    case = pattern(inner)?;
+                                    case = pattern(inner).map_err(|err| syntax(err, arms_map.child_span(i)))?;
                                 } else {
-                                    cases.push_back((case.clone(), to_code(inner)?));
+                                    cases.push_back((
+                                        case.clone(),
+                                        to_code_mapped(inner, arms_map.child(i))?,
+                                        At(arms_map.child(i).span),
+                                    ));
                                 }
                             }
                             return Ok(Code::Case(Box::new(c), Vector(cases)));
@@ -232,8 +322,8 @@ pub fn to_code(v: &Value) -> Result<Code, SpecialFormSyntaxError> {
 
                 _ => {
                     let mut code_app = ImVector::new();
-                    for v_ in app.0.iter() {
-                        code_app.push_back(to_code(v_)?);
+                    for (i, v_) in app.0.iter().enumerate() {
+                        code_app.push_back(to_code_mapped(v_, map.child(i))?);
                     }
                     return Ok(Code::App(Vector(code_app)));
                 }
@@ -242,16 +332,16 @@ pub fn to_code(v: &Value) -> Result<Code, SpecialFormSyntaxError> {
 
         Value::Set(set) => {
             let mut code_set = ImOrdSet::new();
-            for v_ in set.0.iter() {
-                code_set.insert(to_code(v_)?);
+            for (i, v_) in set.0.iter().enumerate() {
+                code_set.insert(to_code_mapped(v_, map.child(i))?);
             }
             return Ok(Code::Set(OrdSet(code_set)));
         }
 
-        Value::Map(map) => {
+        Value::Map(m) => {
             let mut code_map = ImOrdMap::new();
-            for (k_, v_) in map.0.iter() {
-                code_map.insert(to_code(k_)?, to_code(v_)?);
+            for (i, (k_, v_)) in m.0.iter().enumerate() {
+                code_map.insert(to_code_mapped(k_, map.child(2 * i))?, to_code_mapped(v_, map.child(2 * i + 1))?);
             }
             return Ok(Code::Map(OrdMap(code_map)));
         }
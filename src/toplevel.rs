@@ -0,0 +1,68 @@
+//! Evaluation of source files that consist of a sequence of top-level forms.
+//!
+//! Besides regular expressions, two top-level forms are available that bind a name for all
+//! subsequent forms:
+//!
+//! - `(def name exp)` evaluates `exp` and binds the result (immutably) to `name`
+//! - `(defmacro name exp)` evaluates `exp` and makes the result available as the macro `name`
+//!
+//! Both evaluate to the newly bound value.
+
+use std::collections::HashMap;
+
+use im_rc::OrdMap as ImOrdMap;
+
+use crate::compile::StaticError;
+use crate::context::Context;
+use crate::read::{SourceMap, Span};
+use crate::special_forms::{FormType, SpecialFormSyntaxError};
+use crate::value::{Value, Id};
+use crate::{exval_mapped, E};
+
+/// Evaluate a single top-level form, adding any definitions to `env` and `macros`. `map` gives
+/// the spans of the form.
+pub fn exval_form(
+    v: &Value,
+    map: &SourceMap,
+    env: &mut HashMap<Id, (Value, bool)>,
+    macros: &mut ImOrdMap<Id, Value>,
+    cx: &mut Context,
+) -> Result<Value, E> {
+    if let Some(app) = v.as_app() {
+        let ft = match app.0.get(0).and_then(|head| head.as_user_id()) {
+            Some("def") => Some(FormType::Def),
+            Some("defmacro") => Some(FormType::DefMacro),
+            _ => None,
+        };
+
+        if let Some(ft) = ft {
+            if app.0.len() != 3 {
+                return Err(syntax_error(SpecialFormSyntaxError::Arity(ft, app.0.len()), map.span));
+            }
+
+            let name = match app.0[1].as_id() {
+                Some(id) => id.clone(),
+                None => return Err(syntax_error(
+                    SpecialFormSyntaxError::Id(ft, app.0[1].clone()),
+                    map.child_span(1),
+                )),
+            };
+
+            let val = exval_mapped(&app.0[2], map.child(2), env, macros, env, cx)?;
+
+            if ft == FormType::Def {
+                env.insert(name, (val.clone(), false));
+            } else {
+                macros.insert(name, val.clone());
+            }
+
+            return Ok(val);
+        }
+    }
+
+    exval_mapped(v, map, env, macros, env, cx)
+}
+
+fn syntax_error(err: SpecialFormSyntaxError, span: Option<Span>) -> E {
+    E::Static(StaticError::SpecialFormSyntax(err, span))
+}
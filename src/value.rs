@@ -1,8 +1,11 @@
 //! Definition of the objects that the language manipulates at runtime.
 
 use std::{
+    any::Any,
     cmp::Ordering,
+    fmt,
     num::FpCategory,
+    rc::Rc,
 };
 
 use gc::{Gc, GcCell};
@@ -15,8 +18,10 @@ use im_rc::{
 use ropey::Rope as Ropey;
 
 use crate::builtins::{self, type_error, num_args_error, write_spaces};
-use crate::context::Context;
+use crate::context::{Context, TraceEntry};
+use crate::env;
 use crate::gc_foreign::{Vector, OrdSet, OrdMap, NotNan, Rope};
+use crate::profile::ProfileKey;
 use crate::vm::Closure;
 use crate::opaques::{
     vector_cursor::VectorCursor,
@@ -410,6 +415,33 @@ impl Value {
         Value::Fun(Fun::Builtin(b))
     }
 
+    /// Wrap a rust closure as a pavo function taking `arity` arguments. The name is only used
+    /// for debug output.
+    pub fn native<F>(name: &str, arity: usize, f: F, cx: &mut Context) -> Value
+    where
+        F: Fn(Vector<Value>, &mut Context) -> Result<Value, Value> + 'static,
+    {
+        Value::Fun(Fun::Native(
+            NativeFun {
+                name: name.to_string(),
+                arity,
+                fun: Rc::new(f),
+            },
+            cx.next_fun_id(),
+        ))
+    }
+
+    /// Wrap rust data in an opaque value of the given type, pavo code can pass it around but not
+    /// inspect it. Type ids should be obtained from `Context::next_symbol_id`, `typeof` returns
+    /// them as symbols.
+    pub fn host<T: HostData>(type_id: u64, data: T, cx: &mut Context) -> Value {
+        let data: Box<dyn HostData> = Box::new(data);
+        Value::Opaque(cx.next_symbol_id(), Opaque::Host(HostOpaque {
+            type_id,
+            data: Gc::new(data),
+        }))
+    }
+
     pub fn hide(type_id: u64, cx: &mut Context) -> Value {
         Value::Fun(Fun::Opaque {
             hide: true,
@@ -525,6 +557,23 @@ impl Value {
         }
     }
 
+    /// The type id of an opaque value created by the embedding program.
+    pub fn host_type_id(&self) -> Option<u64> {
+        match self {
+            Value::Opaque(_, Opaque::Host(o)) => Some(o.type_id()),
+            _ => None,
+        }
+    }
+
+    /// Access the payload of an opaque value created by the embedding program, if it is of
+    /// type `T`.
+    pub fn as_host<T: HostData>(&self) -> Option<&T> {
+        match self {
+            Value::Opaque(_, Opaque::Host(o)) => o.data.as_any().downcast_ref::<T>(),
+            _ => None,
+        }
+    }
+
     pub fn truthy(&self) -> bool {
         match self {
             Value::Atomic(Atomic::Nil) | Value::Atomic(Atomic::Bool(false)) => false,
@@ -570,17 +619,48 @@ pub enum Fun {
     Closure(Closure, u64),
     Builtin(Builtin),
     Opaque { hide: bool, fun_id: u64, type_id: u64 },
+    Native(NativeFun, u64),
+}
+
+/// The signature of functions that can be provided to pavo code by the embedding rust program.
+pub type NativeFn = dyn Fn(Vector<Value>, &mut Context) -> Result<Value, Value>;
+
+/// A function implemented in rust, see `Value::native`.
+///
+/// The wrapped closure is not traced by the garbage collector, so any values it captures are
+/// kept alive for as long as the function exists.
+#[derive(Clone, Trace, Finalize)]
+pub struct NativeFun {
+    pub name: String,
+    pub arity: usize,
+    #[unsafe_ignore_trace]
+    fun: Rc<NativeFn>,
+}
+
+impl fmt::Debug for NativeFun {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("NativeFun")
+            .field("name", &self.name)
+            .field("arity", &self.arity)
+            .finish()
+    }
+}
+
+impl Fun {
+    // The unique id of all functions except the builtins.
+    fn fun_id(&self) -> Option<u64> {
+        match self {
+            Fun::Builtin(..) => None,
+            Fun::Closure(_, id) | Fun::Native(_, id) | Fun::Opaque { fun_id: id, ..} => Some(*id),
+        }
+    }
 }
 
 impl PartialEq for Fun {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
             (Fun::Builtin(a), Fun::Builtin(b)) => a.eq(b),
-            (Fun::Closure(_, id_a), Fun::Closure(_, id_b)) => id_a.eq(id_b),
-            (Fun::Opaque { fun_id: id_a, ..}, Fun::Opaque { fun_id: id_b, ..}) => id_a.eq(id_b),
-            (Fun::Opaque { fun_id: id_a, ..}, Fun::Closure(_, id_b)) => id_a.eq(id_b),
-            (Fun::Closure(_, id_a), Fun::Opaque { fun_id: id_b, ..}) => id_a.eq(id_b),
-            _ => false,
+            _ => self.fun_id() == other.fun_id(),
         }
     }
 }
@@ -592,12 +672,8 @@ impl Ord for Fun {
         match (self, other) {
             (Fun::Builtin(a), Fun::Builtin(b)) => a.cmp(b),
             (Fun::Builtin(..), _) => Ordering::Less,
-            (Fun::Closure(..), Fun::Builtin(..)) => Ordering::Greater,
-            (Fun::Opaque {..}, Fun::Builtin(..)) => Ordering::Greater,
-            (Fun::Closure(_, id_a), Fun::Closure(_, id_b)) => id_a.cmp(id_b),
-            (Fun::Opaque { fun_id: id_a, ..}, Fun::Opaque { fun_id: id_b, ..}) => id_a.cmp(id_b),
-            (Fun::Opaque { fun_id: id_a, ..}, Fun::Closure(_, id_b)) => id_a.cmp(id_b),
-            (Fun::Closure(_, id_a), Fun::Opaque { fun_id: id_b, ..}) => id_a.cmp(id_b),
+            (_, Fun::Builtin(..)) => Ordering::Greater,
+            _ => self.fun_id().cmp(&other.fun_id()),
         }
     }
 }
@@ -611,7 +687,54 @@ impl PartialOrd for Fun {
 impl Fun {
     pub fn compute(&self, args: Vector<Value>, cx: &mut Context) -> Result<Value, Value> {
         match self {
-            Fun::Closure(c, _) => c.compute(args, cx),
+            // Closures consume fuel per instruction and record their own trace entries.
+            Fun::Closure(c, id) => c.compute_fun(*id, args, cx),
+
+            _ => {
+                cx.profile_enter(self.profile_key(), || self.trace_entry());
+                let result = if cx.consume_fuel() {
+                    self.compute_non_closure(args, cx)
+                } else {
+                    Err(Value::nil())
+                };
+                cx.profile_leave();
+
+                if result.is_err() {
+                    cx.trace_push(self.trace_entry());
+                }
+                result
+            }
+        }
+    }
+
+    // How this function appears in the trace of a value thrown through it.
+    fn trace_entry(&self) -> TraceEntry {
+        match self {
+            Fun::Closure(c, id) => c.trace_entry(Some(*id)),
+            Fun::Builtin(b) => TraceEntry::Builtin(env::builtin_name(*b)),
+            Fun::Native(native, id) => TraceEntry::Native(*id, native.name.clone()),
+            Fun::Opaque { fun_id, .. } => TraceEntry::Opaque(*fun_id),
+        }
+    }
+
+    fn profile_key(&self) -> ProfileKey {
+        match self {
+            Fun::Builtin(b) => ProfileKey::Builtin(*b),
+            _ => ProfileKey::Fun(self.fun_id().unwrap()),
+        }
+    }
+
+    fn compute_non_closure(&self, args: Vector<Value>, cx: &mut Context) -> Result<Value, Value> {
+        match self {
+            Fun::Closure(..) => unreachable!(),
+
+            Fun::Native(native, _) => {
+                if args.0.len() != native.arity {
+                    return Err(num_args_error());
+                }
+
+                (native.fun)(args, cx)
+            }
 
             Fun::Opaque { hide, type_id, ..} => {
                 if args.0.len() != 1 {
@@ -877,6 +1000,7 @@ impl Fun {
             Fun::Builtin(Builtin::Not) => builtins::not(args, cx),
             Fun::Builtin(Builtin::Diverge) => builtins::diverge(args, cx),
             Fun::Builtin(Builtin::Trace) => builtins::trace(args, cx),
+            Fun::Builtin(Builtin::Backtrace) => builtins::backtrace(args, cx),
 
             Fun::Builtin(Builtin::CursorArrNext) => builtins::cursor_arr_next(args, cx),
             Fun::Builtin(Builtin::CursorArrPrev) => builtins::cursor_arr_prev(args, cx),
@@ -921,7 +1045,7 @@ impl Fun {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Trace, Finalize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Trace, Finalize)]
 pub enum Builtin {
     Neq,
     Lt,
@@ -1210,6 +1334,7 @@ pub enum Builtin {
     Symbol,
 
     Trace,
+    Backtrace,
     IsTruthy,
     Typeof,
 
@@ -1220,6 +1345,7 @@ pub enum Builtin {
 pub enum Opaque {
     User(Box<Value>, u64),
     Builtin(BuiltinOpaque),
+    Host(HostOpaque),
 }
 
 impl Opaque {
@@ -1227,10 +1353,61 @@ impl Opaque {
         match self {
             Opaque::User(_, id) => *id,
             Opaque::Builtin(o) => o.type_id(),
+            Opaque::Host(o) => o.type_id(),
         }
     }
 }
 
+/// Rust data that the embedding program can wrap in opaque values, see `Value::host`.
+///
+/// Implemented for all types that can be traced by the garbage collector. Data that needs to be
+/// mutated through the opaque value should use a `GcCell`.
+pub trait HostData: Any + Trace + fmt::Debug {
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<T: Any + Trace + fmt::Debug> HostData for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// The payload of an opaque value created by the embedding program.
+///
+/// Two payloads are considered equal if they have the same type id, opaque values themselves are
+/// distinguished by their creation id.
+#[derive(Debug, Clone, Trace, Finalize)]
+pub struct HostOpaque {
+    type_id: u64,
+    data: Gc<Box<dyn HostData>>,
+}
+
+impl HostOpaque {
+    pub fn type_id(&self) -> u64 {
+        self.type_id
+    }
+}
+
+impl PartialEq for HostOpaque {
+    fn eq(&self, other: &Self) -> bool {
+        self.type_id == other.type_id
+    }
+}
+
+impl Eq for HostOpaque {}
+
+impl Ord for HostOpaque {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.type_id.cmp(&other.type_id)
+    }
+}
+
+impl PartialOrd for HostOpaque {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Trace, Finalize, PartialOrd, Ord)]
 pub enum BuiltinOpaque {
     CursorArr(Gc<GcCell<VectorCursor<Value>>>),
@@ -1420,6 +1597,11 @@ pub fn debug_print(v: &Value, mut indent: usize, indent_inc: usize, out: &mut St
                 Fun::Builtin(b) => {
                     std::fmt::write(out, format_args!("{:?}", b)).unwrap();
                 }
+                Fun::Native(native, id) => {
+                    out.push_str(&id.to_string());
+                    out.push_str(" ");
+                    out.push_str(&native.name);
+                }
             }
             out.push_str(";");
         }
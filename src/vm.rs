@@ -1,48 +1,66 @@
-use std::collections::HashMap;
-
 use gc::{Gc, GcCell};
 use gc_derive::{Trace, Finalize};
 use im_rc::Vector as ImVector;
 
 use crate::builtins::{num_args_error, type_error};
-use crate::context::Context;
-use crate::gc_foreign::{Vector, OrdMap, OrdSet};
+use crate::context::{Context, TraceEntry};
+use crate::gc_foreign::{Vector, OrdSet};
+use crate::profile::ProfileKey;
+use crate::read::Span;
 use crate::value::{Value, Fun, Id, Atomic};
 
 pub type BBId = usize;
-pub type BindingId = usize;
 
+/// A test on a value, see `Instruction::Switch`.
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Trace, Finalize)]
-pub enum CompiledPattern {
+pub enum Test {
+    /// Whether the value is equal to the atomic value.
     Atomic(Atomic),
-    Name(DeBruijn),
-    Arr(Vector<CompiledPattern>),
-    App(Vector<CompiledPattern>),
+    /// Whether the value is equal to the set.
     Set(OrdSet<Value>),
-    Map(OrdMap<Value, CompiledPattern>),
-    Named(DeBruijn, Box<CompiledPattern>),
+    /// Whether the value is an array of the given length.
+    Arr(usize),
+    /// Whether the value is an application of the given length.
+    App(usize),
+    /// Whether the value is a map.
+    Map,
+    /// Whether the value is a map that contains the key.
+    HasKey(Value),
+}
+
+impl Test {
+    fn passes(&self, val: &Value) -> bool {
+        match self {
+            Test::Atomic(a) => val.as_atomic() == Some(a),
+            Test::Set(set) => val.as_set() == Some(set),
+            Test::Arr(len) => val.as_arr().map_or(false, |arr| arr.0.len() == *len),
+            Test::App(len) => val.as_app().map_or(false, |app| app.0.len() == *len),
+            Test::Map => val.as_map().is_some(),
+            Test::HasKey(key) => val.as_map().map_or(false, |map| map.0.contains_key(key)),
+        }
+    }
 }
 
-// Indicates the path from a bound identifier site to its binder.
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Trace, Finalize)]
-pub struct DeBruijn {
-    // How many environments we need to go up to find the binder.
-    pub up: usize,
-    // The numeric id of the binder within its environment.
-    pub id: BindingId,
+/// A test of an `Instruction::Switch`, and the block to jump to if the value passes it.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Trace, Finalize)]
+pub struct Arm {
+    pub test: Test,
+    pub target: BBId,
 }
 
 // Addresses a storage slot where a computation can write `Value`s to (or from where to read them).
+//
+// Bindings that no closure captures live in the registers of the call that binds them. Captured
+// bindings live in boxes instead, which the call and the closures capturing them share.
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Trace, Finalize)]
 pub enum Addr {
     Stack,
-    Environment(DeBruijn),
-}
-
-impl Addr {
-    pub fn env(id: DeBruijn) -> Addr {
-        Addr::Environment(id)
-    }
+    // A register of the current call, the index of its name in `IrChunk::locals`.
+    Local(usize),
+    // A box created by the current call, the index of its name in `IrChunk::boxes`.
+    Boxed(usize),
+    // A box captured by the current closure, the index of its name in `IrChunk::captures`.
+    Captured(usize),
 }
 
 /// A single instruction of the ir.
@@ -59,17 +77,25 @@ pub enum Instruction {
     /// Pop the topmost usize * 2 values and push a map containing them. The stack values
     /// alternate between value and key.
     Map(usize),
-    /// Create a closure value with the given IrChunk, push it to the stack.
-    /// This can't be done via `Instruction::Literal` since the environmen must be set at runtime.
-    FunLiteral(Gc<IrChunk>, usize),
+    /// Create a closure value with the given IrChunk and number of arguments, push it to the
+    /// stack. The closure captures the boxes at the given addresses (each of them `Addr::Boxed`
+    /// or `Addr::Captured`), in the order of `IrChunk::captures` of the chunk.
+    /// This can't be done via `Instruction::Literal` since the boxes must be set at runtime.
+    FunLiteral(Gc<IrChunk>, usize, Vec<Addr>),
     /// Jump to the given basic block. If the bb is `BB_RETURN`, return from the function instead.
     Jump(BBId),
     /// Pop the topmost stack element. Jump to the first basic block if the value was truthy,
     /// jump to the second block otherwise.
     CondJump(BBId, BBId),
-    /// Pop the topmost stack element. Jump to the first basic block if the value matches the
-    /// pattern (bringing bindings into scope), jump to the second block otherwise.
-    Match(CompiledPattern, BBId, BBId),
+    /// Jump to the block of the first arm whose test the value at the Addr passes, or to the
+    /// given block if it passes none of them.
+    Switch(Addr, Vec<Arm>, BBId),
+    /// Write the item at the given index of the array or application at the first Addr to the
+    /// second Addr. Panics if there is no such item (which only happens if compilation is buggy).
+    Item(Addr, usize, Addr),
+    /// Write the entry for the given key of the map at the first Addr to the second Addr. Panics
+    /// if there is no such entry (which only happens if compilation is buggy).
+    Entry(Addr, Value, Addr),
     /// Jump to the current catch handler basic block. If the bb is `BB_RETURN`, the function throws.
     Throw,
     /// Set the catch hander basic block.
@@ -90,10 +116,6 @@ pub enum Instruction {
     Call(usize /*len*/, bool),
     /// Same as `Call`, but performs tco.
     TailCall(usize /*len*/, bool),
-    /// Create a new scope in the environment.
-    PushScope,
-    /// Pop the newest scope in the environment.
-    PopScope,
 }
 use Instruction::*;
 
@@ -101,10 +123,38 @@ use Instruction::*;
 pub const BB_RETURN: BBId = std::usize::MAX;
 
 /// A control flow graph of basic blocks, each consisting of a sequence of statements.
+///
+/// The compiler only ever jumps forward, so each block runs at most once per call. This is what
+/// allows every binding of a call to have a register (or box) of its own.
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Trace, Finalize)]
 pub struct IrChunk {
     // The ir instructions, as a graph of basic blocks.
     pub basic_blocks: Vec<Vec<Instruction>>,
+    // The name of the function if it was defined via `sf-letfn`.
+    pub name: Option<Id>,
+    // The form that defined the function, `None` for top-level code.
+    pub source: Option<Value>,
+    // Where the defining form has been read from, if known.
+    #[unsafe_ignore_trace]
+    pub span: Option<Span>,
+    // The module the chunk has been compiled from, `None` for the entrypoint.
+    #[unsafe_ignore_trace]
+    pub module: Option<String>,
+    // The names of the bindings in the registers of a call, see `Addr::Local`.
+    pub locals: Vec<Id>,
+    // The names of the bindings in the boxes of a call, see `Addr::Boxed`.
+    pub boxes: Vec<Id>,
+    // The names of the bindings the closures capture, see `Addr::Captured`.
+    pub captures: Vec<Id>,
+    // Where each argument is stored when the closure is called.
+    pub params: Vec<Addr>,
+    // For each basic block, the span of the expression whose evaluation it begins (if any and if
+    // known).
+    #[unsafe_ignore_trace]
+    pub block_sources: Vec<Option<Span>>,
+    // The blocks that begin the branches of each `sf-case` in the chunk, in order.
+    #[unsafe_ignore_trace]
+    pub branches: Vec<Vec<BBId>>,
 }
 
 // The local state upon which the instructions to operate. It is local to each invocation of
@@ -118,21 +168,24 @@ struct LocalState {
     pc: (BBId, usize),
     // Temporary storage slots for `Value`s.
     stack: Vec<Value>,
+    // The registers holding the bindings that are not captured, see `Addr::Local`.
+    locals: Vec<Value>,
+    // The boxes holding the bindings that are captured, see `Addr::Boxed`.
+    boxes: Vec<Gc<GcCell<Value>>>,
     // Where to resume execution after something throws. If this is `BB_RETURN`, the function
     // itself throws rather than resuming execution.
     catch_handler: BBId,
-    // The environment in which to resume execution after something throws.
-    catch_env: Gc<GcCell<Environment>>,
 }
 
 impl LocalState {
     // Create and initialize a `LocalState` suitable for executing the given chunk.
-    fn new(_chunk: &IrChunk, catch_env: Gc<GcCell<Environment>>) -> LocalState {
+    fn new(chunk: &IrChunk) -> LocalState {
         LocalState {
             pc: (0, 0),
             stack: vec![],
+            locals: vec![Value::nil(); chunk.locals.len()],
+            boxes: chunk.boxes.iter().map(|_| Gc::new(GcCell::new(Value::nil()))).collect(),
             catch_handler: BB_RETURN,
-            catch_env,
         }
     }
 
@@ -153,92 +206,19 @@ impl LocalState {
     }
 }
 
-#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Trace, Finalize)]
-pub struct Environment {
-    // The bindings local to this environment.
-    bindings: Vec<Value>,
-    // (Mutable) access to the parent binding, which is `None` for the top-level environment.
-    parent: Option<Gc<GcCell<Environment>>>,
-}
-
-impl std::fmt::Debug for Environment {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Env {{ x: {}, y: {:?} }}", self.bindings.len(), self.parent)
-    }
-}
-
-impl Environment {
-    // Look up the value addressed by the given DeBruijnPair. Panics if the address is invalid
-    // (which only happens if compilation is buggy).
-    pub fn get(&self, addr: &DeBruijn) -> Value {
-        if addr.up == 0 {
-            self.bindings[addr.id].clone()
-        } else {
-            let mut addr = addr.clone();
-            addr.up -= 1;
-            self.parent.as_ref().unwrap().borrow().get(&addr)
-        }
-    }
-
-    // Set the value at the given address. Panics if the address is invalid (which only happens if
-    // compilation is buggy).
-    pub fn set(&mut self, addr: &DeBruijn, val: Value) {
-        if addr.up == 0 {
-            if addr.id >= self.bindings.len()  {
-                self.bindings.resize_with(addr.id + 1, Value::nil);
-            }
-            self.bindings[addr.id] = val;
-        } else {
-            let mut addr = addr.clone();
-            addr.up -= 1;
-            self.parent.as_ref().unwrap().borrow_mut().set(&addr, val);
-        }
-    }
-
-    pub fn child(parent: Gc<GcCell<Environment>>) -> Gc<GcCell<Environment>> {
-        let env = Environment::root();
-        env.borrow_mut().parent = Some(parent);
-        env
-    }
-
-    pub fn pop(env: &Gc<GcCell<Environment>>) -> Gc<GcCell<Environment>> {
-        match env.borrow().parent {
-            Some(ref parent) => parent.clone(),
-            None => panic!("Tried to pop the toplevel environment -> buggy compilation"),
-        }
-    }
-
-    pub fn root() -> Gc<GcCell<Environment>> {
-        Gc::new(GcCell::new(Environment {
-            bindings: vec![],
-            parent: None,
-        }))
-    }
-
-    pub fn from_toplevel(toplevel: &HashMap<Id, (Value, bool)>) -> Gc<GcCell<Environment>> {
-        let ret = Environment::root();
-
-        for (id, (val, _)) in toplevel.values().enumerate() {
-            ret.borrow_mut().set(&DeBruijn { up: 0, id}, val.clone());
-        }
-
-        ret
-    }
-}
-
-// An IrChunk together with an environment. This is a runtime value.
+// An IrChunk together with the boxes it captured. This is a runtime value.
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Trace, Finalize)]
 pub struct Closure {
     pub fun: Gc<IrChunk>,
-    pub env: Gc<GcCell<Environment>>,
+    pub captures: Vec<Gc<GcCell<Value>>>,
     pub args: usize,
 }
 
 impl Closure {
-    fn from_chunk(fun: Gc<IrChunk>, env: Gc<GcCell<Environment>>, args: usize) -> Closure {
+    fn from_chunk(fun: Gc<IrChunk>, captures: Vec<Gc<GcCell<Value>>>, args: usize) -> Closure {
         Closure {
             fun,
-            env,
+            captures,
             args,
         }
     }
@@ -250,20 +230,60 @@ impl std::fmt::Debug for Closure {
     }
 }
 
+/// The state of the vm before it executes an instruction, see `Debugger`.
+pub struct Pause<'a> {
+    /// The chunk of the closure that is being executed.
+    pub chunk: &'a IrChunk,
+    /// The function id of the closure, `None` for top-level code.
+    pub fun_id: Option<u64>,
+    /// The basic block and the offset within it of the instruction.
+    pub pc: (BBId, usize),
+    pub instruction: &'a Instruction,
+    /// The temporary values of the closure, the topmost one last.
+    pub stack: &'a [Value],
+    /// The bindings of the call that no closure captures, named by `IrChunk::locals`.
+    pub locals: &'a [Value],
+    /// The bindings of the call that closures capture, named by `IrChunk::boxes`.
+    pub boxes: &'a [Gc<GcCell<Value>>],
+    /// The bindings the closure captured, named by `IrChunk::captures`.
+    pub captures: &'a [Gc<GcCell<Value>>],
+    /// How many calls are currently being executed.
+    pub depth: usize,
+}
+
+/// A hook that is called before the vm executes any instruction, see `Context::set_debugger`.
+pub trait Debugger {
+    fn before_instruction(&mut self, pause: &Pause);
+}
+
 impl Addr {
     // Use an `Addr` to retrieve a value. This can not fail, unless we created erroneous ir code.
-    fn load(&self, local: &mut LocalState, env: &Gc<GcCell<Environment>>) -> Value {
+    fn load(&self, local: &mut LocalState, c: &Closure) -> Value {
         match self {
             Addr::Stack => local.stack.pop().unwrap(),
-            Addr::Environment(de_bruijn) => env.borrow().get(de_bruijn),
+            Addr::Local(i) => local.locals[*i].clone(),
+            Addr::Boxed(i) => local.boxes[*i].borrow().clone(),
+            Addr::Captured(i) => c.captures[*i].borrow().clone(),
         }
     }
 
     // Use an `Addr` to store a value. This can not fail, unless we created erroneous vm code.
-    fn store(&self, val: Value, local: &mut LocalState, env: &Gc<GcCell<Environment>>) {
+    fn store(&self, val: Value, local: &mut LocalState, c: &Closure) {
         match self {
             Addr::Stack => local.stack.push(val),
-            Addr::Environment(de_bruijn) => env.borrow_mut().set(de_bruijn, val),
+            Addr::Local(i) => local.locals[*i] = val,
+            Addr::Boxed(i) => *local.boxes[*i].borrow_mut() = val,
+            Addr::Captured(i) => *c.captures[*i].borrow_mut() = val,
+        }
+    }
+
+    // The box at the `Addr`, for capturing it in a closure. Panics for addresses that are not
+    // boxes (which only happens if compilation is buggy).
+    fn capture(&self, local: &LocalState, c: &Closure) -> Gc<GcCell<Value>> {
+        match self {
+            Addr::Boxed(i) => local.boxes[*i].clone(),
+            Addr::Captured(i) => c.captures[*i].clone(),
+            _ => panic!("Tried to capture an unboxed binding -> buggy compilation"),
         }
     }
 }
@@ -271,28 +291,112 @@ impl Addr {
 impl Closure {
     // To perform the computation, interpret the instructions of the chunk.
     pub fn compute(&self, args: Vector<Value>, cx: &mut Context) -> Result<Value, Value> {
-        do_compute(self.clone(), args, cx)
+        do_compute(self.clone(), None, args, cx)
+    }
+
+    // Like `compute`, but for a closure that is a function value with the given id.
+    pub fn compute_fun(&self, id: u64, args: Vector<Value>, cx: &mut Context) -> Result<Value, Value> {
+        do_compute(self.clone(), Some(id), args, cx)
+    }
+
+    // How this closure appears in the trace of a value thrown through it.
+    pub fn trace_entry(&self, id: Option<u64>) -> TraceEntry {
+        TraceEntry::Closure {
+            id,
+            name: self.fun.name.clone(),
+            source: self.fun.source.clone(),
+            span: self.fun.span,
+            module: self.fun.module.clone(),
+        }
+    }
+}
+
+fn do_compute(c: Closure, id: Option<u64>, args: Vector<Value>, cx: &mut Context) -> Result<Value, Value> {
+    if !cx.enter_native() {
+        return Err(Value::nil());
+    }
+
+    let result = run(c, id, args, cx);
+    cx.leave_native();
+    return result;
+}
+
+// A closure invocation that is suspended while a closure it called is being executed.
+struct Frame {
+    c: Closure,
+    id: Option<u64>,
+    state: LocalState,
+    // Whether to push the return value of the call to the stack.
+    push: bool,
+}
+
+// Prepare the execution of a closure by binding the arguments.
+fn enter(c: &Closure, args: Vector<Value>) -> Result<LocalState, Value> {
+    let mut state = LocalState::new(&c.fun);
+
+    if args.0.len() != c.args {
+        return Err(num_args_error());
+    }
+
+    for (addr, arg) in c.fun.params.iter().zip(args.0.iter()) {
+        addr.store(arg.clone(), &mut state, c);
+    }
+
+    return Ok(state);
+}
+
+// Resume execution at the catch handler after a call threw. If there is no catch handler (or the
+// execution is aborting), the current closure throws as well and the error is returned.
+fn catch(err: Value, state: &mut LocalState, cx: &mut Context) -> Result<(), Value> {
+    if state.catch_handler == BB_RETURN || cx.is_aborting() {
+        return Err(err);
+    } else {
+        cx.trace_caught();
+        state.push(err);
+        state.pc = (state.catch_handler, 0);
+        return Ok(());
     }
 }
 
-fn do_compute(mut c: Closure, mut args: Vector<Value>, cx: &mut Context) -> Result<Value, Value> {
-    let mut state;
+// Calls between closures do not recurse on the rust stack, the calling closure is suspended in a
+// heap-allocated stack of frames instead. This way, the call depth is limited by
+// `Context::max_call_depth` rather than by the size of the rust stack.
+fn run(mut c: Closure, mut id: Option<u64>, args: Vector<Value>, cx: &mut Context) -> Result<Value, Value> {
+    let mut frames: Vec<Frame> = vec![];
+    let mut state = enter(&c, args)?;
+    cx.profile_enter(ProfileKey::closure(id), || c.trace_entry(id));
 
     loop {
-        state = LocalState::new(&c.fun, c.env.clone());
+        // Execute the current closure until it returns or throws.
+        let mut outcome = loop {
+            if !cx.consume_fuel() {
+                break Err(Value::nil());
+            }
 
-        if args.0.len() != c.args {
-            return Err(num_args_error());
-        }
+            if state.pc.1 == 0 && cx.is_covering() {
+                cx.cover_block(&c.fun, state.pc.0);
+            }
 
-        for (i, arg) in args.0.iter().enumerate() {
-            Addr::env(DeBruijn { up: 0, id: i }).store(arg.clone(), &mut state, &c.env);
-        }
+            if cx.is_debugging() {
+                if let Some(instruction) = c.fun.basic_blocks[state.pc.0].get(state.pc.1) {
+                    let depth = cx.call_depth();
+                    cx.debug(&Pause {
+                        chunk: &c.fun,
+                        fun_id: id,
+                        pc: state.pc,
+                        instruction,
+                        stack: &state.stack,
+                        locals: &state.locals,
+                        boxes: &state.boxes,
+                        captures: &c.captures,
+                        depth,
+                    });
+                }
+            }
 
-        loop {
             state.pc.1 += 1;
             match &c.fun.basic_blocks[state.pc.0].get(state.pc.1 - 1) {
-                None => return Ok(state.pop()),
+                None => break Ok(state.pop()),
 
                 Some(Literal(val)) => state.push(val.clone()),
 
@@ -333,18 +437,17 @@ fn do_compute(mut c: Closure, mut args: Vector<Value>, cx: &mut Context) -> Resu
                     state.push(Value::map_from_vec(tmp));
                 }
 
-                Some(FunLiteral(chunk, args)) => state.push(Value::closure(
-                    Closure::from_chunk(
-                        chunk.clone(),
-                        Environment::child(c.env.clone()),
-                        *args
-                    ),
-                    cx
-                )),
+                Some(FunLiteral(chunk, args, captures)) => {
+                    let captures = captures.iter().map(|addr| addr.capture(&state, &c)).collect();
+                    state.push(Value::closure(
+                        Closure::from_chunk(chunk.clone(), captures, *args),
+                        cx
+                    ));
+                }
 
                 Some(Jump(block)) => {
                     if *block == BB_RETURN {
-                        return Ok(state.pop());
+                        break Ok(state.pop());
                     } else {
                         state.pc = (*block, 0);
                     }
@@ -359,37 +462,56 @@ fn do_compute(mut c: Closure, mut args: Vector<Value>, cx: &mut Context) -> Resu
                     }
                 }
 
-                Some(Match(p, yay, nay)) => {
-                    if handle_match(state.pop(), p, &mut state, &c.env) {
-                        state.pc = (*yay, 0);
-                    } else {
-                        state.pc = (*nay, 0);
-                    }
+                Some(Switch(addr, arms, default)) => {
+                    let val = addr.load(&mut state, &c);
+                    let target = arms
+                        .iter()
+                        .find(|arm| arm.test.passes(&val))
+                        .map_or(*default, |arm| arm.target);
+                    state.pc = (target, 0);
+                }
+
+                Some(Item(from, index, to)) => {
+                    let val = from.load(&mut state, &c);
+                    let item = match val.as_arr().or_else(|| val.as_app()) {
+                        Some(items) => items.0[*index].clone(),
+                        None => panic!("Tried to get an item of a non-collection -> buggy compilation"),
+                    };
+                    to.store(item, &mut state, &c);
+                }
+
+                Some(Entry(from, key, to)) => {
+                    let val = from.load(&mut state, &c);
+                    let entry = match val.as_map().and_then(|map| map.0.get(key)) {
+                        Some(entry) => entry.clone(),
+                        None => panic!("Tried to get a missing map entry -> buggy compilation"),
+                    };
+                    to.store(entry, &mut state, &c);
                 }
 
                 Some(Throw) => {
+                    cx.trace_start();
+
                     if state.catch_handler == BB_RETURN {
-                        c.env = state.catch_env.clone();
-                        return Err(state.pop());
+                        break Err(state.pop());
                     } else {
+                        cx.trace_caught();
                         state.pc = (state.catch_handler, 0);
-                        c.env = state.catch_env.clone();
                     }
                 }
 
                 Some(SetCatchHandler(bb)) => {
                     state.catch_handler = *bb;
-                    state.catch_env = c.env.clone();
                 }
 
                 Some(Push(addr)) => {
-                    let val = addr.load(&mut state, &c.env);
+                    let val = addr.load(&mut state, &c);
                     state.push(val);
                 }
 
                 Some(Pop(addr)) => {
                     let val = state.pop();
-                    addr.store(val, &mut state, &c.env);
+                    addr.store(val, &mut state, &c);
                 }
 
                 Some(DoubleTop) => {
@@ -403,146 +525,125 @@ fn do_compute(mut c: Closure, mut args: Vector<Value>, cx: &mut Context) -> Resu
                 }
 
                 Some(Call(num_args, push)) => {
+                    let push = *push;
                     let args = state.args(*num_args);
                     let fun = state.pop();
 
-                    match fun.compute(args, cx) {
+                    let result = match &fun {
+                        Value::Fun(Fun::Closure(new_c, new_id)) => {
+                            if !cx.enter_frame() {
+                                break Err(Value::nil());
+                            }
+
+                            match enter(new_c, args) {
+                                Ok(new_state) => {
+                                    cx.profile_enter(
+                                        ProfileKey::Fun(*new_id),
+                                        || new_c.trace_entry(Some(*new_id)),
+                                    );
+                                    frames.push(Frame {
+                                        c: std::mem::replace(&mut c, new_c.clone()),
+                                        id: std::mem::replace(&mut id, Some(*new_id)),
+                                        state: std::mem::replace(&mut state, new_state),
+                                        push,
+                                    });
+                                    continue;
+                                }
+                                Err(err) => {
+                                    cx.leave_frame();
+                                    Err(err)
+                                }
+                            }
+                        }
+
+                        _ => fun.compute(args, cx),
+                    };
+
+                    match result {
                         Ok(val) => {
-                            if *push {
+                            if push {
                                 state.push(val);
                             }
                         }
                         Err(err) => {
-                            if state.catch_handler == BB_RETURN {
-                                c.env = state.catch_env.clone();
-                                return Err(err);
-                            } else {
-                                c.env = state.catch_env.clone();
-                                state.push(err);
-                                state.pc = (state.catch_handler, 0);
+                            if let Err(err) = catch(err, &mut state, cx) {
+                                break Err(err);
                             }
                         }
                     }
                 }
 
                 Some(TailCall(num_args, push)) => {
+                    let push = *push;
                     let new_args = state.args(*num_args);
                     let fun = state.pop();
 
-                    match &fun {
-                        Value::Fun(Fun::Closure(new_c, _)) => {
-                            c = new_c.clone();
-                            args = new_args;
-                            break;
-                        }
+                    let result = match &fun {
+                        Value::Fun(Fun::Closure(new_c, new_id)) => match enter(new_c, new_args) {
+                            // Replace the current closure rather than suspending it.
+                            Ok(new_state) => {
+                                cx.profile_leave();
+                                c = new_c.clone();
+                                id = Some(*new_id);
+                                cx.profile_enter(ProfileKey::closure(id), || c.trace_entry(id));
+                                state = new_state;
+                                continue;
+                            }
+                            // The call did not happen, so the current closure throws like for any
+                            // other failing call.
+                            Err(err) => Err(err),
+                        },
 
-                        Value::Fun(Fun::Builtin(..)) => {
-                            match fun.compute(new_args, cx) {
-                                Ok(val) => {
-                                    if *push {
-                                        state.push(val);
-                                    }
-                                }
-                                Err(err) => {
-                                    if state.catch_handler == BB_RETURN {
-                                        c.env = state.catch_env.clone();
-                                        return Err(err);
-                                    } else {
-                                        c.env = state.catch_env.clone();
-                                        state.push(err);
-                                        state.pc = (state.catch_handler, 0);
-                                    }
-                                }
+                        Value::Fun(..) => fun.compute(new_args, cx),
+
+                        _ => Err(type_error()),
+                    };
+
+                    match result {
+                        Ok(val) => {
+                            if push {
+                                state.push(val);
                             }
                         }
-
-                        _ => {
-                            let err = type_error();
-                            if state.catch_handler == BB_RETURN {
-                                c.env = state.catch_env.clone();
-                                return Err(err);
-                            } else {
-                                c.env = state.catch_env.clone();
-                                state.push(err);
-                                state.pc = (state.catch_handler, 0);
+                        Err(err) => {
+                            if let Err(err) = catch(err, &mut state, cx) {
+                                break Err(err);
                             }
                         }
                     }
                 }
-
-                Some(PushScope) => {
-                    c.env = Environment::child(c.env.clone());
-                }
-
-                Some(PopScope) => {
-                    c.env = Environment::pop(&c.env)
-                }
             }
-        }
-    }
-
-}
+        };
 
-fn handle_match(
-    val: Value,
-    p: &CompiledPattern,
-    state: &mut LocalState,
-    env: &Gc<GcCell<Environment>>
-) -> bool {
-    match p {
-        CompiledPattern::Name(db) => {
-            Addr::env(db.clone()).store(val, state, env);
-            return true;
-        }
-
-        CompiledPattern::Atomic(a) => match val.as_atomic() {
-            Some(a2) if a2 == a => return true,
-            _ => return false,
-        }
-
-        CompiledPattern::Set(set) => match val.as_set() {
-            Some(set2) if set2 == set => return true,
-            _ => return false,
-        }
-
-        CompiledPattern::Arr(ps) => match val.as_arr() {
-            Some(arr) if arr.0.len() == ps.0.len() => {
-                return ps.0.iter().zip(arr.0.iter()).all(|(p_, v_)|
-                    handle_match(v_.clone(), p_, state, env)
-                );
-            }
-            _ => return false,
-        }
-
-        CompiledPattern::App(ps) => match val.as_app() {
-            Some(app) if app.0.len() == ps.0.len() => {
-                return ps.0.iter().zip(app.0.iter()).all(|(p_, v_)|
-                    handle_match(v_.clone(), p_, state, env)
-                );
+        // The current closure is done, resume its caller (or return if there is none). If the
+        // closure threw and the caller has no catch handler, the caller throws as well.
+        loop {
+            cx.profile_leave();
+            if outcome.is_err() {
+                cx.trace_push(c.trace_entry(id));
             }
-            _ => return false,
-        }
 
-        CompiledPattern::Map(pattern) => match val.as_map() {
-            Some(map) => {
-                for (key, p_) in pattern.0.iter() {
-                    match map.0.get(key) {
-                        None => return false,
-                        Some(val) => {
-                            if !handle_match(val.clone(), p_, state, env) {
-                                return false;
-                            }
-                        }
+            let frame = match frames.pop() {
+                Some(frame) => frame,
+                None => return outcome,
+            };
+            cx.leave_frame();
+            c = frame.c;
+            id = frame.id;
+            state = frame.state;
+
+            match outcome {
+                Ok(val) => {
+                    if frame.push {
+                        state.push(val);
                     }
+                    break;
                 }
-                return true;
+                Err(err) => match catch(err, &mut state, cx) {
+                    Ok(()) => break,
+                    Err(err) => outcome = Err(err),
+                },
             }
-            None => return false,
-        }
-
-        CompiledPattern::Named(db, inner) => {
-            Addr::env(db.clone()).store(val.clone(), state, env);
-            return handle_match(val, inner, state, env);
         }
     }
 }
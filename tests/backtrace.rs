@@ -0,0 +1,53 @@
+//! The functions that uncaught thrown values propagated out of.
+
+use pavo_bootstrap::value::Id;
+use pavo_bootstrap::{ExecuteError, Interpreter, TraceEntry, E};
+
+// The names of the builtins in the trace of the value thrown by evaluating `src`.
+fn builtins(src: &str) -> Vec<String> {
+    match Interpreter::new().eval(src) {
+        Err(ExecuteError::E(E::Eval(_, trace))) => trace
+            .into_iter()
+            .filter_map(|entry| match entry {
+                TraceEntry::Builtin(name) => Some(name),
+                _ => None,
+            })
+            .collect(),
+        other => panic!("expected a thrown value, got {:?}", other),
+    }
+}
+
+#[test]
+fn builtin_names() {
+    // Builtins appear under the names they are bound to, not as their rust variants.
+    assert_eq!(builtins("(int-add 9223372036854775807 1)"), vec!["int-add"]);
+    assert_eq!(builtins("(macro-if 1)"), vec!["macro-if"]);
+    assert_eq!(builtins("(fun-apply int-add [1])"), vec!["int-add", "fun-apply"]);
+}
+
+// The names of the named functions in the trace of the value thrown by evaluating `src`.
+fn closures(src: &str) -> Vec<String> {
+    match Interpreter::new().eval(src) {
+        Err(ExecuteError::E(E::Eval(_, trace))) => trace
+            .into_iter()
+            .filter_map(|entry| match entry {
+                TraceEntry::Closure { name: Some(Id::User(name)), .. } => Some(name),
+                _ => None,
+            })
+            .collect(),
+        other => panic!("expected a thrown value, got {:?}", other),
+    }
+}
+
+#[test]
+fn tail_call_num_args() {
+    // A tail call with the wrong number of arguments throws from the calling function, which has
+    // not been replaced by the callee.
+    assert_eq!(closures("(letfn {f ([a] a) g ([] (f 1 2))} (g))"), vec!["g"]);
+    assert_eq!(
+        Interpreter::new()
+            .eval("(letfn {f ([a] a) g ([] (f 1 2))} (sf-try (g) e e))")
+            .unwrap(),
+        Interpreter::new().read("{:tag :err-num-args}").unwrap()
+    );
+}
@@ -0,0 +1,88 @@
+//! Bindings live in the registers of the call that creates them, only those that closures capture
+//! are boxed. Checks that both kinds behave the same, with and without the optimization passes.
+
+use pavo_bootstrap::value::Value;
+use pavo_bootstrap::Interpreter;
+
+fn eval(src: &str) -> Value {
+    let mut unoptimized = Interpreter::new();
+    unoptimized.set_optimize(false);
+    let expected = unoptimized.eval(src).unwrap();
+
+    assert_eq!(Interpreter::new().eval(src).unwrap(), expected);
+    expected
+}
+
+fn ints(ns: &[i64]) -> Value {
+    Value::arr_from_vec(ns.iter().map(|n| Value::int(*n)).collect())
+}
+
+#[test]
+fn registers_per_call() {
+    // Each recursive call has its own `x`, the inner calls do not overwrite that of the outer ones.
+    assert_eq!(
+        eval("(letfn {f ([n] (if (= n 0)
+                []
+                (let x (int-mul n 10) (arr-insert (f (int-sub n 1)) 0 x))))} (f 3))"),
+        ints(&[30, 20, 10])
+    );
+
+    // Same for mutable bindings that are updated before and after the recursive call.
+    assert_eq!(
+        eval("(letfn {f ([(:mut n)] (if (= n 0)
+                []
+                (sf-do [
+                    (sf-set! n (int-mul n 10))
+                    (let inner (f (int-sub (int-div n 10) 1))
+                        (sf-do [(sf-set! n (int-add n 1)) (arr-insert inner 0 n)]))
+                ])))} (f 3))"),
+        ints(&[31, 21, 11])
+    );
+}
+
+#[test]
+fn captured_bindings() {
+    // Every call boxes its own `x`, so each closure sees the value of the call that created it.
+    assert_eq!(
+        eval("(letfn {
+                adder ([x] (sf-lambda [y] (int-add x y)))
+                run ([acc n] (if (= n 0) acc (run (arr-insert acc 0 ((adder n) 100)) (int-sub n 1))))
+            } (run [] 3))"),
+        ints(&[101, 102, 103])
+    );
+
+    // A binding that is captured next to one that is not.
+    assert_eq!(
+        eval("((sf-lambda [a b] [((sf-lambda [] a)) b]) 1 2)"),
+        ints(&[1, 2])
+    );
+
+    // Closures created in a tail-calling loop keep the values of their own iteration.
+    assert_eq!(
+        eval("(letfn {run ([fs n] (if (= n 0)
+                [((arr-get fs 0)) ((arr-get fs 1)) ((arr-get fs 2))]
+                (run (arr-insert fs 0 (sf-lambda [] n)) (int-sub n 1))))} (run [] 3))"),
+        ints(&[1, 2, 3])
+    );
+}
+
+#[test]
+fn set_captured_binding() {
+    // Both closures share the box of `count`, and so does the function that created it.
+    assert_eq!(
+        eval("((sf-lambda [(:mut count)]
+                (let [inc get] [
+                    (sf-lambda [] (sf-set! count (int-add count 1)))
+                    (sf-lambda [] count)
+                ] (sf-do [(inc) (inc) [(get) count (sf-do [(sf-set! count 10) (get)])]]))) 0)"),
+        ints(&[2, 2, 10])
+    );
+
+    // Setting an uncaptured binding after a closure captured a different one.
+    assert_eq!(
+        eval("((sf-lambda [(:mut a) (:mut b)]
+                (let get (sf-lambda [] a)
+                    (sf-do [(sf-set! b 5) (sf-set! a 7) [(get) a b]]))) 1 2)"),
+        ints(&[7, 7, 5])
+    );
+}
@@ -0,0 +1,122 @@
+//! Requires files with a cache directory, and checks that loading them from the cache gives the
+//! same results as expanding and compiling them.
+
+use std::fs;
+use std::path::PathBuf;
+
+use pavo_bootstrap::value::Value;
+use pavo_bootstrap::{Abort, ExecuteError, Interpreter, E};
+
+// Uses a macro that creates symbols, and creates a symbol when evaluated. Loading the module from
+// the cache must account for the symbols of the expansion.
+const MODULE: &str = "
+[
+    (letfn {foo ([[a, true]] a) bar ([x] (foo [x true]))} (bar 42))
+    (case [1 2 {:a 3}] [x 2 {:a y}] [x y] _ nil)
+    (symbol)
+]
+";
+
+// Creates a directory for a test containing the module, returns the paths of the directory, the
+// cache and the module.
+fn setup(test: &str) -> (PathBuf, PathBuf, PathBuf) {
+    let dir = std::env::temp_dir().join(format!("pavo-bytecode-{}-{}", test, std::process::id()));
+    let cache = dir.join("cache");
+    let module = dir.join("module.pavo");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(&module, MODULE).unwrap();
+    (dir, cache, module)
+}
+
+// The result of requiring the module with the given fuel, and the number of steps taken.
+fn require_fuel(
+    cache: &PathBuf,
+    module: &PathBuf,
+    fuel: Option<u64>,
+) -> (Result<Value, ExecuteError>, u64) {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_cache_dir(Some(cache.clone()));
+    interpreter.set_fuel(fuel);
+    let result = interpreter
+        .eval(&format!("[(symbol) (require {:?} {{}}) (symbol)]", module.display().to_string()));
+    (result, interpreter.fuel_consumed())
+}
+
+fn require(cache: &PathBuf, module: &PathBuf) -> Value {
+    require_fuel(cache, module, None).0.unwrap()
+}
+
+fn cache_file(cache: &PathBuf) -> PathBuf {
+    fs::read_dir(cache).unwrap().next().unwrap().unwrap().path()
+}
+
+fn cache_files(cache: &PathBuf) -> usize {
+    fs::read_dir(cache).map(|entries| entries.count()).unwrap_or(0)
+}
+
+#[test]
+fn cached_results_match() {
+    let (dir, cache, module) = setup("results");
+
+    let fresh = require(&cache, &module);
+    assert_eq!(cache_files(&cache), 1);
+    let cached = require(&cache, &module);
+    assert_eq!(fresh, cached);
+
+    // Changing the file invalidates the entry.
+    fs::write(&module, "[(symbol) 17]").unwrap();
+    let changed = require(&cache, &module);
+    assert_eq!(cache_files(&cache), 2);
+    assert_ne!(fresh, changed);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn corrupt_entries() {
+    let (dir, cache, module) = setup("corrupt");
+    let fresh = require(&cache, &module);
+    let file = cache_file(&cache);
+    let entry = fs::read(&file).unwrap();
+
+    // Corrupt entries are ignored and replaced, rather than running corrupt code.
+    let mut corruptions = vec![vec![], entry[..entry.len() / 2].to_vec()];
+    for i in [0, entry.len() / 3, entry.len() / 2, entry.len() - 20, entry.len() - 1].iter() {
+        let mut corrupt = entry.clone();
+        corrupt[*i] ^= 0x55;
+        corruptions.push(corrupt);
+    }
+    for corrupt in corruptions {
+        fs::write(&file, &corrupt).unwrap();
+        assert_eq!(require(&cache, &module), fresh);
+        assert_eq!(fs::read(&file).unwrap(), entry);
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn cached_fuel() {
+    let (dir, cache, module) = setup("fuel");
+
+    // Loading from the cache takes as many steps as expanding.
+    let (fresh, steps) = require_fuel(&cache, &module, None);
+    let (cached, cached_steps) = require_fuel(&cache, &module, None);
+    assert_eq!(fresh.unwrap(), cached.unwrap());
+    assert_eq!(steps, cached_steps);
+
+    let (exact, _) = require_fuel(&cache, &module, Some(steps));
+    assert_eq!(exact.unwrap(), require(&cache, &module));
+
+    // Running out of fuel happens at the same point with and without the cache.
+    for fuel in [steps - 1, steps / 2, 1].iter() {
+        let (result, consumed) = require_fuel(&cache, &module, Some(*fuel));
+        match result {
+            Err(ExecuteError::E(E::Abort(Abort::OutOfFuel))) => {}
+            other => panic!("expected to run out of fuel, got {:?}", other),
+        }
+        assert_eq!(consumed, *fuel);
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,98 @@
+//! Deep recursion aborts with `Abort::CallDepth` instead of overflowing the stack.
+//!
+//! The tests run on the 2MiB stacks of the test harness threads, so they also check that the
+//! default limits fit into such a stack.
+
+use pavo_bootstrap::value::Value;
+use pavo_bootstrap::{Abort, ExecuteError, Interpreter, E};
+
+// A function that recurses `n` times without tail calls.
+fn direct(n: i64) -> String {
+    format!("(letfn {{f ([n] (if (= n 0) 0 (int-add 1 (f (int-sub n 1)))))}} (f {}))", n)
+}
+
+// Like `direct`, but through `fun-apply`, which recurses on the rust stack.
+fn native(n: i64) -> String {
+    format!(
+        "(letfn {{f ([n] (if (= n 0) 0 (int-add 1 (fun-apply f [(int-sub n 1)]))))}} (f {}))",
+        n
+    )
+}
+
+fn call_depth(result: Result<Value, ExecuteError>) -> bool {
+    match result {
+        Err(ExecuteError::E(E::Abort(Abort::CallDepth))) => true,
+        _ => false,
+    }
+}
+
+#[test]
+fn unbounded_recursion() {
+    let mut interpreter = Interpreter::new();
+    assert!(call_depth(interpreter.eval("(letfn {f ([] (int-add 1 (f)))} (f))")));
+    // The interpreter remains usable afterwards.
+    assert_eq!(interpreter.eval("(int-add 1 2)").unwrap(), Value::int(3));
+}
+
+#[test]
+fn unbounded_native_recursion() {
+    let mut interpreter = Interpreter::new();
+    assert!(call_depth(interpreter.eval("(letfn {f ([] (int-add 1 (fun-apply f [])))} (f))")));
+    assert!(call_depth(interpreter.eval(
+        "(sf-try (letfn {f ([] (int-add 1 (fun-apply f [])))} (f)) err :caught)"
+    )));
+}
+
+#[test]
+fn unbounded_expansion() {
+    let mut interpreter = Interpreter::new();
+    interpreter.eval("(defmacro m (sf-lambda [x] `[(m ~x)]))").unwrap();
+    assert!(call_depth(interpreter.eval("(m 1)")));
+}
+
+#[test]
+fn deeply_nested_code() {
+    // Only macro applications count against the native depth while expanding, not every nested
+    // value, so code nested deeper than the default limit still expands and runs.
+    let mut interpreter = Interpreter::new();
+
+    let lets: String = (0..100).map(|i| format!("(let x{} {} ", i, i)).collect();
+    let lets = format!("{}x99{}", lets, ")".repeat(100));
+    assert_eq!(interpreter.eval(&lets).unwrap(), Value::int(99));
+
+    let cond = format!("(cond [{}42])", "false 0 ".repeat(50));
+    assert_eq!(interpreter.eval(&cond).unwrap(), Value::int(42));
+
+    let threaded = format!("(-> 0 [{}])", "(int-add 1) ".repeat(50));
+    assert_eq!(interpreter.eval(&threaded).unwrap(), Value::int(50));
+
+    let literal = format!("{}{}", "[".repeat(300), "]".repeat(300));
+    let expected = interpreter.read(&literal).unwrap();
+    assert_eq!(interpreter.eval(&format!("(sf-quote {})", literal)).unwrap(), expected);
+    assert_eq!(interpreter.eval(&literal).unwrap(), expected);
+}
+
+#[test]
+fn max_call_depth() {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_max_call_depth(50);
+    assert_eq!(interpreter.eval(&direct(40)).unwrap(), Value::int(40));
+    assert!(call_depth(interpreter.eval(&direct(60))));
+
+    // Deep recursion that does not recurse on the rust stack is only limited by the call depth.
+    let mut interpreter = Interpreter::new();
+    interpreter.set_max_native_depth(30);
+    assert_eq!(interpreter.eval(&direct(10_000)).unwrap(), Value::int(10_000));
+}
+
+#[test]
+fn max_native_depth() {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_max_native_depth(50);
+    assert_eq!(interpreter.eval(&native(40)).unwrap(), Value::int(40));
+    assert!(call_depth(interpreter.eval(&native(60))));
+
+    // The default suffices for moderately deep recursion through builtins.
+    let mut interpreter = Interpreter::new();
+    assert_eq!(interpreter.eval(&native(100)).unwrap(), Value::int(100));
+}
@@ -0,0 +1,134 @@
+//! `sf-case` compiles into a decision tree that examines each part of the value at most once.
+//! Checks that the tree takes the same branch and binds the same values as trying the patterns in
+//! sequence would, with and without the optimization passes.
+
+use pavo_bootstrap::value::Value;
+use pavo_bootstrap::{ExecuteError, Interpreter, E};
+
+// The value of `src`, or the value it throws.
+fn run(src: &str) -> Result<Value, Value> {
+    let run_with = |optimize: bool| {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_optimize(optimize);
+        match interpreter.eval(src) {
+            Ok(v) => Ok(v),
+            Err(ExecuteError::E(E::Eval(thrown, _))) => Err(thrown),
+            Err(other) => panic!("{}: unexpected error {:?}", src, other),
+        }
+    };
+
+    let expected = run_with(false);
+    assert_eq!(run_with(true), expected, "{}", src);
+    expected
+}
+
+fn eval(src: &str) -> Value {
+    run(src).unwrap()
+}
+
+fn read(src: &str) -> Value {
+    Interpreter::new().read(src).unwrap()
+}
+
+fn type_error() -> Value {
+    read("{:tag :err-type}")
+}
+
+#[test]
+fn first_match() {
+    assert_eq!(eval("(sf-case [1 2] [[a 2] :first, [1 b] :second, _ :third])"), read(":first"));
+    assert_eq!(eval("(sf-case [1 3] [[a 2] :first, [1 b] :second, _ :third])"), read(":second"));
+    assert_eq!(eval("(sf-case [2 3] [[a 2] :first, [1 b] :second, _ :third])"), read(":third"));
+
+    // Earlier, more general patterns win over later, more specific ones.
+    assert_eq!(eval("(sf-case 42 [_ :wild, 42 :exact])"), read(":wild"));
+    assert_eq!(eval("(sf-case [1 2] [[a b] :any, [1 2] :exact])"), read(":any"));
+    assert_eq!(eval("(sf-case 1 [[x] :arr, 1 :one, _ :other, 1 :later])"), read(":one"));
+
+    // Patterns of different kinds, interleaved.
+    let src = "(sf-case ~ [[1] :arr-one, 1 :one, [x] :arr, {:a 1} :map, 2 :two, _ :other])";
+    for (v, branch) in [
+        ("[1]", ":arr-one"),
+        ("1", ":one"),
+        ("[2]", ":arr"),
+        ("{:a 1 :b 2}", ":map"),
+        ("2", ":two"),
+        ("{:a 2}", ":other"),
+    ].iter() {
+        assert_eq!(eval(&src.replace("~", v)), read(branch), "{}", v);
+    }
+}
+
+#[test]
+fn binding_order() {
+    // Bindings happen in the order in which they appear in the pattern, the last one wins.
+    assert_eq!(eval("(sf-case [1 2 [3]] [[a 2 [a]] a])"), read("3"));
+    assert_eq!(eval("(sf-case {:a 1 :b 2} [{:a x :b x} x])"), read("2"));
+
+    // A named pattern binds the whole value before the bindings of its subpattern.
+    assert_eq!(eval("(sf-case [42] [(:named a [a]) a])"), read("42"));
+    assert_eq!(eval("(sf-case [42] [(:named a [b]) [a b]])"), read("[[42] 42]"));
+    assert_eq!(eval("(sf-case [[42]] [[(:named a [(:named a b)])] [a b]])"), read("[42 42]"));
+    assert_eq!(
+        eval("(sf-case [42] [(:named (:mut a) [b]) (sf-do [(sf-set! a (int-add b 1)) a])])"),
+        read("43")
+    );
+
+    // The bindings of a branch that does not match do not leak into the one that does.
+    assert_eq!(eval("(sf-case [1 2] [[a 3] a, [b c] [b c]])"), read("[1 2]"));
+    assert_eq!(eval("(sf-case [1 2] [[a 3] a, (:named a [x y]) a])"), read("[1 2]"));
+}
+
+#[test]
+fn map_key_presence() {
+    // An entry whose value is nil is present, a missing entry is not.
+    let src = "(sf-case ~ [{:a x} [:present x], {} :absent])";
+    assert_eq!(eval(&src.replace("~", "{:a nil}")), read("[:present nil]"));
+    assert_eq!(eval(&src.replace("~", "{:b nil}")), read(":absent"));
+    assert_eq!(eval("(sf-case {} [{:a nil} 0, {} 1])"), read("1"));
+    assert_eq!(eval("(sf-case {:a nil} [{:a nil} 0, {} 1])"), read("0"));
+
+    // Each key of a pattern is tested, in any combination.
+    let src = "(sf-case ~ [{:a 1 :b 2} :both, {:b y} [:b y], {:a x} [:a x], _ :neither])";
+    for (v, branch) in [
+        ("{:a 1 :b 2}", ":both"),
+        ("{:a 1 :b 3}", "[:b 3]"),
+        ("{:a 2 :b 2}", "[:b 2]"),
+        ("{:a 1}", "[:a 1]"),
+        ("{:c 1}", ":neither"),
+        ("[:a 1]", ":neither"),
+    ].iter() {
+        assert_eq!(eval(&src.replace("~", v)), read(branch), "{}", v);
+    }
+}
+
+#[test]
+fn arr_and_app() {
+    // Arrays and applications of the same length are told apart.
+    let src = "(sf-case ~ [[a b] [:arr a b], (:app a b) [:app a b], _ :other])";
+    assert_eq!(eval(&src.replace("~", "[1 2]")), read("[:arr 1 2]"));
+    assert_eq!(eval(&src.replace("~", "$(1 2)")), read("[:app 1 2]"));
+    assert_eq!(eval(&src.replace("~", "[1]")), read(":other"));
+    assert_eq!(eval(&src.replace("~", "$(1 2 3)")), read(":other"));
+
+    let src = "(sf-case ~ [(:app 1 x) [:app x], [1 x] [:arr x], [] :empty-arr, (:app) :empty-app])";
+    assert_eq!(eval(&src.replace("~", "$(1 2)")), read("[:app 2]"));
+    assert_eq!(eval(&src.replace("~", "[1 2]")), read("[:arr 2]"));
+    assert_eq!(eval(&src.replace("~", "[]")), read(":empty-arr"));
+    assert_eq!(eval(&src.replace("~", "$()")), read(":empty-app"));
+}
+
+#[test]
+fn err_type_fallthrough() {
+    assert_eq!(run("(sf-case 42 [])"), Err(type_error()));
+    assert_eq!(run("(sf-case 42 [41 0, [42] 1, {42 x} 2])"), Err(type_error()));
+
+    // Failing deep inside of a pattern falls through to the later branches.
+    assert_eq!(eval("(sf-case [1 [2 3]] [[1 [2 4]] 0, [1 x] x, _ 2])"), read("[2 3]"));
+    assert_eq!(eval("(sf-case [1 [2 3]] [[1 [2 4]] 0, [2 x] 1, _ 2])"), read("2"));
+    assert_eq!(run("(sf-case [1 [2 3]] [[1 [2 4]] 0, [2 x] 1])"), Err(type_error()));
+    assert_eq!(run("(sf-case {:a [1]} [{:a [2]} 0, {:a []} 1, {:b x} 2])"), Err(type_error()));
+
+    // The error can be caught like any other thrown value.
+    assert_eq!(eval("(sf-try (sf-case [1] [[2] 0]) err err)"), type_error());
+}
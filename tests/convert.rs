@@ -0,0 +1,168 @@
+//! Conversion between rust data and pavo values, by hand and through the derive macros.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Debug;
+
+use pavo_bootstrap::value::Value;
+use pavo_bootstrap::{FromValue, FromValueError, IntoValue, Interpreter};
+
+fn read(src: &str) -> Value {
+    Interpreter::new().read(src).unwrap()
+}
+
+// Checks that `t` is represented as the pavo value `src` and can be converted back.
+fn round_trip<T: IntoValue + FromValue + Clone + PartialEq + Debug>(t: T, src: &str) {
+    assert_eq!(t.clone().into_value(), read(src), "converting {:?}", t);
+    assert_eq!(T::from_value(&read(src)), Ok(t));
+}
+
+fn error<T: FromValue + Debug>(src: &str) -> FromValueError {
+    match T::from_value(&read(src)) {
+        Err(err) => err,
+        Ok(t) => panic!("expected a conversion error, got {:?}", t),
+    }
+}
+
+#[test]
+fn primitives() {
+    round_trip((), "nil");
+    round_trip(true, "true");
+    round_trip(-3i8, "-3");
+    round_trip(70000u32, "70000");
+    round_trip(-5i64, "-5");
+    round_trip(1.5f64, "1.5");
+    round_trip('x', "'x'");
+    round_trip("abc".to_string(), "\"abc\"");
+    assert_eq!(u64::from_value(&read("42")), Ok(42));
+    assert_eq!(usize::from_value(&read("42")), Ok(42));
+}
+
+#[test]
+fn collections() {
+    round_trip(vec![1i64, 2, 3], "[1 2 3]");
+    round_trip(Some(vec![true]), "[true]");
+    round_trip(None::<i64>, "nil");
+
+    let set: BTreeSet<i64> = vec![1, 2].into_iter().collect();
+    round_trip(set, "@{1 2}");
+
+    let map: BTreeMap<String, i64> = vec![("a".to_string(), 1)].into_iter().collect();
+    round_trip(map, "{\"a\" 1}");
+}
+
+#[test]
+fn tuples() {
+    round_trip((1i64,), "[1]");
+    round_trip((1i64, 'a', "b".to_string()), "[1 'a' \"b\"]");
+    round_trip(
+        (1i64, 2i64, 3i64, 4i64, 5i64, 6i64, 7i64, 8i64, 9i64, 10i64, 11i64, 12i64),
+        "[1 2 3 4 5 6 7 8 9 10 11 12]",
+    );
+}
+
+#[derive(IntoValue, FromValue, Clone, PartialEq, Debug)]
+struct Point {
+    x: i64,
+    y_pos: i64,
+    label: Option<String>,
+}
+
+#[derive(IntoValue, FromValue, Clone, PartialEq, Debug)]
+struct Meters(i64);
+
+#[derive(IntoValue, FromValue, Clone, PartialEq, Debug)]
+struct Pair(i64, bool);
+
+#[derive(IntoValue, FromValue, Clone, PartialEq, Debug)]
+struct Seven(u8, u8, u8, u8, u8, u8, u8);
+
+#[derive(IntoValue, FromValue, Clone, PartialEq, Debug)]
+struct Empty();
+
+#[derive(IntoValue, FromValue, Clone, PartialEq, Debug)]
+struct Unit;
+
+#[derive(IntoValue, FromValue, Clone, PartialEq, Debug)]
+enum Shape<T> {
+    NumArgs,
+    Circle { radius: T, center_point: Point },
+    Line(T, T),
+    Nothing(),
+    Many(u8, u8, u8, u8, u8, u8, u8),
+}
+
+#[test]
+fn derive_structs() {
+    round_trip(
+        Point { x: 1, y_pos: 2, label: Some("p".to_string()) },
+        "{:x 1 :y-pos 2 :label \"p\"}",
+    );
+    round_trip(Meters(3), "3");
+    round_trip(Pair(3, true), "[3 true]");
+    round_trip(Seven(1, 2, 3, 4, 5, 6, 7), "[1 2 3 4 5 6 7]");
+    round_trip(Empty(), "[]");
+    round_trip(Unit, "nil");
+
+    // Missing entries are treated as nil.
+    assert_eq!(
+        Point::from_value(&read("{:x 1 :y-pos 2}")),
+        Ok(Point { x: 1, y_pos: 2, label: None })
+    );
+}
+
+#[test]
+fn derive_enums() {
+    round_trip(Shape::NumArgs::<i64>, "{:tag :num-args}");
+    round_trip(
+        Shape::Circle { radius: 2i64, center_point: Point { x: 0, y_pos: 1, label: None } },
+        "{:tag :circle :radius 2 :center-point {:x 0 :y-pos 1 :label nil}}",
+    );
+    round_trip(Shape::Line(1i64, 2), "{:tag :line :fields [1 2]}");
+    round_trip(Shape::Nothing::<i64>(), "{:tag :nothing :fields []}");
+    round_trip(Shape::Many::<i64>(1, 2, 3, 4, 5, 6, 7), "{:tag :many :fields [1 2 3 4 5 6 7]}");
+}
+
+#[test]
+fn errors() {
+    let err = error::<Vec<u8>>("[1 256]");
+    assert_eq!(err.expected, "an int between 0 and 255");
+    assert_eq!(err.path, vec!["1".to_string()]);
+    assert_eq!(err.to_string(), "expected an int between 0 and 255 at 1, found 256");
+
+    assert_eq!(error::<(i64, i64)>("[1]").to_string(), "expected an array of length 2, found [1]");
+    assert_eq!(error::<Empty>("[1]").to_string(), "expected an array of length 0, found [1]");
+
+    assert_eq!(
+        error::<Point>("{:x 1 :y-pos :no}").to_string(),
+        "expected an int at :y-pos, found :no"
+    );
+    assert_eq!(
+        error::<Point>("{:x 1}").to_string(),
+        "expected a map with the key :y-pos, found {:x 1}"
+    );
+    assert_eq!(error::<Point>("[]").to_string(), "expected a map, found []");
+}
+
+#[test]
+fn enum_errors() {
+    assert_eq!(
+        error::<Shape<i64>>("{:tag :square}").to_string(),
+        "expected a map with the :tag :num-args, :circle, :line, :nothing, :many, found {:tag :square}"
+    );
+    assert_eq!(
+        error::<Shape<i64>>("{:tag 42}").to_string(),
+        "expected a keyword at :tag, found 42"
+    );
+    assert_eq!(
+        error::<Shape<i64>>("{:tag :line :fields [1]}").to_string(),
+        "expected an array of length 2 at :fields, found [1]"
+    );
+    assert_eq!(
+        error::<Shape<i64>>("{:tag :line :fields [1 :x]}").to_string(),
+        "expected an int at :fields 1, found :x"
+    );
+    assert_eq!(
+        error::<Shape<i64>>("{:tag :circle :radius 1 :center-point {:x 1}}").to_string(),
+        "expected a map with the key :y-pos at :center-point, found {:x 1}"
+    );
+}
@@ -0,0 +1,98 @@
+//! The lcov tracefiles of recorded coverage.
+
+use std::io::BufReader;
+
+use pavo_bootstrap::{Coverage, FormReader, Interpreter};
+
+// Two functions with identical bodies, the first one is called twice, the second one once.
+const SRC: &str = "(def f (sf-lambda [x]
+  (if x
+    :yes
+    :no)))
+(def g (sf-lambda [x]
+  (if x
+    :yes
+    :no)))
+(f true)
+(f true)
+(g false)
+";
+
+fn lcov(src: &str) -> Vec<String> {
+    lcov_optimized(src, true)
+}
+
+fn lcov_optimized(src: &str, optimize: bool) -> Vec<String> {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_optimize(optimize);
+    interpreter.set_coverage(Some(Coverage::new()));
+    interpreter.eval(src).unwrap();
+
+    let mut coverage = interpreter.take_coverage().unwrap();
+    for located in FormReader::new(BufReader::new(src.as_bytes())) {
+        coverage.add_entrypoint_source("main.pavo", located.unwrap());
+    }
+
+    let mut out = vec![];
+    coverage.write_lcov(&mut out).unwrap();
+    String::from_utf8(out).unwrap().lines().map(str::to_string).collect()
+}
+
+fn assert_contains(lcov: &[String], line: &str) {
+    assert!(lcov.iter().any(|l| l == line), "missing {:?} in\n{}", line, lcov.join("\n"));
+}
+
+#[test]
+fn repeated_expressions() {
+    let lcov = lcov(SRC);
+    assert_eq!(lcov[1], "SF:main.pavo");
+
+    // Each function is reported at its own position.
+    assert_contains(&lcov, "FNDA:2,fn@1:8");
+    assert_contains(&lcov, "FNDA:1,fn@5:8");
+
+    // The equal branches of the two functions are counted separately.
+    assert_contains(&lcov, "BRDA:3,0,0,2");
+    assert_contains(&lcov, "BRDA:4,0,1,0");
+    assert_contains(&lcov, "BRDA:7,1,0,0");
+    assert_contains(&lcov, "BRDA:8,1,1,1");
+
+    assert_contains(&lcov, "DA:3,2");
+    assert_contains(&lcov, "DA:4,0");
+    assert_contains(&lcov, "DA:7,0");
+    assert_contains(&lcov, "DA:8,1");
+    assert_contains(&lcov, "DA:9,1");
+    assert_contains(&lcov, "DA:10,1");
+    assert_contains(&lcov, "DA:11,1");
+}
+
+#[test]
+fn repeated_toplevel_forms() {
+    let lcov = lcov("(def a [1 2])\n(def a [1 2])\n");
+    assert_contains(&lcov, "DA:1,1");
+    assert_contains(&lcov, "DA:2,1");
+    assert_contains(&lcov, "LF:2");
+    assert_contains(&lcov, "LH:2");
+}
+
+#[test]
+fn dead_branches() {
+    // The second and third branches can never be taken, optimization must not hide them.
+    let src = "(def f (sf-lambda [x]
+  (sf-case x [
+    _ :any
+    42 :never
+    43 :never])))
+(f 1)
+";
+    let branches = |lcov: Vec<String>| -> Vec<String> {
+        lcov.into_iter().filter(|line| line.starts_with("BR")).collect()
+    };
+
+    let optimized = branches(lcov_optimized(src, true));
+    assert_eq!(optimized, branches(lcov_optimized(src, false)));
+    assert_eq!(
+        optimized,
+        vec!["BRDA:3,0,0,1", "BRDA:4,0,1,0", "BRDA:5,0,2,0", "BRF:3", "BRH:1"]
+    );
+}
@@ -0,0 +1,121 @@
+//! Hooks into the vm through the `Debugger` trait, and the step debugger built on it.
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use pavo_bootstrap::value::Value;
+use pavo_bootstrap::{Debugger, Interpreter, Pause, StepDebugger};
+
+// `f` tail-calls itself with 2, 1 and 0. The branches of the `if` begin on lines 3 and 4.
+const SRC: &str = "(sf-letfn {
+  f ([x] (if (= x 0)
+    :zero
+    (f (int-sub x 1))))
+} (f 2))
+";
+
+// Collects the output of a debugger, shared with the test that reads it.
+#[derive(Clone, Default)]
+struct Output(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Run `SRC` in the step debugger with the given commands, returning what it printed.
+fn debug(commands: &[&str]) -> String {
+    let out = Output::default();
+    let mut interpreter = Interpreter::new();
+    interpreter.set_debugger(Some(Box::new(StepDebugger::scripted("main.pavo", commands, out.clone()))));
+    assert_eq!(interpreter.eval(SRC).unwrap(), Value::kw_str("zero"));
+
+    let printed = out.0.borrow().clone();
+    String::from_utf8(printed).unwrap()
+}
+
+// The positions printed whenever the debugger pauses.
+fn pauses(printed: &str) -> Vec<&str> {
+    printed.lines().filter(|line| line.contains(", depth ")).collect()
+}
+
+// The values of `x` printed by the `env` command.
+fn xs(printed: &str) -> Vec<&str> {
+    printed.lines().filter(|line| line.starts_with("  x = ")).collect()
+}
+
+#[test]
+fn function_breakpoint() {
+    let printed = debug(&["b f", "c", "env", "c", "env", "c", "env", "c"]);
+
+    // Once before the first instruction, then whenever `f` is entered.
+    let pauses = pauses(&printed);
+    assert_eq!(pauses.len(), 4, "{}", printed);
+    assert!(pauses[0].starts_with("top-level code"), "{}", printed);
+    for pause in pauses[1..].iter() {
+        assert!(pause.starts_with("function ") && pause.contains(" f (main.pavo:2:"), "{}", printed);
+    }
+
+    assert_eq!(xs(&printed), vec!["  x = 2", "  x = 1", "  x = 0"]);
+}
+
+#[test]
+fn line_breakpoint() {
+    let printed = debug(&["b 4", "c", "env", "c", "env", "c"]);
+    assert_eq!(pauses(&printed).len(), 3, "{}", printed);
+    assert_eq!(xs(&printed), vec!["  x = 2", "  x = 1"]);
+
+    let printed = debug(&["b 3", "c", "env", "c"]);
+    assert_eq!(pauses(&printed).len(), 2, "{}", printed);
+    assert_eq!(xs(&printed), vec!["  x = 0"]);
+
+    // Listing and deleting breakpoints.
+    let printed = debug(&["b 3", "b f", "d 1", "b", "c", "env", "c"]);
+    assert!(printed.contains("  0: line 3\n"), "{}", printed);
+    assert!(!printed.contains("function f"), "{}", printed);
+    assert_eq!(xs(&printed), vec!["  x = 0"]);
+}
+
+#[test]
+fn script_runs_out() {
+    // Without further commands, the program runs to completion.
+    let printed = debug(&["b f"]);
+    assert_eq!(pauses(&printed).len(), 1, "{}", printed);
+}
+
+// Records the locals and the call depth whenever a function is entered.
+struct Entries(Rc<RefCell<Vec<(Value, usize)>>>);
+
+impl Debugger for Entries {
+    fn before_instruction(&mut self, pause: &Pause) {
+        if pause.fun_id.is_some() && pause.pc == (0, 0) {
+            self.0.borrow_mut().push((pause.locals[0].clone(), pause.depth));
+        }
+    }
+}
+
+#[test]
+fn locals_at_pause() {
+    let entries = Rc::new(RefCell::new(vec![]));
+    let mut interpreter = Interpreter::new();
+    interpreter.set_debugger(Some(Box::new(Entries(entries.clone()))));
+    interpreter.eval(SRC).unwrap();
+
+    let entries = entries.borrow();
+    let xs: Vec<Value> = entries.iter().map(|(x, _)| x.clone()).collect();
+    assert_eq!(xs, vec![Value::int(2), Value::int(1), Value::int(0)]);
+
+    // Tail calls replace the caller, so all of them run at the same depth.
+    assert!(entries.iter().all(|(_, depth)| *depth == entries[0].1));
+
+    // Removing the debugger stops the calls.
+    interpreter.set_debugger(None);
+    interpreter.eval(SRC).unwrap();
+    assert_eq!(entries.len(), 3);
+}
@@ -0,0 +1,100 @@
+//! Records the requires of a session and renders them as a dependency graph.
+
+use pavo_bootstrap::deps;
+use pavo_bootstrap::{Interpreter, MemoryResolver};
+
+// `a` and `b` both require `c`, which the top-level code also requires with different options.
+fn interpreter() -> Interpreter {
+    let mut resolver = MemoryResolver::new();
+    resolver.insert("a", "(sf-do [(require \"b\" {}) (require \"c\" {}) 1])");
+    resolver.insert("b", "(sf-do [(require \"c\" {}) 2])");
+    resolver.insert("c", "3");
+
+    let mut interpreter = Interpreter::new();
+    interpreter.set_resolver(resolver);
+    interpreter
+        .eval("[
+            (require \"a\" {})
+            (require \"a\" {})
+            (require \"c\" {:remove (sf-quote @{int-sub})})
+            (sf-try (require \"nope\" {}) err nil)
+        ]")
+        .unwrap();
+    interpreter
+}
+
+#[test]
+fn distinct_requires() {
+    let interpreter = interpreter();
+    let edges: Vec<(Option<&str>, Option<&str>)> = interpreter
+        .requires()
+        .iter()
+        .map(|r| (r.from.as_ref().map(String::as_str), r.to.as_ref().ok().map(String::as_str)))
+        .collect();
+
+    // Requiring `a` again is not recorded, requiring `c` from elsewhere or with other options is.
+    assert_eq!(
+        edges,
+        vec![
+            (None, Some("a")),
+            (Some("a"), Some("b")),
+            (Some("b"), Some("c")),
+            (Some("a"), Some("c")),
+            (None, Some("c")),
+            (None, None),
+        ]
+    );
+}
+
+#[test]
+fn tree() {
+    let interpreter = interpreter();
+    assert_eq!(
+        deps::tree(interpreter.requires(), None),
+        "<top-level>
+├── a
+│   ├── b
+│   │   └── c
+│   └── c (see above)
+├── c {:remove @{int-sub}} (see above)
+└── \"nope\" (unresolved)
+
+required with several sets of options:
+  c
+    {}
+    {:remove @{int-sub}}
+
+unresolved:
+  \"nope\" (required by <top-level>)
+"
+    );
+
+    // The requires of a module, without any modules that were required with several sets of
+    // options or could not be resolved.
+    let requires: Vec<_> = interpreter
+        .requires()
+        .iter()
+        .filter(|r| r.from.is_some())
+        .cloned()
+        .collect();
+    assert_eq!(deps::tree(&requires, Some("a")), "a\n├── b\n│   └── c\n└── c (see above)\n");
+}
+
+#[test]
+fn dot() {
+    let interpreter = interpreter();
+    assert_eq!(
+        deps::dot(interpreter.requires()),
+        "digraph dependencies {
+    \"c\" [color=orange];
+    \"<top-level>\" -> \"a\";
+    \"a\" -> \"b\";
+    \"b\" -> \"c\";
+    \"a\" -> \"c\";
+    \"<top-level>\" -> \"c\" [label=\"{:remove @{int-sub}}\"];
+    \"\\\"nope\\\"\" [style=dashed color=red];
+    \"<top-level>\" -> \"\\\"nope\\\"\";
+}
+"
+    );
+}
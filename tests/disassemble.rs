@@ -0,0 +1,32 @@
+//! The rendering of compiled code, as printed by `--emit ir` and the `:ir` command of the repl.
+
+use std::io::BufReader;
+
+use pavo_bootstrap::repl::{Command, Repl};
+use pavo_bootstrap::{FormReader, Interpreter};
+
+fn ir(src: &str) -> String {
+    let mut repl = Repl::new(Interpreter::new());
+    let form = FormReader::new(BufReader::new(src.as_bytes())).next().unwrap().unwrap();
+    repl.run(Command::Ir, &form).unwrap()
+}
+
+#[test]
+fn closure() {
+    // The chunk of the function is printed below the instruction that creates it, with its
+    // bindings named by the identifiers they were declared with.
+    assert_eq!(
+        ir("(sf-lambda [x] (int-add x 1))"),
+        "top-level code
+bb0:
+    fun-literal 1 [int-add@captured:0]
+        function (1 args)
+        params x@local:0
+        bb0:
+            push int-add@captured:0
+            push x@local:0
+            literal 1
+            tail-call 2
+"
+    );
+}
@@ -0,0 +1,95 @@
+//! Reading forms from a stream, one line of input at a time.
+
+use std::io::BufReader;
+
+use pavo_bootstrap::{FormReader, Interpreter, Located, ParseErrorKind, StreamError};
+
+// Reads all forms of the source through a reader that only buffers a single byte.
+fn stream(src: &str) -> Vec<Result<Located, StreamError>> {
+    FormReader::new(BufReader::with_capacity(1, src.as_bytes())).collect()
+}
+
+fn forms(src: &str) -> Vec<Located> {
+    stream(src).into_iter().map(|form| form.expect("unexpected error")).collect()
+}
+
+fn at(located: &Located) -> (u32, usize, usize) {
+    (located.pos.line, located.pos.column, located.pos.offset)
+}
+
+fn end(located: &Located) -> (u32, usize, usize) {
+    (located.end.line, located.end.column, located.end.offset)
+}
+
+#[test]
+fn values() {
+    let src = "1 [2\n3]\n  foo\n(a\n  b) \"x\ny\" :z";
+    let values: Vec<_> = forms(src).into_iter().map(|form| form.value).collect();
+    let expected: Vec<_> = ["1", "[2 3]", "foo", "(a b)", "\"x\ny\"", ":z"]
+        .iter()
+        .map(|form| Interpreter::new().read(form).unwrap())
+        .collect();
+    assert_eq!(values, expected);
+}
+
+#[test]
+fn positions_of_later_forms() {
+    let src = "1 [2\n3]\n  foo\n(a\n  b) \"x\ny\" :z";
+    let forms = forms(src);
+    let starts: Vec<_> = forms.iter().map(at).collect();
+    assert_eq!(starts, vec![(1, 1, 0), (1, 3, 2), (3, 3, 10), (4, 1, 14), (5, 6, 22), (6, 4, 28)]);
+    assert_eq!(end(&forms[5]), (6, 6, 30));
+}
+
+#[test]
+fn forms_spanning_lines() {
+    let src = "1 [2\n3]\n  foo\n(a\n  b) \"x\ny\" :z";
+    let forms = forms(src);
+
+    assert_eq!(end(&forms[1]), (2, 3, 7));
+    let children: Vec<_> = forms[1].children.iter().map(at).collect();
+    assert_eq!(children, vec![(1, 4, 3), (2, 1, 5)]);
+
+    assert_eq!(end(&forms[3]), (5, 5, 21));
+    let children: Vec<_> = forms[3].children.iter().map(at).collect();
+    assert_eq!(children, vec![(4, 2, 15), (5, 3, 19)]);
+
+    assert_eq!(end(&forms[4]), (6, 3, 27));
+}
+
+#[test]
+fn comments_unicode_and_crlf() {
+    let forms = forms("; comment\r\nαβ 1\r\n(x\r\n y)");
+    let starts: Vec<_> = forms.iter().map(at).collect();
+    assert_eq!(starts, vec![(2, 1, 11), (2, 4, 14), (3, 1, 17)]);
+    assert_eq!(at(&forms[2].children[1]), (4, 2, 22));
+}
+
+#[test]
+fn error_on_later_line() {
+    let mut results = stream("1\n2\n(3\n  $)").into_iter();
+    assert!(results.next().unwrap().is_ok());
+    assert!(results.next().unwrap().is_ok());
+    match results.next() {
+        Some(Err(StreamError::Parse(err))) => {
+            assert_eq!(err.kind, ParseErrorKind::ExpectedExpression);
+            assert_eq!((err.pos.line, err.pos.column, err.pos.offset), (4, 3, 9));
+        }
+        other => panic!("expected a parse error, got {:?}", other),
+    }
+    // Reading stops after the first error.
+    assert!(results.next().is_none());
+}
+
+#[test]
+fn incomplete_at_end_of_stream() {
+    let results = stream("1\n(2\n  3");
+    assert_eq!(results.len(), 2);
+    match &results[1] {
+        Err(StreamError::Parse(err)) => {
+            assert!(err.is_incomplete());
+            assert_eq!((err.pos.line, err.pos.column), (3, 4));
+        }
+        other => panic!("expected a parse error, got {:?}", other),
+    }
+}
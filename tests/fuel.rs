@@ -0,0 +1,78 @@
+//! Limiting the number of steps an evaluation may take.
+
+use pavo_bootstrap::value::Value;
+use pavo_bootstrap::{Abort, ExecuteError, Interpreter, E};
+
+const LOOP: &str = "(letfn {loop ([] (loop))} (loop))";
+const WORK: &str = "(letfn {count ([n] (if (= n 0) :done (count (int-sub n 1))))} (count 20))";
+
+fn out_of_fuel(result: Result<Value, ExecuteError>) -> bool {
+    match result {
+        Err(ExecuteError::E(E::Abort(Abort::OutOfFuel))) => true,
+        _ => false,
+    }
+}
+
+// The number of steps evaluating `src` takes.
+fn steps(src: &str) -> u64 {
+    let mut interpreter = Interpreter::new();
+    let before = interpreter.fuel_consumed();
+    interpreter.eval(src).unwrap();
+    interpreter.fuel_consumed() - before
+}
+
+#[test]
+fn abort() {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_fuel(Some(1_000));
+    assert!(out_of_fuel(interpreter.eval(LOOP)));
+    assert_eq!(interpreter.fuel(), Some(0));
+
+    // The interpreter remains usable once the fuel is replenished.
+    interpreter.set_fuel(None);
+    assert_eq!(interpreter.eval("(int-add 1 2)").unwrap(), Value::int(3));
+}
+
+#[test]
+fn try_does_not_catch() {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_fuel(Some(1_000));
+    assert!(out_of_fuel(interpreter.eval(&format!("(sf-try {} err :caught)", LOOP))));
+
+    // Nor does a try in a function further up the stack.
+    interpreter.set_fuel(Some(1_000));
+    let src = format!("(sf-try ((sf-lambda [] {})) err :caught)", LOOP);
+    assert!(out_of_fuel(interpreter.eval(&src)));
+}
+
+#[test]
+fn consumed() {
+    let steps = steps(WORK);
+    assert!(steps > 20);
+    // Evaluation is deterministic.
+    assert_eq!(steps, self::steps(WORK));
+
+    // Exactly the consumed fuel is enough, one step less is not.
+    let mut interpreter = Interpreter::new();
+    interpreter.set_fuel(Some(steps));
+    assert_eq!(interpreter.eval(WORK).unwrap(), Value::kw_str("done"));
+    assert_eq!(interpreter.fuel(), Some(0));
+
+    let mut interpreter = Interpreter::new();
+    let before = interpreter.fuel_consumed();
+    interpreter.set_fuel(Some(steps - 1));
+    assert!(out_of_fuel(interpreter.eval(WORK)));
+    assert_eq!(interpreter.fuel_consumed() - before, steps - 1);
+}
+
+#[test]
+fn consumed_without_limit() {
+    let mut interpreter = Interpreter::new();
+    let before = interpreter.fuel_consumed();
+    interpreter.set_fuel(Some(1_000_000));
+    interpreter.eval(WORK).unwrap();
+    let limited = interpreter.fuel_consumed() - before;
+    assert_eq!(interpreter.fuel(), Some(1_000_000 - limited));
+
+    assert_eq!(limited, steps(WORK));
+}
@@ -0,0 +1,100 @@
+//! Opaque values that wrap rust data, created through `Interpreter::host`.
+
+use std::cmp::Ordering;
+
+use pavo_bootstrap::value::{Id, Value};
+use pavo_bootstrap::{ExecuteError, Interpreter, E};
+
+fn type_error(interpreter: &Interpreter) -> Value {
+    interpreter.read("{:tag :err-type}").unwrap()
+}
+
+#[test]
+fn downcast() {
+    let mut interpreter = Interpreter::new();
+    let t = interpreter.new_host_type();
+    let h = interpreter.host(t, String::from("hello"));
+
+    assert_eq!(h.host_type_id(), Some(t));
+    assert_eq!(h.as_host::<String>().map(String::as_str), Some("hello"));
+
+    // The wrong rust type, and values that are no host values at all.
+    assert_eq!(h.as_host::<i64>(), None);
+    assert_eq!(Value::int(42).as_host::<i64>(), None);
+    assert_eq!(Value::int(42).host_type_id(), None);
+
+    // Passing through pavo code keeps the data.
+    interpreter.define("h", h);
+    let v = interpreter.eval("(arr-get [h] 0)").unwrap();
+    assert_eq!(v.as_host::<String>().map(String::as_str), Some("hello"));
+}
+
+#[test]
+fn typeof_() {
+    let mut interpreter = Interpreter::new();
+    let t = interpreter.new_host_type();
+    let u = interpreter.new_host_type();
+    assert_ne!(t, u);
+
+    let h = interpreter.host(t, 0i64);
+    interpreter.define("h", h);
+    assert_eq!(interpreter.eval("(typeof h)").unwrap(), Value::Id(Id::Symbol(t)));
+
+    // The type is a symbol like those of user defined opaque types.
+    assert_eq!(interpreter.eval("(typeof (typeof h))").unwrap(), interpreter.read(":symbol").unwrap());
+}
+
+#[test]
+fn equality_and_ordering() {
+    let mut interpreter = Interpreter::new();
+    let t = interpreter.new_host_type();
+    let u = interpreter.new_host_type();
+
+    let a = interpreter.host(u, 1i64);
+    let b = interpreter.host(t, 1i64);
+    let c = interpreter.host(t, String::from("c"));
+
+    // Each host value is only equal to itself, regardless of its data.
+    assert_eq!(a, a.clone());
+    assert_ne!(a, b);
+    assert_ne!(b, c);
+
+    // Host values are ordered by creation, not by type id.
+    assert_eq!(a.cmp(&b), Ordering::Less);
+    assert_eq!(b.cmp(&c), Ordering::Less);
+    assert_eq!(c.cmp(&a), Ordering::Greater);
+
+    // Pavo code agrees.
+    interpreter.define("a", a);
+    interpreter.define("b", b);
+    interpreter.define("c", c);
+    assert_eq!(
+        interpreter.eval("[(= a a) (= a b) (cmp a b) (cmp c a) (< b c)]").unwrap(),
+        interpreter.read("[true false :<, :> true]").unwrap()
+    );
+}
+
+#[test]
+fn err_type() {
+    let mut interpreter = Interpreter::new();
+    let t = interpreter.new_host_type();
+    let h = interpreter.host(t, 0i64);
+    interpreter.define("h", h);
+    let expected = type_error(&interpreter);
+
+    // Neither builtin nor user defined opaque types accept a host value.
+    for src in [
+        "(cursor-arr-next! h)",
+        "((map-get (opaque) :unhide) h)",
+    ].iter() {
+        match interpreter.eval(src) {
+            Err(ExecuteError::E(E::Eval(thrown, _))) => assert_eq!(thrown, expected, "{}", src),
+            other => panic!("{}: expected a type error, got {:?}", src, other),
+        }
+    }
+
+    assert_eq!(
+        interpreter.eval("(sf-try (cursor-arr-next! h) err err)").unwrap(),
+        expected
+    );
+}
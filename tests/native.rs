@@ -0,0 +1,92 @@
+//! Functions implemented in rust and registered through `Interpreter::define_native`.
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use pavo_bootstrap::value::Value;
+use pavo_bootstrap::Interpreter;
+
+// An interpreter with the natives `f` and `g` (in that order) of arity two, which return their
+// first argument.
+fn interpreter() -> Interpreter {
+    let mut interpreter = Interpreter::new();
+    interpreter.define_native("f", 2, |args, _cx| Ok(args.0[0].clone()));
+    interpreter.define_native("g", 2, |args, _cx| Ok(args.0[0].clone()));
+    interpreter
+}
+
+fn eval(src: &str) -> Value {
+    interpreter().eval(src).unwrap()
+}
+
+#[test]
+fn call() {
+    assert_eq!(eval("(f 1 2)"), Value::int(1));
+    assert_eq!(eval("(fun-apply g [3 4])"), Value::int(3));
+}
+
+#[test]
+fn throw() {
+    let mut interpreter = Interpreter::new();
+    interpreter.define_native("fail", 0, |_args, _cx| Err(Value::kw_str("nope")));
+    assert_eq!(interpreter.eval("(sf-try (fail) err err)").unwrap(), Value::kw_str("nope"));
+}
+
+#[test]
+fn num_args() {
+    let calls = Rc::new(Cell::new(0));
+    let mut interpreter = Interpreter::new();
+    let counter = calls.clone();
+    interpreter.define_native("f", 2, move |args, _cx| {
+        counter.set(counter.get() + 1);
+        Ok(args.0[0].clone())
+    });
+
+    let err_num_args = Value::map_from_vec(vec![
+        (Value::kw_str("tag"), Value::kw_str("err-num-args")),
+    ]);
+    assert_eq!(interpreter.eval("(sf-try (f 1) err err)").unwrap(), err_num_args);
+    assert_eq!(interpreter.eval("(sf-try (f 1 2 3) err err)").unwrap(), err_num_args);
+    assert_eq!(interpreter.eval("(sf-try (fun-apply f []) err err)").unwrap(), err_num_args);
+    // The closure is never called with the wrong number of arguments.
+    assert_eq!(calls.get(), 0);
+}
+
+#[test]
+fn eq_natives() {
+    assert_eq!(eval("(= f f)"), Value::bool_(true));
+    assert_eq!(eval("(= f g)"), Value::bool_(false));
+    assert_eq!(eval("(cmp f f)"), Value::kw_str("="));
+    // Natives are ordered by the time of their creation.
+    assert_eq!(eval("(cmp f g)"), Value::kw_str("<"));
+    assert_eq!(eval("(cmp g f)"), Value::kw_str(">"));
+}
+
+#[test]
+fn eq_builtins() {
+    assert_eq!(eval("(= f int-add)"), Value::bool_(false));
+    // Builtins are less than all other functions.
+    assert_eq!(eval("(cmp int-add f)"), Value::kw_str("<"));
+    assert_eq!(eval("(cmp f int-add)"), Value::kw_str(">"));
+}
+
+#[test]
+fn eq_closures() {
+    assert_eq!(eval("(= f (sf-lambda [a b] a))"), Value::bool_(false));
+    // Closures and natives share the same ids, the closure is created after the natives.
+    assert_eq!(eval("(cmp f (sf-lambda [a b] a))"), Value::kw_str("<"));
+    assert_eq!(eval("(cmp (sf-lambda [a b] a) g)"), Value::kw_str(">"));
+
+    let mut interpreter = Interpreter::new();
+    let closure = interpreter.eval("(sf-lambda [a b] a)").unwrap();
+    interpreter.define("c", closure);
+    interpreter.define_native("f", 2, |args, _cx| Ok(args.0[0].clone()));
+    assert_eq!(interpreter.eval("(cmp c f)").unwrap(), Value::kw_str("<"));
+    assert_eq!(interpreter.eval("(cmp f c)").unwrap(), Value::kw_str(">"));
+}
+
+#[test]
+fn collections() {
+    assert_eq!(eval("(set-count @{f g f})"), Value::int(2));
+    assert_eq!(eval("(map-get {f 1 g 2} g)"), Value::int(2));
+}
@@ -0,0 +1,87 @@
+//! Runs the examples of the reference with and without the ir optimization passes, and checks
+//! that both produce the same results.
+
+use std::cell::RefCell;
+use std::fs;
+use std::rc::Rc;
+
+use pavo_bootstrap::value::Value;
+use pavo_bootstrap::{ExecuteError, Interpreter, Vector};
+
+// The assertions of the examples record their arguments instead of throwing, so that a failing
+// assertion does not hide the results of the remaining ones.
+const PRELUDE: &str = "
+(defmacro assert-throw (sf-lambda [totry exception]
+    `(sf-try
+        (sf-do [~totry (sf-throw :assert-throw)])
+        errlklkl
+        (assert-eq errlklkl ~exception)
+    )
+))
+";
+
+type Log = Rc<RefCell<Vec<(&'static str, Vec<Value>)>>>;
+
+fn interpreter(optimize: bool, log: &Log) -> Interpreter {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_optimize(optimize);
+
+    for (name, arity) in [("assert", 1), ("assert-not", 1), ("assert-eq", 2)].iter() {
+        let name: &'static str = *name;
+        let log = log.clone();
+        interpreter.define_native(name, *arity, move |args: Vector<Value>, _cx| {
+            log.borrow_mut().push((name, args.0.iter().cloned().collect()));
+            Ok(Value::nil())
+        });
+    }
+
+    interpreter.eval(PRELUDE).unwrap();
+    interpreter
+}
+
+fn run(src: &str, optimize: bool) -> (Result<Value, ExecuteError>, Vec<(&'static str, Vec<Value>)>) {
+    let log: Log = Rc::new(RefCell::new(vec![]));
+    let result = interpreter(optimize, &log).eval(src);
+    let assertions = log.borrow().clone();
+    (result, assertions)
+}
+
+// The code blocks of the reference that contain pavo code.
+fn examples() -> Vec<String> {
+    let reference = fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/reference.md")).unwrap();
+
+    let mut examples = vec![];
+    let mut current: Option<String> = None;
+    for line in reference.lines() {
+        match current.take() {
+            None => {
+                if line.trim() == "```pavo" {
+                    current = Some(String::new());
+                }
+            }
+            Some(mut example) => {
+                if line.trim() == "```" {
+                    examples.push(example);
+                } else {
+                    example.push_str(line);
+                    example.push('\n');
+                    current = Some(example);
+                }
+            }
+        }
+    }
+
+    examples
+}
+
+#[test]
+fn test_reference_examples_optimized() {
+    let examples = examples();
+    assert!(examples.len() > 0);
+
+    for example in examples.iter() {
+        let unoptimized = run(example, false);
+        let optimized = run(example, true);
+        assert_eq!(optimized, unoptimized, "results differ for the example:\n{}", example);
+    }
+}
@@ -0,0 +1,101 @@
+//! The calls and steps the profiler attributes to each function.
+
+use pavo_bootstrap::{Interpreter, Profiler};
+
+// `f` and `g` call each other (in tail position) until `x` reaches zero:
+//
+// - `(f 3)` calls f with 3, 2, 1, 0 and g with 2, 1, 0
+// - `(g 1)` calls g with 1, 0 and f with 1, 0
+const SRC: &str = "(sf-letfn {
+  f ([x] (if (= x 0) 0 (g (int-sub x 1))))
+  g ([x] (f x))
+} [(f 3) (g 1)])
+";
+
+// The steps and calls of each row of the report, by the label of the function.
+fn rows(report: &str) -> Vec<(String, u64, u64)> {
+    report
+        .lines()
+        .skip(1)
+        .map(|line| {
+            let mut words = line.split_whitespace();
+            let steps = words.next().unwrap().parse().unwrap();
+            let calls = words.next().unwrap().parse().unwrap();
+            let _millis = words.next().unwrap();
+            (words.collect::<Vec<&str>>().join(" "), steps, calls)
+        })
+        .collect()
+}
+
+// The steps and calls of the only function whose label satisfies the predicate.
+fn row<P: Fn(&str) -> bool>(rows: &[(String, u64, u64)], p: P) -> (u64, u64) {
+    let matching: Vec<&(String, u64, u64)> = rows.iter().filter(|(label, _, _)| p(label)).collect();
+    assert_eq!(matching.len(), 1, "{:?}", rows);
+    (matching[0].1, matching[0].2)
+}
+
+fn profile(src: &str) -> Profiler {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_profiler(Some(Profiler::new()));
+    interpreter.eval(src).unwrap();
+    interpreter.take_profiler().unwrap()
+}
+
+#[test]
+fn calls() {
+    let rows = rows(&profile(SRC).report("main.pavo"));
+
+    let (f_steps, f_calls) = row(&rows, |label| label.contains(" f (main.pavo:2:"));
+    let (g_steps, g_calls) = row(&rows, |label| label.contains(" g (main.pavo:3:"));
+    assert_eq!((f_calls, g_calls), (6, 5));
+    assert!(f_steps > g_steps && g_steps > 0, "{:?}", rows);
+
+    assert_eq!(row(&rows, |label| label == "top-level").1, 1);
+    assert_eq!(row(&rows, |label| label == "builtin =").1, 6);
+    assert_eq!(row(&rows, |label| label == "builtin int-sub").1, 4);
+
+    // Each builtin call is a single step.
+    assert_eq!(row(&rows, |label| label == "builtin int-sub").0, 4);
+}
+
+#[test]
+fn deterministic_steps() {
+    // The time differs between runs, the steps and calls do not.
+    let without_time = |report: String| -> Vec<(String, u64, u64)> {
+        let mut rows = rows(&report);
+        rows.sort();
+        rows
+    };
+    assert_eq!(
+        without_time(profile(SRC).report("main.pavo")),
+        without_time(profile(SRC).report("main.pavo"))
+    );
+}
+
+#[test]
+fn folded_stacks() {
+    let mut out = vec![];
+    profile(SRC).write_folded(&mut out, "main.pavo").unwrap();
+    let folded = String::from_utf8(out).unwrap();
+
+    // Tail calls replace the caller on the stack, so no stack is deeper than a builtin called
+    // by a function called from the top-level code.
+    let stacks: Vec<(&str, u64)> = folded
+        .lines()
+        .map(|line| {
+            let (stack, steps) = line.split_at(line.rfind(' ').unwrap());
+            (stack, steps.trim().parse().unwrap())
+        })
+        .collect();
+    assert!(stacks.iter().all(|(stack, steps)| *steps > 0 && stack.split(';').count() <= 3), "{}", folded);
+
+    // All subtractions happen in `f`, which is always entered from the top-level code.
+    let subtractions: Vec<&(&str, u64)> = stacks
+        .iter()
+        .filter(|(stack, _)| stack.ends_with(";builtin int-sub"))
+        .collect();
+    assert_eq!(subtractions.len(), 1, "{}", folded);
+    assert!(subtractions[0].0.starts_with("top-level;function "), "{}", folded);
+    assert!(subtractions[0].0.contains(" f (main.pavo:2:"), "{}", folded);
+    assert_eq!(subtractions[0].1, 4);
+}
@@ -0,0 +1,136 @@
+//! The errors of the reader, and how they are rendered.
+
+use pavo_bootstrap::value::Value;
+use pavo_bootstrap::{ExecuteError, Interpreter, ParseError, ParseErrorKind};
+
+fn read_error(src: &str) -> ParseError {
+    match Interpreter::new().read(src) {
+        Err(err) => err,
+        Ok(v) => panic!("expected a parse error, got {:?}", v),
+    }
+}
+
+fn assert_error(src: &str, kind: ParseErrorKind, line: u32, column: usize) {
+    let err = read_error(src);
+    assert_eq!((err.kind, err.pos.line, err.pos.column), (kind, line, column), "reading {:?}", src);
+}
+
+#[test]
+fn expected_expression() {
+    assert_error("", ParseErrorKind::ExpectedExpression, 1, 1);
+    assert_error(")", ParseErrorKind::ExpectedExpression, 1, 1);
+    assert_error("(1 $ 2)", ParseErrorKind::ExpectedExpression, 1, 4);
+    assert_error("[1 `]", ParseErrorKind::ExpectedExpression, 1, 4);
+    assert_error("{:a 1 $}", ParseErrorKind::ExpectedExpression, 1, 7);
+    assert_error("@{1\n  ~}", ParseErrorKind::ExpectedExpression, 2, 3);
+}
+
+#[test]
+fn expected_identifier() {
+    // Only `str=>id` parses identifiers on their own.
+    let thrown = Interpreter::new().eval("(sf-try (str=>id \"42\") err err)").unwrap();
+    assert_eq!(thrown, Value::map_from_vec(vec![
+        (Value::kw_str("tag"), Value::kw_str("err-identifier")),
+    ]));
+}
+
+#[test]
+fn trailing_input() {
+    assert_error("1 2", ParseErrorKind::TrailingInput, 1, 3);
+}
+
+#[test]
+fn id_too_long() {
+    assert_error(&"a".repeat(256), ParseErrorKind::IdTooLong, 1, 1);
+    assert_error(&format!("[:{}]", "a".repeat(256)), ParseErrorKind::IdTooLong, 1, 3);
+}
+
+#[test]
+fn int_out_of_range() {
+    assert_error("9223372036854775808", ParseErrorKind::IntOutOfRange, 1, 1);
+    assert_error("(0x8000000000000000)", ParseErrorKind::IntOutOfRange, 1, 2);
+}
+
+#[test]
+fn float_out_of_range() {
+    assert_error("1.0e999", ParseErrorKind::FloatOutOfRange, 1, 1);
+}
+
+#[test]
+fn byte_out_of_range() {
+    assert_error("@[1 256]", ParseErrorKind::ByteOutOfRange, 1, 5);
+}
+
+#[test]
+fn invalid_unicode_escape() {
+    assert_error("\"\\{D800}\"", ParseErrorKind::InvalidUnicodeEscape, 1, 4);
+    assert_error("'\\{110000}'", ParseErrorKind::InvalidUnicodeEscape, 1, 4);
+}
+
+#[test]
+fn invalid_escape() {
+    assert_error("\"ab\\q\"", ParseErrorKind::InvalidEscape, 1, 4);
+}
+
+#[test]
+fn unterminated_string() {
+    assert_error("[\"abc", ParseErrorKind::UnterminatedString, 1, 2);
+    assert_error("@@\"abc\"@", ParseErrorKind::UnterminatedString, 1, 1);
+}
+
+#[test]
+fn unbalanced_bracket() {
+    assert_error("(1 2]", ParseErrorKind::UnbalancedBracket { expected: ')' }, 1, 5);
+    assert_error("[1 2", ParseErrorKind::UnbalancedBracket { expected: ']' }, 1, 5);
+    assert_error("{1 2)", ParseErrorKind::UnbalancedBracket { expected: '}' }, 1, 5);
+    assert_error("@{1 2", ParseErrorKind::UnbalancedBracket { expected: '}' }, 1, 6);
+    assert_error("@[1 2)", ParseErrorKind::UnbalancedBracket { expected: ']' }, 1, 6);
+}
+
+#[test]
+fn fresh_name_not_id() {
+    assert_error("@1", ParseErrorKind::FreshNameNotId, 1, 1);
+}
+
+#[test]
+fn missing_separator() {
+    match Interpreter::new().eval("1 (2)(3)") {
+        Err(ExecuteError::Parse(err)) => {
+            assert_eq!((err.kind, err.pos.line, err.pos.column), (ParseErrorKind::MissingSeparator, 1, 6));
+        }
+        other => panic!("expected a parse error, got {:?}", other),
+    }
+}
+
+#[test]
+fn incomplete() {
+    assert!(read_error("(1 2").is_incomplete());
+    assert!(read_error("\"abc").is_incomplete());
+    assert!(!read_error("(1 $ 2)").is_incomplete());
+    assert!(!read_error("(1 2]").is_incomplete());
+}
+
+#[test]
+fn display() {
+    assert_eq!(
+        read_error("(1 $ 2)").to_string(),
+        "1:4: expected an expression\n  |\n1 | (1 $ 2)\n  |    ^"
+    );
+    assert_eq!(
+        read_error("[1\n  (2 3]").to_string(),
+        "2:7: unbalanced bracket, expected `)`\n  |\n2 |   (2 3]\n  |       ^"
+    );
+    assert_eq!(
+        read_error("(0x8000000000000000 1)").to_string(),
+        "1:2: integer out of range\n  |\n1 | (0x8000000000000000 1)\n  |  ^^^^^^^^^^^^^^^^^^"
+    );
+}
+
+#[test]
+fn display_gutter() {
+    let src = format!("{}[1 2 3", "\n".repeat(11));
+    assert_eq!(
+        read_error(&src).to_string(),
+        "12:7: unbalanced bracket, expected `]`\n   |\n12 | [1 2 3\n   |       ^"
+    );
+}
@@ -0,0 +1,115 @@
+//! The commands of the repl, applied to entries as if they had been typed.
+
+use std::io::BufReader;
+
+use pavo_bootstrap::repl::{command, Command, Repl};
+use pavo_bootstrap::{FormReader, Interpreter, E};
+
+// Apply the command of the entry to each of its forms.
+fn enter(repl: &mut Repl, entry: &str) -> Vec<Result<String, E>> {
+    let (cmd, src) = command(entry);
+    FormReader::new(BufReader::new(src.as_bytes()))
+        .map(|form| repl.run(cmd, &form.unwrap()))
+        .collect()
+}
+
+fn ok(repl: &mut Repl, entry: &str) -> String {
+    let mut outputs = enter(repl, entry);
+    assert_eq!(outputs.len(), 1, "{}", entry);
+    outputs.pop().unwrap().unwrap()
+}
+
+fn repl() -> Repl {
+    Repl::new(Interpreter::new())
+}
+
+#[test]
+fn command_splitting() {
+    assert_eq!(command("(int-add 1 2)\n"), (Command::Eval, "(int-add 1 2)\n".to_string()));
+    assert_eq!(command(":help\n"), (Command::Help, "     \n".to_string()));
+
+    // The command is replaced by as many spaces, including any whitespace in front of it.
+    assert_eq!(command(":expand (f 1)\n"), (Command::Expand, format!("{}(f 1)\n", " ".repeat(8))));
+    assert_eq!(command(":check\n  x\n"), (Command::Check, "      \n  x\n".to_string()));
+    assert_eq!(command("  :ir 42\n"), (Command::Ir, format!("{}42\n", " ".repeat(6))));
+
+    // Commands must be followed by whitespace, and only count at the start of an entry.
+    assert_eq!(command(":expanded 1\n"), (Command::Eval, ":expanded 1\n".to_string()));
+    assert_eq!(command(":ir\n"), (Command::Ir, "   \n".to_string()));
+    assert_eq!(command(":ir"), (Command::Eval, ":ir".to_string()));
+    assert_eq!(command("(f :check 1)\n"), (Command::Eval, "(f :check 1)\n".to_string()));
+}
+
+#[test]
+fn positions_as_typed() {
+    let mut repl = repl();
+
+    for entry in [":check (int-add 1 nope)\n", "  :ir (int-add 1 nope)\n", "(int-add 1 nope)\n"].iter() {
+        let column = entry.find("nope").unwrap() + 1;
+        match enter(&mut repl, entry).pop().unwrap() {
+            Err(err) => {
+                let span = err.span().expect("error without a span");
+                assert_eq!((span.start.line, span.start.column), (1, column), "{}", entry);
+            }
+            Ok(out) => panic!("{}: expected an error, got {}", entry, out),
+        }
+    }
+
+    // Later lines of an entry.
+    match enter(&mut repl, ":check [\n  1\n  nope]\n").pop().unwrap() {
+        Err(err) => {
+            let span = err.span().expect("error without a span");
+            assert_eq!((span.start.line, span.start.column), (3, 3));
+        }
+        Ok(out) => panic!("expected an error, got {}", out),
+    }
+}
+
+#[test]
+fn eval() {
+    let mut repl = repl();
+    assert_eq!(ok(&mut repl, "(int-add 1 2)\n"), "3");
+
+    // Definitions persist across entries, even after an error.
+    assert_eq!(ok(&mut repl, "(def x 42)\n"), "42");
+    assert!(enter(&mut repl, "(diverge 0)\n").pop().unwrap().is_err());
+    assert_eq!(ok(&mut repl, "x\n"), "42");
+
+    let outputs: Vec<String> = enter(&mut repl, "(def y 1) y\n").into_iter().map(Result::unwrap).collect();
+    assert_eq!(outputs, vec!["1", "1"]);
+}
+
+#[test]
+fn expand() {
+    let mut repl = repl();
+    ok(&mut repl, "(defmacro twice (sf-lambda [x] [x x]))\n");
+
+    assert_eq!(ok(&mut repl, ":expand (twice 1)\n"), "[\n  1,\n  1,\n]");
+    // Expanding does not evaluate.
+    assert_eq!(ok(&mut repl, ":expand (twice (diverge 0))\n"), "[\n  (\n    diverge,\n    0,\n  ),\n  (\n    diverge,\n    0,\n  ),\n]");
+    assert_eq!(ok(&mut repl, "(twice 1)\n"), "[\n  1,\n  1,\n]");
+}
+
+#[test]
+fn check() {
+    let mut repl = repl();
+    assert_eq!(ok(&mut repl, ":check (diverge 0)\n"), "ok");
+
+    // Checking sees the definitions of earlier entries.
+    assert!(enter(&mut repl, ":check x\n").pop().unwrap().is_err());
+    ok(&mut repl, "(def x 42)\n");
+    assert_eq!(ok(&mut repl, ":check x\n"), "ok");
+}
+
+#[test]
+fn ir() {
+    let mut repl = repl();
+    let out = ok(&mut repl, ":ir (int-add 1 2)\n");
+    assert!(out.starts_with("top-level code\n"), "{}", out);
+    assert!(out.contains("literal 1\n"), "{}", out);
+    assert!(out.contains("call 2\n") || out.contains("tail-call 2\n"), "{}", out);
+
+    // Compiling does not evaluate.
+    let out = ok(&mut repl, ":ir (diverge 0)\n");
+    assert!(out.contains("literal 0\n"), "{}", out);
+}
@@ -0,0 +1,98 @@
+//! Requires the files in `pavo-testfiles`.
+
+use std::path::PathBuf;
+
+use pavo_bootstrap::value::Value;
+use pavo_bootstrap::Interpreter;
+
+fn testfile(name: &str) -> String {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("pavo-testfiles").join(name);
+    path.canonicalize().unwrap().display().to_string()
+}
+
+// Evaluates to the error if `require` throws.
+fn require(name: &str) -> Value {
+    Interpreter::new()
+        .eval(&format!("(sf-try (require {:?} {{}}) err err)", testfile(name)))
+        .unwrap()
+}
+
+// The error that caused an error thrown by evaluating a module, which wraps it in `:err-eval`.
+fn root_cause(err: &Value) -> Value {
+    match err.as_map().and_then(|map| map.0.get(&Value::kw_str("cause"))) {
+        Some(cause) => root_cause(cause),
+        None => err.clone(),
+    }
+}
+
+fn err_cycle(names: &[&str]) -> Value {
+    Value::map_from_vec(vec![
+        (Value::kw_str("tag"), Value::kw_str("err-require")),
+        (
+            Value::kw_str("cycle"),
+            Value::arr_from_vec(names.iter().map(|name| Value::string_from_str(&testfile(name))).collect()),
+        ),
+    ])
+}
+
+#[test]
+fn loads_each_module_once() {
+    assert_eq!(require("a.pavo"), Value::bool_(true));
+}
+
+#[test]
+fn cycle() {
+    assert_eq!(
+        root_cause(&require("cycle-a.pavo")),
+        err_cycle(&["cycle-a.pavo", "cycle-b.pavo", "cycle-a.pavo"])
+    );
+}
+
+#[test]
+fn cycle_from_either_side() {
+    // The error of the first cycle is not cached for the modules on it, requiring another one of
+    // them reports the cycle as seen from that module.
+    let mut interpreter = Interpreter::new();
+    for (first, cycle) in [
+        ("cycle-a.pavo", ["cycle-a.pavo", "cycle-b.pavo", "cycle-a.pavo"]),
+        ("cycle-b.pavo", ["cycle-b.pavo", "cycle-a.pavo", "cycle-b.pavo"]),
+        ("cycle-a.pavo", ["cycle-a.pavo", "cycle-b.pavo", "cycle-a.pavo"]),
+    ].iter() {
+        let err = interpreter
+            .eval(&format!("(sf-try (require {:?} {{}}) err err)", testfile(first)))
+            .unwrap();
+        assert_eq!(root_cause(&err), err_cycle(cycle));
+    }
+}
+
+#[test]
+fn self_cycle() {
+    assert_eq!(
+        root_cause(&require("cycle-self.pavo")),
+        err_cycle(&["cycle-self.pavo", "cycle-self.pavo"])
+    );
+}
+
+#[test]
+fn records_edges() {
+    let mut interpreter = Interpreter::new();
+    interpreter
+        .eval(&format!("[(require {:?} {{}}) (require 42 {{}})]", testfile("a.pavo")))
+        .unwrap_err();
+
+    let edges: Vec<(Option<String>, Option<String>)> = interpreter
+        .requires()
+        .iter()
+        .map(|r| (r.from.clone(), r.to.clone().ok()))
+        .collect();
+    assert_eq!(
+        edges,
+        vec![
+            (None, Some(testfile("a.pavo"))),
+            (Some(testfile("a.pavo")), Some(testfile("b.pavo"))),
+            (Some(testfile("b.pavo")), Some(testfile("c.pavo"))),
+            (Some(testfile("a.pavo")), Some(testfile("c.pavo"))),
+            (None, None),
+        ]
+    );
+}
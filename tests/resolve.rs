@@ -0,0 +1,63 @@
+//! Loads modules through the different resolvers of `require`.
+
+use std::fs;
+
+use pavo_bootstrap::value::Value;
+use pavo_bootstrap::{Interpreter, MemoryResolver, SearchPathResolver};
+
+// Evaluates to the error if `require` throws.
+fn require(interpreter: &mut Interpreter, module: &str) -> Value {
+    interpreter
+        .eval(&format!("(sf-try (require {} {{}}) err err)", module))
+        .unwrap()
+}
+
+#[test]
+fn memory() {
+    let mut resolver = MemoryResolver::new();
+    resolver.insert("answer", "(sf-do [(require \"half\" {}) 42])");
+    resolver.insert("half", "21");
+
+    let mut interpreter = Interpreter::new();
+    interpreter.set_resolver(resolver);
+    assert_eq!(require(&mut interpreter, "\"answer\""), Value::int(42));
+    assert_eq!(require(&mut interpreter, "\"question\""), interpreter.eval("{:tag :err-require}").unwrap());
+}
+
+#[test]
+fn missing_file() {
+    let mut interpreter = Interpreter::new();
+    assert_eq!(
+        require(&mut interpreter, "\"does/not/exist.pavo\""),
+        interpreter.eval("{:tag :err-require}").unwrap()
+    );
+}
+
+#[test]
+fn search_path() {
+    let dir = std::env::temp_dir().join(format!("pavo-resolve-{}", std::process::id()));
+    fs::create_dir_all(dir.join("std")).unwrap();
+    fs::write(dir.join("std").join("collections.pavo"), "[1 2 3]").unwrap();
+
+    let mut interpreter = Interpreter::new();
+    interpreter.set_resolver(SearchPathResolver::new(vec![dir.join("missing"), dir.clone()]));
+    assert_eq!(require(&mut interpreter, ":std/collections"), interpreter.eval("[1 2 3]").unwrap());
+    assert_eq!(require(&mut interpreter, ":std/../std/collections"), interpreter.eval("{:tag :err-require}").unwrap());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn relative_to_requiring_file() {
+    let dir = std::env::temp_dir().join(format!("pavo-relative-{}", std::process::id()));
+    fs::create_dir_all(dir.join("lib")).unwrap();
+    fs::write(dir.join("lib").join("outer.pavo"), "(require \"inner.pavo\" {})").unwrap();
+    fs::write(dir.join("lib").join("inner.pavo"), "42").unwrap();
+    fs::write(dir.join("main.pavo"), "").unwrap();
+
+    let mut interpreter = Interpreter::new();
+    interpreter.set_entrypoint(Some(dir.join("main.pavo").display().to_string()));
+    assert_eq!(require(&mut interpreter, "\"lib/outer.pavo\""), Value::int(42));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,161 @@
+//! Serializing serde types into pavo values and deserializing them back.
+
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::time::{Duration, UNIX_EPOCH, SystemTime};
+
+use serde::de::DeserializeOwned;
+use serde::ser::{Serialize, SerializeStruct, SerializeStructVariant, Serializer};
+
+use pavo_bootstrap::serde_value::{from_value, to_value};
+use pavo_bootstrap::value::Value;
+use pavo_bootstrap::Interpreter;
+
+fn read(src: &str) -> Value {
+    Interpreter::new().read(src).unwrap()
+}
+
+// Checks that `t` is represented as the pavo value `src` and can be deserialized back.
+fn round_trip<T: Serialize + DeserializeOwned + PartialEq + Debug>(t: T, src: &str) {
+    assert_eq!(to_value(&t), Ok(read(src)), "serializing {:?}", t);
+    assert_eq!(from_value::<T>(&read(src)), Ok(t));
+}
+
+fn ser_error<T: Serialize>(t: &T) -> String {
+    to_value(t).unwrap_err().to_string()
+}
+
+fn de_error<T: DeserializeOwned + Debug>(src: &str) -> String {
+    from_value::<T>(&read(src)).unwrap_err().to_string()
+}
+
+#[test]
+fn primitives() {
+    round_trip((), "nil");
+    round_trip(false, "false");
+    round_trip(-7i16, "-7");
+    round_trip(7u64, "7");
+    round_trip(0.25f64, "0.25");
+    round_trip('x', "'x'");
+    round_trip("abc".to_string(), "\"abc\"");
+    round_trip(Some(3i64), "3");
+    round_trip(None::<i64>, "nil");
+}
+
+#[test]
+fn collections() {
+    round_trip(vec![1i64, 2], "[1 2]");
+    round_trip((1i64, true, 'c'), "[1 true 'c']");
+
+    let map: BTreeMap<String, Vec<bool>> = vec![("a".to_string(), vec![true])].into_iter().collect();
+    round_trip(map, "{\"a\" [true]}");
+
+    // Sets deserialize as sequences.
+    assert_eq!(from_value::<Vec<i64>>(&read("@{1 2}")), Ok(vec![1, 2]));
+}
+
+#[test]
+fn values() {
+    // Keywords serialize as strings, sets and applications as arrays, everything else round-trips.
+    let v = read("[nil 1 2.5 'a' \"b\" @[1 2] {4 [5]}]");
+    assert_eq!(to_value(&v), Ok(v.clone()));
+    assert_eq!(from_value::<Value>(&v), Ok(v));
+    assert_eq!(to_value(&read(":kw")), Ok(read("\"kw\"")));
+}
+
+#[test]
+fn structs() {
+    round_trip(Duration::new(1, 2), "{:secs 1 :nanos 2}");
+    // Field names become kebab-case keywords.
+    round_trip(
+        UNIX_EPOCH + Duration::new(3, 4),
+        "{:secs-since-epoch 3 :nanos-since-epoch 4}",
+    );
+}
+
+#[test]
+fn enums() {
+    // Variant names become kebab-case keywords.
+    round_trip(Ok::<i64, String>(1), "{:tag :ok :fields [1]}");
+    round_trip(Err::<i64, String>("e".to_string()), "{:tag :err :fields [\"e\"]}");
+
+    struct Unit;
+    impl Serialize for Unit {
+        fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+            s.serialize_unit_variant("Unit", 0, "NumArgs")
+        }
+    }
+    assert_eq!(to_value(&Unit), Ok(read("{:tag :num-args}")));
+
+    struct Variant;
+    impl Serialize for Variant {
+        fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+            let mut v = s.serialize_struct_variant("Variant", 0, "OutOfRange", 1)?;
+            v.serialize_field("max_value", &3)?;
+            v.end()
+        }
+    }
+    assert_eq!(to_value(&Variant), Ok(read("{:tag :out-of-range :max-value 3}")));
+}
+
+// A struct or struct variant with a single field of the given name.
+struct Field {
+    variant: bool,
+    name: &'static str,
+}
+
+impl Serialize for Field {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        if self.variant {
+            let mut v = s.serialize_struct_variant("Field", 0, "Field", 1)?;
+            v.serialize_field(self.name, &0)?;
+            v.end()
+        } else {
+            let mut v = s.serialize_struct("Field", 1)?;
+            v.serialize_field(self.name, &0)?;
+            v.end()
+        }
+    }
+}
+
+#[test]
+fn serialize_errors() {
+    assert_eq!(
+        ser_error(&u64::max_value()),
+        "18446744073709551615 can not be represented as a pavo int"
+    );
+    assert_eq!(ser_error(&std::f64::NAN), "NaN can not be represented as a pavo float");
+    assert_eq!(
+        ser_error(&Field { variant: false, name: "a b" }),
+        "\"a b\" can not be represented as a pavo keyword"
+    );
+    assert_eq!(
+        ser_error(&Field { variant: false, name: "" }),
+        "\"\" can not be represented as a pavo keyword"
+    );
+    assert_eq!(
+        ser_error(&Field { variant: true, name: "tag" }),
+        "a struct variant can not have a field named tag"
+    );
+    // Plain structs may have a field named `tag`.
+    assert_eq!(to_value(&Field { variant: false, name: "tag" }), Ok(read("{:tag 0}")));
+}
+
+#[test]
+fn deserialize_errors() {
+    assert_eq!(de_error::<i64>("\"a\""), "invalid type: string \"a\", expected i64");
+    assert_eq!(
+        de_error::<Result<i64, String>>("{:fields [1]}"),
+        "expected a map with a keyword under the key :tag"
+    );
+    assert_eq!(
+        de_error::<Result<i64, String>>("{:tag :maybe}"),
+        "unknown variant `maybe`, expected `Ok` or `Err`"
+    );
+    assert_eq!(
+        de_error::<Result<i64, String>>("{:tag :ok}"),
+        "expected a map with the key :fields"
+    );
+    assert_eq!(de_error::<Result<i64, String>>("[]"), "expected a keyword or a tagged map");
+    assert_eq!(de_error::<SystemTime>("{:secs-since-epoch 3}"), "missing field `nanos_since_epoch`");
+}
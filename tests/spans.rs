@@ -0,0 +1,54 @@
+//! The source positions reported for static errors.
+
+use pavo_bootstrap::{ExecuteError, Interpreter};
+
+// The line and column at which evaluating the source fails.
+fn error_at(src: &str) -> (u32, usize) {
+    match Interpreter::new().eval(src) {
+        Err(ExecuteError::E(err)) => {
+            let span = err.span().expect("error without a span");
+            (span.start.line, span.start.column)
+        }
+        other => panic!("expected an error, got {:?}", other),
+    }
+}
+
+#[test]
+fn duplicated_free_identifier() {
+    let src = "(sf-do [
+  (sf-lambda [x] x)
+  x
+])";
+    assert_eq!(error_at(src), (3, 3));
+}
+
+#[test]
+fn duplicated_immutable_assignment() {
+    let src = "(sf-do [
+  (sf-lambda [(:mut x)] (sf-set! x 1))
+  (sf-lambda [x] (sf-set! x 2))
+])";
+    assert_eq!(error_at(src), (3, 27));
+}
+
+#[test]
+fn duplicated_culprit() {
+    let src = "(sf-do [
+  (sf-quote 1)
+  (sf-set! 1 2)
+])";
+    assert_eq!(error_at(src), (3, 12));
+}
+
+#[test]
+fn through_macro() {
+    // `let` moves the body in front of the bound value, the body's `y` is reported first.
+    let src = "(let x y
+  (sf-do [x y]))";
+    assert_eq!(error_at(src), (2, 13));
+}
+
+#[test]
+fn later_toplevel_form() {
+    assert_eq!(error_at("(def a 1)\n(def b c)"), (2, 8));
+}